@@ -0,0 +1,422 @@
+/*
+ * Copyright 2020 Alistair Francis <alistair@alistair23.me>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *    http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Times the laps of a recording against a course, without the UI.
+//! This uses the same timing as the drive page.
+
+use crate::drive::course::Course;
+use crate::drive::fix_quality::FixLimits;
+use crate::drive::gate::GateSettings;
+use crate::drive::read_track::{get_long_and_lat, get_track_points, TrackPoint};
+use crate::drive::replay::add_run_up;
+use crate::drive::timing::{LapTimer, TimingEvent};
+use crate::session_log::{Channel, SessionReader};
+use crate::utils::{duration_to_gps_time, format_time, gps_time_to_duration};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::time::Duration;
+
+pub struct Lap {
+    pub time: Duration,
+    /// The sector times, None if we missed a segment
+    pub sectors: Vec<Option<Duration>>,
+    /// The GPS fix was poor during the lap
+    pub suspect: bool,
+}
+
+pub struct Analysis {
+    pub laps: Vec<Lap>,
+    pub num_sectors: usize,
+}
+
+/// The best, worst and mean of some times
+type Summary = (Duration, Duration, Duration);
+
+fn summary(times: &[Duration]) -> Option<Summary> {
+    let best = *times.iter().min()?;
+    let worst = *times.iter().max()?;
+    let mean = times.iter().sum::<Duration>() / times.len() as u32;
+
+    Some((best, worst, mean))
+}
+
+impl Analysis {
+    fn lap_times(&self) -> Vec<Duration> {
+        self.laps.iter().map(|lap| lap.time).collect()
+    }
+
+    fn sector_times(&self, sector: usize) -> Vec<Duration> {
+        self.laps
+            .iter()
+            .filter_map(|lap| lap.sectors.get(sector).copied().flatten())
+            .collect()
+    }
+
+    /// The best, worst and mean lap times
+    pub fn summary(&self) -> Option<Summary> {
+        summary(&self.lap_times())
+    }
+
+    /// How consistent the lap times are, as a percentage. This is 100%
+    /// minus the coefficient of variation of the lap times, so 100% means
+    /// every lap took the same time. Needs at least two laps.
+    pub fn consistency(&self) -> Option<f64> {
+        if self.laps.len() < 2 {
+            return None;
+        }
+
+        let times: Vec<f64> = self.laps.iter().map(|l| l.time.as_secs_f64()).collect();
+        let mean = times.iter().sum::<f64>() / times.len() as f64;
+        let variance = times.iter().map(|t| (t - mean).powi(2)).sum::<f64>() / times.len() as f64;
+
+        Some((100.0 * (1.0 - variance.sqrt() / mean)).max(0.0))
+    }
+}
+
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.laps.is_empty() {
+            return writeln!(f, "No laps were completed");
+        }
+
+        write!(f, "{:<6} {:>9}", "Lap", "Time")?;
+        for sector in 0..self.num_sectors {
+            write!(f, " {:>9}", format!("S{}", sector + 1))?;
+        }
+        writeln!(f)?;
+
+        for (i, lap) in self.laps.iter().enumerate() {
+            write!(f, "{:<6} {:>9}", i + 1, format_time(lap.time))?;
+            for sector in 0..self.num_sectors {
+                match lap.sectors.get(sector).copied().flatten() {
+                    Some(time) => write!(f, " {:>9}", format_time(time))?,
+                    None => write!(f, " {:>9}", "-")?,
+                }
+            }
+            if lap.suspect {
+                write!(f, "  (poor GPS fix)")?;
+            }
+            writeln!(f)?;
+        }
+
+        let laps = self.summary().unwrap();
+        let sectors: Vec<Option<Summary>> = (0..self.num_sectors)
+            .map(|sector| summary(&self.sector_times(sector)))
+            .collect();
+
+        for (i, name) in ["Best", "Worst", "Mean"].iter().enumerate() {
+            let pick = |times: &Summary| [times.0, times.1, times.2][i];
+
+            write!(f, "{:<6} {:>9}", name, format_time(pick(&laps)))?;
+            for sector in sectors.iter() {
+                match sector {
+                    Some(times) => write!(f, " {:>9}", format_time(pick(times)))?,
+                    None => write!(f, " {:>9}", "-")?,
+                }
+            }
+            writeln!(f)?;
+        }
+
+        match self.consistency() {
+            Some(consistency) => writeln!(f, "Consistency: {:.1}%", consistency),
+            None => writeln!(f, "Consistency: -"),
+        }
+    }
+}
+
+/// Time the laps driven in `points` on `course`. Laps driven
+/// with a fix outside of `limits` are marked as suspect.
+pub fn analyze_points(course: &mut Course, points: &[TrackPoint], limits: &FixLimits) -> Analysis {
+    let num_sectors = course.segments.len();
+    let mut timer = LapTimer::new(course);
+    let mut laps = Vec::new();
+    let mut sectors = vec![None; num_sectors];
+
+    for point in points {
+        let time = match point.time.as_deref().and_then(gps_time_to_duration) {
+            Some(time) => time,
+            None => continue,
+        };
+        let track = point.head.unwrap_or(0.0);
+        let quality = limits.check_point(point);
+
+        for event in timer.update(course, point.lat, point.lon, track, time, quality) {
+            match event {
+                TimingEvent::Started => sectors = vec![None; num_sectors],
+                TimingEvent::Sector(sector_time) => {
+                    if let Some(sector) = sectors.get_mut(sector_time.sector) {
+                        *sector = Some(sector_time.time);
+                    }
+                }
+                TimingEvent::Lap(_, time, suspect) => laps.push(Lap {
+                    time,
+                    sectors: sectors.clone(),
+                    suspect,
+                }),
+                TimingEvent::Finished => {}
+            }
+        }
+    }
+
+    Analysis { laps, num_sectors }
+}
+
+/// Read the GPS fixes from a session log. Older logs don't have the
+/// GPS time of each fix, so the time it was logged is used instead.
+/// The laps are timed again from the fixes, the lap records in the
+/// log are from the course that was driven at the time.
+fn session_points(recording: &Path) -> io::Result<Vec<TrackPoint>> {
+    let reader = SessionReader::open(recording)?;
+    let start_time = reader.start_time;
+
+    Ok(reader
+        .filter(|record| record.channel == Channel::Gps)
+        .filter_map(|record| {
+            // lat, lon, alt, speed, track, status, fix time, hdop, sats, error
+            let values = record.values();
+            if values.len() < 6 {
+                return None;
+            }
+            // Missing values are logged as NaN
+            let optional = |i: usize| values.get(i).copied().filter(|v| v.is_finite());
+
+            let time = match optional(6) {
+                Some(time) if time >= 0.0 => Duration::from_secs_f64(time),
+                _ => start_time + record.time,
+            };
+
+            Some(TrackPoint {
+                lat: values[0],
+                lon: values[1],
+                ele: Some(values[2] as f32),
+                time: Some(duration_to_gps_time(time)),
+                head: Some(values[4] as f32),
+                speed: Some(values[3] as f32),
+                status: Some(values[5] as i32),
+                hdop: optional(7).map(|hdop| hdop as f32),
+                sats: optional(8).map(|sats| sats as u32),
+                error: optional(9).map(|error| error as f32),
+                gate: GateSettings::default(),
+            })
+        })
+        .collect())
+}
+
+/// Time the laps of the recording `recording` (a GPX file or a
+/// `.dslog` session log) on the course from the track file `course`
+pub fn analyze(
+    course: &Path,
+    recording: &Path,
+    circuit: bool,
+    limits: &FixLimits,
+) -> Result<Analysis, String> {
+    let fd = File::open(course).map_err(|e| format!("Unable to open {:?}: {}", course, e))?;
+    let track_points = get_long_and_lat(BufReader::new(fd))
+        .map_err(|e| format!("Unable to read {:?}: {}", course, e))?;
+    if track_points.is_empty() {
+        return Err(format!("{:?} doesn't contain a course", course));
+    }
+    let mut course = Course::from_track_points(&track_points, circuit);
+
+    let mut points: Vec<TrackPoint> = if recording.extension() == Some("dslog".as_ref()) {
+        session_points(recording).map_err(|e| format!("Unable to read {:?}: {}", recording, e))?
+    } else {
+        let fd =
+            File::open(recording).map_err(|e| format!("Unable to open {:?}: {}", recording, e))?;
+        get_track_points(BufReader::new(fd))
+            .map_err(|e| format!("Unable to read {:?}: {}", recording, e))?
+            .into_iter()
+            .flatten()
+            .collect()
+    };
+    add_run_up(&mut points);
+
+    Ok(analyze_points(&mut course, &points, limits))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_consistency() {
+        let lap = |secs| Lap {
+            time: Duration::from_secs(secs),
+            sectors: vec![Some(Duration::from_secs(secs / 2)), None],
+            suspect: secs == 54,
+        };
+        let analysis = Analysis {
+            laps: vec![lap(60), lap(66), lap(54)],
+            num_sectors: 2,
+        };
+
+        assert_eq!(
+            analysis.summary(),
+            Some((
+                Duration::from_secs(54),
+                Duration::from_secs(66),
+                Duration::from_secs(60)
+            ))
+        );
+        let consistency = analysis.consistency().unwrap();
+        assert!((consistency - 91.835).abs() < 0.01);
+
+        let table = analysis.to_string();
+        assert!(table.contains("Worst  01:06:000 00:33:000         -"));
+        assert!(table.contains("3      00:54:000 00:27:000         -  (poor GPS fix)"));
+        assert!(table.contains("Consistency: 91.8%"));
+    }
+
+    #[test]
+    fn test_analyze_tracks() {
+        let analyze_track = |name| {
+            let file = format!("tests/test-track-{}", name);
+            analyze(
+                Path::new(&file),
+                Path::new(&file),
+                false,
+                &FixLimits::default(),
+            )
+            .unwrap()
+        };
+        let secs = |secs| Some(Duration::from_secs(secs));
+
+        // Driving the recorded track is one lap, split into the
+        // recorded segments
+        let analysis = analyze_track("backyard");
+        assert_eq!(analysis.laps.len(), 1);
+        assert_eq!(analysis.laps[0].time, Duration::from_secs(32));
+        assert_eq!(analysis.laps[0].sectors, vec![secs(11), secs(14), secs(7)]);
+
+        let analysis = analyze_track("neighbourhood");
+        assert_eq!(analysis.laps.len(), 1);
+        assert_eq!(analysis.laps[0].time, Duration::from_secs(1132));
+        assert_eq!(
+            analysis.laps[0].sectors,
+            vec![
+                secs(78),
+                secs(174),
+                secs(170),
+                secs(144),
+                secs(294),
+                secs(272)
+            ]
+        );
+
+        let analysis = analyze_track("cowpalace");
+        assert_eq!(analysis.laps.len(), 1);
+        assert_eq!(analysis.laps[0].time, Duration::from_secs(800));
+
+        // The start and finish aren't at the same place, so there
+        // are no laps if this is treated as a circuit
+        let file = Path::new("tests/test-track-carpark");
+        let analysis = analyze(file, file, true, &FixLimits::default()).unwrap();
+        assert!(analysis.laps.is_empty());
+        assert_eq!(analysis.to_string(), "No laps were completed\n");
+    }
+
+    #[test]
+    fn test_suspect_lap() {
+        let file = Path::new("tests/test-track-backyard");
+        let track_points = get_long_and_lat(BufReader::new(File::open(file).unwrap())).unwrap();
+        let mut points: Vec<TrackPoint> =
+            get_track_points(BufReader::new(File::open(file).unwrap()))
+                .unwrap()
+                .into_iter()
+                .flatten()
+                .collect();
+        add_run_up(&mut points);
+        let limits = FixLimits::default();
+
+        let mut course = Course::from_track_points(&track_points, false);
+        let analysis = analyze_points(&mut course, &points, &limits);
+        assert!(!analysis.laps[0].suspect);
+
+        // Lose the fix for a moment halfway round. The lap
+        // still counts, but is suspect.
+        let middle = points.len() / 2;
+        for point in points[middle..middle + 3].iter_mut() {
+            point.status = Some(0);
+        }
+        let mut course = Course::from_track_points(&track_points, false);
+        let analysis = analyze_points(&mut course, &points, &limits);
+        assert_eq!(analysis.laps.len(), 1);
+        assert_eq!(analysis.laps[0].time, Duration::from_secs(32));
+        assert!(analysis.laps[0].suspect);
+
+        // A poor fix also makes the lap suspect
+        for point in points[middle..middle + 3].iter_mut() {
+            point.status = None;
+        }
+        points[middle].hdop = Some(5.0);
+        let mut course = Course::from_track_points(&track_points, false);
+        let analysis = analyze_points(&mut course, &points, &limits);
+        assert!(analysis.laps[0].suspect);
+    }
+
+    #[test]
+    fn test_analyze_session_log() {
+        use crate::session_log::SessionLog;
+
+        let file = Path::new("tests/test-track-backyard");
+        let log_file = std::env::temp_dir().join("dashsight-test-analyze-session.dslog");
+        let mut log = SessionLog::create(&log_file).unwrap();
+        let mut points: Vec<TrackPoint> =
+            get_track_points(BufReader::new(File::open(file).unwrap()))
+                .unwrap()
+                .into_iter()
+                .flatten()
+                .collect();
+        // A poor fix halfway round
+        let middle = points.len() / 2;
+        points[middle].hdop = Some(5.0);
+
+        for point in points.iter() {
+            let time = point
+                .time
+                .as_deref()
+                .and_then(gps_time_to_duration)
+                .unwrap();
+            let values = [
+                point.lat,
+                point.lon,
+                point.ele.unwrap_or(0.0) as f64,
+                point.speed.unwrap_or(0.0) as f64,
+                point.head.unwrap_or(0.0) as f64,
+                1.0,
+                time.as_secs_f64(),
+                point.hdop.map_or(f64::NAN, |hdop| hdop as f64),
+                f64::NAN,
+                f64::NAN,
+            ];
+            log.write_values(Channel::Gps, &values).unwrap();
+            log.write_values(Channel::Imu, &[0.0; 6]).unwrap();
+        }
+        drop(log);
+
+        // The log is written much faster than the track was driven,
+        // but the fix times give the same lap as the track file
+        let analysis = analyze(file, &log_file, false, &FixLimits::default()).unwrap();
+        assert_eq!(analysis.laps.len(), 1);
+        assert_eq!(analysis.num_sectors, 3);
+        assert_eq!(analysis.laps[0].time, Duration::from_secs(32));
+        assert!(analysis.laps[0].suspect);
+
+        std::fs::remove_file(&log_file).unwrap();
+    }
+}
@@ -0,0 +1,605 @@
+/*
+ * Copyright 2020 Alistair Francis <alistair@alistair23.me>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *    http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Creates a course from a recording of a few laps of a circuit.
+//!
+//! The laps are found by looking for where the recording comes back
+//! to the same place, heading the same way. The start/finish line is
+//! put on the straightest part of the lap and the fastest lap is split
+//! into sectors. The course is written as a track file, with a track
+//! segment for each sector, so it can be loaded on the Drive page
+//! as a circuit.
+
+use crate::drive::read_track::{get_track_points, TrackPoint};
+use crate::record::print;
+use crate::utils::{gps_time_to_duration, lat_lon_bearing, lat_lon_distance, GpsData};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::time::Duration;
+
+/// How close, in metres, we need to come back to a point
+/// to have driven a lap
+const CLOSE_DISTANCE: f64 = 20.0;
+
+/// The shortest lap we look for, in metres
+const MIN_LAP_LENGTH: f64 = 300.0;
+
+/// The longest lap we look for, in metres. This is longer than the
+/// Nordschleife and stops us searching the whole recording from
+/// every point when there isn't a lap.
+const MAX_LAP_LENGTH: f64 = 30_000.0;
+
+/// How different the heading can be, in degrees, when we come
+/// back to a point for it to be the same direction
+const MAX_HEADING_DIFFERENCE: f64 = 45.0;
+
+/// The distance either side of a point, in metres, used to
+/// work out how much the track turns there
+const TURN_WINDOW: f64 = 30.0;
+
+/// The turn, in degrees, over `TURN_WINDOW` either side of a point
+/// for it to be part of a corner
+const CORNER_ANGLE: f64 = 30.0;
+
+/// The most the track can turn, in degrees over `TURN_WINDOW` either
+/// side of a point, for it to be a straight
+const STRAIGHT_ANGLE: f64 = 5.0;
+
+/// The shortest sector, in metres
+const MIN_SECTOR_LENGTH: f64 = 100.0;
+
+/// How to split the lap into sectors
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sectors {
+    /// This many sectors of the same length
+    Even(usize),
+    /// A sector for each corner, split on the straights between them
+    Corners,
+}
+
+pub struct ProposedCourse {
+    /// How many laps were found in the recording
+    pub laps: usize,
+    /// The time of the lap used for the course, if the points have times
+    pub lap_time: Option<Duration>,
+    /// The length of the lap, in metres
+    pub length: f64,
+    /// The points of the lap, split into sectors. The first point is
+    /// on the start/finish line.
+    pub sectors: Vec<Vec<TrackPoint>>,
+}
+
+/// The difference between two headings, in degrees
+fn heading_difference(a: f64, b: f64) -> f64 {
+    let diff = (a - b).abs() % 360.0;
+
+    diff.min(360.0 - diff)
+}
+
+/// The points of a recording, along with the distance driven
+/// to each point and the heading at each point
+struct Route {
+    points: Vec<TrackPoint>,
+    distance: Vec<f64>,
+    heading: Vec<f64>,
+}
+
+impl Route {
+    fn new(points: Vec<TrackPoint>) -> Route {
+        // Drop the points where we didn't move
+        let mut moving: Vec<TrackPoint> = Vec::new();
+        for point in points {
+            match moving.last() {
+                Some(last) if last.lat == point.lat && last.lon == point.lon => {}
+                _ => moving.push(point),
+            }
+        }
+
+        let mut distance = Vec::with_capacity(moving.len());
+        let mut heading = Vec::with_capacity(moving.len());
+        let mut total = 0.0;
+
+        for (i, point) in moving.iter().enumerate() {
+            if i > 0 {
+                let prev = &moving[i - 1];
+                total += lat_lon_distance(prev.lat, prev.lon, point.lat, point.lon);
+            }
+            distance.push(total);
+
+            let bearing = match (i.checked_sub(1).map(|p| &moving[p]), moving.get(i + 1)) {
+                (_, Some(next)) => lat_lon_bearing(point.lat, point.lon, next.lat, next.lon),
+                (Some(prev), None) => lat_lon_bearing(prev.lat, prev.lon, point.lat, point.lon),
+                (None, None) => 0.0,
+            };
+            heading.push(point.head.map(|h| h as f64).unwrap_or(bearing));
+        }
+
+        // The course needs a heading for every point
+        for (point, heading) in moving.iter_mut().zip(heading.iter()) {
+            point.head.get_or_insert(*heading as f32);
+        }
+
+        Route {
+            points: moving,
+            distance,
+            heading,
+        }
+    }
+
+    fn distance_between(&self, a: usize, b: usize) -> f64 {
+        let a = &self.points[a];
+        let b = &self.points[b];
+
+        lat_lon_distance(a.lat, a.lon, b.lat, b.lon)
+    }
+
+    /// Find where we come back to the point at `from`, going the
+    /// same way, after at least one lap.
+    fn closure(&self, from: usize) -> Option<usize> {
+        let mut found: Option<usize> = None;
+
+        for i in from + 1..self.points.len() {
+            let travelled = self.distance[i] - self.distance[from];
+            if travelled > MAX_LAP_LENGTH {
+                break;
+            }
+            if travelled < MIN_LAP_LENGTH {
+                continue;
+            }
+
+            let close = self.distance_between(from, i) < CLOSE_DISTANCE
+                && heading_difference(self.heading[from], self.heading[i]) < MAX_HEADING_DIFFERENCE;
+
+            match found {
+                Some(_) if !close => break,
+                // Keep going while we get closer
+                Some(best)
+                    if self.distance_between(from, i) < self.distance_between(from, best) =>
+                {
+                    found = Some(i)
+                }
+                Some(_) => {}
+                None if close => found = Some(i),
+                None => {}
+            }
+        }
+
+        found
+    }
+
+    /// How much the track turns at `index`, in degrees
+    fn turn(&self, index: usize, start: usize, end: usize) -> f64 {
+        let mut before = index;
+        while before > start && self.distance[index] - self.distance[before] < TURN_WINDOW {
+            before -= 1;
+        }
+        let mut after = index;
+        while after < end && self.distance[after] - self.distance[index] < TURN_WINDOW {
+            after += 1;
+        }
+
+        let a = &self.points[before];
+        let p = &self.points[index];
+        let b = &self.points[after];
+
+        // At the ends use the heading at the point instead
+        let heading_in = match before == index {
+            true => self.heading[index],
+            false => lat_lon_bearing(a.lat, a.lon, p.lat, p.lon),
+        };
+        let heading_out = match after == index {
+            true => self.heading[index],
+            false => lat_lon_bearing(p.lat, p.lon, b.lat, b.lon),
+        };
+
+        heading_difference(heading_in, heading_out)
+    }
+
+    fn time(&self, index: usize) -> Option<Duration> {
+        self.points[index]
+            .time
+            .as_deref()
+            .and_then(gps_time_to_duration)
+    }
+
+    fn lap_time(&self, start: usize, end: usize) -> Option<Duration> {
+        self.time(end)?.checked_sub(self.time(start)?)
+    }
+}
+
+/// Find the middle of the longest straight between `start` and `end`
+fn longest_straight(route: &Route, start: usize, end: usize) -> Option<usize> {
+    let mut longest: Option<(usize, usize)> = None;
+    let mut straight_start = None;
+
+    for i in start..=end {
+        let straight = i < end && route.turn(i, start, end) < STRAIGHT_ANGLE;
+        match (straight_start, straight) {
+            (None, true) => straight_start = Some(i),
+            (Some(s), false) => {
+                let length = |(s, e): (usize, usize)| route.distance[e] - route.distance[s];
+                match longest {
+                    Some(l) if length(l) >= length((s, i - 1)) => {}
+                    _ => longest = Some((s, i - 1)),
+                }
+                straight_start = None;
+            }
+            _ => {}
+        }
+    }
+
+    let (s, e) = longest?;
+    let middle = (route.distance[s] + route.distance[e]) / 2.0;
+    (s..=e).find(|i| route.distance[*i] >= middle)
+}
+
+/// Split the lap from `start` to `end` into sectors, returns the
+/// index where each sector starts
+fn sector_starts(route: &Route, start: usize, end: usize, sectors: Sectors) -> Vec<usize> {
+    let length = route.distance[end] - route.distance[start];
+    let index_at = |distance: f64| {
+        (start..end)
+            .find(|i| route.distance[*i] - route.distance[start] >= distance)
+            .unwrap_or(end)
+    };
+
+    let mut starts = match sectors {
+        Sectors::Even(count) => (0..count.max(1))
+            .map(|i| index_at(length * i as f64 / count.max(1) as f64))
+            .collect(),
+        Sectors::Corners => {
+            let turns: Vec<f64> = (start..end).map(|i| route.turn(i, start, end)).collect();
+
+            // Find where each corner starts and ends
+            let mut corners: Vec<(usize, usize)> = Vec::new();
+            let mut corner_start = None;
+            for (i, turn) in turns.iter().enumerate() {
+                match (corner_start, *turn >= CORNER_ANGLE) {
+                    (None, true) => corner_start = Some(i),
+                    (Some(s), false) => {
+                        corners.push((s, i));
+                        corner_start = None;
+                    }
+                    _ => {}
+                }
+            }
+
+            // Split on the straightest point between two corners
+            let mut starts = vec![start];
+            for pair in corners.windows(2) {
+                let (from, to) = (pair[0].1, pair[1].0);
+                let straightest = (from..to)
+                    .min_by(|a, b| turns[*a].partial_cmp(&turns[*b]).unwrap())
+                    .unwrap_or(from);
+                starts.push(start + straightest);
+            }
+            starts
+        }
+    };
+
+    // Don't create tiny sectors
+    starts.dedup();
+    let mut merged: Vec<usize> = Vec::new();
+    for index in starts {
+        match merged.last() {
+            Some(last) if route.distance[index] - route.distance[*last] < MIN_SECTOR_LENGTH => {}
+            _ => merged.push(index),
+        }
+    }
+    while merged.len() > 1
+        && route.distance[end] - route.distance[*merged.last().unwrap()] < MIN_SECTOR_LENGTH
+    {
+        merged.pop();
+    }
+
+    merged
+}
+
+/// Find the laps in `points` and propose a course
+pub fn propose(points: Vec<TrackPoint>, sectors: Sectors) -> Result<ProposedCourse, String> {
+    let route = Route::new(points);
+
+    // Find the first lap
+    let (first_start, first_end) = (0..route.points.len())
+        .find_map(|i| route.closure(i).map(|end| (i, end)))
+        .ok_or_else(|| "Unable to find a lap in the recording".to_string())?;
+
+    // Put the start/finish line in the middle of the longest straight,
+    // as long as we can still find a lap from there
+    let start = longest_straight(&route, first_start, first_end)
+        .filter(|i| route.closure(*i).is_some())
+        .unwrap_or(first_start);
+
+    // Find all of the laps from the start/finish line
+    let mut laps: Vec<(usize, usize)> = Vec::new();
+    let mut lap_start = start;
+    while let Some(lap_end) = route.closure(lap_start) {
+        laps.push((lap_start, lap_end));
+        lap_start = lap_end;
+    }
+    if laps.is_empty() {
+        laps.push((first_start, first_end));
+    }
+
+    // Use the fastest lap, it's probably the best line
+    let (start, end) = *laps
+        .iter()
+        .min_by_key(|(s, e)| route.lap_time(*s, *e).unwrap_or(Duration::MAX))
+        .unwrap();
+
+    let starts = sector_starts(&route, start, end, sectors);
+    let mut sector_points = Vec::new();
+    for (i, sector_start) in starts.iter().enumerate() {
+        let sector_end = starts.get(i + 1).copied().unwrap_or(end);
+        sector_points.push(route.points[*sector_start..=sector_end].to_vec());
+    }
+
+    Ok(ProposedCourse {
+        laps: laps.len(),
+        lap_time: route.lap_time(start, end),
+        length: route.distance[end] - route.distance[start],
+        sectors: sector_points,
+    })
+}
+
+/// Write the course as a track file, with a track segment for each sector
+pub fn write_course(course: &ProposedCourse, file: &Path) -> Result<(), std::io::Error> {
+    let mut fd = File::create(file)?;
+
+    print::gpx_start(&mut fd)?;
+    print::gpx_metadata(&mut fd)?;
+    let name = file
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("course");
+    print::gpx_track_start(&mut fd, name.to_string())?;
+
+    for sector in &course.sectors {
+        print::gpx_track_seg_start(&mut fd)?;
+        for point in sector {
+            let fix = GpsData {
+                lat: point.lat,
+                lon: point.lon,
+                alt: point.ele.unwrap_or(0.0),
+                status: point.status.unwrap_or(1),
+                time: point
+                    .time
+                    .clone()
+                    .unwrap_or_else(|| "1970-01-01T00:00:00.000Z".to_string()),
+                speed: point.speed.unwrap_or(0.0),
+                track: point.head.unwrap_or(0.0),
+                hdop: point.hdop,
+                sats: point.sats,
+                error: point.error,
+            };
+            print::gpx_point_info(&mut fd, &fix, None)?;
+        }
+        print::gpx_track_seg_stop(&mut fd)?;
+    }
+
+    print::gpx_track_stop(&mut fd)?;
+    print::gpx_stop(&mut fd)?;
+    fd.sync_all()
+}
+
+/// Create a course from the recording `recording` and save it to `course`
+pub fn create(recording: &Path, course: &Path, sectors: Sectors) -> Result<ProposedCourse, String> {
+    let fd = File::open(recording).map_err(|e| format!("Unable to open {:?}: {}", recording, e))?;
+    let points: Vec<TrackPoint> = get_track_points(BufReader::new(fd))
+        .map_err(|e| format!("Unable to read {:?}: {}", recording, e))?
+        .into_iter()
+        .flatten()
+        .collect();
+
+    let proposed = propose(points, sectors)?;
+    write_course(&proposed, course).map_err(|e| format!("Unable to write {:?}: {}", course, e))?;
+
+    Ok(proposed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze::analyze;
+    use crate::drive::fix_quality::FixLimits;
+    use std::f64::consts::FRAC_PI_2;
+    use std::time::UNIX_EPOCH;
+
+    /// A 400m by 200m rectangle, with rounded corners, as (x, y) in
+    /// metres every 5 metres along the track. This starts at the
+    /// start of the bottom straight and goes anticlockwise.
+    fn rectangle() -> Vec<(f64, f64)> {
+        let (width, height, radius) = (400.0, 200.0, 30.0);
+        let mut points = Vec::new();
+
+        // Each straight, followed by the centre of the corner at the end
+        // of it and the angle the corner starts at
+        let straights = [
+            ((radius, 0.0), (1.0, 0.0), (width - radius, radius)),
+            (
+                (width, radius),
+                (0.0, 1.0),
+                (width - radius, height - radius),
+            ),
+            (
+                (width - radius, height),
+                (-1.0, 0.0),
+                (radius, height - radius),
+            ),
+            ((0.0, height - radius), (0.0, -1.0), (radius, radius)),
+        ];
+
+        for (i, ((x, y), (dx, dy), (cx, cy))) in straights.iter().enumerate() {
+            let length = if i % 2 == 0 { width } else { height } - 2.0 * radius;
+            for d in (0..length as usize).step_by(5) {
+                points.push((x + dx * d as f64, y + dy * d as f64));
+            }
+
+            let start = (i as f64 - 1.0) * FRAC_PI_2;
+            for d in (0..(FRAC_PI_2 * radius) as usize).step_by(5) {
+                let angle = start + d as f64 / radius;
+                points.push((cx + radius * angle.cos(), cy + radius * angle.sin()));
+            }
+        }
+
+        points
+    }
+
+    /// Write a recording of driving out of the pits and then a lap of the
+    /// rectangle at each of the `speeds`, in metres per second
+    fn write_recording(file: &Path, speeds: &[f64]) {
+        let lap = rectangle();
+        // Where the pit lane joins the bottom straight
+        let join = lap.iter().position(|(x, _)| *x >= 150.0).unwrap();
+
+        let mut points: Vec<(f64, f64, f64)> = (0..5)
+            .map(|i| (100.0 + 10.0 * i as f64, -20.0 + 4.0 * i as f64, 10.0))
+            .collect();
+        for (i, speed) in speeds.iter().enumerate() {
+            let skip = if i == 0 { join } else { 0 };
+            points.extend(lap.iter().skip(skip).map(|(x, y)| (*x, *y, *speed)));
+        }
+        // A slow lap back to the pits
+        let in_lap = lap.iter().chain(lap.iter().take(join));
+        points.extend(in_lap.map(|(x, y)| (*x, *y, 10.0)));
+
+        let mut fd = File::create(file).unwrap();
+        print::gpx_start(&mut fd).unwrap();
+        print::gpx_track_start(&mut fd, "laps".to_string()).unwrap();
+        print::gpx_track_seg_start(&mut fd).unwrap();
+
+        let mut time = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        for (i, (x, y, speed)) in points.iter().enumerate() {
+            let (next_x, next_y, _) = points.get(i + 1).unwrap_or_else(|| &points[i - 1]);
+            let (dx, dy) = (next_x - x, next_y - y);
+            let mut track = dx.atan2(dy).to_degrees();
+            if i + 1 == points.len() {
+                track += 180.0;
+            }
+
+            let utc: chrono::DateTime<chrono::Utc> = time.into();
+            let fix = GpsData {
+                lat: -37.8 + y / 111_320.0,
+                lon: 145.0 + x / (111_320.0 * (-37.8_f64).to_radians().cos()),
+                alt: 0.0,
+                status: 1,
+                time: utc.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+                speed: *speed as f32,
+                track: ((track + 360.0) % 360.0) as f32,
+                hdop: None,
+                sats: None,
+                error: None,
+            };
+            print::gpx_point_info(&mut fd, &fix, None).unwrap();
+
+            if i + 1 < points.len() {
+                time += Duration::from_secs_f64((dx * dx + dy * dy).sqrt() / speed);
+            }
+        }
+
+        print::gpx_track_seg_stop(&mut fd).unwrap();
+        print::gpx_track_stop(&mut fd).unwrap();
+        print::gpx_stop(&mut fd).unwrap();
+    }
+
+    #[test]
+    fn test_create_course() {
+        let dir = std::env::temp_dir();
+        let recording = dir.join("dashsight-auto-course-recording.gpx");
+        let course = dir.join("dashsight-auto-course.gpx");
+        write_recording(&recording, &[20.0, 25.0, 22.0]);
+
+        let proposed = create(&recording, &course, Sectors::Even(4)).unwrap();
+        assert_eq!(proposed.laps, 3);
+        assert!((proposed.length - 1148.5).abs() < 10.0);
+        // The fastest lap is used, which is half of the lap
+        // at 25m/s and half at 22m/s
+        let lap_time = proposed.lap_time.unwrap().as_secs_f64();
+        assert!((lap_time - (574.25 / 25.0 + 574.25 / 22.0)).abs() < 1.0);
+        assert_eq!(proposed.sectors.len(), 4);
+
+        // The start/finish line is in the middle of the top straight
+        let start = &proposed.sectors[0][0];
+        assert!((start.lon - 145.002_27).abs() < 0.000_01);
+        assert!((start.head.unwrap() - 270.0).abs() < 1.0);
+
+        // Timing the recording on the new course finds each lap
+        let analysis = analyze(&course, &recording, true, &FixLimits::default()).unwrap();
+        assert_eq!(analysis.num_sectors, 4);
+        assert_eq!(analysis.laps.len(), 3);
+        assert!(analysis
+            .laps
+            .iter()
+            .all(|lap| lap.sectors.iter().all(|s| s.is_some())));
+        let best = analysis.summary().unwrap().0.as_secs_f64();
+        assert!((best - lap_time).abs() < 1.0);
+
+        // There is a sector for each corner
+        let proposed = create(&recording, &course, Sectors::Corners).unwrap();
+        assert_eq!(proposed.sectors.len(), 4);
+        let analysis = analyze(&course, &recording, true, &FixLimits::default()).unwrap();
+        assert_eq!(analysis.laps.len(), 3);
+
+        std::fs::remove_file(recording).unwrap();
+        std::fs::remove_file(course).unwrap();
+    }
+
+    #[test]
+    fn test_no_laps() {
+        // The carpark recording doesn't come back to where it started
+        let recording = Path::new("tests/test-track-carpark");
+        let course = std::env::temp_dir().join("dashsight-auto-course-carpark.gpx");
+
+        assert!(create(recording, &course, Sectors::Corners).is_err());
+        assert!(!course.exists());
+    }
+
+    #[test]
+    fn test_max_lap_length() {
+        use crate::drive::gate::GateSettings;
+        use crate::utils::lat_lon_destination;
+
+        // A circle, with a point every 100m
+        let circle = |length: f64| -> Vec<TrackPoint> {
+            let num_points = (length / 100.0) as usize;
+            let radius = length / (2.0 * std::f64::consts::PI);
+
+            (0..=num_points)
+                .map(|i| {
+                    let angle = 360.0 * i as f64 / num_points as f64;
+                    let (lat, lon) = lat_lon_destination(37.0, -122.0, angle, radius);
+                    TrackPoint {
+                        lat,
+                        lon,
+                        ele: None,
+                        time: None,
+                        head: Some(((angle + 90.0) % 360.0) as f32),
+                        speed: None,
+                        status: None,
+                        hdop: None,
+                        sats: None,
+                        error: None,
+                        gate: GateSettings::default(),
+                    }
+                })
+                .collect()
+        };
+
+        assert_eq!(Route::new(circle(20_000.0)).closure(0), Some(200));
+        assert_eq!(Route::new(circle(40_000.0)).closure(0), None);
+    }
+}
@@ -0,0 +1,119 @@
+/*
+ * Copyright 2020 Alistair Francis <alistair@alistair23.me>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *    http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::drive::can::CanConfig;
+use crate::drive::fix_quality::FixLimits;
+use crate::drive::poll::PollRate;
+use crate::gps_source::GpsSource;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+
+/// The DashSight configuration.
+/// This is read from `$DASHSIGHT_CONFIG` if set, otherwise from
+/// `$XDG_CONFIG_HOME/dashsight/config.json` (or
+/// `~/.config/dashsight/config.json`). Any missing fields, or a
+/// missing file, fall back to the defaults.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Where to get the GPS data from
+    pub gps: GpsSource,
+    /// The serial device the ELM327 OBDII adapter is connected to
+    pub obdii_device: String,
+    /// The baud rate used to talk to the ELM327
+    pub obdii_baud: u32,
+    /// How often to poll each OBDII PID, by the name used in the
+    /// CSV header. PIDs that aren't listed use the default rates.
+    pub obdii_rates: HashMap<String, PollRate>,
+    /// Read the signals in a DBC file from the CAN bus, as well
+    /// as polling the car over OBDII
+    pub can: Option<CanConfig>,
+    /// Attach the OBDII and IMU data to each point of the recorded
+    /// GPX files. The Drive page also records a `-drive.gpx` file.
+    pub gpx_telemetry: bool,
+    /// How accurate the GPS fix needs to be to time laps. Laps
+    /// timed with a worse fix are marked as suspect.
+    pub fix_limits: FixLimits,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            gps: GpsSource::default(),
+            obdii_device: "/dev/ttyS3".to_string(),
+            obdii_baud: 38400,
+            obdii_rates: HashMap::new(),
+            can: None,
+            gpx_telemetry: false,
+            fix_limits: FixLimits::default(),
+        }
+    }
+}
+
+impl Config {
+    /// The directory containing the config file. Other state, such
+    /// as the GPX journals, is kept here as well.
+    pub fn dir() -> Option<PathBuf> {
+        let mut dir = Config::path()?;
+        dir.pop();
+        Some(dir)
+    }
+
+    fn path() -> Option<PathBuf> {
+        if let Some(path) = env::var_os("DASHSIGHT_CONFIG") {
+            return Some(PathBuf::from(path));
+        }
+
+        let mut path = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => {
+                let mut home = PathBuf::from(env::var_os("HOME")?);
+                home.push(".config");
+                home
+            }
+        };
+        path.push("dashsight");
+        path.push("config.json");
+
+        Some(path)
+    }
+
+    /// Load the configuration, using the defaults if there is no
+    /// config file or if it can't be parsed.
+    pub fn load() -> Config {
+        let path = match Config::path() {
+            Some(path) => path,
+            None => return Config::default(),
+        };
+
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(_) => return Config::default(),
+        };
+
+        match serde_json::from_reader(BufReader::new(file)) {
+            Ok(config) => config,
+            Err(e) => {
+                println!("Unable to parse {:?}, using defaults: {:?}", path, e);
+                Config::default()
+            }
+        }
+    }
+}
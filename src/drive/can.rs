@@ -0,0 +1,171 @@
+/*
+ * Copyright 2020 Alistair Francis <alistair@alistair23.me>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *    http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Passive logging of the CAN bus. A lot of cars broadcast values,
+//! such as the wheel speeds or steering angle, much faster than they
+//! can be polled over OBDII. The frames are read from a SocketCAN
+//! interface, nothing is ever sent, and the signals described in a
+//! DBC file are handled the same as the OBDII values.
+
+use crate::config::Config;
+use crate::drive::dbc::Dbc;
+use crate::drive::obdii::{OBDIICommandType, OBDIIData};
+use crate::drive::threading::ThreadingRef;
+use crate::session_log::Channel;
+use serde::{Deserialize, Serialize};
+use socketcan::CANSocket;
+use std::io;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant};
+
+static DBC: OnceLock<Dbc> = OnceLock::new();
+
+/// How long to wait for a frame before checking if we should stop
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The shortest time between sending the same signal to the UI.
+/// Every value is still logged.
+const DISPLAY_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CanConfig {
+    /// The SocketCAN interface, such as "can0" or "vcan0"
+    pub interface: String,
+    /// The DBC file describing the signals. Relative paths are
+    /// from the config directory.
+    pub dbc: PathBuf,
+    /// The signals to decode, by name. Every signal in the DBC
+    /// file is decoded if this is empty.
+    pub signals: Vec<String>,
+}
+
+impl Default for CanConfig {
+    fn default() -> Self {
+        CanConfig {
+            interface: "can0".to_string(),
+            dbc: PathBuf::from("car.dbc"),
+            signals: Vec::new(),
+        }
+    }
+}
+
+impl CanConfig {
+    fn dbc_path(&self) -> PathBuf {
+        match Config::dir() {
+            Some(dir) if self.dbc.is_relative() => dir.join(&self.dbc),
+            _ => self.dbc.clone(),
+        }
+    }
+}
+
+/// Load the signals from the DBC file in the config. This should
+/// be called once, before any CAN frames are read.
+pub fn load_dbc() {
+    let dbc = match Config::load().can {
+        Some(can) => match Dbc::load(&can.dbc_path()) {
+            Ok(dbc) => dbc.select(&can.signals),
+            Err(e) => {
+                println!("Unable to read {:?}: {}", can.dbc_path(), e);
+                Dbc::default()
+            }
+        },
+        None => Dbc::default(),
+    };
+
+    if DBC.set(dbc).is_err() {
+        println!("The DBC file has already been loaded");
+    }
+}
+
+/// The CAN signals, the index of each one is used in
+/// `OBDIICommandType::Can`.
+pub fn dbc() -> &'static Dbc {
+    DBC.get_or_init(Dbc::default)
+}
+
+pub fn can_thread(
+    thread_info: ThreadingRef,
+    obdii_tx: std::sync::mpsc::Sender<OBDIIData>,
+    config: &CanConfig,
+) {
+    let names: Vec<&str> = dbc().signals().map(|s| s.name.as_str()).collect();
+    if names.is_empty() {
+        println!("No CAN signals to decode in {:?}", config.dbc_path());
+        return;
+    }
+    thread_info.log(Channel::CanNames, names.join("\n").as_bytes());
+
+    // When each signal was last sent to the UI
+    let mut displayed: Vec<Option<Instant>> = vec![None; names.len()];
+
+    while !thread_info.close.lock().unwrap().get() {
+        let socket = match CANSocket::open(&config.interface) {
+            Ok(socket) => socket,
+            Err(e) => {
+                println!("Unable to open {}: {:?}", config.interface, e);
+                thread::sleep(Duration::from_secs(10));
+                continue;
+            }
+        };
+
+        if let Err(e) = socket.set_read_timeout(READ_TIMEOUT) {
+            println!("Unable to set the CAN timeout: {:?}", e);
+            return;
+        }
+
+        while !thread_info.close.lock().unwrap().get() {
+            let frame = match socket.read_frame() {
+                Ok(frame) => frame,
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    continue
+                }
+                Err(e) => {
+                    // The interface has gone down, try to reopen it
+                    println!("Lost connection to {}: {:?}", config.interface, e);
+                    thread::sleep(Duration::from_secs(1));
+                    break;
+                }
+            };
+
+            if frame.is_error() || frame.is_rtr() {
+                continue;
+            }
+
+            let now = Instant::now();
+            for (index, value) in dbc().decode(frame.id(), frame.is_extended(), frame.data()) {
+                thread_info.log_values(Channel::Can(index), &[value]);
+
+                let last = &mut displayed[index as usize];
+                if matches!(last, Some(last) if now.duration_since(*last) < DISPLAY_INTERVAL) {
+                    continue;
+                }
+                *last = Some(now);
+
+                let data = OBDIIData::from_float(OBDIICommandType::Can(index), value);
+                thread_info.telemetry.lock().unwrap().update_obdii(&data);
+                if obdii_tx.send(data).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,167 @@
+/*
+ * Copyright 2020 Alistair Francis <alistair@alistair23.me>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *    http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The clock used to time laps. Laps are timed using the GPS time of
+//! each fix, so they aren't affected by the system clock changing or
+//! by how long the fix took to reach us. If a fix doesn't have a time
+//! we carry on from the last GPS time using the monotonic clock.
+
+use crate::utils::gps_time_to_duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long a PPS pulse can be used to line up the fixes
+const MAX_PULSE_AGE: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+pub struct GpsClock {
+    /// A GPS time, since the Unix Epoch, and when it happened
+    anchor: Option<(Duration, Instant)>,
+    /// When the last PPS pulse arrived
+    pulse: Option<Instant>,
+}
+
+impl GpsClock {
+    pub fn new() -> GpsClock {
+        GpsClock::default()
+    }
+
+    /// A PPS pulse arrived at `at`. The pulse marks the start of a GPS
+    /// second, which is more precise than when the fix arrives.
+    pub fn pps(&mut self, at: Instant) {
+        self.pulse = Some(at);
+    }
+
+    /// Returns the time of a fix with the GPS time `time`
+    pub fn fix_time(&mut self, time: &str) -> Duration {
+        self.fix_time_at(time, Instant::now())
+    }
+
+    fn fix_time_at(&mut self, time: &str, at: Instant) -> Duration {
+        let time = match gps_time_to_duration(time) {
+            Some(time) if time.as_secs() > 0 => time,
+            _ => return self.now_at(at),
+        };
+
+        self.anchor = match self.pulse {
+            Some(pulse) if at.saturating_duration_since(pulse) < MAX_PULSE_AGE => {
+                // The fix was taken after the pulse, but arrived a
+                // bit later. The pulse was at the nearest second to
+                // when the fix was taken, less the time since the pulse.
+                let since_pulse = at.saturating_duration_since(pulse);
+                let second = time.checked_sub(since_pulse).unwrap_or_default();
+                let second = Duration::from_secs(second.as_secs_f64().round() as u64);
+                Some((second, pulse))
+            }
+            _ => Some((time, at)),
+        };
+
+        time
+    }
+
+    /// Returns when the GPS time `time` was on the monotonic clock,
+    /// if we have had a GPS time to line them up with
+    pub fn instant(&self, time: Duration) -> Option<Instant> {
+        let (anchor, at) = self.anchor?;
+
+        match time.checked_sub(anchor) {
+            Some(after) => at.checked_add(after),
+            None => at.checked_sub(anchor - time),
+        }
+    }
+
+    /// Returns the GPS time at `at`, carrying on from the last
+    /// fix with the monotonic clock. This is in whole milliseconds,
+    /// the same as the times in a GPX file.
+    fn now_at(&mut self, at: Instant) -> Duration {
+        let (time, since) = *self.anchor.get_or_insert_with(|| {
+            // We haven't had a GPS time yet, start from the system time
+            let time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            (time, at)
+        });
+
+        let time = time + at.saturating_duration_since(since);
+        Duration::from_millis(time.as_millis() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gps_time() {
+        let mut clock = GpsClock::new();
+        let start = Instant::now();
+
+        assert_eq!(
+            clock.fix_time_at("2020-10-17T14:49:10.250Z", start),
+            Duration::new(1602946150, 250_000_000)
+        );
+
+        // No time, carry on from the last fix
+        let later = start + Duration::from_millis(500);
+        assert_eq!(
+            clock.fix_time_at("1970-01-01T00:00:00.000Z", later),
+            Duration::new(1602946150, 750_000_000)
+        );
+        assert_eq!(
+            clock.fix_time_at("", later + Duration::from_millis(1)),
+            Duration::new(1602946150, 751_000_000)
+        );
+    }
+
+    #[test]
+    fn test_pps() {
+        let mut clock = GpsClock::new();
+        let pulse = Instant::now();
+
+        // The fix at .200 took 150ms to reach us
+        clock.pps(pulse);
+        let arrived = pulse + Duration::from_millis(350);
+        assert_eq!(
+            clock.fix_time_at("2020-10-17T14:49:10.200Z", arrived),
+            Duration::new(1602946150, 200_000_000)
+        );
+
+        // Carrying on from the pulse doesn't include the delay
+        assert_eq!(
+            clock.now_at(pulse + Duration::from_millis(400)),
+            Duration::new(1602946150, 400_000_000)
+        );
+
+        // The fix was taken before it arrived
+        assert_eq!(
+            clock.instant(Duration::new(1602946150, 200_000_000)),
+            Some(pulse + Duration::from_millis(200))
+        );
+    }
+
+    #[test]
+    fn test_no_gps_time() {
+        let mut clock = GpsClock::new();
+        let start = Instant::now();
+
+        // Start from the system time, then only use the monotonic clock
+        let first = clock.now_at(start);
+        assert!(first.as_secs() > 0);
+        assert_eq!(
+            clock.fix_time_at("not a time", start + Duration::from_secs(2)),
+            first + Duration::from_secs(2)
+        );
+    }
+}
@@ -15,9 +15,33 @@
  */
 
 use crate::drive::read_track::Coord;
+use crate::utils::lat_lon_distance;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
 use std::time::Duration;
 
+/// The version of the course file we write. Bump this and add a
+/// migration to `Course::migrate()` when the layout changes.
+///
+/// Files without a version are from before the format was versioned.
+/// They were written as a JSON string containing the `Course`, and
+/// the oldest ones only have a single `start` and `finish` instead
+/// of `segments`.
+const COURSE_FILE_VERSION: u64 = 1;
+
+/// How far apart (in metres) the start or finish of a saved segment
+/// can be from ours for the saved times to still be used
+const SAME_PLACE: f64 = 1.0;
+
+#[derive(Serialize)]
+struct CourseFile<'a> {
+    version: u64,
+    course: &'a Course,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Segment {
     pub start: Coord,
@@ -28,10 +52,19 @@ impl Segment {
     pub fn new(start: Coord, finish: Coord) -> Self {
         Self { start, finish }
     }
+
+    /// Check if `other` starts and finishes in the same place
+    fn same_place(&self, other: &Segment) -> bool {
+        let close =
+            |a: &Coord, b: &Coord| lat_lon_distance(a.lat, a.lon, b.lat, b.lon) < SAME_PLACE;
+
+        close(&self.start, &other.start) && close(&self.finish, &other.finish)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Course {
+    /// The lap times, in the order they were driven
     pub times: Vec<Duration>,
     pub last: Duration,
     pub best: Duration,
@@ -39,10 +72,31 @@ pub struct Course {
     pub last_location_time: Option<Duration>,
     pub worst: Duration,
     pub segments: Vec<Segment>,
+    /// The start and finish are the same line, so finishing
+    /// one lap starts the next one.
+    #[serde(default)]
+    pub circuit: bool,
+    /// The best time for each sector, a sector goes from the start
+    /// of one segment to the start of the next (or the finish).
+    #[serde(default)]
+    pub best_sectors: Vec<Option<Duration>>,
+}
+
+/// A finished sector, sent to the UI
+pub struct SectorTime {
+    pub sector: usize,
+    pub time: Duration,
+    pub best: Duration,
+    /// The difference to the previous best time for this sector,
+    /// true if we were quicker
+    pub diff: Option<(bool, Duration)>,
+    pub theoretical_best: Option<Duration>,
 }
 
 impl Course {
-    pub fn new(segments: Vec<Segment>) -> Course {
+    pub fn new(segments: Vec<Segment>, circuit: bool) -> Course {
+        let best_sectors = vec![None; segments.len()];
+
         Course {
             times: Vec::new(),
             last: Duration::new(0, 0),
@@ -51,8 +105,179 @@ impl Course {
             last_location_time: None,
             worst: Duration::new(0, 0),
             segments,
+            circuit,
+            best_sectors,
+        }
+    }
+
+    /// Create a course from the points of a track file, each track
+    /// segment is a segment of the course.
+    pub fn from_track_points(track_points: &[Vec<Coord>], circuit: bool) -> Course {
+        let segments = track_points
+            .iter()
+            .filter(|points| !points.is_empty())
+            .map(|points| {
+                let first = points.first().unwrap();
+                let last = points.last().unwrap();
+
+                Segment::new(first.clone(), last.clone())
+            })
+            .collect();
+
+        Course::new(segments, circuit)
+    }
+
+    /// Load a times file saved from the drive page
+    pub fn load(path: &Path) -> Result<Course, String> {
+        let file = File::open(path).map_err(|e| e.to_string())?;
+
+        Course::from_reader(BufReader::new(file))
+    }
+
+    fn from_reader<R: Read>(reader: R) -> Result<Course, String> {
+        let value: Value = serde_json::from_reader(reader).map_err(|e| e.to_string())?;
+
+        serde_json::from_value(Course::migrate(value)?).map_err(|e| e.to_string())
+    }
+
+    /// Convert any older layout into the current `Course` layout
+    fn migrate(value: Value) -> Result<Value, String> {
+        // Old files are double encoded, the Course is saved as a JSON string
+        let mut value = match value {
+            Value::String(serialized) => {
+                serde_json::from_str(&serialized).map_err(|e| e.to_string())?
+            }
+            value => value,
+        };
+
+        let version = match value.get("version") {
+            Some(version) => version
+                .as_u64()
+                .ok_or_else(|| format!("Invalid course file version: {}", version))?,
+            None => 0,
+        };
+
+        match version {
+            0 => {
+                let course = value
+                    .as_object_mut()
+                    .ok_or_else(|| "Course file isn't a JSON object".to_string())?;
+
+                // A single start and finish, from before segments
+                if !course.contains_key("segments") {
+                    let start = course.remove("start");
+                    let finish = course.remove("finish");
+
+                    match (start, finish) {
+                        (Some(start), Some(finish)) => {
+                            course.insert(
+                                "segments".to_string(),
+                                serde_json::json!([{ "start": start, "finish": finish }]),
+                            );
+                        }
+                        _ => return Err("Course file doesn't have any segments".to_string()),
+                    }
+                }
+
+                Ok(value)
+            }
+            COURSE_FILE_VERSION => value
+                .get_mut("course")
+                .map(Value::take)
+                .ok_or_else(|| "Course file doesn't contain a course".to_string()),
+            _ => Err(format!("Unsupported course file version: {}", version)),
         }
     }
+
+    /// Save the course as a versioned JSON object
+    pub fn save<W: Write>(&self, writer: W) -> Result<(), String> {
+        let file = CourseFile {
+            version: COURSE_FILE_VERSION,
+            course: self,
+        };
+
+        serde_json::to_writer(writer, &file).map_err(|e| e.to_string())
+    }
+
+    /// Carry over the times from a previous session, so we can
+    /// compare against the previous best lap. The times are only
+    /// used if they were saved on the same course.
+    pub fn resume(&mut self, saved: Course) -> Result<(), String> {
+        let same_course = saved.segments.len() == self.segments.len()
+            && saved
+                .segments
+                .iter()
+                .zip(self.segments.iter())
+                .all(|(saved, ours)| saved.same_place(ours));
+        if !same_course {
+            return Err("The times were saved on a different course".to_string());
+        }
+
+        self.times = saved.times;
+        self.last = saved.last;
+        self.best = saved.best;
+        self.worst = saved.worst;
+        self.best_times = saved.best_times;
+
+        // The sectors only make sense if they match our segments
+        if saved.best_sectors.len() == self.segments.len() {
+            self.best_sectors = saved.best_sectors;
+        }
+
+        Ok(())
+    }
+
+    /// Record a finished lap and update the last, best and
+    /// worst times. Returns the lap number.
+    pub fn add_lap(&mut self, time: Duration) -> usize {
+        self.times.push(time);
+        self.last = time;
+
+        if let Some(best) = self.times.iter().min() {
+            self.best = *best;
+        }
+        if let Some(worst) = self.times.iter().max() {
+            self.worst = *worst;
+        }
+
+        self.times.len()
+    }
+
+    /// Record the time for a sector and update the best sector times
+    pub fn add_sector(&mut self, sector: usize, time: Duration) -> SectorTime {
+        if self.best_sectors.len() <= sector {
+            self.best_sectors.resize(sector + 1, None);
+        }
+
+        let diff = self.best_sectors[sector].map(|best| match best.checked_sub(time) {
+            Some(diff) => (true, diff),
+            None => (false, time - best),
+        });
+
+        let best = match self.best_sectors[sector] {
+            Some(best) if best <= time => best,
+            _ => time,
+        };
+        self.best_sectors[sector] = Some(best);
+
+        SectorTime {
+            sector,
+            time,
+            best,
+            diff,
+            theoretical_best: self.theoretical_best(),
+        }
+    }
+
+    /// The sum of the best sector times, if we have a time
+    /// for every sector
+    pub fn theoretical_best(&self) -> Option<Duration> {
+        if self.best_sectors.is_empty() {
+            return None;
+        }
+
+        self.best_sectors.iter().copied().sum()
+    }
 }
 
 pub struct MapWrapper {
@@ -74,3 +299,140 @@ impl MapWrapper {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_lap() {
+        let mut course = Course::new(Vec::new(), true);
+
+        assert_eq!(course.add_lap(Duration::from_millis(65_250)), 1);
+        assert_eq!(course.add_lap(Duration::from_millis(62_100)), 2);
+        assert_eq!(course.add_lap(Duration::from_millis(63_900)), 3);
+
+        assert_eq!(
+            course.times,
+            vec![
+                Duration::from_millis(65_250),
+                Duration::from_millis(62_100),
+                Duration::from_millis(63_900)
+            ]
+        );
+        assert_eq!(course.last, Duration::from_millis(63_900));
+        assert_eq!(course.best, Duration::from_millis(62_100));
+        assert_eq!(course.worst, Duration::from_millis(65_250));
+    }
+
+    #[test]
+    fn test_resume() {
+        let segments = vec![Segment::new(
+            Coord::new(37.0, -122.0, None),
+            Coord::new(37.1, -122.0, None),
+        )];
+        let mut saved = Course::new(segments, false);
+        saved.add_lap(Duration::from_millis(65_250));
+        saved.add_lap(Duration::from_millis(62_100));
+        saved.add_sector(0, Duration::from_millis(62_100));
+        saved.best_times = vec![vec![(
+            Coord::new(37.0, -122.0, None),
+            Duration::from_secs(0),
+        )]];
+
+        let mut file = Vec::new();
+        saved.save(&mut file).unwrap();
+
+        let segments = vec![Segment::new(
+            Coord::new(37.0, -122.0, None),
+            Coord::new(37.1, -122.0, None),
+        )];
+        let mut course = Course::new(segments, false);
+        course
+            .resume(Course::from_reader(file.as_slice()).unwrap())
+            .unwrap();
+
+        assert_eq!(course.times.len(), 2);
+        assert_eq!(course.best, Duration::from_millis(62_100));
+        assert_eq!(course.worst, Duration::from_millis(65_250));
+        assert_eq!(course.best_times.len(), 1);
+        assert_eq!(
+            course.theoretical_best(),
+            Some(Duration::from_millis(62_100))
+        );
+
+        // The finish has moved, so the times are for a different course
+        let segments = vec![Segment::new(
+            Coord::new(37.0, -122.0, None),
+            Coord::new(37.1, -122.001, None),
+        )];
+        let mut course = Course::new(segments, false);
+        assert!(course
+            .resume(Course::from_reader(file.as_slice()).unwrap())
+            .is_err());
+        assert!(course.times.is_empty());
+        assert!(course.best_times.is_empty());
+
+        assert!(Course::from_reader("{}".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_load_versions() {
+        let segments = vec![Segment::new(
+            Coord::new(37.0, -122.0, None),
+            Coord::new(37.1, -122.0, None),
+        )];
+        let mut saved = Course::new(segments, true);
+        saved.add_lap(Duration::from_millis(65_250));
+
+        // Unversioned, double encoded
+        let mut file = Vec::new();
+        let serialized = serde_json::to_string(&saved).unwrap();
+        serde_json::to_writer(&mut file, &serialized).unwrap();
+
+        let course = Course::from_reader(file.as_slice()).unwrap();
+        assert_eq!(course.best, Duration::from_millis(65_250));
+        assert_eq!(course.segments.len(), 1);
+        assert!(course.circuit);
+
+        // Newer than we understand
+        assert!(Course::from_reader(r#"{"version":99,"course":{}}"#.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_load_legacy() {
+        let course = Course::load(Path::new("tests/test-track-cowpalace-saved")).unwrap();
+
+        assert_eq!(course.segments.len(), 1);
+        assert_eq!(course.segments[0].start.lat, 37.7060849);
+        assert_eq!(course.segments[0].finish.lon, -122.420065833);
+        assert_eq!(course.times.len(), 0);
+        assert!(!course.circuit);
+        assert!(course.best_sectors.is_empty());
+    }
+
+    #[test]
+    fn test_add_sector() {
+        let segments = vec![
+            Segment::new(Coord::new(0.0, 0.0, None), Coord::new(0.0, 0.0, None)),
+            Segment::new(Coord::new(0.0, 0.0, None), Coord::new(0.0, 0.0, None)),
+        ];
+        let mut course = Course::new(segments, true);
+
+        let sector = course.add_sector(0, Duration::from_millis(30_500));
+        assert_eq!(sector.diff, None);
+        assert_eq!(sector.theoretical_best, None);
+
+        let sector = course.add_sector(1, Duration::from_millis(32_000));
+        assert_eq!(sector.theoretical_best, Some(Duration::from_millis(62_500)));
+
+        let sector = course.add_sector(0, Duration::from_millis(31_000));
+        assert_eq!(sector.diff, Some((false, Duration::from_millis(500))));
+        assert_eq!(sector.best, Duration::from_millis(30_500));
+
+        let sector = course.add_sector(1, Duration::from_millis(31_250));
+        assert_eq!(sector.diff, Some((true, Duration::from_millis(750))));
+        assert_eq!(sector.best, Duration::from_millis(31_250));
+        assert_eq!(sector.theoretical_best, Some(Duration::from_millis(61_750)));
+    }
+}
@@ -0,0 +1,364 @@
+/*
+ * Copyright 2020 Alistair Francis <alistair@alistair23.me>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *    http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Manufacturer specific PIDs, such as the oil temperature or boost,
+//! defined by the user in `pids.json` next to the config file.
+//! The definitions are loaded once at startup, each one is then
+//! polled, logged and charted like the standard OBDII PIDs.
+
+use crate::config::Config;
+use crate::drive::elm327::{Elm327, Elm327Error};
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+use std::sync::OnceLock;
+
+static CUSTOM_PIDS: OnceLock<Vec<CustomPid>> = OnceLock::new();
+
+/// A PID as written in the definition file
+#[derive(Deserialize)]
+struct Definition {
+    name: String,
+    /// The CAN header (or other protocol header) to send the request
+    /// to, in hex. The adapter's default is used if this isn't set.
+    header: Option<String>,
+    /// The mode, in hex, such as "22"
+    mode: String,
+    /// The PID, in hex, such as "1310"
+    pid: String,
+    /// How to convert the response into a value, A is the first
+    /// data byte, B the second and so on. For example "(A*256+B)/10"
+    formula: String,
+    #[serde(default)]
+    units: String,
+    /// The range of the chart
+    min: f64,
+    max: f64,
+}
+
+/// A formula from the definition file
+#[derive(Debug, PartialEq)]
+enum Expr {
+    Num(f64),
+    /// A data byte, 0 is A
+    Byte(usize),
+    Neg(Box<Expr>),
+    Op(char, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn eval(&self, data: &[u8]) -> Option<f64> {
+        match self {
+            Expr::Num(n) => Some(*n),
+            Expr::Byte(i) => data.get(*i).map(|b| *b as f64),
+            Expr::Neg(e) => Some(-e.eval(data)?),
+            Expr::Op(op, a, b) => {
+                let a = a.eval(data)?;
+                let b = b.eval(data)?;
+                match op {
+                    '+' => Some(a + b),
+                    '-' => Some(a - b),
+                    '*' => Some(a * b),
+                    _ if b == 0.0 => None,
+                    _ => Some(a / b),
+                }
+            }
+        }
+    }
+}
+
+/// Parses formulas made of numbers, the data bytes A to Z,
+/// + - * / and brackets
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn parse(formula: &'a str) -> Result<Expr, String> {
+        let mut parser = Parser {
+            chars: formula.chars().peekable(),
+        };
+
+        let expr = parser.sum()?;
+        match parser.next() {
+            None => Ok(expr),
+            Some(c) => Err(format!("Unexpected '{}' in \"{}\"", c, formula)),
+        }
+    }
+
+    /// The next character that isn't a space
+    fn peek(&mut self) -> Option<char> {
+        while matches!(self.chars.peek(), Some(c) if c.is_whitespace()) {
+            self.chars.next();
+        }
+        self.chars.peek().cloned()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        self.peek()?;
+        self.chars.next()
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        let mut expr = self.product()?;
+        while let Some(op) = self.peek().filter(|c| *c == '+' || *c == '-') {
+            self.next();
+            expr = Expr::Op(op, Box::new(expr), Box::new(self.product()?));
+        }
+        Ok(expr)
+    }
+
+    fn product(&mut self) -> Result<Expr, String> {
+        let mut expr = self.value()?;
+        while let Some(op) = self.peek().filter(|c| *c == '*' || *c == '/') {
+            self.next();
+            expr = Expr::Op(op, Box::new(expr), Box::new(self.value()?));
+        }
+        Ok(expr)
+    }
+
+    fn value(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some('-') => Ok(Expr::Neg(Box::new(self.value()?))),
+            Some('(') => {
+                let expr = self.sum()?;
+                match self.next() {
+                    Some(')') => Ok(expr),
+                    _ => Err("Missing ')'".to_string()),
+                }
+            }
+            Some(c) if c.is_ascii_uppercase() => Ok(Expr::Byte((c as u8 - b'A') as usize)),
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let mut number = c.to_string();
+                while let Some(c) = self
+                    .chars
+                    .peek()
+                    .filter(|c| c.is_ascii_digit() || **c == '.')
+                {
+                    number.push(*c);
+                    self.chars.next();
+                }
+                number
+                    .parse()
+                    .map(Expr::Num)
+                    .map_err(|_| format!("Bad number {}", number))
+            }
+            Some(c) => Err(format!("Unexpected '{}'", c)),
+            None => Err("The formula ended early".to_string()),
+        }
+    }
+}
+
+/// Convert a string of hex characters into bytes
+fn parse_hex(hex: &str) -> Result<Vec<u8>, String> {
+    let hex = hex.trim();
+    if hex.is_empty() {
+        return Err("Empty hex value".to_string());
+    }
+
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .filter(|pair| pair.len() == 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| format!("Bad hex value \"{}\"", hex))
+        })
+        .collect()
+}
+
+pub struct CustomPid {
+    /// The name used in the CSV files and the config
+    pub name: String,
+    pub header: Option<String>,
+    pub mode: u8,
+    pub pid: Vec<u8>,
+    formula: Expr,
+    pub units: String,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl CustomPid {
+    fn from_definition(def: Definition) -> Result<CustomPid, String> {
+        let mode = parse_hex(&def.mode)?;
+        if mode.len() != 1 {
+            return Err(format!("Bad mode \"{}\"", def.mode));
+        }
+        // 11 bit CAN headers, such as 7E0, are an odd length
+        if let Some(header) = &def.header {
+            if header.is_empty() || !header.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("Bad header \"{}\"", header));
+            }
+        }
+
+        Ok(CustomPid {
+            formula: Parser::parse(&def.formula)?,
+            pid: parse_hex(&def.pid)?,
+            mode: mode[0],
+            name: def.name,
+            header: def.header,
+            units: def.units,
+            min: def.min,
+            max: def.max,
+        })
+    }
+
+    /// Convert the data bytes returned by the car into the value
+    pub fn decode(&self, data: &[u8]) -> Option<f64> {
+        self.formula.eval(data)
+    }
+
+    /// Request the PID and return the data bytes, without the
+    /// mode and PID echo
+    pub fn query<P: Read + Write>(&self, elm: &mut Elm327<P>) -> Result<Vec<u8>, Elm327Error> {
+        elm.set_header(self.header.as_deref())?;
+
+        let mut request = vec![self.mode];
+        request.extend_from_slice(&self.pid);
+        let responses = elm.request(&request)?;
+
+        responses
+            .iter()
+            .find(|r| r.starts_with(&self.pid))
+            .map(|r| r[self.pid.len()..].to_vec())
+            .ok_or_else(|| Elm327Error::BadResponse(format!("{:02X?}", responses)))
+    }
+}
+
+/// Read the PID definitions from `reader`. Definitions that
+/// can't be used are skipped.
+fn read_definitions<R: Read>(reader: R) -> Result<Vec<CustomPid>, String> {
+    let defs: Vec<Definition> = serde_json::from_reader(reader).map_err(|e| e.to_string())?;
+
+    let mut pids = Vec::new();
+    for def in defs {
+        let name = def.name.clone();
+        match CustomPid::from_definition(def) {
+            // The index is logged as a single byte
+            Ok(pid) if pids.len() < 256 => pids.push(pid),
+            Ok(_) => println!("{}: Too many custom PIDs", name),
+            Err(e) => println!("{}: Unable to use the PID: {}", name, e),
+        }
+    }
+
+    Ok(pids)
+}
+
+fn load(path: &Path) -> Vec<CustomPid> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return Vec::new(),
+    };
+
+    match read_definitions(BufReader::new(file)) {
+        Ok(pids) => pids,
+        Err(e) => {
+            println!("Unable to parse {:?}: {}", path, e);
+            Vec::new()
+        }
+    }
+}
+
+/// Load the definitions from `pids.json` in the config directory.
+/// This should be called once, before any PIDs are polled.
+pub fn load_definitions() {
+    let pids = match Config::dir() {
+        Some(dir) => load(&dir.join("pids.json")),
+        None => Vec::new(),
+    };
+
+    if CUSTOM_PIDS.set(pids).is_err() {
+        println!("The custom PIDs have already been loaded");
+    }
+}
+
+/// The custom PIDs, the index of each one is used in
+/// `OBDIICommandType::Custom`.
+pub fn custom_pids() -> &'static [CustomPid] {
+    CUSTOM_PIDS.get().map(|p| p.as_slice()).unwrap_or(&[])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drive::elm327::tests::Elm327Sim;
+
+    #[test]
+    fn test_formula() {
+        let formula = Parser::parse("(A * 256 + B) / 10").unwrap();
+        assert_eq!(formula.eval(&[0x01, 0x2C]), Some(30.0));
+        // Not enough data
+        assert_eq!(formula.eval(&[0x01]), None);
+
+        let formula = Parser::parse("A-40").unwrap();
+        assert_eq!(formula.eval(&[0x14]), Some(-20.0));
+
+        let formula = Parser::parse("-A*0.5+1").unwrap();
+        assert_eq!(formula.eval(&[0x04]), Some(-1.0));
+
+        assert_eq!(Parser::parse("A/0").unwrap().eval(&[1]), None);
+        assert!(Parser::parse("(A+B").is_err());
+        assert!(Parser::parse("A+").is_err());
+        assert!(Parser::parse("A B").is_err());
+        assert!(Parser::parse("a+1").is_err());
+    }
+
+    #[test]
+    fn test_read_definitions() {
+        let file = r#"[
+            { "name": "OIL_TEMP", "header": "7E0", "mode": "22", "pid": "1310",
+              "formula": "A-40", "units": "C", "min": -40, "max": 160 },
+            { "name": "BOOST", "mode": "01", "pid": "0B", "formula": "A-101",
+              "units": "kPa", "min": -100, "max": 150 },
+            { "name": "BROKEN", "mode": "22", "pid": "131", "formula": "A",
+              "min": 0, "max": 1 }
+        ]"#;
+
+        let pids = read_definitions(file.as_bytes()).unwrap();
+        assert_eq!(pids.len(), 2);
+        assert_eq!(pids[0].name, "OIL_TEMP");
+        assert_eq!(pids[0].header.as_deref(), Some("7E0"));
+        assert_eq!(pids[0].mode, 0x22);
+        assert_eq!(pids[0].pid, vec![0x13, 0x10]);
+        assert_eq!(pids[1].units, "kPa");
+        assert_eq!(pids[1].header, None);
+
+        assert!(read_definitions("{}".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_query() {
+        let mut sim = Elm327Sim::default();
+        sim.responses
+            .insert("221310".to_string(), "62 13 10 8C".to_string());
+        let mut elm = Elm327::new(sim);
+        elm.init().unwrap();
+
+        let file = r#"[{ "name": "OIL_TEMP", "header": "7E0", "mode": "22",
+            "pid": "1310", "formula": "A-40", "min": -40, "max": 160 }]"#;
+        let pids = read_definitions(file.as_bytes()).unwrap();
+
+        let data = pids[0].query(&mut elm).unwrap();
+        assert_eq!(pids[0].decode(&data), Some(100.0));
+
+        // Mode 1 goes back to the default header
+        elm.set_header(None).unwrap();
+        assert_eq!(elm.query(0x01, 0x0C).unwrap(), vec![0x1A, 0xF8]);
+    }
+}
@@ -0,0 +1,464 @@
+/*
+ * Copyright 2020 Alistair Francis <alistair@alistair23.me>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *    http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Reads the messages and signals from a DBC file and decodes
+//! them from CAN frames. Only the `BO_` and `SG_` lines are used,
+//! everything else in the file is ignored.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// The most signals that can be decoded, each one is logged
+/// with its index
+pub const MAX_SIGNALS: usize = 0x1000;
+
+/// Set in the DBC message ID for 29 bit IDs
+const EXTENDED_ID: u32 = 0x8000_0000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mux {
+    /// Always in the message
+    None,
+    /// Only in the message when the multiplexor has this value
+    Value(u64),
+}
+
+#[derive(Clone, Debug)]
+pub struct Signal {
+    pub name: String,
+    /// The first bit, for big endian signals this is the most
+    /// significant bit
+    start: u16,
+    len: u16,
+    little_endian: bool,
+    signed: bool,
+    factor: f64,
+    offset: f64,
+    mux: Mux,
+    pub min: f64,
+    pub max: f64,
+    pub units: String,
+}
+
+impl Signal {
+    /// Parse a `SG_` line, such as
+    /// `SG_ WheelSpeedFL : 0|16@1+ (0.01,0) [0|300] "km/h" ABS`
+    /// The multiplexor is returned as true.
+    fn parse(line: &str) -> Result<(Signal, bool), String> {
+        let line = line.trim().trim_start_matches("SG_").trim();
+        let (name, layout) = line.split_once(':').ok_or("Missing ':'")?;
+
+        let mut name = name.split_whitespace();
+        let signal_name = name.next().ok_or("Missing the name")?.to_string();
+        let (mux, multiplexor) = match name.next() {
+            None => (Mux::None, false),
+            Some("M") => (Mux::None, true),
+            Some(m) => {
+                let value = m
+                    .trim_start_matches('m')
+                    .trim_end_matches('M')
+                    .parse()
+                    .map_err(|_| format!("Bad multiplexer \"{}\"", m))?;
+                (Mux::Value(value), false)
+            }
+        };
+
+        // start|len@order sign
+        let bits = layout.split_whitespace().next().ok_or("Missing the bits")?;
+        let (start, rest) = bits.split_once('|').ok_or("Missing the length")?;
+        let (len, rest) = rest.split_once('@').ok_or("Missing the byte order")?;
+        let start: u16 = start.parse().map_err(|_| "Bad start bit")?;
+        let len: u16 = len.parse().map_err(|_| "Bad length")?;
+        if len == 0 || len > 64 {
+            return Err(format!("Unable to decode a {} bit signal", len));
+        }
+        let little_endian = match rest.get(0..1) {
+            Some("1") => true,
+            Some("0") => false,
+            _ => return Err(format!("Bad byte order \"{}\"", rest)),
+        };
+        let signed = rest.ends_with('-');
+
+        let (factor, offset) = pair(layout, '(', ')', ',')?;
+        let (min, max) = pair(layout, '[', ']', '|')?;
+        let units = layout.split('"').nth(1).unwrap_or("").to_string();
+
+        let mut signal = Signal {
+            name: signal_name,
+            start,
+            len,
+            little_endian,
+            signed,
+            factor,
+            offset,
+            mux,
+            min,
+            max,
+            units,
+        };
+
+        // A lot of files don't set the range, so use every value
+        // the signal can have.
+        if signal.max <= signal.min {
+            let (low, high) = if signed {
+                (
+                    -(2f64.powi(len as i32 - 1)),
+                    2f64.powi(len as i32 - 1) - 1.0,
+                )
+            } else {
+                (0.0, 2f64.powi(len as i32) - 1.0)
+            };
+            let (low, high) = (low * factor + offset, high * factor + offset);
+            signal.min = low.min(high);
+            signal.max = low.max(high);
+        }
+
+        Ok((signal, multiplexor))
+    }
+
+    /// The raw value of the signal in `data`
+    fn raw(&self, data: &[u8]) -> Option<u64> {
+        let bit = |pos: u16| -> Option<u64> {
+            let byte = data.get(pos as usize / 8)?;
+            Some(((byte >> (pos % 8)) & 1) as u64)
+        };
+
+        let mut raw: u64 = 0;
+        if self.little_endian {
+            for i in 0..self.len {
+                raw |= bit(self.start + i)? << i;
+            }
+        } else {
+            // The bits are numbered from the least significant bit
+            // of each byte, but the signal carries on into the
+            // most significant bit of the next byte.
+            let mut pos = self.start;
+            for _ in 0..self.len {
+                raw = (raw << 1) | bit(pos)?;
+                pos = match pos % 8 {
+                    0 => pos + 15,
+                    _ => pos - 1,
+                };
+            }
+        }
+
+        Some(raw)
+    }
+
+    /// The value of the signal in `data`
+    pub fn decode(&self, data: &[u8]) -> Option<f64> {
+        let raw = self.raw(data)?;
+
+        let value = if self.signed && self.len < 64 && raw & (1 << (self.len - 1)) != 0 {
+            (raw | (!0 << self.len)) as i64 as f64
+        } else if self.signed {
+            raw as i64 as f64
+        } else {
+            raw as f64
+        };
+
+        Some(value * self.factor + self.offset)
+    }
+}
+
+/// Parse two numbers between `open` and `close`, split by `sep`
+fn pair(text: &str, open: char, close: char, sep: char) -> Result<(f64, f64), String> {
+    let inner = text
+        .split_once(open)
+        .and_then(|(_, rest)| rest.split_once(close))
+        .map(|(inner, _)| inner)
+        .ok_or_else(|| format!("Missing {}{}", open, close))?;
+    let (a, b) = inner
+        .split_once(sep)
+        .ok_or_else(|| format!("Bad values \"{}\"", inner))?;
+
+    match (a.trim().parse(), b.trim().parse()) {
+        (Ok(a), Ok(b)) => Ok((a, b)),
+        _ => Err(format!("Bad values \"{}\"", inner)),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub id: u32,
+    pub extended: bool,
+    pub name: String,
+    multiplexor: Option<Signal>,
+    signals: Vec<Signal>,
+    /// The index of the first signal, out of every signal in the file
+    first: usize,
+}
+
+impl Message {
+    /// Parse a `BO_` line, such as `BO_ 1200 WheelSpeeds: 8 ABS`
+    fn parse(line: &str) -> Result<Message, String> {
+        let mut fields = line.trim().trim_start_matches("BO_").split_whitespace();
+        let id: u32 = fields
+            .next()
+            .and_then(|id| id.parse().ok())
+            .ok_or("Bad message ID")?;
+        let name = fields
+            .next()
+            .ok_or("Missing the name")?
+            .trim_end_matches(':')
+            .to_string();
+
+        Ok(Message {
+            id: id & !EXTENDED_ID,
+            extended: id & EXTENDED_ID != 0,
+            name,
+            multiplexor: None,
+            signals: Vec::new(),
+            first: 0,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Dbc {
+    messages: Vec<Message>,
+}
+
+impl Dbc {
+    fn new(messages: Vec<Message>) -> Dbc {
+        let mut dbc = Dbc {
+            messages: Vec::new(),
+        };
+        let mut count = 0;
+
+        for mut message in messages {
+            if count + message.signals.len() > MAX_SIGNALS {
+                println!("{}: Too many CAN signals", message.name);
+                message.signals.truncate(MAX_SIGNALS - count);
+            }
+            if message.signals.is_empty() {
+                continue;
+            }
+
+            message.first = count;
+            count += message.signals.len();
+            dbc.messages.push(message);
+        }
+
+        dbc
+    }
+
+    /// Read the signals from a DBC file. Signals that can't be
+    /// decoded are skipped.
+    pub fn parse<R: BufRead>(reader: R) -> Result<Dbc, String> {
+        let mut messages: Vec<Message> = Vec::new();
+        // If the signals that follow belong to the last message
+        let mut in_message = false;
+
+        for (number, line) in reader.lines().enumerate() {
+            let line = line.map_err(|e| e.to_string())?;
+            let trimmed = line.trim_start();
+
+            if trimmed.starts_with("BO_ ") {
+                match Message::parse(trimmed) {
+                    Ok(message) => {
+                        messages.push(message);
+                        in_message = true;
+                    }
+                    Err(e) => {
+                        println!("Line {}: {}", number + 1, e);
+                        in_message = false;
+                    }
+                }
+            } else if trimmed.starts_with("SG_ ") && in_message {
+                let message = messages.last_mut().unwrap();
+
+                match Signal::parse(trimmed) {
+                    Ok((signal, true)) => {
+                        message.multiplexor = Some(signal.clone());
+                        message.signals.push(signal);
+                    }
+                    Ok((signal, false)) => message.signals.push(signal),
+                    Err(e) => println!("Line {}: {}", number + 1, e),
+                }
+            } else if !trimmed.is_empty() {
+                in_message = false;
+            }
+        }
+
+        Ok(Dbc::new(messages))
+    }
+
+    pub fn load(path: &Path) -> Result<Dbc, String> {
+        let file = File::open(path).map_err(|e| e.to_string())?;
+        Dbc::parse(BufReader::new(file))
+    }
+
+    /// Only keep the signals in `names`, or every signal if
+    /// `names` is empty
+    pub fn select(self, names: &[String]) -> Dbc {
+        if names.is_empty() {
+            return self;
+        }
+
+        for name in names {
+            if !self.signals().any(|s| &s.name == name) {
+                println!("{}: Not in the DBC file", name);
+            }
+        }
+
+        let messages = self
+            .messages
+            .into_iter()
+            .map(|mut message| {
+                message.signals.retain(|s| names.contains(&s.name));
+                message
+            })
+            .collect();
+
+        Dbc::new(messages)
+    }
+
+    /// Every signal, in the order of their indexes
+    pub fn signals(&self) -> impl Iterator<Item = &Signal> {
+        self.messages.iter().flat_map(|m| m.signals.iter())
+    }
+
+    pub fn signal(&self, index: u16) -> Option<&Signal> {
+        self.signals().nth(index as usize)
+    }
+
+    /// Decode the signals in a CAN frame. Returns the index and
+    /// value of each signal.
+    pub fn decode(&self, id: u32, extended: bool, data: &[u8]) -> Vec<(u16, f64)> {
+        let message = match self
+            .messages
+            .iter()
+            .find(|m| m.id == id && m.extended == extended)
+        {
+            Some(message) => message,
+            None => return Vec::new(),
+        };
+
+        let mux = message.multiplexor.as_ref().and_then(|m| m.raw(data));
+
+        message
+            .signals
+            .iter()
+            .enumerate()
+            .filter(|(_, signal)| match signal.mux {
+                Mux::None => true,
+                Mux::Value(value) => mux == Some(value),
+            })
+            .filter_map(|(i, signal)| Some(((message.first + i) as u16, signal.decode(data)?)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DBC: &str = r#"VERSION ""
+
+NS_ :
+    CM_
+
+BU_: ABS EPS
+
+BO_ 1200 WheelSpeeds: 8 ABS
+ SG_ WheelSpeedFL : 0|16@1+ (0.01,0) [0|300] "km/h" Vector__XXX
+ SG_ WheelSpeedFR : 16|16@1+ (0.01,0) [0|300] "km/h" Vector__XXX
+
+BO_ 384 Steering: 4 EPS
+ SG_ SteeringAngle : 7|16@0- (0.1,0) [-780|780] "deg" Vector__XXX
+ SG_ SteeringRate : 23|8@0+ (4,0) [0|0] "deg/s" Vector__XXX
+
+BO_ 2147484672 Brakes: 8 ABS
+ SG_ Page M : 0|8@1+ (1,0) [0|3] "" Vector__XXX
+ SG_ BrakePressureFront m0 : 8|16@1+ (0.1,0) [0|250] "bar" Vector__XXX
+ SG_ BrakePressureRear m1 : 8|16@1+ (0.1,0) [0|250] "bar" Vector__XXX
+ SG_ Broken : 8|16@2+ (0.1,0) [0|250] "bar" Vector__XXX
+
+CM_ SG_ 1200 WheelSpeedFL "Front left";
+"#;
+
+    #[test]
+    fn test_parse() {
+        let dbc = Dbc::parse(DBC.as_bytes()).unwrap();
+
+        let names: Vec<&str> = dbc.signals().map(|s| s.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "WheelSpeedFL",
+                "WheelSpeedFR",
+                "SteeringAngle",
+                "SteeringRate",
+                "Page",
+                "BrakePressureFront",
+                "BrakePressureRear"
+            ]
+        );
+
+        assert_eq!(dbc.messages[2].id, 0x400);
+        assert!(dbc.messages[2].extended);
+
+        let speed = dbc.signal(0).unwrap();
+        assert_eq!(speed.units, "km/h");
+        assert_eq!((speed.min, speed.max), (0.0, 300.0));
+
+        // The range comes from the length when it isn't set
+        let rate = dbc.signal(3).unwrap();
+        assert_eq!((rate.min, rate.max), (0.0, 1020.0));
+
+        let dbc = dbc.select(&["SteeringRate".to_string(), "BrakePressureRear".to_string()]);
+        let names: Vec<&str> = dbc.signals().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["SteeringRate", "BrakePressureRear"]);
+    }
+
+    #[test]
+    fn test_decode() {
+        let dbc = Dbc::parse(DBC.as_bytes()).unwrap();
+
+        // Little endian
+        let values = dbc.decode(1200, false, &[0x10, 0x27, 0xE8, 0x03, 0, 0, 0, 0]);
+        assert_eq!(values, vec![(0, 100.0), (1, 10.0)]);
+
+        // Big endian, -90.0 degrees and 8 deg/s
+        let values = dbc.decode(384, false, &[0xFC, 0x7C, 0x02, 0x00]);
+        assert_eq!(values, vec![(2, -90.0), (3, 8.0)]);
+
+        // Not enough data for the rate
+        assert_eq!(dbc.decode(384, false, &[0xFC, 0x7C]), vec![(2, -90.0)]);
+
+        // Unknown or the wrong type of ID
+        assert_eq!(dbc.decode(0x123, false, &[0; 8]), vec![]);
+        assert_eq!(dbc.decode(0x400, false, &[0; 8]), vec![]);
+    }
+
+    #[test]
+    fn test_multiplexed() {
+        let dbc = Dbc::parse(DBC.as_bytes()).unwrap();
+
+        let values = dbc.decode(0x400, true, &[0x00, 0xE8, 0x03, 0, 0, 0, 0, 0]);
+        assert_eq!(values, vec![(4, 0.0), (5, 100.0)]);
+
+        let values = dbc.decode(0x400, true, &[0x01, 0xF4, 0x01, 0, 0, 0, 0, 0]);
+        assert_eq!(values, vec![(4, 1.0), (6, 50.0)]);
+
+        // The multiplexor is still used if it isn't selected
+        let dbc = dbc.select(&["BrakePressureRear".to_string()]);
+        let values = dbc.decode(0x400, true, &[0x01, 0xF4, 0x01, 0, 0, 0, 0, 0]);
+        assert_eq!(values, vec![(0, 50.0)]);
+    }
+}
@@ -0,0 +1,245 @@
+/*
+ * Copyright 2020 Alistair Francis <alistair@alistair23.me>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *    http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::drive::read_track::Coord;
+use crate::utils::{lat_lon_bearing, lat_lon_distance};
+use std::time::Duration;
+
+/// How far away from the reference lap we can be and still
+/// be on it, in metres
+const MAX_OFF_TRACK: f64 = 25.0;
+
+/// How many reference points behind and ahead of the last match
+/// we search. This avoids matching a different part of the track
+/// that happens to be close by.
+const SEARCH_BEHIND: usize = 5;
+const SEARCH_AHEAD: usize = 50;
+
+struct ReferencePoint {
+    /// Metres east and north of the first point
+    x: f64,
+    y: f64,
+    /// Distance along the lap, in metres
+    distance: f64,
+    /// Time into the lap
+    time: Duration,
+}
+
+/// A previous lap, used to work out how far ahead or behind we are
+pub struct ReferenceLap {
+    origin: (f64, f64),
+    points: Vec<ReferencePoint>,
+    last_match: usize,
+}
+
+impl ReferenceLap {
+    /// Create a reference lap from the `best_times` of a `Course`.
+    /// Returns None if there aren't enough points.
+    pub fn new(lap: &[Vec<(Coord, Duration)>]) -> Option<ReferenceLap> {
+        let first = lap.iter().flatten().next()?;
+        let mut reference = ReferenceLap {
+            origin: (first.0.lat, first.0.lon),
+            points: Vec::new(),
+            last_match: 0,
+        };
+
+        let mut distance = 0.0;
+        for (coord, time) in lap.iter().flatten() {
+            let (x, y) = reference.local(coord.lat, coord.lon);
+
+            if let Some(prev) = reference.points.last() {
+                distance += ((x - prev.x).powi(2) + (y - prev.y).powi(2)).sqrt();
+            }
+
+            reference.points.push(ReferencePoint {
+                x,
+                y,
+                distance,
+                time: *time,
+            });
+        }
+
+        if reference.points.len() < 2 {
+            return None;
+        }
+
+        Some(reference)
+    }
+
+    /// Convert a location into metres (east, north) from the first point
+    fn local(&self, lat: f64, lon: f64) -> (f64, f64) {
+        let (origin_lat, origin_lon) = self.origin;
+        let distance = lat_lon_distance(origin_lat, origin_lon, lat, lon);
+        let bearing = lat_lon_bearing(origin_lat, origin_lon, lat, lon).to_radians();
+
+        (distance * bearing.sin(), distance * bearing.cos())
+    }
+
+    /// The time of the reference lap
+    pub fn lap_time(&self) -> Duration {
+        self.points.last().unwrap().time
+    }
+
+    /// Start searching from the beginning of the lap again
+    pub fn reset(&mut self) {
+        self.last_match = 0;
+    }
+
+    /// Find the closest point on the reference lap between `start`
+    /// and `end`. Returns the index of the line, how far along the
+    /// line the point is and how far away from the line we are.
+    fn closest(&self, x: f64, y: f64, start: usize, end: usize) -> Option<(usize, f64, f64)> {
+        let mut closest: Option<(usize, f64, f64)> = None;
+
+        for i in start..end {
+            let (a, b) = (&self.points[i], &self.points[i + 1]);
+            let (dx, dy) = (b.x - a.x, b.y - a.y);
+            let len = dx.powi(2) + dy.powi(2);
+
+            let fraction = if len > 0.0 {
+                (((x - a.x) * dx + (y - a.y) * dy) / len).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+
+            let off_track =
+                ((a.x + fraction * dx - x).powi(2) + (a.y + fraction * dy - y).powi(2)).sqrt();
+
+            match closest {
+                Some((_, _, dist)) if dist <= off_track => {}
+                _ => closest = Some((i, fraction, off_track)),
+            }
+        }
+
+        closest.filter(|(_, _, dist)| *dist <= MAX_OFF_TRACK)
+    }
+
+    /// Project a location onto the reference lap.
+    /// Returns the distance along the lap and the time the
+    /// reference lap took to get there.
+    pub fn project(&mut self, lat: f64, lon: f64) -> Option<(f64, Duration)> {
+        let (x, y) = self.local(lat, lon);
+        let last_line = self.points.len() - 1;
+
+        let start = self.last_match.saturating_sub(SEARCH_BEHIND);
+        let end = (self.last_match + SEARCH_AHEAD).min(last_line);
+
+        // If we have lost track of where we are search the whole lap
+        let (i, fraction, _) = self
+            .closest(x, y, start, end)
+            .or_else(|| self.closest(x, y, 0, last_line))?;
+        self.last_match = i;
+
+        let (a, b) = (&self.points[i], &self.points[i + 1]);
+        let distance = a.distance + fraction * (b.distance - a.distance);
+        let time = match b.time.checked_sub(a.time) {
+            Some(diff) => a.time + diff.mul_f64(fraction),
+            None => a.time,
+        };
+
+        Some((distance, time))
+    }
+}
+
+/// Compare our elapsed time to the reference lap at the same point.
+/// Returns the delta (true if we are quicker) and the lap time we
+/// are on track for.
+pub fn predict(
+    elapsed: Duration,
+    reference_time: Duration,
+    reference_lap_time: Duration,
+) -> ((bool, Duration), Duration) {
+    match reference_time.checked_sub(elapsed) {
+        Some(diff) => (
+            (true, diff),
+            reference_lap_time.checked_sub(diff).unwrap_or_default(),
+        ),
+        None => {
+            let diff = elapsed - reference_time;
+            ((false, diff), reference_lap_time + diff)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::lat_lon_destination;
+
+    /// A 100m lap heading north, taking 10 seconds
+    fn straight_lap() -> Vec<Vec<(Coord, Duration)>> {
+        let mut lap = Vec::new();
+
+        for i in 0..=10 {
+            let (lat, lon) = lat_lon_destination(37.0, -122.0, 0.0, i as f64 * 10.0);
+            lap.push((Coord::new(lat, lon, None), Duration::from_secs(i)));
+        }
+
+        vec![lap]
+    }
+
+    #[test]
+    fn test_project() {
+        let mut reference = ReferenceLap::new(&straight_lap()).unwrap();
+        assert_eq!(reference.lap_time(), Duration::from_secs(10));
+
+        // 55m along the lap and a few metres off to the side
+        let (lat, lon) = lat_lon_destination(37.0, -122.0, 0.0, 55.0);
+        let (lat, lon) = lat_lon_destination(lat, lon, 90.0, 3.0);
+        let (distance, time) = reference.project(lat, lon).unwrap();
+        assert!((distance - 55.0).abs() < 0.001);
+        assert!((time.as_secs_f64() - 5.5).abs() < 0.001);
+
+        // Nowhere near the track
+        assert_eq!(reference.project(37.0, -121.99), None);
+    }
+
+    #[test]
+    fn test_not_enough_points() {
+        assert!(ReferenceLap::new(&Vec::new()).is_none());
+        assert!(ReferenceLap::new(&[vec![(
+            Coord::new(37.0, -122.0, None),
+            Duration::from_secs(0)
+        )]])
+        .is_none());
+    }
+
+    #[test]
+    fn test_predict() {
+        assert_eq!(
+            predict(
+                Duration::from_millis(5_000),
+                Duration::from_millis(5_500),
+                Duration::from_secs(60)
+            ),
+            (
+                (true, Duration::from_millis(500)),
+                Duration::from_millis(59_500)
+            )
+        );
+        assert_eq!(
+            predict(
+                Duration::from_millis(6_250),
+                Duration::from_millis(5_500),
+                Duration::from_secs(60)
+            ),
+            (
+                (false, Duration::from_millis(750)),
+                Duration::from_millis(60_750)
+            )
+        );
+    }
+}
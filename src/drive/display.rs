@@ -14,17 +14,21 @@
  * limitations under the License.
  */
 
+use crate::config::Config;
 use crate::display::*;
-use crate::drive::course::Segment;
-use crate::drive::course::{Course, MapWrapper};
+use crate::drive::can;
+use crate::drive::course::{Course, MapWrapper, SectorTime};
+use crate::drive::dtc::DtcReport;
+use crate::drive::fix_quality::FixQuality;
 use crate::drive::gps;
 use crate::drive::imu;
 use crate::drive::obdii;
 use crate::drive::prepare;
-use crate::drive::read_track::Coord;
+use crate::drive::replay;
 use crate::drive::temp;
 use crate::drive::threading::Threading;
 use crate::drive::threading::ThreadingRef;
+use crate::gps_source::GpsSource;
 use dissolve::strip_html_tags;
 use gtk::prelude::*;
 use gtk::ResponseType;
@@ -56,42 +60,42 @@ pub fn button_press_event(display: DisplayRef, track_sel_info: prepare::TrackSel
     let mut champlain_view = champlain::gtk_embed::get_view(track_sel_info.map_widget.clone());
 
     let track_points = track_sel_info.track_points.take();
+    let circuit = track_sel_info.circuit.get();
+    let num_sectors = track_points.len();
 
     let (location_tx, location_rx) = mpsc::channel::<(f64, f64, i32, Option<bool>)>();
-    let (elapsed_tx, elapsed_rx) = mpsc::channel::<Duration>();
-    let (times_tx, times_rx) = mpsc::channel::<(Duration, Duration, Duration)>();
+    let (elapsed_tx, elapsed_rx) = mpsc::channel::<(Duration, Option<Duration>)>();
+    let (times_tx, times_rx) = mpsc::channel::<(usize, Duration, Duration, Duration, bool)>();
     let (time_diff_tx, time_diff_rx) = mpsc::channel::<(bool, Duration)>();
     let (obdii_tx, obdii_rx) = mpsc::channel::<obdii::OBDIIData>();
+    let (channels_tx, channels_rx) = mpsc::channel::<Vec<obdii::OBDIICommandType>>();
+    let (rates_tx, rates_rx) = mpsc::channel::<Vec<(obdii::OBDIICommandType, f64)>>();
+    let (dtc_tx, dtc_rx) = mpsc::channel::<Result<DtcReport, String>>();
     let (imu_tx, imu_rx) = mpsc::channel::<(f64, f64, Option<f64>, Option<f64>)>();
     let (imu_page_tx, imu_page_rx) = mpsc::channel::<(f64, f64, Option<f64>, Option<f64>)>();
     let (temp_tx, temp_rx) = mpsc::channel::<Vec<f64>>();
+    let (sector_tx, sector_rx) = mpsc::channel::<SectorTime>();
+    let (fix_tx, fix_rx) = mpsc::channel::<(FixQuality, String)>();
     let thread_info = Threading::new();
+    let config = Config::load();
 
     let window: gtk::ApplicationWindow = builder
         .get_object("MainPage")
         .expect("Couldn't find MainPage in ui file.");
 
     let thread_info_weak = ThreadingRef::downgrade(&thread_info);
+    let gps_config = config.clone();
+    let drive_log = if config.gpx_telemetry {
+        Some(replay::side_file(
+            &track_sel_info.track_file.borrow(),
+            "-drive.gpx",
+        ))
+    } else {
+        None
+    };
     let _handler_gpsd = thread::spawn(move || {
         let thread_info = upgrade_weak!(thread_info_weak);
-        let mut segments = Vec::new();
-
-        for points in track_points {
-            segments.push(Segment::new(
-                Coord::new(
-                    (&points).first().unwrap().lat,
-                    (&points).first().unwrap().lon,
-                    (&points).first().unwrap().head,
-                ),
-                Coord::new(
-                    (&points).last().unwrap().lat,
-                    (&points).last().unwrap().lon,
-                    (&points).last().unwrap().head,
-                ),
-            ));
-        }
-
-        let mut course_info = Course::new(segments);
+        let mut course_info = Course::from_track_points(&track_points, circuit);
 
         gps::gpsd_thread(
             thread_info,
@@ -99,32 +103,213 @@ pub fn button_press_event(display: DisplayRef, track_sel_info: prepare::TrackSel
             times_tx,
             time_diff_tx,
             location_tx,
+            sector_tx,
+            fix_tx,
             &mut course_info,
+            &gps_config,
+            drive_log,
         );
     });
 
-    let mut track_name = track_sel_info.track_file.borrow().clone();
+    if let GpsSource::Replay { file, speed } = config.gps.clone() {
+        // Replay the recorded data instead of reading from the hardware
+        replay::spawn_replay_threads(
+            &thread_info,
+            &file,
+            speed,
+            obdii_tx,
+            channels_tx,
+            imu_tx,
+            imu_page_tx,
+            Some(temp_tx),
+        );
+    } else {
+        thread_info.start_session_log(&replay::side_file(
+            &track_sel_info.track_file.borrow(),
+            "-session.dslog",
+        ));
+
+        if let Some(can_config) = config.can.clone() {
+            let thread_info_weak = ThreadingRef::downgrade(&thread_info);
+            let obdii_tx = obdii_tx.clone();
+            let _handler_can = thread::spawn(move || {
+                let thread_info = upgrade_weak!(thread_info_weak);
+
+                can::can_thread(thread_info, obdii_tx, &can_config);
+            });
+        }
+
+        let thread_info_weak = ThreadingRef::downgrade(&thread_info);
+        let _handler_obdii = thread::spawn(move || {
+            let thread_info = upgrade_weak!(thread_info_weak);
+
+            obdii::obdii_thread(
+                thread_info,
+                obdii_tx,
+                channels_tx,
+                rates_tx,
+                dtc_tx,
+                &config,
+            );
+        });
+
+        let thread_info_weak = ThreadingRef::downgrade(&thread_info);
+        let _handler_imu = thread::spawn(move || {
+            let thread_info = upgrade_weak!(thread_info_weak);
+
+            imu::imu_thread(thread_info, imu_tx, imu_page_tx);
+        });
+
+        let thread_info_weak = ThreadingRef::downgrade(&thread_info);
+        let _handler_imu = thread::spawn(move || {
+            let thread_info = upgrade_weak!(thread_info_weak);
+
+            temp::temp_thread(thread_info, temp_tx);
+        });
+    }
+
     let thread_info_weak = ThreadingRef::downgrade(&thread_info);
-    let _handler_obdii = thread::spawn(move || {
-        let thread_info = upgrade_weak!(thread_info_weak);
+    let display_weak = DisplayRef::downgrade(&display);
+    glib::timeout_add_local(10, move || {
+        let thread_info = upgrade_weak!(thread_info_weak, glib::source::Continue(false));
+        let display = upgrade_weak!(display_weak, glib::source::Continue(false));
+
+        let builder = display.builder.clone();
 
-        obdii::obdii_thread(thread_info, obdii_tx, &mut track_name).unwrap();
+        if thread_info.close.lock().unwrap().get() {
+            return glib::source::Continue(false);
+        }
+
+        thread_info.time_update_idle_thread(&elapsed_rx, &times_rx, &time_diff_rx, builder)
     });
 
-    let mut track_name = track_sel_info.track_file.borrow().clone();
+    // Add a row to the sectors page for each sector
+    let sector_grid = builder
+        .get_object::<gtk::Grid>("SectorGrid")
+        .expect("Can't find SectorGrid in ui file.");
+    let mut sector_labels = Vec::new();
+    for i in 0..num_sectors {
+        let row = i as i32 + 1;
+        let name = gtk::Label::new(Some(&format!("{}", i + 1)));
+        let time = gtk::Label::new(Some("--:--:---"));
+        let best = gtk::Label::new(Some("--:--:---"));
+        let diff = gtk::Label::new(None);
+
+        sector_grid.attach(&name, 0, row, 1, 1);
+        sector_grid.attach(&time, 1, row, 1, 1);
+        sector_grid.attach(&best, 2, row, 1, 1);
+        sector_grid.attach(&diff, 3, row, 1, 1);
+
+        sector_labels.push((time, best, diff));
+    }
+
     let thread_info_weak = ThreadingRef::downgrade(&thread_info);
-    let _handler_imu = thread::spawn(move || {
-        let thread_info = upgrade_weak!(thread_info_weak);
+    let display_weak = DisplayRef::downgrade(&display);
+    glib::timeout_add_local(10, move || {
+        let thread_info = upgrade_weak!(thread_info_weak, glib::source::Continue(false));
+        let display = upgrade_weak!(display_weak, glib::source::Continue(false));
+
+        let builder = display.builder.clone();
+
+        if thread_info.close.lock().unwrap().get() {
+            for child in sector_grid.get_children() {
+                if sector_grid.get_cell_top_attach(&child) > 0 {
+                    sector_grid.remove(&child);
+                }
+            }
+            return glib::source::Continue(false);
+        }
 
-        imu::imu_thread(thread_info, imu_tx, imu_page_tx, &mut track_name);
+        thread_info.sector_update_idle_thread(&sector_rx, &sector_labels, builder)
     });
 
-    let mut track_name = track_sel_info.track_file.borrow().clone();
     let thread_info_weak = ThreadingRef::downgrade(&thread_info);
-    let _handler_imu = thread::spawn(move || {
-        let thread_info = upgrade_weak!(thread_info_weak);
+    let display_weak = DisplayRef::downgrade(&display);
+    let obdii_data = Rc::new(RefCell::new(obdii::OBDIIGraphData::new()));
+    thread_info.set_cairo_graphs(&builder, &obdii_data);
+
+    // Add a chart to the OBDII page for each custom PID and CAN signal
+    let custom_box = builder
+        .get_object::<gtk::Box>("OBDIICustomCharts")
+        .expect("Can't find OBDIICustomCharts in ui file.");
+    let mut custom_charts = Vec::new();
+    let commands = obdii::OBDIICommandType::custom()
+        .into_iter()
+        .chain(obdii::OBDIICommandType::can());
+    for command in commands {
+        let (units, min, max) = match command.chart() {
+            Some(chart) => chart,
+            None => continue,
+        };
+        let caption = if units.is_empty() {
+            command.name().to_string()
+        } else {
+            format!("{} ({})", command.name(), units)
+        };
+
+        let chart = gtk::DrawingArea::new();
+        chart.set_size_request(-1, 200);
+
+        let obdii_data_cloned = obdii_data.clone();
+        chart.connect_draw(move |me, cr| {
+            let width = me.get_allocated_width() as f64 * 0.07;
+            let height = me.get_allocated_width() as f64 * 0.07;
+
+            let root = CairoBackend::new(cr, (500, 500))
+                .unwrap()
+                .into_drawing_area();
+
+            let mut chart = ChartBuilder::on(&root)
+                .margin(10)
+                .caption(&caption, ("sans-serif", 30).into_font())
+                .x_label_area_size(width as u32)
+                .y_label_area_size(height as u32)
+                .build_cartesian_2d(0..100 as u32, min..max)
+                .unwrap();
+
+            chart.configure_mesh().draw().unwrap();
+
+            if let Some(values) = obdii_data_cloned.borrow().chart_values(command) {
+                chart
+                    .draw_series(AreaSeries::new(
+                        values.iter().enumerate().map(|(x, y)| (x as u32, *y)),
+                        min,
+                        &MAGENTA.mix(0.2),
+                    ))
+                    .unwrap();
+            }
+
+            Inhibit(true)
+        });
+
+        custom_box.pack_start(&chart, true, true, 0);
+        custom_charts.push(chart);
+    }
+
+    let charts_thread_weak = ThreadingRef::downgrade(&thread_info);
+    glib::timeout_add_local(100, move || {
+        let thread_info = upgrade_weak!(charts_thread_weak, glib::source::Continue(false));
+
+        if thread_info.close.lock().unwrap().get() {
+            for chart in custom_charts.iter() {
+                custom_box.remove(chart);
+            }
+            return glib::source::Continue(false);
+        }
+
+        for chart in custom_charts.iter() {
+            chart.queue_draw();
+        }
+        glib::source::Continue(true)
+    });
+
+    glib::timeout_add_local(10, move || {
+        let thread_info = upgrade_weak!(thread_info_weak, glib::source::Continue(false));
+        let display = upgrade_weak!(display_weak, glib::source::Continue(false));
+
+        let builder = display.builder.clone();
 
-        temp::temp_thread(thread_info, temp_tx, &mut track_name);
+        thread_info.obdii_update_idle_thread(&obdii_rx, builder, &obdii_data)
     });
 
     let thread_info_weak = ThreadingRef::downgrade(&thread_info);
@@ -139,21 +324,77 @@ pub fn button_press_event(display: DisplayRef, track_sel_info: prepare::TrackSel
             return glib::source::Continue(false);
         }
 
-        thread_info.time_update_idle_thread(&elapsed_rx, &times_rx, &time_diff_rx, builder)
+        thread_info.obdii_channels_idle_thread(&channels_rx, builder)
     });
 
     let thread_info_weak = ThreadingRef::downgrade(&thread_info);
     let display_weak = DisplayRef::downgrade(&display);
-    let obdii_data = Rc::new(RefCell::new(obdii::OBDIIGraphData::new()));
-    thread_info.set_cairo_graphs(&builder, &obdii_data);
+    glib::timeout_add_local(10, move || {
+        let thread_info = upgrade_weak!(thread_info_weak, glib::source::Continue(false));
+        let display = upgrade_weak!(display_weak, glib::source::Continue(false));
 
+        let builder = display.builder.clone();
+
+        if thread_info.close.lock().unwrap().get() {
+            return glib::source::Continue(false);
+        }
+
+        thread_info.obdii_rates_idle_thread(&rates_rx, builder)
+    });
+
+    let thread_info_weak = ThreadingRef::downgrade(&thread_info);
+    let display_weak = DisplayRef::downgrade(&display);
     glib::timeout_add_local(10, move || {
         let thread_info = upgrade_weak!(thread_info_weak, glib::source::Continue(false));
         let display = upgrade_weak!(display_weak, glib::source::Continue(false));
 
         let builder = display.builder.clone();
 
-        thread_info.obdii_update_idle_thread(&obdii_rx, builder, &obdii_data)
+        if thread_info.close.lock().unwrap().get() {
+            return glib::source::Continue(false);
+        }
+
+        thread_info.dtc_update_idle_thread(&dtc_rx, builder)
+    });
+
+    let dtc_read_button = builder
+        .get_object::<gtk::Button>("DtcReadButton")
+        .expect("Can't find DtcReadButton in ui file.");
+
+    let thread_info_weak = ThreadingRef::downgrade(&thread_info);
+    dtc_read_button.connect_clicked(move |_| {
+        let thread_info = upgrade_weak!(thread_info_weak);
+
+        thread_info.read_dtcs.lock().unwrap().set(true);
+    });
+
+    let dtc_clear_button = builder
+        .get_object::<gtk::Button>("DtcClearButton")
+        .expect("Can't find DtcClearButton in ui file.");
+
+    let thread_info_weak = ThreadingRef::downgrade(&thread_info);
+    let window_weak = window.downgrade();
+    dtc_clear_button.connect_clicked(move |_| {
+        let thread_info = upgrade_weak!(thread_info_weak);
+        let window = upgrade_weak!(window_weak);
+
+        let dialog = gtk::MessageDialog::new(
+            Some(&window),
+            gtk::DialogFlags::MODAL,
+            gtk::MessageType::Question,
+            gtk::ButtonsType::YesNo,
+            "Clear the trouble codes?",
+        );
+        dialog.set_property_secondary_text(Some(
+            "This also clears the freeze frame and resets the emissions monitors.",
+        ));
+
+        let response = dialog.run();
+        dialog.close();
+
+        if response == ResponseType::Yes {
+            thread_info.clear_dtcs.lock().unwrap().set(true);
+        }
     });
 
     let thread_info_weak = ThreadingRef::downgrade(&thread_info);
@@ -171,6 +412,21 @@ pub fn button_press_event(display: DisplayRef, track_sel_info: prepare::TrackSel
         thread_info.temp_update_idle_thread(&temp_rx, builder)
     });
 
+    let thread_info_weak = ThreadingRef::downgrade(&thread_info);
+    let display_weak = DisplayRef::downgrade(&display);
+    glib::timeout_add_local(10, move || {
+        let thread_info = upgrade_weak!(thread_info_weak, glib::source::Continue(false));
+        let display = upgrade_weak!(display_weak, glib::source::Continue(false));
+
+        let builder = display.builder.clone();
+
+        if thread_info.close.lock().unwrap().get() {
+            return glib::source::Continue(false);
+        }
+
+        thread_info.fix_update_idle_thread(&fix_rx, builder)
+    });
+
     let imu_area: gtk::DrawingArea = builder
         .get_object("AccelDrawingArea")
         .expect("Couldn't find AccelDrawingArea in ui file.");
@@ -246,6 +502,34 @@ pub fn button_press_event(display: DisplayRef, track_sel_info: prepare::TrackSel
         }
     });
 
+    let load_button = builder
+        .get_object::<gtk::Button>("DriveOptionsPopOverLoad")
+        .expect("Can't find DriveOptionsPopOverLoad in ui file.");
+
+    let thread_info_weak = ThreadingRef::downgrade(&thread_info);
+    let window_weak = window.downgrade();
+    load_button.connect_clicked(move |_| {
+        let thread_info = upgrade_weak!(thread_info_weak);
+        let window = upgrade_weak!(window_weak);
+
+        let file_chooser = gtk::FileChooserNative::new(
+            Some("Load times from"),
+            Some(&window),
+            gtk::FileChooserAction::Open,
+            Some("Open"),
+            Some("Close"),
+        );
+
+        let response = file_chooser.run();
+        if response == ResponseType::Accept {
+            if let Some(filepath) = file_chooser.get_filename() {
+                let mut time_file = thread_info.time_file.write().unwrap();
+                *time_file = filepath;
+                thread_info.deserialise.lock().unwrap().set(true);
+            }
+        }
+    });
+
     let calibrate_button = display
         .builder
         .get_object::<gtk::Button>("CalibrateOptionsPopOverSave")
@@ -524,6 +808,20 @@ impl Threading {
             }
         });
 
+        let theoretical_best_time = builder
+            .get_object::<gtk::Label>("TheoreticalBestTime")
+            .expect("Can't find TheoreticalBestTime in ui file.");
+        theoretical_best_time.connect_size_allocate({
+            move |me, allocation| {
+                let markup = format!(
+                    "<span font_desc=\"{}\" foreground=\"#5c5c35356666\">{}</span>",
+                    allocation.width / 6,
+                    strip_html_tags(&me.get_text()).first().unwrap()
+                );
+                me.set_markup(&markup);
+            }
+        });
+
         let worst_time = builder
             .get_object::<gtk::Label>("WorstTime")
             .expect("Can't find WorstTime in ui file.");
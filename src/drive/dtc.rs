@@ -0,0 +1,283 @@
+/*
+ * Copyright 2020 Alistair Francis <alistair@alistair23.me>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *    http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Reads and clears the diagnostic trouble codes (DTCs) stored by
+//! the car, along with the freeze frame saved when the MIL came on.
+
+use crate::drive::elm327::{Elm327, Elm327Error};
+use crate::drive::obdii::{OBDIICommandType, OBDIIData};
+use std::fmt;
+use std::io::{Read, Write};
+
+/// The mode used to clear the DTCs
+const CLEAR_MODE: u8 = 0x04;
+
+/// A diagnostic trouble code, such as P0133
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Dtc(pub u16);
+
+impl fmt::Display for Dtc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let system = ['P', 'C', 'B', 'U'][(self.0 >> 14) as usize];
+        write!(
+            f,
+            "{}{:X}{:03X}",
+            system,
+            (self.0 >> 12) & 0x3,
+            self.0 & 0xFFF
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DtcKind {
+    /// Confirmed codes, these turn on the MIL
+    Stored,
+    /// Codes seen during the current or last drive cycle
+    Pending,
+    /// Codes that can't be cleared, they go once the car has
+    /// checked the fault is fixed
+    Permanent,
+}
+
+impl DtcKind {
+    /// The mode used to read the codes
+    pub fn mode(self) -> u8 {
+        match self {
+            DtcKind::Stored => 0x03,
+            DtcKind::Pending => 0x07,
+            DtcKind::Permanent => 0x0A,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DtcKind::Stored => "stored",
+            DtcKind::Pending => "pending",
+            DtcKind::Permanent => "permanent",
+        }
+    }
+}
+
+/// The values saved by the car when a DTC was stored
+pub struct FreezeFrame {
+    /// The DTC that caused the freeze frame to be saved
+    pub dtc: Dtc,
+    pub values: Vec<OBDIIData>,
+}
+
+pub struct DtcReport {
+    pub stored: Vec<Dtc>,
+    pub pending: Vec<Dtc>,
+    pub permanent: Vec<Dtc>,
+    pub freeze_frame: Option<FreezeFrame>,
+}
+
+impl DtcReport {
+    pub fn codes(&self) -> [(DtcKind, &[Dtc]); 3] {
+        [
+            (DtcKind::Stored, &self.stored),
+            (DtcKind::Pending, &self.pending),
+            (DtcKind::Permanent, &self.permanent),
+        ]
+    }
+}
+
+/// Decode the DTCs in a response to mode 3, 7 or 10. CAN responses
+/// start with the number of codes.
+fn decode_dtcs(data: &[u8], can: bool) -> Vec<Dtc> {
+    let data = if can {
+        data.get(1..).unwrap_or(&[])
+    } else {
+        data
+    };
+
+    data.chunks_exact(2)
+        .map(|c| u16::from_be_bytes([c[0], c[1]]))
+        // Unused codes are padded with zeros
+        .filter(|c| *c != 0)
+        .map(Dtc)
+        .collect()
+}
+
+/// Read the `kind` DTCs from every ECU
+pub fn read_dtcs<P: Read + Write>(
+    elm: &mut Elm327<P>,
+    kind: DtcKind,
+) -> Result<Vec<Dtc>, Elm327Error> {
+    let can = elm.is_can();
+
+    match elm.request(&[kind.mode()]) {
+        Ok(responses) => Ok(responses.iter().flat_map(|r| decode_dtcs(r, can)).collect()),
+        // Some cars don't respond if there aren't any codes, older
+        // cars don't support permanent codes.
+        Err(Elm327Error::NoData) | Err(Elm327Error::Unsupported(_)) => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Request `pid` from the first freeze frame. Returns None if
+/// the car doesn't have it.
+fn query_freeze_frame<P: Read + Write>(
+    elm: &mut Elm327<P>,
+    pid: u8,
+) -> Result<Option<Vec<u8>>, Elm327Error> {
+    let responses = match elm.request(&[0x02, pid, 0x00]) {
+        Ok(responses) => responses,
+        Err(Elm327Error::Io(e)) => return Err(Elm327Error::Io(e)),
+        Err(_) => return Ok(None),
+    };
+
+    Ok(responses
+        .iter()
+        .find(|r| r.len() > 2 && r[0] == pid && r[1] == 0x00)
+        .map(|r| r[2..].to_vec()))
+}
+
+/// Read the freeze frame, if the car has saved one
+pub fn read_freeze_frame<P: Read + Write>(
+    elm: &mut Elm327<P>,
+) -> Result<Option<FreezeFrame>, Elm327Error> {
+    // PID 2 is the DTC that caused the freeze frame, 0 if there isn't one
+    let dtc = match query_freeze_frame(elm, 0x02)? {
+        Some(data) if data.len() >= 2 && (data[0] != 0 || data[1] != 0) => {
+            Dtc(u16::from_be_bytes([data[0], data[1]]))
+        }
+        _ => return Ok(None),
+    };
+
+    let mut values = Vec::new();
+    for command in OBDIICommandType::all() {
+        if let Some(data) = query_freeze_frame(elm, command.pid())? {
+            if let Some(value) = OBDIIData::decode(command, &data) {
+                values.push(value);
+            }
+        }
+    }
+
+    Ok(Some(FreezeFrame { dtc, values }))
+}
+
+/// Read all of the DTCs and the freeze frame
+pub fn read_report<P: Read + Write>(elm: &mut Elm327<P>) -> Result<DtcReport, Elm327Error> {
+    Ok(DtcReport {
+        stored: read_dtcs(elm, DtcKind::Stored)?,
+        pending: read_dtcs(elm, DtcKind::Pending)?,
+        permanent: read_dtcs(elm, DtcKind::Permanent)?,
+        freeze_frame: read_freeze_frame(elm)?,
+    })
+}
+
+/// Clear the stored and pending DTCs and the freeze frame. This also
+/// turns off the MIL and resets the emissions monitors.
+pub fn clear_dtcs<P: Read + Write>(elm: &mut Elm327<P>) -> Result<(), Elm327Error> {
+    elm.request(&[CLEAR_MODE])?;
+    Ok(())
+}
+
+/// The session log record for reading the `kind` DTCs, or for
+/// clearing them if `kind` is None
+pub fn log_record(kind: Option<DtcKind>, codes: &[Dtc]) -> Vec<u8> {
+    let mut data = vec![kind.map(|k| k.mode()).unwrap_or(CLEAR_MODE)];
+    for code in codes {
+        data.extend_from_slice(&code.0.to_be_bytes());
+    }
+    data
+}
+
+/// Convert a session log record back into the event and the codes
+pub fn decode_log_record(data: &[u8]) -> Option<(&'static str, Vec<Dtc>)> {
+    let event = match *data.first()? {
+        CLEAR_MODE => "cleared",
+        mode => [DtcKind::Stored, DtcKind::Pending, DtcKind::Permanent]
+            .iter()
+            .find(|k| k.mode() == mode)?
+            .name(),
+    };
+
+    Some((event, decode_dtcs(&data[1..], false)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drive::elm327::tests::Elm327Sim;
+
+    #[test]
+    fn test_dtc() {
+        assert_eq!(Dtc(0x0133).to_string(), "P0133");
+        assert_eq!(Dtc(0x4123).to_string(), "C0123");
+        assert_eq!(Dtc(0x9234).to_string(), "B1234");
+        assert_eq!(Dtc(0xC001).to_string(), "U0001");
+
+        assert_eq!(
+            decode_dtcs(&[0x02, 0x01, 0x33, 0x01, 0x71], true),
+            vec![Dtc(0x0133), Dtc(0x0171)]
+        );
+        assert_eq!(
+            decode_dtcs(&[0x01, 0x33, 0x00, 0x00, 0x00, 0x00], false),
+            vec![Dtc(0x0133)]
+        );
+    }
+
+    #[test]
+    fn test_read_report() {
+        let mut sim = Elm327Sim::default();
+        sim.responses
+            .insert("03".to_string(), "43 02 01 33 01 71".to_string());
+        sim.responses
+            .insert("07".to_string(), "47 01 03 00".to_string());
+        sim.responses
+            .insert("020200".to_string(), "42 02 00 01 33".to_string());
+        sim.responses
+            .insert("020C00".to_string(), "42 0C 00 1A F8".to_string());
+        sim.responses.insert("04".to_string(), "44".to_string());
+        let mut elm = Elm327::new(sim);
+        elm.init().unwrap();
+
+        let report = read_report(&mut elm).unwrap();
+        assert_eq!(report.stored, vec![Dtc(0x0133), Dtc(0x0171)]);
+        assert_eq!(report.pending, vec![Dtc(0x0300)]);
+        // Permanent codes aren't supported
+        assert!(report.permanent.is_empty());
+
+        let freeze_frame = report.freeze_frame.unwrap();
+        assert_eq!(freeze_frame.dtc, Dtc(0x0133));
+        assert_eq!(freeze_frame.values.len(), 1);
+        assert_eq!(freeze_frame.values[0].command, OBDIICommandType::Rpm);
+        assert_eq!(freeze_frame.values[0].val_float, Some(1726.0));
+
+        clear_dtcs(&mut elm).unwrap();
+    }
+
+    #[test]
+    fn test_log_record() {
+        let codes = [Dtc(0x0133), Dtc(0xC001)];
+
+        let record = log_record(Some(DtcKind::Pending), &codes);
+        assert_eq!(record, vec![0x07, 0x01, 0x33, 0xC0, 0x01]);
+        assert_eq!(
+            decode_log_record(&record),
+            Some(("pending", codes.to_vec()))
+        );
+
+        assert_eq!(
+            decode_log_record(&log_record(None, &[])),
+            Some(("cleared", Vec::new()))
+        );
+        assert_eq!(decode_log_record(&[0x01]), None);
+    }
+}
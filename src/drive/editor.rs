@@ -0,0 +1,384 @@
+/*
+ * Copyright 2020 Alistair Francis <alistair@alistair23.me>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *    http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Edits the start, sector and finish lines of a course before
+//! driving it. The lines can only be moved along the recorded track,
+//! so they always go across it.
+
+use crate::drive::gate::{GateGeometry, GateSettings};
+use crate::drive::read_track::Coord;
+use crate::record::print;
+use crate::utils::lat_lon_distance;
+use std::fs::File;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+/// How far a line can be rotated from square across the track, in degrees.
+/// This doesn't change the heading used to check we are driving the
+/// right way.
+pub const MAX_ROTATION: f32 = 25.0;
+
+/// The narrowest and widest a line can be, in metres
+pub const MIN_WIDTH: f32 = 2.0;
+pub const MAX_WIDTH: f32 = 100.0;
+
+/// A timing line across the track
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Line {
+    /// The track point the line goes through
+    pub index: usize,
+    /// The rotation from square across the track, in degrees
+    pub rotation: f32,
+    /// The width of the line, in metres
+    pub width: f32,
+}
+
+pub struct CourseEditor {
+    /// All of the points of the track
+    points: Vec<Coord>,
+    /// The start of each sector, followed by the finish
+    lines: Vec<Line>,
+}
+
+impl CourseEditor {
+    /// Edit the course from the points of a track file. Each
+    /// track segment is a sector.
+    pub fn new(track_points: &[Vec<Coord>]) -> CourseEditor {
+        let mut points: Vec<Coord> = Vec::new();
+        let mut lines = Vec::new();
+
+        for segment in track_points.iter().filter(|s| !s.is_empty()) {
+            // Saved courses share the point between two sectors
+            let shared = match (points.last(), segment.first()) {
+                (Some(last), Some(first)) => last.lat == first.lat && last.lon == first.lon,
+                _ => false,
+            };
+            if shared {
+                points.pop();
+            }
+
+            let geometry = GateGeometry::default().with(&segment[0].gate);
+            lines.push(Line {
+                index: points.len(),
+                rotation: geometry.angle,
+                width: geometry.width,
+            });
+            points.extend(segment.iter().cloned());
+        }
+
+        let finish = GateGeometry::default().with(&points.last().unwrap().gate);
+        lines.push(Line {
+            index: points.len() - 1,
+            rotation: finish.angle,
+            width: finish.width,
+        });
+
+        CourseEditor { points, lines }
+    }
+
+    pub fn points(&self) -> &[Coord] {
+        &self.points
+    }
+
+    /// The start of each sector, followed by the finish
+    pub fn lines(&self) -> &[Line] {
+        &self.lines
+    }
+
+    pub fn num_sectors(&self) -> usize {
+        self.lines.len() - 1
+    }
+
+    /// The track points `line` can be moved to without
+    /// passing the lines either side of it
+    fn range(&self, line: usize) -> RangeInclusive<usize> {
+        let min = match line {
+            0 => 0,
+            _ => self.lines[line - 1].index + 1,
+        };
+        let max = match self.lines.get(line + 1) {
+            Some(next) => next.index.saturating_sub(1),
+            None => self.points.len() - 1,
+        };
+
+        min..=max
+    }
+
+    /// Move `line` to the track point `index`. A line can't be moved past
+    /// the lines either side of it. Returns where the line ended up.
+    pub fn move_line(&mut self, line: usize, index: usize) -> usize {
+        let range = self.range(line);
+
+        // There is no room to move the line
+        if range.is_empty() {
+            return self.lines[line].index;
+        }
+
+        self.lines[line].index = index.clamp(*range.start(), *range.end());
+        self.lines[line].index
+    }
+
+    /// The line closest to `lat` and `lon`, if it is within half
+    /// the width of the line from its point on the track
+    pub fn line_at(&self, lat: f64, lon: f64) -> Option<usize> {
+        self.lines
+            .iter()
+            .enumerate()
+            .map(|(i, line)| {
+                let point = &self.points[line.index];
+                (
+                    i,
+                    lat_lon_distance(lat, lon, point.lat, point.lon),
+                    line.width,
+                )
+            })
+            .filter(|(_, distance, width)| *distance <= *width as f64 / 2.0)
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+            .map(|(i, _, _)| i)
+    }
+
+    /// The track point closest to `lat` and `lon` that
+    /// `line` can be moved to
+    pub fn nearest_point(&self, line: usize, lat: f64, lon: f64) -> usize {
+        self.range(line)
+            .map(|i| {
+                let point = &self.points[i];
+                (i, lat_lon_distance(lat, lon, point.lat, point.lon))
+            })
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+            .map(|(i, _)| i)
+            .unwrap_or(self.lines[line].index)
+    }
+
+    /// Rotate `line` by `rotation` degrees from square across the track
+    pub fn set_rotation(&mut self, line: usize, rotation: f32) {
+        self.lines[line].rotation = rotation.clamp(-MAX_ROTATION, MAX_ROTATION);
+    }
+
+    pub fn set_width(&mut self, line: usize, width: f32) {
+        self.lines[line].width = width.clamp(MIN_WIDTH, MAX_WIDTH);
+    }
+
+    /// Split `sector` in half. Returns the new line, or None if
+    /// the sector is too short to split.
+    pub fn add_sector(&mut self, sector: usize) -> Option<usize> {
+        let start = self.lines[sector].index;
+        let finish = self.lines[sector + 1].index;
+        if finish - start < 2 {
+            return None;
+        }
+
+        self.lines.insert(
+            sector + 1,
+            Line {
+                index: (start + finish) / 2,
+                rotation: 0.0,
+                width: GateGeometry::default().width,
+            },
+        );
+
+        Some(sector + 1)
+    }
+
+    /// Remove `line`, joining the sectors either side of it. The start
+    /// and finish can't be removed. Returns true if the line was removed.
+    pub fn remove_line(&mut self, line: usize) -> bool {
+        if line == 0 || line + 1 >= self.lines.len() {
+            return false;
+        }
+
+        self.lines.remove(line);
+        true
+    }
+
+    /// The point, heading, angle and width of `line`
+    pub fn gate(&self, line: usize) -> Coord {
+        let line = &self.lines[line];
+        let point = &self.points[line.index];
+
+        Coord {
+            lat: point.lat,
+            lon: point.lon,
+            head: point.head,
+            gate: GateSettings {
+                width: Some(line.width),
+                angle: Some(line.rotation).filter(|rotation| *rotation != 0.0),
+                ..point.gate
+            },
+        }
+    }
+
+    /// The points of each segment of the edited course
+    pub fn segments(&self) -> Vec<Vec<Coord>> {
+        (0..self.num_sectors())
+            .map(|sector| {
+                let start = self.lines[sector].index;
+                let finish = self.lines[sector + 1].index;
+
+                let mut points = Vec::new();
+                points.push(self.gate(sector));
+                points.extend(self.points[start + 1..finish].iter().cloned());
+                points.push(self.gate(sector + 1));
+                points
+            })
+            .collect()
+    }
+
+    /// Save the edited course as a track file
+    pub fn save(&self, file: &Path) -> Result<(), std::io::Error> {
+        let mut fd = File::create(file)?;
+
+        print::gpx_start(&mut fd)?;
+        print::gpx_metadata(&mut fd)?;
+        let name = file
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("course");
+        print::gpx_track_start(&mut fd, name.to_string())?;
+
+        for segment in self.segments() {
+            print::gpx_track_seg_start(&mut fd)?;
+            for coord in segment.iter() {
+                print::gpx_course_point(&mut fd, coord)?;
+            }
+            print::gpx_track_seg_stop(&mut fd)?;
+        }
+
+        print::gpx_track_stop(&mut fd)?;
+        print::gpx_stop(&mut fd)?;
+        fd.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drive::read_track::get_long_and_lat;
+    use std::io::BufReader;
+
+    /// Three segments of ten points, heading north
+    fn track() -> Vec<Vec<Coord>> {
+        (0..3)
+            .map(|segment| {
+                (0..10)
+                    .map(|i| {
+                        let lat = 37.0 + (segment * 10 + i) as f64 * 0.0001;
+                        Coord::new(lat, -122.0, Some(1.0))
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_edit_lines() {
+        let mut editor = CourseEditor::new(&track());
+        assert_eq!(editor.points().len(), 30);
+        let indexes: Vec<usize> = editor.lines().iter().map(|l| l.index).collect();
+        assert_eq!(indexes, vec![0, 10, 20, 29]);
+
+        // Lines can't pass each other
+        assert_eq!(editor.move_line(1, 25), 19);
+        assert_eq!(editor.move_line(1, 5), 5);
+        assert_eq!(editor.move_line(0, 8), 4);
+        assert_eq!(editor.move_line(3, 100), 29);
+
+        assert_eq!(editor.add_sector(1), Some(2));
+        let indexes: Vec<usize> = editor.lines().iter().map(|l| l.index).collect();
+        assert_eq!(indexes, vec![4, 5, 12, 20, 29]);
+        // The first sector is too short to split
+        assert_eq!(editor.add_sector(0), None);
+
+        assert!(!editor.remove_line(0));
+        assert!(!editor.remove_line(4));
+        assert!(editor.remove_line(1));
+        assert_eq!(editor.num_sectors(), 3);
+
+        editor.set_rotation(0, -90.0);
+        editor.set_width(0, 12.5);
+        let gate = editor.gate(0);
+        // Only the line is rotated, not the heading we need to be going
+        assert_eq!(gate.head, Some(1.0));
+        assert_eq!(gate.gate.angle, Some(-MAX_ROTATION));
+        assert_eq!(gate.gate.width, Some(12.5));
+        editor.set_width(0, 1000.0);
+        assert_eq!(editor.gate(0).gate.width, Some(MAX_WIDTH));
+
+        // The sectors share the line between them
+        let segments = editor.segments();
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[0].len(), 9);
+        assert_eq!(segments[0][0].gate.angle, Some(-MAX_ROTATION));
+        assert_eq!(segments[0].last().unwrap().lat, segments[1][0].lat);
+    }
+
+    #[test]
+    fn test_drag_lines() {
+        let mut editor = CourseEditor::new(&track());
+        let points = editor.points().to_vec();
+
+        // Only grab a line when close to it
+        assert_eq!(editor.line_at(points[10].lat, -122.0), Some(1));
+        assert_eq!(editor.line_at(points[10].lat, -122.001), None);
+        assert_eq!(editor.line_at(points[15].lat, -122.0), None);
+
+        // Snap to the track, stopping before the next line
+        assert_eq!(editor.nearest_point(1, points[14].lat, -122.00001), 14);
+        assert_eq!(editor.nearest_point(1, points[25].lat, -122.0), 19);
+        assert_eq!(editor.nearest_point(0, points[2].lat, -121.999), 2);
+        assert_eq!(editor.move_line(1, 14), 14);
+    }
+
+    #[test]
+    fn test_single_point() {
+        let mut editor = CourseEditor::new(&[vec![Coord::new(37.0, -122.0, Some(1.0))]]);
+        let indexes: Vec<usize> = editor.lines().iter().map(|l| l.index).collect();
+        assert_eq!(indexes, vec![0, 0]);
+
+        // There is nowhere to move the lines to
+        assert_eq!(editor.move_line(0, 5), 0);
+        assert_eq!(editor.move_line(1, 5), 0);
+        assert_eq!(editor.nearest_point(0, 38.0, -122.0), 0);
+    }
+
+    #[test]
+    fn test_save() {
+        let file = std::env::temp_dir().join("dashsight-test-editor.gpx");
+        let mut editor = CourseEditor::new(&track());
+        editor.move_line(0, 3);
+        editor.set_rotation(0, 10.0);
+        editor.set_width(2, 30.0);
+        editor.remove_line(1);
+        editor.save(&file).unwrap();
+
+        let track_points = get_long_and_lat(BufReader::new(File::open(&file).unwrap())).unwrap();
+        assert_eq!(track_points.len(), 2);
+        assert_eq!(track_points[0][0].lat, editor.points()[3].lat);
+        assert_eq!(track_points[0][0].head, Some(1.0));
+        assert_eq!(track_points[0][0].gate.angle, Some(10.0));
+        assert_eq!(track_points[1][0].gate.width, Some(30.0));
+
+        // Loading the saved course gives the same lines
+        let loaded = CourseEditor::new(&track_points);
+        assert_eq!(loaded.points().len(), 27);
+        let indexes: Vec<usize> = loaded.lines().iter().map(|l| l.index).collect();
+        assert_eq!(indexes, vec![0, 17, 26]);
+        assert_eq!(loaded.lines()[0].rotation, 10.0);
+        assert_eq!(loaded.lines()[1].width, 30.0);
+
+        std::fs::remove_file(&file).unwrap();
+    }
+}
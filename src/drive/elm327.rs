@@ -0,0 +1,579 @@
+/*
+ * Copyright 2020 Alistair Francis <alistair@alistair23.me>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *    http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait for the ELM327 to reply to a single command.
+/// The first OBDII request can take several seconds while the
+/// adapter searches for the car's protocol.
+const ELM327_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum Elm327Error {
+    /// Reading or writing the serial port failed
+    Io(io::Error),
+    /// The car didn't respond to the request
+    NoData,
+    /// The ELM327 couldn't talk to the car
+    UnableToConnect,
+    /// The ELM327 didn't understand the command
+    Unsupported(String),
+    /// The response couldn't be decoded
+    BadResponse(String),
+}
+
+impl From<io::Error> for Elm327Error {
+    fn from(err: io::Error) -> Self {
+        Elm327Error::Io(err)
+    }
+}
+
+pub struct Elm327<P: Read + Write> {
+    port: P,
+    protocol: Option<String>,
+    /// The header set with `ATSH`, None if it is the default
+    header: Option<String>,
+}
+
+impl Elm327<Box<dyn serialport::SerialPort>> {
+    /// Open the ELM327 connected to `device` and initialise it.
+    /// `device` can be a real serial port or a pseudo-terminal
+    /// connected to an ELM327 simulator.
+    pub fn open(device: &str, baud: u32) -> Result<Self, Elm327Error> {
+        let port = serialport::new(device, baud)
+            .timeout(ELM327_TIMEOUT)
+            .open()
+            .map_err(io::Error::from)?;
+
+        let mut elm = Elm327::new(port);
+        elm.init()?;
+
+        Ok(elm)
+    }
+}
+
+impl<P: Read + Write> Elm327<P> {
+    pub fn new(port: P) -> Self {
+        Elm327 {
+            port,
+            protocol: None,
+            header: None,
+        }
+    }
+
+    /// The OBDII protocol number reported by `ATDPN`, if known
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    /// Reset the ELM327 and configure it so that responses are
+    /// easy to parse, then let it automatically detect the protocol
+    /// the car is using.
+    pub fn init(&mut self) -> Result<(), Elm327Error> {
+        // Reset, this also gives us a prompt we can sync to
+        self.command("ATZ")?;
+        thread::sleep(Duration::from_millis(500));
+
+        // Echo off
+        self.command("ATE0")?;
+        // Linefeeds off
+        self.command("ATL0")?;
+        // Headers off
+        self.command("ATH0")?;
+        // Spaces off, some clones don't support this so ignore errors
+        let _ = self.command("ATS0");
+
+        // Automatically detect the protocol
+        self.command("ATSP0")?;
+
+        // The first request triggers the protocol search
+        match self.query(0x01, 0x00) {
+            Ok(_) | Err(Elm327Error::NoData) => {}
+            Err(e) => return Err(e),
+        }
+
+        let protocol = self.command("ATDPN")?;
+        self.protocol = protocol.first().map(|p| p.to_string());
+
+        Ok(())
+    }
+
+    /// Send a raw command and return the lines of the response,
+    /// with any blank lines, echo and status messages removed.
+    pub fn command(&mut self, cmd: &str) -> Result<Vec<String>, Elm327Error> {
+        self.port.write_all(cmd.as_bytes())?;
+        self.port.write_all(b"\r")?;
+        self.port.flush()?;
+
+        let response = self.read_until_prompt()?;
+
+        let mut lines = Vec::new();
+        for line in response.split(['\r', '\n']) {
+            let line = line.trim();
+
+            if line.is_empty() || line == cmd || line.starts_with("SEARCHING") {
+                continue;
+            }
+            if line.starts_with("BUS INIT") && line.ends_with("OK") {
+                continue;
+            }
+
+            match line {
+                "?" => return Err(Elm327Error::Unsupported(cmd.to_string())),
+                "NO DATA" | "STOPPED" => return Err(Elm327Error::NoData),
+                "UNABLE TO CONNECT" | "CAN ERROR" | "BUS ERROR" | "BUS INIT: ...ERROR" => {
+                    return Err(Elm327Error::UnableToConnect)
+                }
+                _ => lines.push(line.to_string()),
+            }
+        }
+
+        Ok(lines)
+    }
+
+    /// True if the car uses CAN, this changes the format of
+    /// some responses.
+    pub fn is_can(&self) -> bool {
+        // "A6" is protocol 6, found automatically
+        let protocol = match self.protocol() {
+            Some(p) if p.len() == 2 => &p[1..],
+            Some(p) => p,
+            None => return false,
+        };
+
+        matches!(protocol, "6" | "7" | "8" | "9" | "A" | "B" | "C")
+    }
+
+    /// Send requests to `header`, or to every ECU if `header` is None
+    pub fn set_header(&mut self, header: Option<&str>) -> Result<(), Elm327Error> {
+        if self.header.as_deref() == header {
+            return Ok(());
+        }
+
+        let default = match self.protocol().and_then(|p| p.chars().last()) {
+            // 29 bit CAN
+            Some('7') | Some('9') => "18DB33F1",
+            _ if self.is_can() => "7DF",
+            _ => "686AF1",
+        };
+        self.command(&format!("ATSH{}", header.unwrap_or(default)))?;
+        self.header = header.map(|h| h.to_string());
+
+        Ok(())
+    }
+
+    /// Send `request`, the mode followed by any PID and frame number,
+    /// and return the response of each ECU without the mode echo.
+    /// Responses split over more than one CAN frame are joined back
+    /// together.
+    pub fn request(&mut self, request: &[u8]) -> Result<Vec<Vec<u8>>, Elm327Error> {
+        // The response to mode 0x01 is 0x41, modes above 0xBF can't
+        // be answered like this
+        let response_mode = request
+            .first()
+            .and_then(|mode| mode.checked_add(0x40))
+            .ok_or_else(|| Elm327Error::BadResponse(format!("Invalid request {:02X?}", request)))?;
+
+        let cmd: String = request.iter().map(|b| format!("{:02X}", b)).collect();
+        let lines = self.command(&cmd)?;
+
+        let mut messages = Vec::new();
+        // A multi frame response starts with its length, then each
+        // frame is numbered "0:", "1:" and so on.
+        let mut length = None;
+        let mut frames: Vec<u8> = Vec::new();
+
+        for line in lines.iter() {
+            if let Some((index, data)) = line.split_once(':') {
+                if index.trim() == "0" && !frames.is_empty() {
+                    messages.push(join_frames(&mut frames, &mut length));
+                }
+                frames.extend(parse_hex(data).unwrap_or_default());
+                continue;
+            }
+
+            if !frames.is_empty() {
+                messages.push(join_frames(&mut frames, &mut length));
+            }
+
+            if line.len() == 3 {
+                length = usize::from_str_radix(line, 16).ok();
+            } else if let Some(bytes) = parse_hex(line) {
+                messages.push(bytes);
+            }
+        }
+        if !frames.is_empty() {
+            messages.push(join_frames(&mut frames, &mut length));
+        }
+
+        let responses: Vec<Vec<u8>> = messages
+            .into_iter()
+            .filter(|m| m.first() == Some(&response_mode))
+            .map(|m| m[1..].to_vec())
+            .collect();
+
+        if responses.is_empty() {
+            return Err(Elm327Error::BadResponse(lines.join(" ")));
+        }
+
+        Ok(responses)
+    }
+
+    /// Request `pid` in `mode` and return the data bytes of the first
+    /// ECU that responded, without the mode and PID echo.
+    pub fn query(&mut self, mode: u8, pid: u8) -> Result<Vec<u8>, Elm327Error> {
+        let responses = self.request(&[mode, pid])?;
+
+        responses
+            .iter()
+            .find(|r| r.first() == Some(&pid))
+            .map(|r| r[1..].to_vec())
+            .ok_or_else(|| Elm327Error::BadResponse(format!("{:02X?}", responses)))
+    }
+
+    /// Ask the car which PIDs it supports in `mode`. PIDs 0x00, 0x20,
+    /// 0x40... each return a bitmap of the next 32 PIDs, the last of
+    /// which says if the next bitmap can be requested.
+    pub fn supported_pids(&mut self, mode: u8) -> Result<Vec<u8>, Elm327Error> {
+        let mut supported = Vec::new();
+        let mut base = 0x00;
+
+        loop {
+            let data = match self.query(mode, base) {
+                Ok(data) => data,
+                Err(Elm327Error::Io(e)) => return Err(Elm327Error::Io(e)),
+                Err(e) if base == 0x00 => return Err(e),
+                // Some ECUs claim a bitmap they don't answer
+                Err(_) => break,
+            };
+
+            let pids = parse_supported(base, &data)
+                .ok_or_else(|| Elm327Error::BadResponse(format!("{:02X?}", data)))?;
+            supported.extend(pids);
+
+            match base.checked_add(0x20) {
+                Some(next) if supported.contains(&next) => base = next,
+                _ => break,
+            }
+        }
+
+        Ok(supported)
+    }
+
+    fn read_until_prompt(&mut self) -> Result<String, Elm327Error> {
+        let mut response = Vec::new();
+        let mut buf = [0; 64];
+
+        loop {
+            let len = self.port.read(&mut buf)?;
+            if len == 0 {
+                return Err(Elm327Error::Io(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "ELM327 closed the connection",
+                )));
+            }
+
+            for c in buf[..len].iter() {
+                match *c {
+                    b'>' => return Ok(String::from_utf8_lossy(&response).to_string()),
+                    // Some adapters send NULL bytes, just ignore them
+                    0 => {}
+                    c => response.push(c),
+                }
+            }
+        }
+    }
+}
+
+/// Returns the message made from `frames`, without the padding
+/// after `length` bytes
+fn join_frames(frames: &mut Vec<u8>, length: &mut Option<usize>) -> Vec<u8> {
+    let mut message = std::mem::take(frames);
+    if let Some(length) = length.take() {
+        message.truncate(length);
+    }
+    message
+}
+
+/// Convert a line of hex characters, with or without spaces,
+/// into bytes.
+fn parse_hex(line: &str) -> Option<Vec<u8>> {
+    let hex: Vec<char> = line.chars().filter(|c| !c.is_whitespace()).collect();
+
+    if hex.is_empty() || hex.len() % 2 != 0 {
+        return None;
+    }
+
+    let mut bytes = Vec::with_capacity(hex.len() / 2);
+    for pair in hex.chunks(2) {
+        let s: String = pair.iter().collect();
+        bytes.push(u8::from_str_radix(&s, 16).ok()?);
+    }
+
+    Some(bytes)
+}
+
+/// Convert the bitmap returned for the supported PIDs request `base`
+/// into the list of PIDs. The most significant bit is PID `base + 1`.
+fn parse_supported(base: u8, data: &[u8]) -> Option<Vec<u8>> {
+    let bitmap = u32::from_be_bytes([*data.first()?, *data.get(1)?, *data.get(2)?, *data.get(3)?]);
+
+    Some(
+        (0..32)
+            .filter(|i| bitmap & (0x8000_0000 >> i) != 0)
+            .filter_map(|i| base.checked_add(i + 1))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::collections::VecDeque;
+
+    /// A very small ELM327 simulator, this responds to commands the
+    /// same way an adapter connected to a car would.
+    pub struct Elm327Sim {
+        echo: bool,
+        spaces: bool,
+        command: Vec<u8>,
+        output: VecDeque<u8>,
+        pub responses: HashMap<String, String>,
+    }
+
+    impl Default for Elm327Sim {
+        fn default() -> Self {
+            let mut responses = HashMap::new();
+            responses.insert("0100".to_string(), "41 00 BE 3E B8 11".to_string());
+            responses.insert("010C".to_string(), "41 0C 1A F8".to_string());
+            responses.insert("0111".to_string(), "41 11 80".to_string());
+
+            Elm327Sim {
+                echo: true,
+                spaces: true,
+                command: Vec::new(),
+                output: VecDeque::new(),
+                responses,
+            }
+        }
+    }
+
+    impl Elm327Sim {
+        fn respond(&mut self, cmd: &str) -> String {
+            match cmd {
+                "ATZ" => "\r\rELM327 v1.5".to_string(),
+                "ATE0" => {
+                    self.echo = false;
+                    "OK".to_string()
+                }
+                "ATS0" => {
+                    self.spaces = false;
+                    "OK".to_string()
+                }
+                "ATL0" | "ATH0" | "ATSP0" => "OK".to_string(),
+                cmd if cmd.starts_with("ATSH") => "OK".to_string(),
+                "ATDPN" => "A6".to_string(),
+                cmd => match self.responses.get(cmd) {
+                    Some(resp) if self.spaces => resp.clone(),
+                    Some(resp) => resp.replace(' ', ""),
+                    None if cmd.starts_with("AT") => "?".to_string(),
+                    None => "NO DATA".to_string(),
+                },
+            }
+        }
+    }
+
+    impl Read for Elm327Sim {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut len = 0;
+            while len < buf.len() {
+                match self.output.pop_front() {
+                    Some(c) => {
+                        buf[len] = c;
+                        len += 1;
+                    }
+                    None => break,
+                }
+            }
+            Ok(len)
+        }
+    }
+
+    impl Write for Elm327Sim {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            for c in buf.iter() {
+                if *c == b'\r' {
+                    let cmd = String::from_utf8_lossy(&self.command).to_string();
+                    self.command.clear();
+
+                    if self.echo {
+                        self.output.extend(cmd.as_bytes());
+                        self.output.push_back(b'\r');
+                    }
+                    let resp = self.respond(&cmd);
+                    self.output.extend(resp.as_bytes());
+                    self.output.extend(b"\r\r>");
+                } else {
+                    self.command.push(*c);
+                }
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_init() {
+        let mut elm = Elm327::new(Elm327Sim::default());
+
+        elm.init().unwrap();
+
+        assert_eq!(elm.protocol(), Some("A6"));
+    }
+
+    #[test]
+    fn test_query() {
+        let mut elm = Elm327::new(Elm327Sim::default());
+        elm.init().unwrap();
+
+        assert_eq!(elm.query(0x01, 0x0C).unwrap(), vec![0x1A, 0xF8]);
+        assert_eq!(elm.query(0x01, 0x11).unwrap(), vec![0x80]);
+
+        match elm.query(0x01, 0x05) {
+            Err(Elm327Error::NoData) => {}
+            r => panic!("Unexpected response: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_request() {
+        let mut sim = Elm327Sim::default();
+        // Two ECUs, one with a multi frame response
+        sim.responses.insert(
+            "03".to_string(),
+            "43 01 01 33\r00A\r0: 43 04 01 71 01 72\r1: 03 00 04 20 00 00 00".to_string(),
+        );
+        let mut elm = Elm327::new(sim);
+        elm.init().unwrap();
+        assert!(elm.is_can());
+
+        let responses = elm.request(&[0x03]).unwrap();
+        assert_eq!(
+            responses,
+            vec![
+                vec![0x01, 0x01, 0x33],
+                vec![0x04, 0x01, 0x71, 0x01, 0x72, 0x03, 0x00, 0x04, 0x20]
+            ]
+        );
+
+        // There is no response mode for these
+        assert!(matches!(
+            elm.request(&[0xC0, 0x00]),
+            Err(Elm327Error::BadResponse(_))
+        ));
+        assert!(matches!(elm.request(&[]), Err(Elm327Error::BadResponse(_))));
+    }
+
+    /// Run the simulator on the other end of a pseudo-terminal, so the
+    /// ELM327 is opened and used the same way as a real serial port
+    #[cfg(unix)]
+    #[test]
+    fn test_pty() {
+        use serialport::{SerialPort, TTYPort};
+
+        let (mut master, slave) = TTYPort::pair().unwrap();
+        let device = slave.name().unwrap();
+
+        let sim = thread::spawn(move || {
+            let mut sim = Elm327Sim::default();
+            let mut buf = [0; 64];
+
+            loop {
+                let len = match master.read(&mut buf) {
+                    Ok(len) => len,
+                    Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                    // The other end has been closed
+                    Err(_) => return,
+                };
+
+                sim.write_all(&buf[..len]).unwrap();
+                let mut response = Vec::new();
+                sim.read_to_end(&mut response).unwrap();
+                master.write_all(&response).unwrap();
+            }
+        });
+
+        let mut elm = Elm327::open(&device, 38400).unwrap();
+        assert_eq!(elm.protocol(), Some("A6"));
+        assert_eq!(elm.query(0x01, 0x0C).unwrap(), vec![0x1A, 0xF8]);
+        assert_eq!(elm.supported_pids(0x01).unwrap().len(), 17);
+
+        drop(elm);
+        drop(slave);
+        sim.join().unwrap();
+    }
+
+    #[test]
+    fn test_supported_pids() {
+        let mut sim = Elm327Sim::default();
+        sim.responses
+            .insert("0120".to_string(), "41 20 80 00 00 00".to_string());
+        let mut elm = Elm327::new(sim);
+        elm.init().unwrap();
+
+        let supported = elm.supported_pids(0x01).unwrap();
+        assert!(supported.contains(&0x0C));
+        assert!(supported.contains(&0x11));
+        assert!(!supported.contains(&0x10));
+        // From the second bitmap
+        assert!(supported.contains(&0x21));
+        assert_eq!(supported.len(), 18);
+
+        // The car doesn't support mode 9
+        match elm.supported_pids(0x09) {
+            Err(Elm327Error::NoData) => {}
+            r => panic!("Unexpected response: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_parse_supported() {
+        assert_eq!(
+            parse_supported(0x00, &[0x80, 0x00, 0x00, 0x01]),
+            Some(vec![0x01, 0x20])
+        );
+        // There is no PID 0x100
+        assert_eq!(
+            parse_supported(0xE0, &[0x00, 0x00, 0x00, 0x03]),
+            Some(vec![0xFF])
+        );
+        assert_eq!(parse_supported(0x00, &[0xFF, 0xFF]), None);
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(parse_hex("41 0C 1A F8"), Some(vec![0x41, 0x0C, 0x1A, 0xF8]));
+        assert_eq!(parse_hex("410C1AF8"), Some(vec![0x41, 0x0C, 0x1A, 0xF8]));
+        assert_eq!(parse_hex("410"), None);
+        assert_eq!(parse_hex("SEARCHING..."), None);
+    }
+}
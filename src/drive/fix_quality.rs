@@ -0,0 +1,182 @@
+/*
+ * Copyright 2020 Alistair Francis <alistair@alistair23.me>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *    http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Decides if a GPS fix is accurate enough to time laps with.
+//! Fixes without a position are ignored by the lap timer, and laps
+//! timed with a degraded fix are marked as suspect.
+
+use crate::drive::read_track::TrackPoint;
+use crate::utils::GpsData;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FixQuality {
+    /// We don't know where we are
+    NoFix,
+    /// We have a position, but it isn't very accurate
+    Degraded,
+    Good,
+}
+
+/// The limits for a good fix. Anything the GPS doesn't report
+/// isn't checked.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FixLimits {
+    /// The fewest satellites used for the fix
+    pub min_sats: u32,
+    /// The largest horizontal dilution of precision
+    pub max_hdop: f32,
+    /// The largest estimated horizontal position error, in metres
+    pub max_error: f32,
+}
+
+impl Default for FixLimits {
+    fn default() -> Self {
+        FixLimits {
+            min_sats: 6,
+            max_hdop: 2.0,
+            max_error: 5.0,
+        }
+    }
+}
+
+impl FixLimits {
+    fn check(
+        &self,
+        status: i32,
+        hdop: Option<f32>,
+        sats: Option<u32>,
+        error: Option<f32>,
+    ) -> FixQuality {
+        if status == 0 {
+            return FixQuality::NoFix;
+        }
+
+        let degraded = matches!(sats, Some(sats) if sats < self.min_sats)
+            || matches!(hdop, Some(hdop) if hdop > self.max_hdop)
+            || matches!(error, Some(error) if error > self.max_error);
+
+        if degraded {
+            FixQuality::Degraded
+        } else {
+            FixQuality::Good
+        }
+    }
+
+    /// The quality of a fix from the GPS
+    pub fn check_fix(&self, fix: &GpsData) -> FixQuality {
+        self.check(fix.status, fix.hdop, fix.sats, fix.error)
+    }
+
+    /// The quality of a recorded point. Points without a
+    /// status are assumed to have a fix.
+    pub fn check_point(&self, point: &TrackPoint) -> FixQuality {
+        self.check(
+            point.status.unwrap_or(1),
+            point.hdop,
+            point.sats,
+            point.error,
+        )
+    }
+}
+
+/// Describe the quality of `fix`, for showing to the driver
+pub fn describe(fix: &GpsData, quality: FixQuality) -> String {
+    let mut text = match quality {
+        FixQuality::NoFix => return "No GPS fix".to_string(),
+        FixQuality::Degraded => "Poor GPS".to_string(),
+        FixQuality::Good => "GPS".to_string(),
+    };
+
+    if let Some(sats) = fix.sats {
+        write!(text, " {} sats", sats).unwrap();
+    }
+    if let Some(hdop) = fix.hdop {
+        write!(text, " HDOP {:.1}", hdop).unwrap();
+    }
+    if let Some(error) = fix.error {
+        write!(text, " ±{:.1}m", error).unwrap();
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fix(status: i32, hdop: Option<f32>, sats: Option<u32>, error: Option<f32>) -> GpsData {
+        GpsData {
+            lat: 37.706,
+            lon: -122.42,
+            alt: 30.7,
+            status,
+            time: "2020-10-17T14:49:10.000Z".to_string(),
+            speed: 12.5,
+            track: 206.4,
+            hdop,
+            sats,
+            error,
+        }
+    }
+
+    #[test]
+    fn test_fix_quality() {
+        let limits = FixLimits::default();
+
+        assert_eq!(
+            limits.check_fix(&fix(0, Some(0.9), Some(10), None)),
+            FixQuality::NoFix
+        );
+        assert_eq!(
+            limits.check_fix(&fix(1, Some(0.9), Some(10), Some(1.5))),
+            FixQuality::Good
+        );
+        // Nothing to check
+        assert_eq!(
+            limits.check_fix(&fix(2, None, None, None)),
+            FixQuality::Good
+        );
+
+        assert_eq!(
+            limits.check_fix(&fix(1, Some(0.9), Some(4), None)),
+            FixQuality::Degraded
+        );
+        assert_eq!(
+            limits.check_fix(&fix(1, Some(3.5), Some(10), None)),
+            FixQuality::Degraded
+        );
+        assert_eq!(
+            limits.check_fix(&fix(1, None, None, Some(12.0))),
+            FixQuality::Degraded
+        );
+    }
+
+    #[test]
+    fn test_describe() {
+        let good = fix(1, Some(0.9), Some(10), Some(1.46));
+        assert_eq!(
+            describe(&good, FixQuality::Good),
+            "GPS 10 sats HDOP 0.9 ±1.5m"
+        );
+
+        let poor = fix(1, Some(4.0), None, None);
+        assert_eq!(describe(&poor, FixQuality::Degraded), "Poor GPS HDOP 4.0");
+        assert_eq!(describe(&poor, FixQuality::NoFix), "No GPS fix");
+    }
+}
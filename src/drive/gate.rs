@@ -0,0 +1,391 @@
+/*
+ * Copyright 2020 Alistair Francis <alistair@alistair23.me>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *    http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::drive::read_track::Coord;
+use crate::utils::{lat_lon_bearing, lat_lon_destination, lat_lon_distance, right_direction};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// The size of a timing gate and how close to the recorded
+/// heading we need to be going to cross it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GateGeometry {
+    /// The width of the line across the track, in metres
+    pub width: f32,
+    /// How far the gate extends along the track, in metres. Half is
+    /// before the line and half after. This is drawn on the map to
+    /// show which way the line is crossed.
+    pub depth: f32,
+    /// How far from the recorded heading we can be going,
+    /// in degrees either side
+    pub tolerance: f32,
+    /// How far the line is turned from square across the recorded
+    /// heading, in degrees clockwise. This doesn't change the
+    /// heading we need to be going.
+    pub angle: f32,
+}
+
+impl Default for GateGeometry {
+    fn default() -> Self {
+        GateGeometry {
+            width: 20.0,
+            depth: 4.0,
+            tolerance: 30.0,
+            angle: 0.0,
+        }
+    }
+}
+
+/// The gate settings from a course file, for a single gate or for
+/// the whole course. Anything that isn't set uses the default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GateSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub depth: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tolerance: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub angle: Option<f32>,
+}
+
+impl GateSettings {
+    pub fn is_empty(&self) -> bool {
+        *self == GateSettings::default()
+    }
+
+    /// Use the settings from `other` for anything we don't set
+    pub fn or(self, other: GateSettings) -> GateSettings {
+        GateSettings {
+            width: self.width.or(other.width),
+            depth: self.depth.or(other.depth),
+            tolerance: self.tolerance.or(other.tolerance),
+            angle: self.angle.or(other.angle),
+        }
+    }
+}
+
+impl GateGeometry {
+    /// Change anything set in `settings`
+    pub fn with(self, settings: &GateSettings) -> GateGeometry {
+        GateGeometry {
+            width: settings.width.unwrap_or(self.width),
+            depth: settings.depth.unwrap_or(self.depth),
+            tolerance: settings.tolerance.unwrap_or(self.tolerance),
+            angle: settings.angle.unwrap_or(self.angle),
+        }
+    }
+
+    /// The corners of the gate at (`lat`, `lon`) heading `head`, as (lat, lon).
+    /// These go front left, front right, back right then back left.
+    pub fn corners(&self, lat: f64, lon: f64, head: f32) -> [(f64, f64); 4] {
+        let head = (head + self.angle) as f64;
+        let (half_width, half_depth) = (self.width as f64 / 2.0, self.depth as f64 / 2.0);
+
+        let front = lat_lon_destination(lat, lon, head, half_depth);
+        let back = lat_lon_destination(lat, lon, head + 180.0, half_depth);
+        let side = |(lat, lon): (f64, f64), right: bool| {
+            let bearing = if right { head + 90.0 } else { head - 90.0 };
+            lat_lon_destination(lat, lon, bearing, half_width)
+        };
+
+        [
+            side(front, false),
+            side(front, true),
+            side(back, true),
+            side(back, false),
+        ]
+    }
+}
+
+/// A timing line across the track.
+/// The line goes through the recorded point, perpendicular to
+/// the recorded heading turned by the gate angle.
+pub struct Gate {
+    lat: f64,
+    lon: f64,
+    head: Option<f32>,
+    geometry: GateGeometry,
+}
+
+impl Gate {
+    pub fn new(coord: &Coord) -> Gate {
+        Gate {
+            lat: coord.lat,
+            lon: coord.lon,
+            head: coord.head,
+            geometry: GateGeometry::default().with(&coord.gate),
+        }
+    }
+
+    pub fn geometry(&self) -> &GateGeometry {
+        &self.geometry
+    }
+
+    /// Check if `track` is close enough to the recorded heading
+    /// to cross the gate
+    pub fn right_direction(&self, track: f32) -> bool {
+        right_direction(self.head, track, self.geometry.tolerance)
+    }
+
+    /// The direction the line is crossed in, square to the line
+    fn line_head(&self) -> Option<f64> {
+        self.head
+            .map(|head| head as f64 + self.geometry.angle as f64)
+    }
+
+    /// The two ends of the line, as (lat, lon), if we know
+    /// which way it goes
+    pub fn ends(&self) -> Option<((f64, f64), (f64, f64))> {
+        let head = self.line_head()?;
+        let half_width = self.geometry.width as f64 / 2.0;
+
+        Some((
+            lat_lon_destination(self.lat, self.lon, head - 90.0, half_width),
+            lat_lon_destination(self.lat, self.lon, head + 90.0, half_width),
+        ))
+    }
+
+    /// How far `lat`, `lon` is from the middle of the line, in metres
+    pub fn distance(&self, lat: f64, lon: f64) -> f64 {
+        lat_lon_distance(self.lat, self.lon, lat, lon)
+    }
+
+    /// Convert a location into metres (east, north) from the gate.
+    /// This keeps the distance and bearing from the gate correct.
+    fn local(&self, lat: f64, lon: f64) -> (f64, f64) {
+        let distance = lat_lon_distance(self.lat, self.lon, lat, lon);
+        let bearing = lat_lon_bearing(self.lat, self.lon, lat, lon).to_radians();
+
+        (distance * bearing.sin(), distance * bearing.cos())
+    }
+
+    /// Check if the line was crossed, in the direction of the recorded
+    /// heading, when moving from `prev` to `cur` (both (lat, lon)).
+    /// Returns how far between the two points the crossing was,
+    /// from 0.0 (at `prev`) to 1.0 (at `cur`).
+    pub fn crossing(&self, prev: (f64, f64), cur: (f64, f64)) -> Option<f64> {
+        let (prev_x, prev_y) = self.local(prev.0, prev.1);
+        let (cur_x, cur_y) = self.local(cur.0, cur.1);
+
+        let (head_x, head_y) = match self.line_head() {
+            Some(head) => {
+                let head = head.to_radians();
+                (head.sin(), head.cos())
+            }
+            None => {
+                // We don't know which way the line goes, so assume
+                // it is perpendicular to the way we are going.
+                let len = ((cur_x - prev_x).powi(2) + (cur_y - prev_y).powi(2)).sqrt();
+                if len == 0.0 {
+                    return None;
+                }
+                ((cur_x - prev_x) / len, (cur_y - prev_y) / len)
+            }
+        };
+
+        // Distance in front of the line
+        let prev_dist = prev_x * head_x + prev_y * head_y;
+        let cur_dist = cur_x * head_x + cur_y * head_y;
+
+        // A fix exactly on the line has crossed it, so leaving the
+        // line on the next fix doesn't count as crossing it again.
+        if prev_dist >= 0.0 || cur_dist < 0.0 {
+            return None;
+        }
+
+        let fraction = -prev_dist / (cur_dist - prev_dist);
+
+        // Check that we crossed close enough to the recorded point
+        let x = prev_x + fraction * (cur_x - prev_x);
+        let y = prev_y + fraction * (cur_y - prev_y);
+        if (x * head_y - y * head_x).abs() > self.geometry.width as f64 / 2.0 {
+            return None;
+        }
+
+        Some(fraction)
+    }
+}
+
+/// Returns the time that is `fraction` of the way from `prev` to `cur`
+pub fn crossing_time(prev: Duration, cur: Duration, fraction: f64) -> Duration {
+    match cur.checked_sub(prev) {
+        Some(diff) => prev + diff.mul_f64(fraction),
+        None => cur,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Roughly how many metres are in 1 degree latitude
+    const METRES_PER_DEGREE: f64 = 111_111.0;
+
+    fn north_gate() -> Gate {
+        Gate::new(&Coord::new(37.0, -122.0, Some(0.0)))
+    }
+
+    #[test]
+    fn test_crossing() {
+        let gate = north_gate();
+        let step = 2.0 / METRES_PER_DEGREE;
+
+        // 2m before to 2m after the line
+        let fraction = gate
+            .crossing((37.0 - step, -122.0), (37.0 + step, -122.0))
+            .unwrap();
+        assert!((fraction - 0.5).abs() < 0.001);
+
+        // 2m before to 6m after the line
+        let fraction = gate
+            .crossing((37.0 - step, -122.0), (37.0 + 3.0 * step, -122.0))
+            .unwrap();
+        assert!((fraction - 0.25).abs() < 0.001);
+
+        // Landing on the line, then leaving it, is only one crossing
+        assert_eq!(
+            gate.crossing((37.0 - step, -122.0), (37.0, -122.0)),
+            Some(1.0)
+        );
+        assert_eq!(gate.crossing((37.0, -122.0), (37.0 + step, -122.0)), None);
+
+        // Haven't reached the line yet
+        assert_eq!(
+            gate.crossing((37.0 - 2.0 * step, -122.0), (37.0 - step, -122.0)),
+            None
+        );
+
+        // Wrong direction
+        assert_eq!(
+            gate.crossing((37.0 + step, -122.0), (37.0 - step, -122.0)),
+            None
+        );
+
+        // Too far to the side
+        assert_eq!(
+            gate.crossing((37.0 - step, -121.999), (37.0 + step, -121.999)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_gate_geometry() {
+        let mut coord = Coord::new(37.0, -122.0, Some(90.0));
+        coord.gate.width = Some(40.0);
+        let gate = Gate::new(&coord);
+        let step = 2.0 / METRES_PER_DEGREE;
+
+        // 15m to the side is still on the wider line
+        let side = 15.0 / METRES_PER_DEGREE;
+        assert!(gate
+            .crossing((37.0 + side, -122.0 - step), (37.0 + side, -122.0 + step))
+            .is_some());
+        assert!(north_gate()
+            .crossing((37.0 - step, -122.0 + side), (37.0 + step, -122.0 + side))
+            .is_none());
+
+        // Heading east the line goes north to south
+        let (left, right) = gate.ends().unwrap();
+        assert!((lat_lon_distance(37.0, -122.0, left.0, left.1) - 20.0).abs() < 0.001);
+        assert!((lat_lon_distance(37.0, -122.0, right.0, right.1) - 20.0).abs() < 0.001);
+        assert!(left.0 > 37.0 && right.0 < 37.0);
+        assert!((lat_lon_bearing(left.0, left.1, right.0, right.1) - 180.0).abs() < 0.01);
+
+        assert!(Gate::new(&Coord::new(37.0, -122.0, None)).ends().is_none());
+
+        // The course can set a narrower heading tolerance
+        assert!(gate.right_direction(115.0));
+        coord.gate = coord.gate.or(GateSettings {
+            tolerance: Some(20.0),
+            ..Default::default()
+        });
+        let gate = Gate::new(&coord);
+        assert_eq!(gate.geometry().width, 40.0);
+        assert!(gate.right_direction(105.0));
+        assert!(!gate.right_direction(115.0));
+    }
+
+    #[test]
+    fn test_gate_corners() {
+        let geometry = GateGeometry {
+            width: 10.0,
+            depth: 4.0,
+            tolerance: 30.0,
+            angle: 0.0,
+        };
+        let corners = geometry.corners(37.0, -122.0, 45.0);
+
+        // Each corner is the same distance from the middle, at
+        // the right angle from the heading
+        let diagonal = (5.0_f64.powi(2) + 2.0_f64.powi(2)).sqrt();
+        let angle = (5.0_f64 / 2.0).atan().to_degrees();
+        let bearings = [45.0 - angle, 45.0 + angle, 225.0 - angle, 225.0 + angle];
+
+        for (corner, bearing) in corners.iter().zip(bearings.iter()) {
+            let distance = lat_lon_distance(37.0, -122.0, corner.0, corner.1);
+            assert!((distance - diagonal).abs() < 0.001);
+            let expected = (bearing + 360.0) % 360.0;
+            assert!((lat_lon_bearing(37.0, -122.0, corner.0, corner.1) - expected).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_rotated_gate() {
+        let mut coord = Coord::new(37.0, -122.0, Some(0.0));
+        coord.gate.angle = Some(25.0);
+        coord.gate.tolerance = Some(15.0);
+        let gate = Gate::new(&coord);
+
+        // The line is turned, but we still go the recorded heading
+        assert!(gate.right_direction(10.0));
+        assert!(!gate.right_direction(30.0));
+
+        let (left, right) = gate.ends().unwrap();
+        assert!((lat_lon_bearing(left.0, left.1, right.0, right.1) - 115.0).abs() < 0.01);
+
+        let step = 2.0 / METRES_PER_DEGREE;
+        assert!(gate
+            .crossing((37.0 - step, -122.0), (37.0 + step, -122.0))
+            .is_some());
+    }
+
+    #[test]
+    fn test_crossing_no_heading() {
+        let gate = Gate::new(&Coord::new(37.0, -122.0, None));
+        let step = 2.0 / METRES_PER_DEGREE;
+
+        assert!(gate
+            .crossing((37.0 - step, -122.0), (37.0 + step, -122.0))
+            .is_some());
+        assert!(gate
+            .crossing((37.0 + step, -122.0), (37.0 - step, -122.0))
+            .is_some());
+        assert_eq!(
+            gate.crossing((37.0 + step, -122.0), (37.0 + 2.0 * step, -122.0)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_crossing_time() {
+        assert_eq!(
+            crossing_time(Duration::from_secs(10), Duration::from_secs(11), 0.25),
+            Duration::from_millis(10_250)
+        );
+    }
+}
@@ -14,39 +14,52 @@
  * limitations under the License.
  */
 
-use crate::drive::course::Course;
-use crate::drive::read_track::Coord;
+use crate::config::Config;
+use crate::drive::clock::GpsClock;
+use crate::drive::course::{Course, SectorTime};
+use crate::drive::delta::{predict, ReferenceLap};
+use crate::drive::fix_quality::{describe, FixQuality};
 use crate::drive::threading::ThreadingRef;
-use crate::utils::{genereate_polygon, lat_lon_comp, right_direction};
-use gpsd_proto::handshake;
-use nalgebra::geometry::{Isometry2, Point2};
-use ncollide2d::query::PointQuery;
-use ncollide2d::shape::ConvexPolygon;
-use std::fs::OpenOptions;
+use crate::drive::timing::{LapTimer, TimingEvent};
+use crate::gps_source::{GpsReader, GpsSource};
+use crate::record::journal::Journal;
+use crate::record::print;
+use crate::session_log::Channel;
+use crate::utils::{duration_to_gps_time, gps_time_to_duration, GpsError};
+use std::fs::{File, OpenOptions};
 use std::io;
-use std::net::TcpStream;
-use std::time::{Duration, SystemTime};
-use std::vec::Vec;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
+#[allow(clippy::too_many_arguments)]
 pub fn gpsd_thread(
     thread_info: ThreadingRef,
-    elapsed_tx: std::sync::mpsc::Sender<Duration>,
-    times_tx: std::sync::mpsc::Sender<(Duration, Duration, Duration)>,
+    elapsed_tx: std::sync::mpsc::Sender<(Duration, Option<Duration>)>,
+    times_tx: std::sync::mpsc::Sender<(usize, Duration, Duration, Duration, bool)>,
     time_diff_tx: std::sync::mpsc::Sender<(bool, Duration)>,
     location_tx: std::sync::mpsc::Sender<(f64, f64, i32, Option<bool>)>,
+    sector_tx: std::sync::mpsc::Sender<SectorTime>,
+    fix_tx: std::sync::mpsc::Sender<(FixQuality, String)>,
     course_info: &mut Course,
+    config: &Config,
+    drive_log: Option<PathBuf>,
 ) {
-    let gpsd_connect;
+    let mut gps_reader;
+
+    // A replay shares its clock with the replays of the other data
+    let replay_clock = match &config.gps {
+        GpsSource::Replay { file, speed } => Some(thread_info.replay_clock(file, *speed)),
+        _ => None,
+    };
 
     loop {
-        let stream = TcpStream::connect("127.0.0.1:2947");
-        match stream {
-            Ok(stream) => {
-                gpsd_connect = stream;
+        match GpsReader::connect(&config.gps, replay_clock.clone()) {
+            Ok(reader) => {
+                gps_reader = reader;
                 break;
             }
             Err(err) => {
-                println!("Failed to connect to GPSD: {:?}", err);
+                println!("Failed to connect to the GPS: {:?}", err);
                 if thread_info.close.lock().unwrap().get() {
                     return;
                 }
@@ -56,173 +69,184 @@ pub fn gpsd_thread(
         }
     }
 
-    let mut reader = io::BufReader::new(&gpsd_connect);
-    let mut writer = io::BufWriter::new(&gpsd_connect);
-
-    handshake(&mut reader, &mut writer).unwrap();
-
-    let start_poly = genereate_polygon(
-        course_info.segments.first().unwrap().start.lat,
-        course_info.segments.first().unwrap().start.lon,
-        course_info
-            .segments
-            .first()
-            .unwrap()
-            .start
-            .head
-            .unwrap_or(0.0),
-    );
-    let finish_poly = genereate_polygon(
-        course_info.segments.last().unwrap().finish.lat,
-        course_info.segments.last().unwrap().finish.lon,
-        course_info
-            .segments
-            .last()
-            .unwrap()
-            .finish
-            .head
-            .unwrap_or(0.0),
-    );
-
-    let mut segment_times: Vec<(Coord, Duration)> = Vec::new();
-    let mut lap_times: Vec<Vec<(Coord, Duration)>> = Vec::new();
-    let mut segment_starts: Vec<ConvexPolygon<f64>> = Vec::new();
-
-    for segment in &course_info.segments {
-        segment_starts.push(genereate_polygon(
-            segment.start.lat,
-            segment.start.lon,
-            segment.start.head.unwrap_or(0.0),
-        ));
-    }
+    let mut timer = LapTimer::new(course_info);
+    let mut clock = GpsClock::new();
 
-    let mut lap_start = SystemTime::now();
-    let mut elapsed_time: Duration = Duration::from_secs(0);
-    let mut on_track: bool = false;
+    // The best lap, used for the live delta
+    let mut reference = ReferenceLap::new(&course_info.best_times);
+
+    // Record the drive, with the OBDII and IMU data, as a GPX file
+    let mut drive_log = drive_log.and_then(|file_name| match start_drive_log(&file_name) {
+        Ok(drive_log) => Some(drive_log),
+        Err(e) => {
+            println!("Unable to start the drive log {:?}: {:?}", file_name, e);
+            None
+        }
+    });
 
     while !thread_info.close.lock().unwrap().get() {
-        let msg = crate::utils::get_gps_lat_lon(&mut reader);
+        if thread_info.deserialise.lock().unwrap().get() {
+            thread_info.deserialise.lock().unwrap().set(false);
 
-        match msg {
-            Ok((lat, lon, _alt, status, _time, _speed, track)) => {
-                // Check to see if we should start the timer
-                if !on_track
-                    && start_poly.contains_point(&Isometry2::identity(), &Point2::new(lat, lon))
-                    && right_direction(course_info.segments.first().unwrap().start.head, track)
-                {
-                    lap_start = SystemTime::now();
-                    on_track = true;
-                    thread_info.on_track.lock().unwrap().set(true);
-                    thread_info.start_on_track.lock().unwrap().set(true);
-                    lap_times.clear();
-                    segment_times.clear();
-                }
+            let time_file = thread_info.time_file.read().unwrap().clone();
+            match Course::load(&time_file).and_then(|saved| course_info.resume(saved)) {
+                Ok(()) => {
+                    reference = ReferenceLap::new(&course_info.best_times);
 
-                // Check to see if we should stop the timer
-                if on_track
-                    && finish_poly.contains_point(&Isometry2::identity(), &Point2::new(lat, lon))
-                    && right_direction(course_info.segments.last().unwrap().finish.head, track)
-                {
-                    thread_info.on_track.lock().unwrap().set(false);
-                    on_track = false;
-
-                    course_info.times.push(elapsed_time);
-                    course_info.last = elapsed_time;
-                    course_info.times.sort_unstable();
-                    if let Some(worst) = course_info.times.last() {
-                        course_info.worst = *worst;
-                    }
-                    if let Some(best) = course_info.times.first() {
-                        course_info.best = *best;
-                        // If we just set the best time, update the
-                        // best_times vector
-                        if *best == elapsed_time {
-                            course_info.best_times.clear();
-                            course_info.best_times.append(&mut lap_times);
-                        }
-                    }
                     times_tx
-                        .send((course_info.last, course_info.best, course_info.worst))
+                        .send((
+                            course_info.times.len(),
+                            course_info.last,
+                            course_info.best,
+                            course_info.worst,
+                            false,
+                        ))
                         .unwrap();
+                }
+                Err(e) => {
+                    println!("Unable to load {:?}: {}", time_file, e);
+                }
+            }
+        }
 
-                    // Update the diff display
-                    if let Some(diff) = course_info.best.checked_sub(elapsed_time) {
-                        time_diff_tx.send((true, diff)).unwrap();
-                    }
-                    // Check if elapsed_time - best is greater then 0
-                    // In this case we are slower then previous best
-                    if let Some(diff) = elapsed_time.checked_sub(course_info.best) {
-                        time_diff_tx.send((false, diff)).unwrap();
-                    }
-                } else if on_track {
-                    elapsed_time = lap_start.elapsed().unwrap();
-                    elapsed_tx.send(elapsed_time).unwrap();
+        let msg = gps_reader.get_gps_data(&thread_info.close);
+
+        match msg {
+            Ok(mut fix) => {
+                let (lat, lon, status, track) = (fix.lat, fix.lon, fix.status, fix.track);
+                if let Some(pulse) = gps_reader.take_pps() {
+                    clock.pps(pulse);
                 }
+                let time = clock.fix_time(&fix.time);
+                if gps_time_to_duration(&fix.time) != Some(time) {
+                    // The fix didn't have a time, record the one we used
+                    // so the recording gives the same lap times
+                    fix.time = duration_to_gps_time(time);
+                }
+                // When the fix was taken, to pick the telemetry closest to it
+                let taken = clock.instant(time).unwrap_or_else(Instant::now);
+                let quality = config.fix_limits.check_fix(&fix);
 
-                // Save lap time data
-                let mut time_delta_diff: Option<bool> = None;
-                if on_track {
-                    // Save the current location and time to a vector
-                    segment_times.push((
-                        Coord {
-                            lat,
-                            lon,
-                            head: None,
-                        },
-                        elapsed_time,
-                    ));
-
-                    // Split a new segment
-                    for segment in &segment_starts {
-                        // Check if we match the start of a segment
-                        if segment.contains_point(&Isometry2::identity(), &Point2::new(lat, lon)) {
-                            lap_times.push(segment_times);
-                            segment_times = Vec::new();
-                        }
-                    }
+                thread_info.log_fix(&fix);
+                fix_tx.send((quality, describe(&fix, quality))).unwrap();
 
-                    // Check if the current location matches a previous one
-                    for segment in &course_info.best_times {
-                        for (loc, el) in segment {
-                            if lat_lon_comp(loc.lat, loc.lon, lat, lon) {
-                                // This point matches a previous point
-                                course_info.last_location_time =
-                                    Some(*el - segment.first().unwrap().1);
+                for event in timer.update(course_info, lat, lon, track, time, quality) {
+                    match event {
+                        TimingEvent::Started => {
+                            if let Some(reference) = reference.as_mut() {
+                                reference.reset();
                             }
+                            thread_info.on_track.lock().unwrap().set(true);
+                            thread_info.start_on_track.lock().unwrap().set(true);
                         }
-                    }
+                        TimingEvent::Sector(sector_time) => {
+                            thread_info.log_values(
+                                Channel::Sector,
+                                &[sector_time.sector as f64, sector_time.time.as_secs_f64()],
+                            );
+                            sector_tx.send(sector_time).unwrap();
+                        }
+                        TimingEvent::Lap(lap, elapsed_time, suspect) => {
+                            thread_info.log_values(
+                                Channel::Lap,
+                                &[
+                                    lap as f64,
+                                    elapsed_time.as_secs_f64(),
+                                    if suspect { 1.0 } else { 0.0 },
+                                ],
+                            );
 
-                    let segment_diff = elapsed_time - lap_times.last().unwrap().first().unwrap().1;
+                            if course_info.best == elapsed_time {
+                                reference = ReferenceLap::new(&course_info.best_times);
+                            }
+                            times_tx
+                                .send((
+                                    lap,
+                                    course_info.last,
+                                    course_info.best,
+                                    course_info.worst,
+                                    suspect,
+                                ))
+                                .unwrap();
 
-                    match course_info.last_location_time {
-                        Some(llt) => {
-                            // Check if best - segment_diff is greater then 0
-                            // In this case we are quicker then previous best
-                            if let Some(diff) = llt.checked_sub(segment_diff) {
-                                time_delta_diff = Some(true);
+                            // Update the diff display
+                            if let Some(diff) = course_info.best.checked_sub(elapsed_time) {
                                 time_diff_tx.send((true, diff)).unwrap();
                             }
-                            // Check if segment_diff - best is greater then 0
+                            // Check if elapsed_time - best is greater then 0
                             // In this case we are slower then previous best
-                            if let Some(diff) = segment_diff.checked_sub(llt) {
-                                time_delta_diff = Some(false);
+                            if let Some(diff) = elapsed_time.checked_sub(course_info.best) {
                                 time_diff_tx.send((false, diff)).unwrap();
                             }
                         }
+                        TimingEvent::Finished => {
+                            thread_info.on_track.lock().unwrap().set(false);
+                        }
+                    }
+                }
+
+                let mut time_delta_diff: Option<bool> = None;
+                if timer.on_track() {
+                    let elapsed_time = timer.elapsed_time();
+
+                    // Compare against the best lap at the same point on the track
+                    let mut predicted = None;
+                    match reference.as_mut().and_then(|r| r.project(lat, lon)) {
+                        Some((_distance, reference_time)) => {
+                            course_info.last_location_time = Some(reference_time);
+
+                            let lap_time = reference.as_ref().unwrap().lap_time();
+                            let ((quicker, diff), predicted_time) =
+                                predict(elapsed_time, reference_time, lap_time);
+
+                            time_delta_diff = Some(quicker);
+                            time_diff_tx.send((quicker, diff)).unwrap();
+                            predicted = Some(predicted_time);
+                        }
                         None => {
                             // No time data, just reset to +00:00:000
+                            course_info.last_location_time = None;
                             time_delta_diff = None;
                             time_diff_tx.send((false, Duration::new(0, 0))).unwrap();
                         }
                     }
+
+                    elapsed_tx.send((elapsed_time, predicted)).unwrap();
                 }
 
                 location_tx
                     .send((lat, lon, status, time_delta_diff))
                     .unwrap();
+
+                let mut write_failed = false;
+                if let Some((fd, journal)) = drive_log.as_mut() {
+                    // Only record the point if we are moving
+                    if fix.speed > 0.5 {
+                        let telemetry = thread_info.telemetry.lock().unwrap().snapshot_at(taken);
+                        if let Err(e) = print::gpx_point_info(fd, &fix, Some(telemetry)) {
+                            println!("Unable to write the drive log, stopping it: {:?}", e);
+                            write_failed = true;
+                        }
+                    }
+
+                    if let Some(journal) = journal.as_mut() {
+                        if let Err(e) = journal.sync(fd) {
+                            println!("Unable to sync the drive log: {:?}", e);
+                        }
+                    }
+                }
+                if write_failed {
+                    // Stop recording the drive, the journal is left
+                    // behind so what was written can be repaired.
+                    drive_log = None;
+                }
+            }
+            Err(GpsError::EndOfStream) => {
+                println!("The GPS has stopped sending data");
+                break;
             }
             Err(err) => {
-                println!("Failed to get a message from GPSD: {:?}", err);
+                println!("Failed to get a message from the GPS: {:?}", err);
                 std::thread::sleep(std::time::Duration::from_millis(30));
                 continue;
             }
@@ -238,9 +262,9 @@ pub fn gpsd_thread(
 
             match track_file.as_mut() {
                 Ok(fd) => {
-                    let serialized = serde_json::to_string(&course_info).unwrap();
-
-                    serde_json::to_writer(fd, &serialized).unwrap();
+                    if let Err(e) = course_info.save(fd) {
+                        println!("Unable to save times: {}", e);
+                    }
                 }
                 Err(e) => {
                     println!("Unable to open file: {:?}", e);
@@ -248,4 +272,47 @@ pub fn gpsd_thread(
             }
         }
     }
+
+    if let Some(drive_log) = drive_log {
+        if let Err(e) = finish_drive_log(drive_log) {
+            println!("Unable to finish the drive log: {:?}", e);
+        }
+    }
+}
+
+/// Create the GPX file the drive is recorded to, with a journal
+/// so it can be repaired if we don't finish it
+fn start_drive_log(file_name: &Path) -> io::Result<(File, Option<Journal>)> {
+    let mut fd = File::create(file_name)?;
+    print::gpx_start(&mut fd)?;
+    print::gpx_metadata(&mut fd)?;
+    if let Some(name) = file_name.file_name().and_then(|n| n.to_str()) {
+        print::gpx_track_start(&mut fd, name.to_string())?;
+    }
+    print::gpx_track_seg_start(&mut fd)?;
+
+    let journal = match Journal::create(file_name) {
+        Ok(mut journal) => {
+            journal.sync_now(&fd)?;
+            Some(journal)
+        }
+        Err(e) => {
+            println!("Unable to create a journal: {:?}", e);
+            None
+        }
+    };
+
+    Ok((fd, journal))
+}
+
+/// Close the elements of the drive log and remove its journal
+fn finish_drive_log((mut fd, journal): (File, Option<Journal>)) -> io::Result<()> {
+    print::gpx_track_seg_stop(&mut fd)?;
+    print::gpx_track_stop(&mut fd)?;
+    print::gpx_stop(&mut fd)?;
+
+    match journal {
+        Some(journal) => journal.finish(&fd),
+        None => fd.sync_all(),
+    }
 }
@@ -15,13 +15,11 @@
  */
 
 use crate::drive::threading::ThreadingRef;
+use crate::session_log::Channel;
 use ahrs::{Ahrs, Madgwick};
 use industrial_io as iio;
 use nalgebra::geometry::UnitQuaternion;
 use nalgebra::Vector3;
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::path::PathBuf;
 
 pub const IMU_SAMPLE_FREQ: f64 = 60.0;
 
@@ -286,7 +284,6 @@ pub fn imu_thread(
     thread_info: ThreadingRef,
     imu_tx: std::sync::mpsc::Sender<(f64, f64, Option<f64>, Option<f64>)>,
     imu_page_tx: std::sync::mpsc::Sender<(f64, f64, Option<f64>, Option<f64>)>,
-    file_name: &mut PathBuf,
 ) {
     // Create the IIO context
     let ctx;
@@ -316,25 +313,8 @@ pub fn imu_thread(
 
     imu_context.set_sampling_freq();
 
-    // Open the file to save data
-    let mut name = file_name.file_stem().unwrap().to_str().unwrap().to_string();
-    name.push_str("-imu.cvs");
-    file_name.pop();
-    file_name.push(name);
-
-    let mut imu_file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(&file_name);
-    let fd = imu_file.as_mut().unwrap();
-
     let mut ahrs = Madgwick::new(IMU_SAMPLE_FREQ / 1000.0, 0.1);
 
-    // Write the CVS headers
-    writeln!(fd, "accel x, accel y, accel z, gyro x, gyro y, gyro z").unwrap();
-
     while !thread_info.close.lock().unwrap().get() {
         if thread_info.calibrate.lock().unwrap().get() {
             println!("Calibrating, make sure there is no acceleration");
@@ -352,12 +332,6 @@ pub fn imu_thread(
         let accel_data = imu_context.get_accel_data();
         let accel_rotated = imu_context.rotate_data(&accel_data);
 
-        // Write the acceleration data to file
-        for data in accel_rotated.iter() {
-            write!(fd, "{}", data).unwrap();
-            write!(fd, ",").unwrap();
-        }
-
         // Calculate absolute G force in X and Y
         let g_force = accel_rotated[0].powi(2) + accel_rotated[1].powi(2);
         let g_force = g_force.sqrt() / 9.8;
@@ -366,6 +340,12 @@ pub fn imu_thread(
             imu_context.max_g_force = g_force;
         }
 
+        thread_info
+            .telemetry
+            .lock()
+            .unwrap()
+            .update_accel(accel_rotated[0], accel_rotated[1]);
+
         // Send acceleration data to be drawn on the screen
         imu_tx
             .send((accel_rotated[0], accel_rotated[1], None, None))
@@ -384,15 +364,18 @@ pub fn imu_thread(
         let gyro_data = imu_context.get_gyro_data();
         let gyro_rotated = imu_context.rotate_data(&gyro_data);
 
-        // Write the gyro data to a file
-        for (i, data) in gyro_rotated.iter().enumerate() {
-            write!(fd, "{}", data).unwrap();
-            if i < 2 {
-                write!(fd, ",").unwrap();
-            }
-        }
-
-        writeln!(fd).unwrap();
+        // Log the acceleration and gyro data
+        thread_info.log_values(
+            Channel::Imu,
+            &[
+                accel_rotated[0],
+                accel_rotated[1],
+                accel_rotated[2],
+                gyro_rotated[0],
+                gyro_rotated[1],
+                gyro_rotated[2],
+            ],
+        );
 
         let mag_data = imu_context.get_mag_data();
 
@@ -413,8 +396,6 @@ pub fn imu_thread(
         //     unit_quat_rotated.euler_angles()
         // );
     }
-
-    fd.flush().unwrap();
 }
 
 #[cfg(test)]
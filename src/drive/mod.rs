@@ -14,12 +14,25 @@
  * limitations under the License.
  */
 
+pub mod can;
+pub mod clock;
 pub mod course;
+pub mod custom_pid;
+pub mod dbc;
+pub mod delta;
 pub mod display;
+pub mod dtc;
+pub mod editor;
+pub mod elm327;
+pub mod fix_quality;
+pub mod gate;
 pub mod gps;
 pub mod imu;
 pub mod obdii;
+pub mod poll;
 pub mod prepare;
 pub mod read_track;
+pub mod replay;
 pub mod temp;
 pub mod threading;
+pub mod timing;
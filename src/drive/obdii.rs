@@ -14,18 +14,21 @@
  * limitations under the License.
  */
 
-extern crate cpython;
+use crate::config::Config;
+use crate::drive::can;
+use crate::drive::custom_pid::custom_pids;
+use crate::drive::dtc::{self, DtcReport};
+use crate::drive::elm327::{Elm327, Elm327Error};
+use crate::drive::poll::{PollRate, PollScheduler};
 use crate::drive::threading::ThreadingRef;
-use chrono::Utc;
-use cpython::{PyResult, Python};
+use crate::session_log::Channel;
 use std::collections::VecDeque;
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::path::PathBuf;
+use std::fmt;
+use std::io::{Read, Write};
 use std::thread;
 use std::time::Duration;
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum OBDIICommandType {
     Rpm,
     Throttle,
@@ -36,23 +39,237 @@ pub enum OBDIICommandType {
     IntakeTemp,
     ShortFuelT1,
     LongFuelT1,
+    Speed,
     FuelStatus,
-}
-
-#[derive(PartialEq)]
-enum PythonReturns {
-    Float,
-    Long,
-    PyStr,
+    /// A PID from the definition file, by its index
+    Custom(u8),
+    /// A signal from the DBC file, by its index
+    Can(u16),
 }
 
 struct OBDIICommands {
     command: OBDIICommandType,
-    com_string: String,
-    ret: PythonReturns,
+    com_string: &'static str,
+    pid: u8,
+    /// How often to poll the PID, unless the config says otherwise
+    rate: PollRate,
+}
+
+static COMMANDS: [OBDIICommands; 11] = [
+    OBDIICommands {
+        command: OBDIICommandType::Rpm,
+        com_string: "RPM",
+        pid: 0x0C,
+        rate: PollRate {
+            rate: 10.0,
+            priority: 3,
+        },
+    },
+    OBDIICommands {
+        command: OBDIICommandType::Throttle,
+        com_string: "THROTTLE_POS",
+        pid: 0x11,
+        rate: PollRate {
+            rate: 10.0,
+            priority: 3,
+        },
+    },
+    OBDIICommands {
+        command: OBDIICommandType::EngineLoad,
+        com_string: "ENGINE_LOAD",
+        pid: 0x04,
+        rate: PollRate {
+            rate: 5.0,
+            priority: 2,
+        },
+    },
+    OBDIICommands {
+        command: OBDIICommandType::TimingAdv,
+        com_string: "TIMING_ADVANCE",
+        pid: 0x0E,
+        rate: PollRate {
+            rate: 5.0,
+            priority: 2,
+        },
+    },
+    OBDIICommands {
+        command: OBDIICommandType::Maf,
+        com_string: "MAF",
+        pid: 0x10,
+        rate: PollRate {
+            rate: 5.0,
+            priority: 2,
+        },
+    },
+    OBDIICommands {
+        command: OBDIICommandType::CoolantTemp,
+        com_string: "COOLANT_TEMP",
+        pid: 0x05,
+        rate: PollRate {
+            rate: 1.0,
+            priority: 1,
+        },
+    },
+    OBDIICommands {
+        command: OBDIICommandType::IntakeTemp,
+        com_string: "INTAKE_TEMP",
+        pid: 0x0F,
+        rate: PollRate {
+            rate: 1.0,
+            priority: 1,
+        },
+    },
+    OBDIICommands {
+        command: OBDIICommandType::ShortFuelT1,
+        com_string: "SHORT_FUEL_TRIM_1",
+        pid: 0x06,
+        rate: PollRate {
+            rate: 2.0,
+            priority: 1,
+        },
+    },
+    OBDIICommands {
+        command: OBDIICommandType::LongFuelT1,
+        com_string: "LONG_FUEL_TRIM_1",
+        pid: 0x07,
+        rate: PollRate {
+            rate: 0.5,
+            priority: 0,
+        },
+    },
+    OBDIICommands {
+        command: OBDIICommandType::Speed,
+        com_string: "SPEED",
+        pid: 0x0D,
+        rate: PollRate {
+            rate: 5.0,
+            priority: 2,
+        },
+    },
+    OBDIICommands {
+        command: OBDIICommandType::FuelStatus,
+        com_string: "FUEL_STATUS",
+        pid: 0x03,
+        rate: PollRate {
+            rate: 0.5,
+            priority: 0,
+        },
+    },
+];
+
+impl OBDIICommandType {
+    /// Find the command from the name used in the CSV header
+    pub fn from_name(name: &str) -> Option<OBDIICommandType> {
+        COMMANDS
+            .iter()
+            .find(|c| c.com_string == name.trim())
+            .map(|c| c.command)
+            .or_else(|| {
+                custom_pids()
+                    .iter()
+                    .position(|p| p.name == name.trim())
+                    .map(|i| OBDIICommandType::Custom(i as u8))
+            })
+            .or_else(|| {
+                can::dbc()
+                    .signals()
+                    .position(|s| s.name == name.trim())
+                    .map(|i| OBDIICommandType::Can(i as u16))
+            })
+    }
+
+    /// Find the command from the mode 1 PID
+    pub fn from_pid(pid: u8) -> Option<OBDIICommandType> {
+        COMMANDS.iter().find(|c| c.pid == pid).map(|c| c.command)
+    }
+
+    /// The name used in the CSV header
+    pub fn name(self) -> &'static str {
+        match self {
+            OBDIICommandType::Custom(index) => {
+                return custom_pids()
+                    .get(index as usize)
+                    .map(|p| p.name.as_str())
+                    .unwrap_or("CUSTOM");
+            }
+            OBDIICommandType::Can(index) => {
+                return can::dbc()
+                    .signal(index)
+                    .map(|s| s.name.as_str())
+                    .unwrap_or("CAN");
+            }
+            _ => {}
+        }
+
+        COMMANDS
+            .iter()
+            .find(|c| c.command == self)
+            .map(|c| c.com_string)
+            .unwrap()
+    }
+
+    /// The units and chart range of a custom PID or CAN signal
+    pub fn chart(self) -> Option<(&'static str, f64, f64)> {
+        match self {
+            OBDIICommandType::Custom(index) => custom_pids()
+                .get(index as usize)
+                .map(|p| (p.units.as_str(), p.min, p.max)),
+            OBDIICommandType::Can(index) => can::dbc()
+                .signal(index)
+                .map(|s| (s.units.as_str(), s.min, s.max)),
+            _ => None,
+        }
+    }
+
+    /// The mode 1 PID, custom PIDs and CAN signals don't have one
+    pub fn pid(self) -> u8 {
+        COMMANDS
+            .iter()
+            .find(|c| c.command == self)
+            .map(|c| c.pid)
+            .unwrap_or_default()
+    }
+
+    /// How often to poll the command by default
+    pub fn poll_rate(self) -> PollRate {
+        COMMANDS
+            .iter()
+            .find(|c| c.command == self)
+            .map(|c| c.rate)
+            .unwrap_or_default()
+    }
+
+    /// Every standard command we know how to decode
+    pub fn all() -> Vec<OBDIICommandType> {
+        COMMANDS.iter().map(|c| c.command).collect()
+    }
+
+    /// The commands for the PIDs in the definition file
+    pub fn custom() -> Vec<OBDIICommandType> {
+        (0..custom_pids().len())
+            .map(|i| OBDIICommandType::Custom(i as u8))
+            .collect()
+    }
+
+    /// The commands for the signals in the DBC file
+    pub fn can() -> Vec<OBDIICommandType> {
+        (0..can::dbc().signals().count())
+            .map(|i| OBDIICommandType::Can(i as u16))
+            .collect()
+    }
+
+    /// The commands to poll on a car that supports the mode 1 PIDs
+    /// in `supported`
+    pub fn supported(supported: &[u8]) -> Vec<OBDIICommandType> {
+        COMMANDS
+            .iter()
+            .filter(|c| supported.contains(&c.pid))
+            .map(|c| c.command)
+            .collect()
+    }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum OBDIIFuelStatus {
     OpenLoopTemp,
     ClosedLoopO2Sense,
@@ -61,18 +278,152 @@ pub enum OBDIIFuelStatus {
     ClosedLoopFault,
 }
 
+impl fmt::Display for OBDIIFuelStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match self {
+            OBDIIFuelStatus::OpenLoopTemp => "Open loop due to insufficient engine temperature",
+            OBDIIFuelStatus::ClosedLoopO2Sense => {
+                "Closed loop, using oxygen sensor feedback to determine fuel mix"
+            }
+            OBDIIFuelStatus::OpenLoopLoad => {
+                "Open loop due to engine load OR fuel cut due to deceleration"
+            }
+            OBDIIFuelStatus::OpenLoopFailure => "Open loop due to system failure",
+            OBDIIFuelStatus::ClosedLoopFault => {
+                "Closed loop, using at least one oxygen sensor but there is a fault in the feedback system"
+            }
+        };
+        write!(f, "{}", status)
+    }
+}
+
 pub struct OBDIIData {
     pub command: OBDIICommandType,
     pub val_float: Option<f64>,
-    pub val_long: Option<u32>,
+    pub val_long: Option<i32>,
     pub val_fuel_status: Option<OBDIIFuelStatus>,
 }
 
+impl OBDIIData {
+    fn new(command: OBDIICommandType) -> OBDIIData {
+        OBDIIData {
+            command,
+            val_float: None,
+            val_long: None,
+            val_fuel_status: None,
+        }
+    }
+
+    /// The value of a command that has already been decoded
+    pub fn from_float(command: OBDIICommandType, value: f64) -> OBDIIData {
+        let mut ret = OBDIIData::new(command);
+        ret.val_float = Some(value);
+        ret
+    }
+
+    /// Decode the data bytes returned by the car for `command`.
+    /// The values use the same units as the OBDII specification.
+    pub fn decode(command: OBDIICommandType, data: &[u8]) -> Option<OBDIIData> {
+        let a = *data.first()? as f64;
+        let mut ret = OBDIIData::new(command);
+
+        match command {
+            OBDIICommandType::Rpm => {
+                ret.val_float = Some(((a * 256.0) + *data.get(1)? as f64) / 4.0);
+            }
+            OBDIICommandType::Throttle | OBDIICommandType::EngineLoad => {
+                ret.val_float = Some(a * 100.0 / 255.0);
+            }
+            OBDIICommandType::TimingAdv => {
+                ret.val_float = Some((a / 2.0) - 64.0);
+            }
+            OBDIICommandType::Maf => {
+                ret.val_float = Some(((a * 256.0) + *data.get(1)? as f64) / 100.0);
+            }
+            OBDIICommandType::CoolantTemp | OBDIICommandType::IntakeTemp => {
+                ret.val_long = Some(data[0] as i32 - 40);
+            }
+            OBDIICommandType::ShortFuelT1 | OBDIICommandType::LongFuelT1 => {
+                ret.val_float = Some((a * 100.0 / 128.0) - 100.0);
+            }
+            OBDIICommandType::Speed => {
+                ret.val_float = Some(a);
+            }
+            OBDIICommandType::FuelStatus => {
+                // Only fuel system 1 is reported, this is a bit field
+                // where only one bit should be set.
+                ret.val_fuel_status = Some(match data[0] {
+                    0x01 => OBDIIFuelStatus::OpenLoopTemp,
+                    0x02 => OBDIIFuelStatus::ClosedLoopO2Sense,
+                    0x04 => OBDIIFuelStatus::OpenLoopLoad,
+                    0x08 => OBDIIFuelStatus::OpenLoopFailure,
+                    0x10 => OBDIIFuelStatus::ClosedLoopFault,
+                    _ => return None,
+                });
+            }
+            OBDIICommandType::Custom(index) => {
+                ret.val_float = Some(custom_pids().get(index as usize)?.decode(data)?);
+            }
+            // CAN signals are decoded from the frames, not polled
+            OBDIICommandType::Can(_) => return None,
+        }
+
+        Some(ret)
+    }
+
+    /// Convert a value previously written to a CSV file back into the data
+    pub fn from_value(command: OBDIICommandType, value: &str) -> Option<OBDIIData> {
+        let value = value.trim();
+        let mut ret = OBDIIData::new(command);
+
+        match command {
+            OBDIICommandType::CoolantTemp | OBDIICommandType::IntakeTemp => {
+                ret.val_long = Some(value.parse::<f64>().ok()? as i32);
+            }
+            OBDIICommandType::FuelStatus => {
+                let status = [
+                    OBDIIFuelStatus::OpenLoopTemp,
+                    OBDIIFuelStatus::ClosedLoopO2Sense,
+                    OBDIIFuelStatus::OpenLoopLoad,
+                    OBDIIFuelStatus::OpenLoopFailure,
+                    OBDIIFuelStatus::ClosedLoopFault,
+                ]
+                .iter()
+                .find(|s| s.to_string() == value)?;
+                ret.val_fuel_status = Some(*status);
+            }
+            _ => {
+                ret.val_float = Some(value.parse().ok()?);
+            }
+        }
+
+        Some(ret)
+    }
+}
+
+impl fmt::Display for OBDIIData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(val) = self.val_float {
+            write!(f, "{}", val)
+        } else if let Some(val) = self.val_long {
+            write!(f, "{}", val)
+        } else if let Some(val) = self.val_fuel_status {
+            write!(f, "{}", val)
+        } else {
+            Ok(())
+        }
+    }
+}
+
 pub struct OBDIIGraphData {
     pub rpm: VecDeque<f64>,
     pub throttle: VecDeque<f64>,
     pub load: VecDeque<f64>,
     pub maf: VecDeque<f64>,
+    /// One for each custom PID
+    pub custom: Vec<VecDeque<f64>>,
+    /// One for each CAN signal
+    pub can: Vec<VecDeque<f64>>,
 }
 
 pub static VECTOR_LEN: usize = 100;
@@ -84,111 +435,134 @@ impl OBDIIGraphData {
             throttle: VecDeque::with_capacity(VECTOR_LEN),
             load: VecDeque::with_capacity(VECTOR_LEN),
             maf: VecDeque::with_capacity(VECTOR_LEN),
+            custom: custom_pids()
+                .iter()
+                .map(|_| VecDeque::with_capacity(VECTOR_LEN))
+                .collect(),
+            can: can::dbc()
+                .signals()
+                .map(|_| VecDeque::with_capacity(VECTOR_LEN))
+                .collect(),
         }
     }
-}
 
-pub fn obdii_thread(
-    thread_info: ThreadingRef,
-    obdii_tx: std::sync::mpsc::Sender<OBDIIData>,
-    file_name: &mut PathBuf,
-) -> PyResult<()> {
-    let gli = Python::acquire_gil();
-    let py = gli.python();
-
-    let commands: [OBDIICommands; 10] = [
-        OBDIICommands {
-            command: OBDIICommandType::Rpm,
-            com_string: "RPM".to_string(),
-            ret: PythonReturns::Float,
-        },
-        OBDIICommands {
-            command: OBDIICommandType::Throttle,
-            com_string: "THROTTLE_POS".to_string(),
-            ret: PythonReturns::Float,
-        },
-        OBDIICommands {
-            command: OBDIICommandType::EngineLoad,
-            com_string: "ENGINE_LOAD".to_string(),
-            ret: PythonReturns::Float,
-        },
-        OBDIICommands {
-            command: OBDIICommandType::TimingAdv,
-            com_string: "TIMING_ADVANCE".to_string(),
-            ret: PythonReturns::Float,
-        },
-        OBDIICommands {
-            command: OBDIICommandType::Maf,
-            com_string: "MAF".to_string(),
-            ret: PythonReturns::Float,
-        },
-        OBDIICommands {
-            command: OBDIICommandType::CoolantTemp,
-            com_string: "COOLANT_TEMP".to_string(),
-            ret: PythonReturns::Long,
-        },
-        OBDIICommands {
-            command: OBDIICommandType::IntakeTemp,
-            com_string: "INTAKE_TEMP".to_string(),
-            ret: PythonReturns::Long,
-        },
-        OBDIICommands {
-            command: OBDIICommandType::ShortFuelT1,
-            com_string: "SHORT_FUEL_TRIM_1".to_string(),
-            ret: PythonReturns::Float,
-        },
-        OBDIICommands {
-            command: OBDIICommandType::LongFuelT1,
-            com_string: "LONG_FUEL_TRIM_1".to_string(),
-            ret: PythonReturns::Float,
-        },
-        OBDIICommands {
-            command: OBDIICommandType::FuelStatus,
-            com_string: "FUEL_STATUS".to_string(),
-            ret: PythonReturns::PyStr,
-        },
-    ];
+    /// The chart data of a custom PID or CAN signal
+    pub fn chart_values(&self, command: OBDIICommandType) -> Option<&VecDeque<f64>> {
+        match command {
+            OBDIICommandType::Custom(index) => self.custom.get(index as usize),
+            OBDIICommandType::Can(index) => self.can.get(index as usize),
+            _ => None,
+        }
+    }
 
-    let mut name = file_name.file_stem().unwrap().to_str().unwrap().to_string();
+    pub fn chart_values_mut(&mut self, command: OBDIICommandType) -> Option<&mut VecDeque<f64>> {
+        match command {
+            OBDIICommandType::Custom(index) => self.custom.get_mut(index as usize),
+            OBDIICommandType::Can(index) => self.can.get_mut(index as usize),
+            _ => None,
+        }
+    }
+}
 
-    name.push_str("-obdii.cvs");
+/// Work out which commands the car supports. If the car can't tell
+/// us, try all of them.
+fn discover_commands<P: Read + Write>(
+    elm: &mut Elm327<P>,
+) -> Result<Vec<OBDIICommandType>, Elm327Error> {
+    let commands = match elm.supported_pids(0x01) {
+        Ok(supported) => OBDIICommandType::supported(&supported),
+        Err(Elm327Error::Io(e)) => return Err(Elm327Error::Io(e)),
+        Err(e) => {
+            println!("Unable to read the supported PIDs: {:?}", e);
+            return Ok(OBDIICommandType::all());
+        }
+    };
 
-    file_name.pop();
-    file_name.push(name);
+    for command in OBDIICommandType::all() {
+        if !commands.contains(&command) {
+            println!("{}: Not supported by the car", command.name());
+        }
+    }
 
-    let mut obdii_file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(&file_name);
+    Ok(commands)
+}
 
-    let fd = obdii_file.as_mut().unwrap();
+/// Request `command` from the car and return the data bytes
+fn query<P: Read + Write>(
+    elm: &mut Elm327<P>,
+    command: OBDIICommandType,
+) -> Result<Vec<u8>, Elm327Error> {
+    match command {
+        OBDIICommandType::Custom(index) => match custom_pids().get(index as usize) {
+            Some(pid) => pid.query(elm),
+            None => Err(Elm327Error::Unsupported(command.name().to_string())),
+        },
+        _ => {
+            elm.set_header(None)?;
+            elm.query(0x01, command.pid())
+        }
+    }
+}
 
-    write!(fd, "Time").unwrap();
+/// Read the DTCs, clearing them first if `clear` is set, and send
+/// them to the UI. Only losing the adapter is returned as an error.
+fn update_dtcs<P: Read + Write>(
+    thread_info: &ThreadingRef,
+    elm: &mut Elm327<P>,
+    dtc_tx: &std::sync::mpsc::Sender<Result<DtcReport, String>>,
+    clear: bool,
+) -> Result<(), Elm327Error> {
+    elm.set_header(None)?;
+
+    if clear {
+        match dtc::clear_dtcs(elm) {
+            Ok(()) => thread_info.log(Channel::Dtc, &dtc::log_record(None, &[])),
+            Err(Elm327Error::Io(e)) => return Err(Elm327Error::Io(e)),
+            Err(e) => {
+                let _ = dtc_tx.send(Err(format!("Unable to clear the codes: {:?}", e)));
+                return Ok(());
+            }
+        }
+    }
 
-    for command in commands.iter() {
-        write!(fd, ",{}", command.com_string).unwrap();
+    match dtc::read_report(elm) {
+        Ok(report) => {
+            for (kind, codes) in report.codes().iter() {
+                thread_info.log(Channel::Dtc, &dtc::log_record(Some(*kind), codes));
+            }
+            let _ = dtc_tx.send(Ok(report));
+        }
+        Err(Elm327Error::Io(e)) => return Err(Elm327Error::Io(e)),
+        Err(e) => {
+            let _ = dtc_tx.send(Err(format!("Unable to read the codes: {:?}", e)));
+        }
     }
 
-    writeln!(fd).unwrap();
+    Ok(())
+}
 
+pub fn obdii_thread(
+    thread_info: ThreadingRef,
+    obdii_tx: std::sync::mpsc::Sender<OBDIIData>,
+    channels_tx: std::sync::mpsc::Sender<Vec<OBDIICommandType>>,
+    rates_tx: std::sync::mpsc::Sender<Vec<(OBDIICommandType, f64)>>,
+    dtc_tx: std::sync::mpsc::Sender<Result<DtcReport, String>>,
+    config: &Config,
+) {
     while !thread_info.close.lock().unwrap().get() {
-        let pyobd_res;
+        let mut elm;
 
         loop {
-            let res = py.import("obdii_connect");
-
-            match res {
-                Ok(r) => {
-                    pyobd_res = r;
+            match Elm327::open(&config.obdii_device, config.obdii_baud) {
+                Ok(e) => {
+                    elm = e;
                     break;
                 }
                 Err(e) => {
                     println!("Unable to conect to OBDII {:?}", e);
                     thread::sleep(Duration::from_secs(10));
                     if thread_info.close.lock().unwrap().get() {
-                        return Ok(());
+                        return;
                     } else {
                         continue;
                     }
@@ -196,113 +570,186 @@ pub fn obdii_thread(
             }
         }
 
-        while !thread_info.close.lock().unwrap().get() {
-            write!(fd, "{}", Utc::now()).unwrap();
-
-            for command in commands.iter() {
-                let py_ret = pyobd_res.call(py, "c_get_data", (&command.com_string,), None)?;
-
-                let data: OBDIIData;
-
-                if command.ret == PythonReturns::Float {
-                    let ret_res = py_ret.extract(py);
-
-                    let ret: f64;
-                    match ret_res {
-                        Ok(r) => {
-                            ret = r;
-                        }
-                        Err(e) => {
-                            println!(
-                                "{}: Error in extracting the float: {:?}; {:?}",
-                                command.com_string, py_ret, e
-                            );
-                            continue;
-                        }
-                    }
+        let mut commands = match discover_commands(&mut elm) {
+            Ok(commands) => commands,
+            Err(e) => {
+                println!("Lost connection to OBDII {:?}", e);
+                continue;
+            }
+        };
+
+        // Record what we are polling, so the log can be converted
+        // with a column for each of them.
+        let pids: Vec<u8> = commands.iter().map(|c| c.pid()).collect();
+        thread_info.log(Channel::ObdiiPids, &pids);
+
+        // Custom PIDs are always polled, the car doesn't list them
+        if !custom_pids().is_empty() {
+            let names: Vec<&str> = custom_pids().iter().map(|p| p.name.as_str()).collect();
+            thread_info.log(Channel::CustomNames, names.join("\n").as_bytes());
+            commands.extend(OBDIICommandType::custom());
+        }
 
-                    write!(fd, ",{}", ret).unwrap();
-
-                    data = OBDIIData {
-                        command: command.command,
-                        val_float: Some(ret),
-                        val_long: None,
-                        val_fuel_status: None,
-                    };
-                } else if command.ret == PythonReturns::Long {
-                    let ret_res = py_ret.extract(py);
-
-                    let ret: u32;
-                    match ret_res {
-                        Ok(r) => {
-                            ret = r;
-                        }
-                        Err(e) => {
-                            println!(
-                                "{}: Error in extracting the long: {:?}; {:?}",
-                                command.com_string, py_ret, e
-                            );
-                            continue;
-                        }
-                    }
+        let _ = channels_tx.send(commands.clone());
 
-                    write!(fd, ",{}", ret).unwrap();
-
-                    data = OBDIIData {
-                        command: command.command,
-                        val_float: None,
-                        val_long: Some(ret),
-                        val_fuel_status: None,
-                    };
-                } else {
-                    let ret_res = py_ret.extract(py);
-
-                    let ret: String;
-                    match ret_res {
-                        Ok(r) => {
-                            ret = r;
-                        }
-                        Err(e) => {
-                            println!(
-                                "{}: Error in extracting the string: {:?}; {:?}",
-                                command.com_string, py_ret, e
-                            );
-                            continue;
-                        }
-                    }
+        // Check why the MIL is on every time we connect
+        thread_info.read_dtcs.lock().unwrap().set(true);
 
-                    write!(fd, ",{}", ret).unwrap();
+        let mut scheduler = PollScheduler::new(&commands, &config.obdii_rates);
+        let mut connected = true;
 
-                    let mut fuel_status = OBDIIFuelStatus::OpenLoopTemp;
+        while connected && !thread_info.close.lock().unwrap().get() {
+            if let Some(rates) = scheduler.rates() {
+                let _ = rates_tx.send(rates);
+            }
 
-                    if ret.eq("Open loop due to insufficient engine temperature") {
-                        fuel_status = OBDIIFuelStatus::OpenLoopTemp;
-                    } else if ret.eq("Closed loop, using oxygen sensor feedback to determine fuel mix") {
-                        fuel_status = OBDIIFuelStatus::ClosedLoopO2Sense;
-                    } else if ret.eq("Open loop due to engine load OR fuel cut due to deceleration") {
-                        fuel_status = OBDIIFuelStatus::OpenLoopLoad;
-                    } else if ret.eq("Open loop due to system failure") {
-                        fuel_status = OBDIIFuelStatus::OpenLoopFailure;
-                    } else if ret.eq("Closed loop, using at least one oxygen sensor but there is a fault in the feedback system") {
-                        fuel_status = OBDIIFuelStatus::ClosedLoopFault;
-                    }
+            let clear = thread_info.clear_dtcs.lock().unwrap().get();
+            if clear || thread_info.read_dtcs.lock().unwrap().get() {
+                thread_info.clear_dtcs.lock().unwrap().set(false);
+                thread_info.read_dtcs.lock().unwrap().set(false);
 
-                    data = OBDIIData {
-                        command: command.command,
-                        val_float: None,
-                        val_long: None,
-                        val_fuel_status: Some(fuel_status),
-                    };
+                if let Err(e) = update_dtcs(&thread_info, &mut elm, &dtc_tx, clear) {
+                    println!("Lost connection to OBDII {:?}", e);
+                    connected = false;
+                    continue;
                 }
+            }
 
-                obdii_tx.send(data).unwrap();
+            let command = match scheduler.next() {
+                Ok(command) => command,
+                Err(wait) => {
+                    thread::sleep(wait);
+                    continue;
+                }
+            };
+
+            let data = match query(&mut elm, command) {
+                Ok(data) => data,
+                Err(Elm327Error::Io(e)) => {
+                    // We have lost the adapter, try to reconnect
+                    println!("Lost connection to OBDII {:?}", e);
+                    connected = false;
+                    continue;
+                }
+                Err(e) => {
+                    println!("{}: Error reading from the car: {:?}", command.name(), e);
+                    scheduler.polled(command, false);
+                    continue;
+                }
+            };
+            scheduler.polled(command, true);
+
+            // Log the raw response, it can be decoded again later.
+            // Custom PIDs are logged once decoded, so the log can be
+            // used without the definition file.
+            if !matches!(command, OBDIICommandType::Custom(_)) {
+                thread_info.log(Channel::Obdii(command.pid()), &data);
             }
 
-            writeln!(fd).unwrap();
+            match OBDIIData::decode(command, &data) {
+                Some(obdii_data) => {
+                    if let (OBDIICommandType::Custom(index), Some(value)) =
+                        (command, obdii_data.val_float)
+                    {
+                        thread_info.log_values(Channel::Custom(index), &[value]);
+                    }
+                    thread_info
+                        .telemetry
+                        .lock()
+                        .unwrap()
+                        .update_obdii(&obdii_data);
+                    obdii_tx.send(obdii_data).unwrap();
+                }
+                None => {
+                    println!(
+                        "{}: Unable to decode the response: {:?}",
+                        command.name(),
+                        data
+                    );
+                }
+            }
         }
     }
+}
 
-    fd.flush().unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drive::elm327::tests::Elm327Sim;
 
-    Ok(())
+    #[test]
+    fn test_decode() {
+        let rpm = OBDIIData::decode(OBDIICommandType::Rpm, &[0x1A, 0xF8]).unwrap();
+        assert_eq!(rpm.val_float, Some(1726.0));
+
+        let throttle = OBDIIData::decode(OBDIICommandType::Throttle, &[0xFF]).unwrap();
+        assert_eq!(throttle.val_float, Some(100.0));
+
+        let timing = OBDIIData::decode(OBDIICommandType::TimingAdv, &[0x90]).unwrap();
+        assert_eq!(timing.val_float, Some(8.0));
+
+        let maf = OBDIIData::decode(OBDIICommandType::Maf, &[0x01, 0x2C]).unwrap();
+        assert_eq!(maf.val_float, Some(3.0));
+
+        let coolant = OBDIIData::decode(OBDIICommandType::CoolantTemp, &[0x14]).unwrap();
+        assert_eq!(coolant.val_long, Some(-20));
+
+        let trim = OBDIIData::decode(OBDIICommandType::ShortFuelT1, &[0x80]).unwrap();
+        assert_eq!(trim.val_float, Some(0.0));
+
+        let speed = OBDIIData::decode(OBDIICommandType::Speed, &[0x57]).unwrap();
+        assert_eq!(speed.val_float, Some(87.0));
+
+        let status = OBDIIData::decode(OBDIICommandType::FuelStatus, &[0x02, 0x00]).unwrap();
+        assert_eq!(
+            status.val_fuel_status,
+            Some(OBDIIFuelStatus::ClosedLoopO2Sense)
+        );
+
+        // Not enough data
+        assert!(OBDIIData::decode(OBDIICommandType::Rpm, &[0x1A]).is_none());
+        assert!(OBDIIData::decode(OBDIICommandType::Throttle, &[]).is_none());
+    }
+
+    #[test]
+    fn test_from_value() {
+        let command = OBDIICommandType::from_name("RPM").unwrap();
+        let rpm = OBDIIData::from_value(command, "1726.5").unwrap();
+        assert_eq!(rpm.val_float, Some(1726.5));
+
+        let command = OBDIICommandType::from_name("COOLANT_TEMP").unwrap();
+        let coolant = OBDIIData::from_value(command, "87").unwrap();
+        assert_eq!(coolant.val_long, Some(87));
+
+        let command = OBDIICommandType::from_name("FUEL_STATUS").unwrap();
+        let status = OBDIIFuelStatus::OpenLoopLoad.to_string();
+        let status = OBDIIData::from_value(command, &status).unwrap();
+        assert_eq!(status.val_fuel_status, Some(OBDIIFuelStatus::OpenLoopLoad));
+
+        assert!(OBDIIData::from_value(command, "").is_none());
+        assert!(OBDIICommandType::from_name("FUEL_PRESSURE").is_none());
+    }
+
+    #[test]
+    fn test_discover_commands() {
+        let mut elm = Elm327::new(Elm327Sim::default());
+        elm.init().unwrap();
+
+        // The simulated car doesn't have a MAF sensor
+        let commands = discover_commands(&mut elm).unwrap();
+        assert_eq!(commands.len(), 10);
+        assert_eq!(commands[0], OBDIICommandType::Rpm);
+        assert!(!commands.contains(&OBDIICommandType::Maf));
+        assert!(commands.contains(&OBDIICommandType::LongFuelT1));
+
+        // Without the bitmap everything is polled
+        let mut sim = Elm327Sim::default();
+        sim.responses.remove("0100");
+        let mut elm = Elm327::new(sim);
+        elm.init().unwrap();
+        assert_eq!(
+            discover_commands(&mut elm).unwrap(),
+            OBDIICommandType::all()
+        );
+    }
 }
@@ -0,0 +1,274 @@
+/*
+ * Copyright 2020 Alistair Francis <alistair@alistair23.me>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *    http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Decides which OBDII PID to poll next. Each PID has a target rate,
+//! so values that change quickly (such as the RPM) are polled more
+//! often than ones that change slowly (such as the temperatures).
+//! When more than one PID is due the highest priority goes first.
+
+use crate::drive::obdii::OBDIICommandType;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How often the achieved rates are measured
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// The slowest a PID is polled, in Hz
+const MIN_RATE: f64 = 0.01;
+
+/// How often to poll a PID
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PollRate {
+    /// The target rate in Hz, 0 stops the PID being polled.
+    /// Anything slower than `MIN_RATE` is polled at `MIN_RATE`.
+    pub rate: f64,
+    /// When more than one PID is due the highest priority is polled
+    /// first. A busy bus can starve the lower priorities.
+    pub priority: u8,
+}
+
+impl Default for PollRate {
+    fn default() -> Self {
+        PollRate {
+            rate: 1.0,
+            priority: 0,
+        }
+    }
+}
+
+struct PollChannel {
+    command: OBDIICommandType,
+    period: Duration,
+    priority: u8,
+    /// When the PID is next due
+    next: Instant,
+    /// How many responses we have had in this window
+    responses: u32,
+}
+
+pub struct PollScheduler {
+    channels: Vec<PollChannel>,
+    window_start: Instant,
+}
+
+impl PollScheduler {
+    /// Poll `commands`. `rates` overrides the default rate of the
+    /// commands, by the name used in the CSV header.
+    pub fn new(commands: &[OBDIICommandType], rates: &HashMap<String, PollRate>) -> PollScheduler {
+        PollScheduler::new_at(commands, rates, Instant::now())
+    }
+
+    fn new_at(
+        commands: &[OBDIICommandType],
+        rates: &HashMap<String, PollRate>,
+        now: Instant,
+    ) -> PollScheduler {
+        let channels = commands
+            .iter()
+            .filter_map(|command| {
+                let rate = rates
+                    .get(command.name())
+                    .cloned()
+                    .unwrap_or_else(|| command.poll_rate());
+                if !rate.rate.is_finite() {
+                    println!("Invalid poll rate for {}: {}", command.name(), rate.rate);
+                    return None;
+                }
+                if rate.rate <= 0.0 {
+                    return None;
+                }
+
+                Some(PollChannel {
+                    command: *command,
+                    period: Duration::from_secs_f64(1.0 / rate.rate.max(MIN_RATE)),
+                    priority: rate.priority,
+                    next: now,
+                    responses: 0,
+                })
+            })
+            .collect();
+
+        PollScheduler {
+            channels,
+            window_start: now,
+        }
+    }
+
+    /// The next command to poll, or how long to wait until one is due
+    pub fn next(&self) -> Result<OBDIICommandType, Duration> {
+        self.next_at(Instant::now())
+    }
+
+    fn next_at(&self, now: Instant) -> Result<OBDIICommandType, Duration> {
+        let due = self
+            .channels
+            .iter()
+            .filter(|c| c.next <= now)
+            // The highest priority, then the one that has waited longest
+            .max_by(|a, b| a.priority.cmp(&b.priority).then(b.next.cmp(&a.next)));
+
+        match due {
+            Some(channel) => Ok(channel.command),
+            None => Err(self
+                .channels
+                .iter()
+                .map(|c| c.next.saturating_duration_since(now))
+                .min()
+                .unwrap_or(RATE_WINDOW)),
+        }
+    }
+
+    /// `command` has been polled, `responded` is true if the
+    /// car sent back a value
+    pub fn polled(&mut self, command: OBDIICommandType, responded: bool) {
+        self.polled_at(command, responded, Instant::now())
+    }
+
+    fn polled_at(&mut self, command: OBDIICommandType, responded: bool, now: Instant) {
+        if let Some(channel) = self.channels.iter_mut().find(|c| c.command == command) {
+            // If we have fallen behind don't try to catch up,
+            // that would only delay everything else.
+            channel.next = (channel.next + channel.period).max(now);
+            if responded {
+                channel.responses += 1;
+            }
+        }
+    }
+
+    /// The rate, in Hz, each command has been read at. This is
+    /// only returned once a second.
+    pub fn rates(&mut self) -> Option<Vec<(OBDIICommandType, f64)>> {
+        self.rates_at(Instant::now())
+    }
+
+    fn rates_at(&mut self, now: Instant) -> Option<Vec<(OBDIICommandType, f64)>> {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed < RATE_WINDOW {
+            return None;
+        }
+        self.window_start = now;
+
+        Some(
+            self.channels
+                .iter_mut()
+                .map(|c| {
+                    let rate = c.responses as f64 / elapsed.as_secs_f64();
+                    c.responses = 0;
+                    (c.command, rate)
+                })
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        let start = Instant::now();
+        let commands = [
+            OBDIICommandType::CoolantTemp,
+            OBDIICommandType::Rpm,
+            OBDIICommandType::Throttle,
+        ];
+        let mut scheduler = PollScheduler::new_at(&commands, &HashMap::new(), start);
+
+        // Poll every 10ms for a second, a query takes about that long
+        let mut counts = HashMap::new();
+        for i in 0..100 {
+            let now = start + Duration::from_millis(i * 10);
+            if let Ok(command) = scheduler.next_at(now) {
+                *counts.entry(command.name()).or_insert(0) += 1;
+                scheduler.polled_at(command, true, now);
+            }
+        }
+
+        assert_eq!(counts["RPM"], 10);
+        assert_eq!(counts["THROTTLE_POS"], 10);
+        assert_eq!(counts["COOLANT_TEMP"], 1);
+
+        let rates = scheduler.rates_at(start + Duration::from_secs(1)).unwrap();
+        assert_eq!(rates[1], (OBDIICommandType::Rpm, 10.0));
+        assert_eq!(rates[0], (OBDIICommandType::CoolantTemp, 1.0));
+        // The window has restarted
+        assert!(scheduler
+            .rates_at(start + Duration::from_millis(1500))
+            .is_none());
+    }
+
+    #[test]
+    fn test_priority() {
+        let start = Instant::now();
+        let mut rates = HashMap::new();
+        rates.insert(
+            "COOLANT_TEMP".to_string(),
+            PollRate {
+                rate: 1.0,
+                priority: 5,
+            },
+        );
+        rates.insert(
+            "SPEED".to_string(),
+            PollRate {
+                rate: 0.0,
+                priority: 5,
+            },
+        );
+        let commands = [
+            OBDIICommandType::Rpm,
+            OBDIICommandType::CoolantTemp,
+            OBDIICommandType::Speed,
+        ];
+        let mut scheduler = PollScheduler::new_at(&commands, &rates, start);
+
+        // Everything is due, the coolant temperature now goes first
+        assert_eq!(scheduler.next_at(start), Ok(OBDIICommandType::CoolantTemp));
+        scheduler.polled_at(OBDIICommandType::CoolantTemp, true, start);
+        assert_eq!(scheduler.next_at(start), Ok(OBDIICommandType::Rpm));
+        scheduler.polled_at(OBDIICommandType::Rpm, false, start);
+
+        // The speed is never polled, so wait for the RPM
+        assert_eq!(scheduler.next_at(start), Err(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn test_invalid_rates() {
+        let start = Instant::now();
+        let mut rates = HashMap::new();
+        let rate = |rate| PollRate { rate, priority: 0 };
+        rates.insert("RPM".to_string(), rate(f64::NAN));
+        rates.insert("SPEED".to_string(), rate(f64::INFINITY));
+        rates.insert("COOLANT_TEMP".to_string(), rate(1e-300));
+        let commands = [
+            OBDIICommandType::Rpm,
+            OBDIICommandType::Speed,
+            OBDIICommandType::CoolantTemp,
+        ];
+        let mut scheduler = PollScheduler::new_at(&commands, &rates, start);
+
+        // Only the coolant temperature is polled, at the slowest rate
+        assert_eq!(scheduler.next_at(start), Ok(OBDIICommandType::CoolantTemp));
+        scheduler.polled_at(OBDIICommandType::CoolantTemp, true, start);
+        assert_eq!(
+            scheduler.next_at(start),
+            Err(Duration::from_secs_f64(1.0 / MIN_RATE))
+        );
+    }
+}
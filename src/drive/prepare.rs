@@ -16,9 +16,13 @@
 
 use crate::display::*;
 use crate::drive::display;
+use crate::drive::editor::CourseEditor;
+use crate::drive::gate::Gate;
 use crate::drive::read_track;
-use crate::utils::genereate_polygon;
+use crate::utils::{genereate_polygon, lat_lon_distance};
+use champlain::path_layer::ChamplainPathLayer;
 use gtk::prelude::*;
+use gtk::ResponseType;
 use std::cell::Cell;
 use std::cell::RefCell;
 use std::fs::OpenOptions;
@@ -28,22 +32,49 @@ use std::process;
 use std::rc::Rc;
 use std::vec::Vec;
 
+/// If the start and finish are closer then this (in metres)
+/// we assume the track is a circuit
+const CIRCUIT_DISTANCE: f64 = 20.0;
+
 pub struct TrackSelection {
     pub track_file: RefCell<std::path::PathBuf>,
     pub track_points: Cell<Vec<Vec<crate::drive::read_track::Coord>>>,
+    pub circuit: Cell<bool>,
     pub map_widget: gtk::Widget,
-    map_layers: Cell<Vec<champlain::path_layer::ChamplainPathLayer>>,
+    map_layers: Cell<Vec<ChamplainPathLayer>>,
+    /// The layers for the timing lines, two for each line
+    line_layers: Cell<Vec<ChamplainPathLayer>>,
+    editor: RefCell<Option<CourseEditor>>,
+    /// The line being edited
+    selected_line: Cell<usize>,
+    /// Is the selected line being dragged on the map
+    dragging: Cell<bool>,
 }
 
 pub type TrackSelectionRef = Rc<TrackSelection>;
 
+/// Replace the nodes of `layer` with `points`
+fn set_nodes(layer: &mut ChamplainPathLayer, points: &[(f64, f64)]) {
+    layer.remove_all();
+
+    for (lat, lon) in points.iter() {
+        let mut c_point = champlain::coordinate::ChamplainCoordinate::new_full(*lat, *lon);
+        layer.add_node(c_point.borrow_mut_location());
+    }
+}
+
 impl TrackSelection {
     fn new(champlain_widget: gtk::Widget) -> TrackSelectionRef {
         TrackSelectionRef::new(Self {
             track_file: RefCell::new(PathBuf::new()),
             track_points: Cell::new(Vec::new()),
+            circuit: Cell::new(false),
             map_widget: champlain_widget,
             map_layers: Cell::new(Vec::new()),
+            line_layers: Cell::new(Vec::new()),
+            editor: RefCell::new(None),
+            selected_line: Cell::new(0),
+            dragging: Cell::new(false),
         })
     }
 
@@ -62,13 +93,28 @@ impl TrackSelection {
                 .create(false)
                 .open(&filepath);
 
-            self.track_file.replace(filepath);
+            let track_file = match track_file {
+                Ok(file) => file,
+                Err(e) => {
+                    println!("Unable to open {:?}: {}", filepath, e);
+                    return;
+                }
+            };
+
+            let reader = BufReader::new(track_file);
+            let track_points = match read_track::get_long_and_lat(reader) {
+                Ok(track_points) => track_points,
+                Err(e) => {
+                    println!("Unable to load {:?}: {}", filepath, e);
+                    return;
+                }
+            };
 
-            let reader = BufReader::new(track_file.unwrap());
-            let track_points = read_track::get_long_and_lat(reader);
+            self.track_file.replace(filepath);
 
             // Remove all current layers
             let mut new_map_layers = self.map_layers.take();
+            new_map_layers.append(&mut self.line_layers.take());
             while !new_map_layers.is_empty() {
                 new_map_layers.pop().unwrap().remove_all();
             }
@@ -95,80 +141,210 @@ impl TrackSelection {
             new_map_layers.push(path_layer);
             self.map_layers.replace(new_map_layers);
 
-            // Add the start polygons
-            for segment in &track_points {
-                let mut path_layer = champlain::path_layer::ChamplainPathLayer::new();
+            // Default to a circuit if the finish is back at the start
+            let start = track_points.first().unwrap().first().unwrap();
+            let finish = track_points.last().unwrap().last().unwrap();
+            let circuit_button = builder
+                .get_object::<gtk::CheckButton>("LoadMapCircuitButton")
+                .expect("Can't find LoadMapCircuitButton in ui file.");
+            circuit_button.set_active(
+                lat_lon_distance(start.lat, start.lon, finish.lat, finish.lon) < CIRCUIT_DISTANCE,
+            );
 
-                let start_poly = genereate_polygon(
-                    segment.first().unwrap().lat,
-                    segment.first().unwrap().lon,
-                    segment.first().unwrap().head.unwrap_or(0.0),
-                );
+            // Let the start, sector and finish lines be edited
+            let editor = CourseEditor::new(&track_points);
+            let line_scale = builder
+                .get_object::<gtk::Scale>("LoadMapLineScale")
+                .expect("Can't find LoadMapLineScale in ui file.");
+            line_scale.set_range(0.0, (editor.points().len() - 1) as f64);
+            self.editor.replace(Some(editor));
+            self.fill_line_combo(&builder, 0);
+
+            for name in [
+                "LoadMapLineCombo",
+                "LoadMapLineScale",
+                "LoadMapRotateSpin",
+                "LoadMapWidthSpin",
+                "LoadMapAddSectorButton",
+                "LoadMapSaveButton",
+            ]
+            .iter()
+            {
+                builder
+                    .get_object::<gtk::Widget>(name)
+                    .unwrap_or_else(|| panic!("Can't find {} in ui file.", name))
+                    .set_sensitive(true);
+            }
 
-                path_layer.set_stroke_colour(champlain::clutter_colour::ClutterColor::new(
-                    255, 255, 255, 150,
-                ));
+            self.track_points.replace(track_points);
 
-                for coord in start_poly.points().iter() {
-                    let mut c_point =
-                        champlain::coordinate::ChamplainCoordinate::new_full(coord[0], coord[1]);
-                    path_layer.add_node(c_point.borrow_mut_location());
-                }
-                // Add the first point again to create a closed shape
-                let mut c_point = champlain::coordinate::ChamplainCoordinate::new_full(
-                    start_poly.points()[0][0],
-                    start_poly.points()[0][1],
-                );
-                path_layer.add_node(c_point.borrow_mut_location());
+            let forward_button = builder
+                .get_object::<gtk::Button>("LoadMapForwardButton")
+                .expect("Can't find LoadMapForwardButton in ui file.");
 
-                champlain_view.add_layer(path_layer.borrow_mut_layer());
-                let mut new_map_layers = self.map_layers.take();
-                new_map_layers.push(path_layer);
-                self.map_layers.replace(new_map_layers);
-            }
+            forward_button.set_sensitive(true);
+        }
+    }
 
-            // Add the end polygon
-            let mut path_layer = champlain::path_layer::ChamplainPathLayer::new();
+    /// Run `edit` on the course editor with the selected line
+    fn edit<T>(&self, edit: impl FnOnce(&mut CourseEditor, usize) -> T) -> Option<T> {
+        let mut editor = self.editor.borrow_mut();
 
-            let end_poly = genereate_polygon(
-                track_points.last().unwrap().last().unwrap().lat,
-                track_points.last().unwrap().last().unwrap().lon,
-                track_points
-                    .last()
-                    .unwrap()
-                    .last()
-                    .unwrap()
-                    .head
-                    .unwrap_or(0.0),
-            );
+        editor
+            .as_mut()
+            .map(|editor| edit(editor, self.selected_line.get()))
+    }
+
+    /// List the lines in LoadMapLineCombo and select `line`
+    fn fill_line_combo(&self, builder: &gtk::Builder, line: usize) {
+        let num_lines = match self.editor.borrow().as_ref() {
+            Some(editor) => editor.lines().len(),
+            None => return,
+        };
+
+        let line_combo = builder
+            .get_object::<gtk::ComboBoxText>("LoadMapLineCombo")
+            .expect("Can't find LoadMapLineCombo in ui file.");
+
+        line_combo.remove_all();
+        for i in 0..num_lines {
+            let name = match i {
+                0 => "Start".to_string(),
+                i if i + 1 == num_lines => "Finish".to_string(),
+                i => format!("Sector {}", i + 1),
+            };
+            line_combo.append_text(&name);
+        }
+
+        // This calls select_line()
+        line_combo.set_active(Some(line as u32));
+    }
+
+    /// The latitude and longitude of the map at `x` and `y`
+    fn map_lat_lon(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        let mut champlain_view = champlain::gtk_embed::get_view(self.map_widget.clone());
 
-            path_layer
-                .set_stroke_colour(champlain::clutter_colour::ClutterColor::new(0, 0, 0, 150));
+        (
+            champlain_view.y_to_latitude(y),
+            champlain_view.x_to_longitude(x),
+        )
+    }
+
+    /// Show the settings of `line` so it can be edited
+    fn select_line(&self, builder: &gtk::Builder, line: usize) {
+        let (selected, num_lines) = match self.editor.borrow().as_ref() {
+            Some(editor) => (editor.lines()[line], editor.lines().len()),
+            None => return,
+        };
+        self.selected_line.set(line);
+
+        builder
+            .get_object::<gtk::Scale>("LoadMapLineScale")
+            .expect("Can't find LoadMapLineScale in ui file.")
+            .set_value(selected.index as f64);
+        builder
+            .get_object::<gtk::SpinButton>("LoadMapRotateSpin")
+            .expect("Can't find LoadMapRotateSpin in ui file.")
+            .set_value(selected.rotation as f64);
+        builder
+            .get_object::<gtk::SpinButton>("LoadMapWidthSpin")
+            .expect("Can't find LoadMapWidthSpin in ui file.")
+            .set_value(selected.width as f64);
+
+        // The start and finish can't be removed
+        builder
+            .get_object::<gtk::Button>("LoadMapRemoveSectorButton")
+            .expect("Can't find LoadMapRemoveSectorButton in ui file.")
+            .set_sensitive(line > 0 && line + 1 < num_lines);
+
+        self.draw_lines();
+    }
+
+    /// Draw the start, sector and finish lines on the map. Each
+    /// line has a polygon showing which way to cross it.
+    fn draw_lines(&self) {
+        let editor = self.editor.borrow();
+        let editor = match editor.as_ref() {
+            Some(editor) => editor,
+            None => return,
+        };
+        let mut champlain_view = champlain::gtk_embed::get_view(self.map_widget.clone());
+        let mut line_layers = self.line_layers.take();
+        let num_lines = editor.lines().len();
 
-            for coord in end_poly.points().iter() {
-                let mut c_point =
-                    champlain::coordinate::ChamplainCoordinate::new_full(coord[0], coord[1]);
-                path_layer.add_node(c_point.borrow_mut_location());
+        for line in 0..num_lines {
+            let gate = editor.gate(line);
+
+            // Reuse the layers from last time
+            while line_layers.len() < (line + 1) * 2 {
+                let mut path_layer = champlain::path_layer::ChamplainPathLayer::new();
+                champlain_view.add_layer(path_layer.borrow_mut_layer());
+                line_layers.push(path_layer);
             }
-            // Add the first point again to create a closed shape
-            let mut c_point = champlain::coordinate::ChamplainCoordinate::new_full(
-                end_poly.points()[0][0],
-                end_poly.points()[0][1],
+
+            let selected = line == self.selected_line.get();
+            let colour = || match (selected, line + 1 == num_lines) {
+                (true, _) => champlain::clutter_colour::ClutterColor::new(255, 165, 0, 200),
+                (false, true) => champlain::clutter_colour::ClutterColor::new(0, 0, 0, 150),
+                (false, false) => champlain::clutter_colour::ClutterColor::new(255, 255, 255, 150),
+            };
+
+            let timing_gate = Gate::new(&gate);
+            let poly = genereate_polygon(
+                gate.lat,
+                gate.lon,
+                gate.head.unwrap_or(0.0),
+                timing_gate.geometry(),
             );
-            path_layer.add_node(c_point.borrow_mut_location());
+            let mut poly_points: Vec<(f64, f64)> =
+                poly.points().iter().map(|p| (p[0], p[1])).collect();
+            // Add the first point again to create a closed shape
+            poly_points.push(poly_points[0]);
 
-            champlain_view.add_layer(path_layer.borrow_mut_layer());
-            let mut new_map_layers = self.map_layers.take();
-            new_map_layers.push(path_layer);
-            self.map_layers.replace(new_map_layers);
+            let poly_layer = &mut line_layers[line * 2];
+            poly_layer.set_stroke_colour(colour());
+            set_nodes(poly_layer, &poly_points);
 
-            self.track_points.replace(track_points);
+            let line_layer = &mut line_layers[line * 2 + 1];
+            line_layer.set_stroke_colour(colour());
+            match timing_gate.ends() {
+                Some((left, right)) => set_nodes(line_layer, &[left, right]),
+                None => set_nodes(line_layer, &[]),
+            }
+        }
 
-            let forward_button = builder
-                .get_object::<gtk::Button>("LoadMapForwardButton")
-                .expect("Can't find LoadMapForwardButton in ui file.");
+        // Clear the layers of removed lines
+        for layer in line_layers.iter_mut().skip(num_lines * 2) {
+            layer.remove_all();
+        }
 
-            forward_button.set_sensitive(true);
+        self.line_layers.replace(line_layers);
+    }
+
+    /// Save the edited course to a new track file
+    fn save_clicked(&self, display: DisplayRef) {
+        let window: gtk::ApplicationWindow = display
+            .builder
+            .get_object("MainPage")
+            .expect("Couldn't find MainPage in ui file.");
+
+        let file_chooser = gtk::FileChooserNative::new(
+            Some("Save course as"),
+            Some(&window),
+            gtk::FileChooserAction::Save,
+            Some("Save"),
+            Some("Close"),
+        );
+
+        let response = file_chooser.run();
+        if response == ResponseType::Accept {
+            if let Some(filepath) = file_chooser.get_filename() {
+                if let Some(Err(e)) = self.edit(|editor, _| editor.save(&filepath)) {
+                    println!("Unable to save {:?}: {}", filepath, e);
+                    return;
+                }
+                self.track_file.replace(filepath);
+            }
         }
     }
 }
@@ -222,6 +398,165 @@ pub fn button_press_event(display: DisplayRef) {
         track_sel_info.file_picker_clicked(display);
     });
 
+    let line_combo = builder
+        .get_object::<gtk::ComboBoxText>("LoadMapLineCombo")
+        .expect("Can't find LoadMapLineCombo in ui file.");
+
+    let display_weak = DisplayRef::downgrade(&display);
+    let track_sel_info_weak = TrackSelectionRef::downgrade(&track_sel_info);
+    line_combo.connect_changed(move |line_combo| {
+        let display = upgrade_weak!(display_weak);
+        let track_sel_info = upgrade_weak!(track_sel_info_weak);
+        if let Some(line) = line_combo.get_active() {
+            track_sel_info.select_line(&display.builder, line as usize);
+        }
+    });
+
+    let line_scale = builder
+        .get_object::<gtk::Scale>("LoadMapLineScale")
+        .expect("Can't find LoadMapLineScale in ui file.");
+
+    let track_sel_info_weak = TrackSelectionRef::downgrade(&track_sel_info);
+    line_scale.connect_value_changed(move |line_scale| {
+        let track_sel_info = upgrade_weak!(track_sel_info_weak);
+        let index = line_scale.get_value() as usize;
+
+        if let Some(moved) = track_sel_info.edit(|editor, line| editor.move_line(line, index)) {
+            // Stop at the lines either side
+            if moved != index {
+                line_scale.set_value(moved as f64);
+            }
+            track_sel_info.draw_lines();
+        }
+    });
+
+    // Lines can also be dragged along the track on the map,
+    // the slider is still useful to fine tune where they are
+    champlain_widget.add_events(
+        gdk::EventMask::BUTTON_PRESS_MASK
+            | gdk::EventMask::BUTTON_RELEASE_MASK
+            | gdk::EventMask::POINTER_MOTION_MASK,
+    );
+
+    let track_sel_info_weak = TrackSelectionRef::downgrade(&track_sel_info);
+    champlain_widget.connect_button_press_event(move |_, event| {
+        let track_sel_info = upgrade_weak!(track_sel_info_weak, Inhibit(false));
+        if event.get_button() != 1 {
+            return Inhibit(false);
+        }
+
+        let (lat, lon) = track_sel_info.map_lat_lon(event.get_position());
+        let line = match track_sel_info.edit(|editor, _| editor.line_at(lat, lon)) {
+            Some(Some(line)) => line,
+            // Let the map be panned instead
+            _ => return Inhibit(false),
+        };
+
+        // This calls select_line()
+        line_combo.set_active(Some(line as u32));
+        track_sel_info.dragging.set(true);
+        Inhibit(true)
+    });
+
+    let track_sel_info_weak = TrackSelectionRef::downgrade(&track_sel_info);
+    let drag_scale = line_scale.clone();
+    champlain_widget.connect_motion_notify_event(move |_, event| {
+        let track_sel_info = upgrade_weak!(track_sel_info_weak, Inhibit(false));
+        if !track_sel_info.dragging.get() {
+            return Inhibit(false);
+        }
+
+        let (lat, lon) = track_sel_info.map_lat_lon(event.get_position());
+        if let Some(index) = track_sel_info.edit(|editor, line| {
+            let index = editor.nearest_point(line, lat, lon);
+            editor.move_line(line, index)
+        }) {
+            // This redraws the lines
+            drag_scale.set_value(index as f64);
+        }
+        Inhibit(true)
+    });
+
+    let track_sel_info_weak = TrackSelectionRef::downgrade(&track_sel_info);
+    champlain_widget.connect_button_release_event(move |_, _| {
+        let track_sel_info = upgrade_weak!(track_sel_info_weak, Inhibit(false));
+
+        Inhibit(track_sel_info.dragging.replace(false))
+    });
+
+    let rotate_spin = builder
+        .get_object::<gtk::SpinButton>("LoadMapRotateSpin")
+        .expect("Can't find LoadMapRotateSpin in ui file.");
+
+    let track_sel_info_weak = TrackSelectionRef::downgrade(&track_sel_info);
+    rotate_spin.connect_value_changed(move |rotate_spin| {
+        let track_sel_info = upgrade_weak!(track_sel_info_weak);
+        let rotation = rotate_spin.get_value() as f32;
+
+        track_sel_info.edit(|editor, line| editor.set_rotation(line, rotation));
+        track_sel_info.draw_lines();
+    });
+
+    let width_spin = builder
+        .get_object::<gtk::SpinButton>("LoadMapWidthSpin")
+        .expect("Can't find LoadMapWidthSpin in ui file.");
+
+    let track_sel_info_weak = TrackSelectionRef::downgrade(&track_sel_info);
+    width_spin.connect_value_changed(move |width_spin| {
+        let track_sel_info = upgrade_weak!(track_sel_info_weak);
+        let width = width_spin.get_value() as f32;
+
+        track_sel_info.edit(|editor, line| editor.set_width(line, width));
+        track_sel_info.draw_lines();
+    });
+
+    let add_sector_button = builder
+        .get_object::<gtk::Button>("LoadMapAddSectorButton")
+        .expect("Can't find LoadMapAddSectorButton in ui file.");
+
+    let display_weak = DisplayRef::downgrade(&display);
+    let track_sel_info_weak = TrackSelectionRef::downgrade(&track_sel_info);
+    add_sector_button.connect_clicked(move |_| {
+        let display = upgrade_weak!(display_weak);
+        let track_sel_info = upgrade_weak!(track_sel_info_weak);
+
+        // Split the sector after the selected line, or
+        // the last sector if the finish is selected
+        let new_line = track_sel_info
+            .edit(|editor, line| editor.add_sector(line.min(editor.num_sectors() - 1)));
+        if let Some(Some(new_line)) = new_line {
+            track_sel_info.fill_line_combo(&display.builder, new_line);
+        }
+    });
+
+    let remove_sector_button = builder
+        .get_object::<gtk::Button>("LoadMapRemoveSectorButton")
+        .expect("Can't find LoadMapRemoveSectorButton in ui file.");
+
+    let display_weak = DisplayRef::downgrade(&display);
+    let track_sel_info_weak = TrackSelectionRef::downgrade(&track_sel_info);
+    remove_sector_button.connect_clicked(move |_| {
+        let display = upgrade_weak!(display_weak);
+        let track_sel_info = upgrade_weak!(track_sel_info_weak);
+
+        let line = track_sel_info.selected_line.get();
+        if let Some(true) = track_sel_info.edit(|editor, line| editor.remove_line(line)) {
+            track_sel_info.fill_line_combo(&display.builder, line - 1);
+        }
+    });
+
+    let save_button = builder
+        .get_object::<gtk::Button>("LoadMapSaveButton")
+        .expect("Can't find LoadMapSaveButton in ui file.");
+
+    let display_weak = DisplayRef::downgrade(&display);
+    let track_sel_info_weak = TrackSelectionRef::downgrade(&track_sel_info);
+    save_button.connect_clicked(move |_| {
+        let display = upgrade_weak!(display_weak);
+        let track_sel_info = upgrade_weak!(track_sel_info_weak);
+        track_sel_info.save_clicked(display);
+    });
+
     let forward_button = builder
         .get_object::<gtk::Button>("LoadMapForwardButton")
         .expect("Can't find LoadMapForwardButton in ui file.");
@@ -236,6 +571,17 @@ pub fn button_press_event(display: DisplayRef) {
             .upgrade()
             .unwrap();
 
+        let circuit_button = display
+            .builder
+            .get_object::<gtk::CheckButton>("LoadMapCircuitButton")
+            .expect("Can't find LoadMapCircuitButton in ui file.");
+        track_sel_info.circuit.set(circuit_button.get_active());
+
+        // Drive the edited course
+        if let Some(editor) = track_sel_info.editor.borrow().as_ref() {
+            track_sel_info.track_points.replace(editor.segments());
+        }
+
         map_frame.remove(&champlain_widget);
 
         display::button_press_event(display, track_sel_info);
@@ -14,92 +14,321 @@
  * limitations under the License.
  */
 
+use crate::drive::gate::GateSettings;
+use crate::utils::lat_lon_bearing;
+use roxmltree::{Document, Node};
 use serde::{Deserialize, Serialize};
-use std::io::BufRead;
+use std::fmt;
+use std::io::{self, Read};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Coord {
     pub lat: f64,
     pub lon: f64,
     pub head: Option<f32>,
+    /// The size of the timing gate at this point, anything
+    /// that isn't set uses the default
+    #[serde(default, skip_serializing_if = "GateSettings::is_empty")]
+    pub gate: GateSettings,
 }
 
 impl Coord {
     pub fn new(lat: f64, lon: f64, head: Option<f32>) -> Self {
-        Self { lat, lon, head }
+        Self {
+            lat,
+            lon,
+            head,
+            gate: GateSettings::default(),
+        }
+    }
+}
+
+/// A single point read from a GPX file
+#[derive(Clone)]
+pub struct TrackPoint {
+    pub lat: f64,
+    pub lon: f64,
+    pub ele: Option<f32>,
+    pub time: Option<String>,
+    pub head: Option<f32>,
+    /// Speed in metres per second
+    pub speed: Option<f32>,
+    pub status: Option<i32>,
+    pub hdop: Option<f32>,
+    pub sats: Option<u32>,
+    /// The estimated horizontal position error, in metres
+    pub error: Option<f32>,
+    /// The timing gate settings, from the point or the track
+    pub gate: GateSettings,
+}
+
+#[derive(Debug)]
+pub enum GpxError {
+    /// Reading the file failed
+    Io(io::Error),
+    /// The file isn't valid XML
+    Xml(roxmltree::Error),
+    /// The file is XML, but not GPX
+    NotGpx,
+    /// A point is missing, or has an invalid, lat or lon
+    InvalidPoint(String),
+    /// The file doesn't contain any points
+    NoPoints,
+}
+
+impl From<io::Error> for GpxError {
+    fn from(err: io::Error) -> Self {
+        GpxError::Io(err)
+    }
+}
+
+impl From<roxmltree::Error> for GpxError {
+    fn from(err: roxmltree::Error) -> Self {
+        GpxError::Xml(err)
+    }
+}
+
+impl fmt::Display for GpxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GpxError::Io(err) => write!(f, "Unable to read the GPX file: {}", err),
+            GpxError::Xml(err) => write!(f, "Invalid XML: {}", err),
+            GpxError::NotGpx => write!(f, "Not a GPX file"),
+            GpxError::InvalidPoint(point) => write!(f, "Invalid point: {}", point),
+            GpxError::NoPoints => write!(f, "No points in the GPX file"),
+        }
+    }
+}
+
+/// Returns the first child element called `name`, ignoring the namespace
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children()
+        .find(|n| n.is_element() && n.tag_name().name() == name)
+}
+
+/// Returns the elements called `name` directly below `node`
+fn children<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children()
+        .filter(move |n| n.is_element() && n.tag_name().name() == name)
+}
+
+/// Returns the text of the first element called `name` below `node`.
+/// This includes elements inside `<extensions>`, in any namespace.
+fn element_text<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.descendants()
+        .find(|n| n.is_element() && n.tag_name().name() == name)
+        .and_then(|n| n.text())
+        .map(|text| text.trim())
+}
+
+/// Parse the timing gate settings below `node`
+fn parse_gate_settings(node: Node) -> GateSettings {
+    let value = |name| element_text(node, name).and_then(|value| value.parse().ok());
+
+    GateSettings {
+        width: value("gateWidth"),
+        depth: value("gateDepth"),
+        tolerance: value("gateTolerance"),
+        angle: value("gateAngle"),
+    }
+}
+
+/// Parse the gate settings for a whole `<trk>` or `<rte>`,
+/// which are in its `<extensions>`
+fn parse_track_gate_settings(node: Node) -> GateSettings {
+    children(node, "extensions")
+        .next()
+        .map(parse_gate_settings)
+        .unwrap_or_default()
+}
+
+/// Use the gate settings of the track for anything the points don't set
+fn apply_gate_settings(points: &mut [TrackPoint], settings: GateSettings) {
+    for point in points.iter_mut() {
+        point.gate = point.gate.or(settings);
+    }
+}
+
+/// Parse a `<trkpt>`, `<rtept>` or `<wpt>`
+fn parse_point(node: Node) -> Result<TrackPoint, GpxError> {
+    let lat = node
+        .attribute("lat")
+        .and_then(|lat| lat.trim().parse().ok());
+    let lon = node
+        .attribute("lon")
+        .and_then(|lon| lon.trim().parse().ok());
+
+    let (lat, lon) = match (lat, lon) {
+        (Some(lat), Some(lon)) => (lat, lon),
+        _ => {
+            let pos = node.document().text_pos_at(node.range().start);
+            return Err(GpxError::InvalidPoint(format!(
+                "<{}> at line {}",
+                node.tag_name().name(),
+                pos.row
+            )));
+        }
+    };
+
+    // The heading is a <course> in GPX 1.0 and in the Garmin
+    // TrackPointExtension. Older DashSight files use <degreesType>.
+    let head = element_text(node, "course")
+        .or_else(|| element_text(node, "degreesType"))
+        .and_then(|head| head.parse().ok());
+
+    // GPX 1.1 has a standard <sat> element, we write <sats> in
+    // the DashSight extensions.
+    let sats = element_text(node, "sats")
+        .or_else(|| element_text(node, "sat"))
+        .and_then(|sats| sats.parse().ok());
+
+    Ok(TrackPoint {
+        lat,
+        lon,
+        ele: element_text(node, "ele").and_then(|ele| ele.parse().ok()),
+        time: element_text(node, "time").map(|time| time.to_string()),
+        head,
+        speed: element_text(node, "speed").and_then(|speed| speed.parse().ok()),
+        status: element_text(node, "status").and_then(|status| status.parse().ok()),
+        hdop: element_text(node, "hdop").and_then(|hdop| hdop.parse().ok()),
+        sats,
+        error: element_text(node, "positionError").and_then(|error| error.parse().ok()),
+        gate: parse_gate_settings(node),
+    })
+}
+
+/// Parse all of the points called `name` below `node`
+fn parse_points(node: Node, name: &str) -> Result<Vec<TrackPoint>, GpxError> {
+    let mut points = Vec::new();
+
+    for point in children(node, name) {
+        let point = parse_point(point)?;
+
+        // Let's assume a lat/lon of 0 is just invalid
+        if point.lat != 0.0 && point.lon != 0.0 {
+            points.push(point);
+        }
+    }
+
+    fill_headings(&mut points);
+
+    Ok(points)
+}
+
+/// Work out the heading for points that don't have one, from
+/// the next point that is somewhere else (or the previous one
+/// for the last point).
+fn fill_headings(points: &mut [TrackPoint]) {
+    for i in 0..points.len() {
+        if points[i].head.is_some() {
+            continue;
+        }
+
+        let (lat, lon) = (points[i].lat, points[i].lon);
+        let moved = |p: &&TrackPoint| p.lat != lat || p.lon != lon;
+
+        let head = points[i + 1..]
+            .iter()
+            .find(moved)
+            .map(|next| lat_lon_bearing(lat, lon, next.lat, next.lon))
+            .or_else(|| {
+                points[..i]
+                    .iter()
+                    .rev()
+                    .find(moved)
+                    .map(|prev| lat_lon_bearing(prev.lat, prev.lon, lat, lon))
+            });
+
+        points[i].head = head.map(|head| head as f32);
     }
 }
 
-pub fn get_long_and_lat(
-    reader: std::io::BufReader<std::fs::File>,
-) -> Vec<Vec<crate::drive::read_track::Coord>> {
-    let mut reader_iterator = reader.lines().map(|l| l.unwrap());
+/// Read every point, with the elevation and time, from a GPX file.
+/// Each `<trkseg>` and `<rte>` is returned as a separate vector.
+/// If the file doesn't have any tracks or routes the waypoints
+/// are returned instead.
+pub fn get_track_points<R: Read>(mut reader: R) -> Result<Vec<Vec<TrackPoint>>, GpxError> {
+    let mut text = String::new();
+    reader.read_to_string(&mut text)?;
+
+    let doc = Document::parse(&text)?;
+    let gpx = doc.root_element();
+    if gpx.tag_name().name() != "gpx" {
+        return Err(GpxError::NotGpx);
+    }
+
     let mut track_vec = Vec::new();
-    let mut coord_vec = Vec::new();
-
-    // Skip the first 13 lines
-    // TODO: Be better with verification
-    reader_iterator.next();
-    reader_iterator.next();
-    reader_iterator.next();
-    reader_iterator.next();
-    reader_iterator.next();
-    reader_iterator.next();
-    reader_iterator.next();
-    reader_iterator.next();
-    reader_iterator.next();
-    reader_iterator.next();
-    reader_iterator.next();
-    reader_iterator.next();
-    reader_iterator.next();
-
-    let mut lat: f64 = 0.0;
-    let mut lon: f64 = 0.0;
-    let mut head: Option<f32> = None;
-
-    for line in reader_iterator {
-        let trim_line = line.trim();
-        if let Some(trkpt_num) = trim_line.find("<trkpt") {
-            if let Some(trkpt_line) = trim_line.get((trkpt_num + 5)..) {
-                let split_line: Vec<&str> = trkpt_line.split('"').collect();
-
-                lat = split_line[1].parse().unwrap();
-                lon = split_line[3].parse().unwrap();
-            }
-        } else if let Some(degrees_num) = trim_line.find("<degreesType") {
-            if let Some(degrees_line) = trim_line.get((degrees_num + 1)..) {
-                let split_line: Vec<&str> = degrees_line.split('<').collect();
-                let split_line: Vec<&str> = split_line[0].split('>').collect();
 
-                head = Some(split_line[1].parse().unwrap());
-            }
-        } else if trim_line.find("</trkpt").is_some() {
-            // Let's assume a lat/lon of 0 is just invalid
-            if lat != 0.0 && lon != 0.0 && head.unwrap_or(0.0) > 0.0 {
-                let c = Coord { lat, lon, head };
-                coord_vec.push(c);
+    for trk in children(gpx, "trk") {
+        let settings = parse_track_gate_settings(trk);
+
+        for trkseg in children(trk, "trkseg") {
+            let mut points = parse_points(trkseg, "trkpt")?;
+            apply_gate_settings(&mut points, settings);
+            track_vec.push(points);
+        }
+    }
+
+    for rte in children(gpx, "rte") {
+        let mut points = parse_points(rte, "rtept")?;
+        apply_gate_settings(&mut points, parse_track_gate_settings(rte));
+        track_vec.push(points);
+    }
+
+    track_vec.retain(|points| !points.is_empty());
+
+    if track_vec.is_empty() {
+        let waypoints = parse_points(gpx, "wpt")?;
+        if !waypoints.is_empty() {
+            track_vec.push(waypoints);
+        }
+    }
+
+    if track_vec.is_empty() {
+        return Err(GpxError::NoPoints);
+    }
+
+    Ok(track_vec)
+}
+
+pub fn get_long_and_lat<R: Read>(reader: R) -> Result<Vec<Vec<Coord>>, GpxError> {
+    let mut track_vec = Vec::new();
+
+    for points in get_track_points(reader)? {
+        let mut coord_vec = Vec::new();
+
+        for point in points {
+            // Ignore points where we never moved, so have no heading.
+            // A heading of 0 is north, so is kept.
+            if point.head.is_some() {
+                coord_vec.push(Coord {
+                    lat: point.lat,
+                    lon: point.lon,
+                    head: point.head,
+                    gate: point.gate,
+                });
             }
-            lat = 0.0;
-            lon = 0.0;
-            head = None;
-        } else if trim_line.find("</trkseg").is_some() {
+        }
+
+        if !coord_vec.is_empty() {
             track_vec.push(coord_vec);
-            coord_vec = Vec::new()
         }
     }
 
-    // This is to maintain backwards compatability with single
-    // segment tracks.
-    if !coord_vec.is_empty() {
-        track_vec.push(coord_vec);
+    if track_vec.is_empty() {
+        return Err(GpxError::NoPoints);
     }
 
-    track_vec
+    Ok(track_vec)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::drive::gate::GateGeometry;
     use crate::utils::genereate_polygon;
     use ncollide2d::query::PointQuery;
     use std::fs::OpenOptions;
@@ -113,7 +342,7 @@ mod tests {
             .create(false)
             .open("tests/test-track-carpark");
         let reader = BufReader::new(track_file.unwrap());
-        let track_points = get_long_and_lat(reader);
+        let track_points = get_long_and_lat(reader).unwrap();
         track_points.first().unwrap().first().unwrap();
 
         let track_file = OpenOptions::new()
@@ -122,7 +351,7 @@ mod tests {
             .create(false)
             .open("tests/test-track-cowpalace");
         let reader = BufReader::new(track_file.unwrap());
-        let track_points = get_long_and_lat(reader);
+        let track_points = get_long_and_lat(reader).unwrap();
         track_points.first().unwrap().first().unwrap();
 
         let track_file = OpenOptions::new()
@@ -131,7 +360,7 @@ mod tests {
             .create(false)
             .open("tests/test-track-backyard");
         let reader = BufReader::new(track_file.unwrap());
-        let track_points = get_long_and_lat(reader);
+        let track_points = get_long_and_lat(reader).unwrap();
         track_points.first().unwrap().first().unwrap();
 
         let track_file = OpenOptions::new()
@@ -140,7 +369,7 @@ mod tests {
             .create(false)
             .open("tests/test-track-neighbourhood");
         let reader = BufReader::new(track_file.unwrap());
-        let track_points = get_long_and_lat(reader);
+        let track_points = get_long_and_lat(reader).unwrap();
         track_points.first().unwrap().first().unwrap();
     }
 
@@ -152,7 +381,7 @@ mod tests {
             .create(false)
             .open("tests/test-track-cowpalace");
         let reader = BufReader::new(track_file.unwrap());
-        let track_points = get_long_and_lat(reader);
+        let track_points = get_long_and_lat(reader).unwrap();
 
         let start_poly = genereate_polygon(
             track_points.first().unwrap().first().unwrap().lat,
@@ -164,6 +393,7 @@ mod tests {
                 .unwrap()
                 .head
                 .unwrap_or(0.0),
+            &GateGeometry::default(),
         );
 
         assert_eq!(
@@ -190,4 +420,93 @@ mod tests {
             false
         );
     }
+
+    #[test]
+    fn test_other_gpx() {
+        // GPX 1.0, everything on one line and self-closing points
+        let gpx = r#"<?xml version="1.0"?><gpx version="1.0" creator="Other" xmlns="http://www.topografix.com/GPX/1/0"><trk><trkseg><trkpt lat="37.0" lon="-122.0"><ele>10</ele><time>2020-10-17T14:49:10Z</time><course>90.5</course></trkpt><trkpt lat="37.0001" lon="-122.0"/><trkpt lat="37.0002" lon="-122.0"/></trkseg></trk></gpx>"#;
+        let track_points = get_track_points(gpx.as_bytes()).unwrap();
+
+        assert_eq!(track_points.len(), 1);
+        let points = &track_points[0];
+        assert_eq!(points.len(), 3);
+        assert_eq!(points[0].ele, Some(10.0));
+        assert_eq!(points[0].time.as_deref(), Some("2020-10-17T14:49:10Z"));
+        assert_eq!(points[0].head, Some(90.5));
+        assert_eq!(points[1].time, None);
+
+        // Heading north, worked out from the points
+        assert!(points[1].head.unwrap() < 0.1);
+        assert!(points[2].head.unwrap() < 0.1);
+    }
+
+    #[test]
+    fn test_routes_and_waypoints() {
+        let gpx = r#"<gpx version="1.1" xmlns="http://www.topografix.com/GPX/1/1" xmlns:ext="http://example.com/ext">
+            <wpt lat="1.0" lon="1.0"/>
+            <rte>
+                <rtept lat="37.0" lon="-122.0"/>
+                <rtept lat="37.0" lon="-121.9999"><extensions><ext:degreesType>45</ext:degreesType></extensions></rtept>
+            </rte>
+        </gpx>"#;
+        let track_points = get_track_points(gpx.as_bytes()).unwrap();
+
+        assert_eq!(track_points.len(), 1);
+        assert!((track_points[0][0].head.unwrap() - 90.0).abs() < 0.1);
+        assert_eq!(track_points[0][1].head, Some(45.0));
+
+        let gpx = r#"<gpx version="1.1"><wpt lat="37.0" lon="-122.0"/><wpt lat="36.9999" lon="-122.0"/></gpx>"#;
+        let track_points = get_long_and_lat(gpx.as_bytes()).unwrap();
+        assert_eq!(track_points[0].len(), 2);
+        assert!((track_points[0][0].head.unwrap() - 180.0).abs() < 0.1);
+
+        // Points heading north are kept
+        let gpx = r#"<gpx version="1.1"><wpt lat="37.0" lon="-122.0"/><wpt lat="37.0001" lon="-122.0"/></gpx>"#;
+        let track_points = get_long_and_lat(gpx.as_bytes()).unwrap();
+        assert_eq!(track_points[0].len(), 2);
+        assert_eq!(track_points[0][0].head, Some(0.0));
+    }
+
+    #[test]
+    fn test_gate_settings() {
+        let gpx = r#"<gpx version="1.1" xmlns:dashsight="https://github.com/DashSight/DashSight/GpxExtensions/v1">
+            <trk>
+                <extensions><dashsight:gateWidth>8</dashsight:gateWidth><dashsight:gateTolerance>15</dashsight:gateTolerance></extensions>
+                <trkseg>
+                    <trkpt lat="37.0" lon="-122.0"><extensions><dashsight:gateWidth>12.5</dashsight:gateWidth></extensions></trkpt>
+                    <trkpt lat="37.0001" lon="-122.0"/>
+                </trkseg>
+            </trk>
+        </gpx>"#;
+        let track_points = get_track_points(gpx.as_bytes()).unwrap();
+
+        // The point's own settings are used before the track's
+        let gate = track_points[0][0].gate;
+        assert_eq!(gate.width, Some(12.5));
+        assert_eq!(gate.depth, None);
+        assert_eq!(gate.tolerance, Some(15.0));
+        assert_eq!(track_points[0][1].gate.width, Some(8.0));
+    }
+
+    #[test]
+    fn test_gpx_errors() {
+        assert!(matches!(
+            get_track_points("not xml".as_bytes()),
+            Err(GpxError::Xml(_))
+        ));
+        assert!(matches!(
+            get_track_points("<kml></kml>".as_bytes()),
+            Err(GpxError::NotGpx)
+        ));
+        assert!(matches!(
+            get_track_points("<gpx><trk><trkseg></trkseg></trk></gpx>".as_bytes()),
+            Err(GpxError::NoPoints)
+        ));
+        assert!(matches!(
+            get_track_points(
+                r#"<gpx><trk><trkseg><trkpt lat="37.0"/></trkseg></trk></gpx>"#.as_bytes()
+            ),
+            Err(GpxError::InvalidPoint(_))
+        ));
+    }
 }
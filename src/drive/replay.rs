@@ -0,0 +1,665 @@
+/*
+ * Copyright 2020 Alistair Francis <alistair@alistair23.me>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *    http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Replays a recorded session, the GPX track along with the
+//! `-session.dslog` file (or the `-obdii.cvs` and `-imu.cvs` files
+//! from older recordings), as if it was coming from the hardware.
+
+use crate::drive::obdii::{OBDIICommandType, OBDIIData};
+use crate::drive::read_track::{get_track_points, TrackPoint};
+use crate::drive::threading::ThreadingRef;
+use crate::session_log::{Channel, SessionReader};
+use crate::utils::{
+    duration_to_gps_time, gps_time_to_duration, lat_lon_bearing, lat_lon_destination,
+    lat_lon_distance, GpsData, GpsError,
+};
+use std::cell::Cell;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The IMU log doesn't include timestamps, the IMU thread
+/// records roughly this many samples a second.
+const IMU_REPLAY_FREQ: f64 = 40.0;
+
+/// How far before the first point the replay starts, in metres
+const RUN_UP: f64 = 5.0;
+
+/// The longest we sleep for before checking if the replay should stop
+const WAIT_STEP: Duration = Duration::from_millis(100);
+
+/// Returns the name of a file recorded alongside `file`,
+/// for example `track-imu.cvs` for `track`.
+pub fn side_file(file: &Path, suffix: &str) -> PathBuf {
+    let mut name = file.file_stem().unwrap().to_str().unwrap().to_string();
+    name.push_str(suffix);
+
+    let mut side_file = file.to_path_buf();
+    side_file.pop();
+    side_file.push(name);
+
+    side_file
+}
+
+/// A recording of the track starts exactly on the start line, which
+/// doesn't count as crossing it. Add a point a little before, and a
+/// second earlier than, the first one so the line is crossed when it
+/// is replayed.
+pub fn add_run_up(points: &mut Vec<TrackPoint>) {
+    let first = match points.first() {
+        Some(first) => first,
+        None => return,
+    };
+
+    let head = first.head.map(|head| head as f64).or_else(|| {
+        let next = points.get(1)?;
+        Some(lat_lon_bearing(first.lat, first.lon, next.lat, next.lon))
+    });
+
+    if let Some(head) = head {
+        let mut run_up = first.clone();
+        let (lat, lon) = lat_lon_destination(first.lat, first.lon, head + 180.0, RUN_UP);
+        run_up.lat = lat;
+        run_up.lon = lon;
+        run_up.time = first
+            .time
+            .as_deref()
+            .and_then(gps_time_to_duration)
+            .and_then(|time| time.checked_sub(Duration::from_secs(1)))
+            .map(duration_to_gps_time);
+        points.insert(0, run_up);
+    }
+}
+
+/// The time of the first point of the GPX file `file`
+fn first_gps_time(file: &Path) -> Option<Duration> {
+    let reader = BufReader::new(File::open(file).ok()?);
+
+    get_track_points(reader)
+        .ok()?
+        .into_iter()
+        .flatten()
+        .find_map(|point| point.time.as_deref().and_then(gps_time_to_duration))
+}
+
+/// The time of the first record in the session log `file`
+fn first_session_time(file: &Path) -> Option<Duration> {
+    let mut reader = SessionReader::open(file).ok()?;
+    let start_time = reader.start_time;
+
+    reader.next().map(|record| start_time + record.time)
+}
+
+/// The time of the first row of the OBDII CSV file `file`
+fn first_obdii_time(file: &Path) -> Option<Duration> {
+    let reader = BufReader::new(File::open(file).ok()?);
+
+    // Skip the CVS headers
+    reader
+        .lines()
+        .skip(1)
+        .map_while(Result::ok)
+        .find_map(|line| line.split(',').next().and_then(obdii_time_to_duration))
+}
+
+/// Sleeps until a sample recorded at a time is due, taking the replay
+/// speed into account. The GPS, OBDII and IMU replays share a clock
+/// so they stay in sync with each other.
+pub struct ReplayClock {
+    start: Instant,
+    /// The earliest time in the recording, this is replayed straight away
+    epoch: Duration,
+    /// When the data was first recorded, ignoring the run up
+    recorded: Duration,
+    speed: f64,
+}
+
+pub type ReplayClockRef = Arc<ReplayClock>;
+
+impl ReplayClock {
+    /// Start replaying the GPX file `file`, and the data recorded
+    /// alongside it, `speed` times faster than real time.
+    pub fn start(file: &Path, speed: f64) -> ReplayClockRef {
+        let times = [
+            first_gps_time(file),
+            first_session_time(&side_file(file, "-session.dslog")),
+            first_obdii_time(&side_file(file, "-obdii.cvs")),
+        ];
+        let recorded = times.iter().flatten().min().copied().unwrap_or_default();
+        // The replayed GPS starts a second before the first point,
+        // see add_run_up()
+        let run_up = times[0].and_then(|time| time.checked_sub(Duration::from_secs(1)));
+        let epoch = run_up.map_or(recorded, |run_up| run_up.min(recorded));
+
+        ReplayClockRef::new(ReplayClock {
+            start: Instant::now(),
+            epoch,
+            recorded,
+            speed: if speed > 0.0 { speed } else { 1.0 },
+        })
+    }
+
+    /// Wait until the sample recorded at `time` is due.
+    /// Returns false, without waiting any longer, if `close` is set.
+    fn wait_until(&self, time: Duration, close: &Mutex<Cell<bool>>) -> bool {
+        let due = time
+            .checked_sub(self.epoch)
+            .unwrap_or_default()
+            .div_f64(self.speed);
+
+        loop {
+            if close.lock().unwrap().get() {
+                return false;
+            }
+
+            let elapsed = self.start.elapsed();
+            if due <= elapsed {
+                return true;
+            }
+            thread::sleep((due - elapsed).min(WAIT_STEP));
+        }
+    }
+}
+
+/// Replays the points of a GPX file as GPS data
+pub struct ReplayGps {
+    points: Vec<TrackPoint>,
+    index: usize,
+    clock: ReplayClockRef,
+    last_speed: f32,
+}
+
+impl ReplayGps {
+    /// Load the GPX file, the points are replayed when `clock` says
+    pub fn open(file: &Path, clock: ReplayClockRef) -> io::Result<ReplayGps> {
+        let reader = BufReader::new(File::open(file)?);
+        let mut points: Vec<TrackPoint> = get_track_points(reader)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?
+            .into_iter()
+            .flatten()
+            .collect();
+        add_run_up(&mut points);
+
+        Ok(ReplayGps {
+            points,
+            index: 0,
+            clock,
+            last_speed: 0.0,
+        })
+    }
+
+    /// Returns the next point once it is due. Once every point has
+    /// been replayed, or if `close` is set while waiting, this
+    /// returns `GpsError::EndOfStream`.
+    pub fn get_gps_data(&mut self, close: &Mutex<Cell<bool>>) -> Result<GpsData, GpsError> {
+        let point = match self.points.get(self.index) {
+            Some(point) => point,
+            None => return Err(GpsError::EndOfStream),
+        };
+
+        let time = point.time.as_deref().and_then(gps_time_to_duration);
+
+        if let Some(time) = time {
+            if !self.clock.wait_until(time, close) {
+                return Err(GpsError::EndOfStream);
+            }
+        }
+
+        // Work out the speed from the previous point
+        if self.index > 0 {
+            let prev = &self.points[self.index - 1];
+            let prev_time = prev.time.as_deref().and_then(gps_time_to_duration);
+
+            if let (Some(time), Some(prev_time)) = (time, prev_time) {
+                if time > prev_time {
+                    let distance = lat_lon_distance(prev.lat, prev.lon, point.lat, point.lon);
+                    self.last_speed = (distance / (time - prev_time).as_secs_f64()) as f32;
+                }
+            }
+        }
+
+        self.index += 1;
+
+        Ok(GpsData {
+            lat: point.lat,
+            lon: point.lon,
+            alt: point.ele.unwrap_or(0.0),
+            status: point.status.unwrap_or(1),
+            time: point
+                .time
+                .clone()
+                .unwrap_or_else(|| "1970-01-01T00:00:00.000Z".to_string()),
+            speed: point.speed.unwrap_or(self.last_speed),
+            track: point.head.unwrap_or(0.0),
+            hdop: point.hdop,
+            sats: point.sats,
+            error: point.error,
+        })
+    }
+}
+
+type ImuSender = std::sync::mpsc::Sender<(f64, f64, Option<f64>, Option<f64>)>;
+type ChannelsSender = std::sync::mpsc::Sender<Vec<OBDIICommandType>>;
+
+/// Start replaying the OBDII, IMU and temperature data recorded
+/// alongside `file`. The session log is used if there is one, otherwise
+/// the CSV files written by older versions are replayed. Those don't
+/// include the temperatures. They share the replay clock of the GPS.
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_replay_threads(
+    thread_info: &ThreadingRef,
+    file: &Path,
+    speed: f64,
+    obdii_tx: std::sync::mpsc::Sender<OBDIIData>,
+    channels_tx: ChannelsSender,
+    imu_tx: ImuSender,
+    imu_page_tx: ImuSender,
+    temp_tx: Option<std::sync::mpsc::Sender<Vec<f64>>>,
+) {
+    let session_file = side_file(file, "-session.dslog");
+    let clock = thread_info.replay_clock(file, speed);
+
+    if session_file.exists() {
+        let thread_info = thread_info.clone();
+        thread::spawn(move || {
+            session_replay_thread(
+                thread_info,
+                obdii_tx,
+                channels_tx,
+                imu_tx,
+                imu_page_tx,
+                temp_tx,
+                &session_file,
+                &clock,
+            );
+        });
+    } else {
+        let replay_file = file.to_path_buf();
+        let thread_info_clone = thread_info.clone();
+        let clock_clone = clock.clone();
+        thread::spawn(move || {
+            obdii_replay_thread(
+                thread_info_clone,
+                obdii_tx,
+                channels_tx,
+                &replay_file,
+                &clock_clone,
+            );
+        });
+
+        let replay_file = file.to_path_buf();
+        let thread_info_clone = thread_info.clone();
+        thread::spawn(move || {
+            imu_replay_thread(thread_info_clone, imu_tx, imu_page_tx, &replay_file, &clock);
+        });
+    }
+}
+
+/// Send the acceleration to the telemetry and the IMU channels.
+/// Returns false if the channels have been closed.
+fn replay_accel(
+    thread_info: &ThreadingRef,
+    imu_tx: &ImuSender,
+    imu_page_tx: &ImuSender,
+    accel: (f64, f64),
+    max_g_force: &mut f64,
+) -> bool {
+    // Calculate absolute G force in X and Y
+    let g_force = (accel.0.powi(2) + accel.1.powi(2)).sqrt() / 9.8;
+    if g_force > *max_g_force {
+        *max_g_force = g_force;
+    }
+
+    thread_info
+        .telemetry
+        .lock()
+        .unwrap()
+        .update_accel(accel.0, accel.1);
+
+    imu_tx.send((accel.0, accel.1, None, None)).is_ok()
+        && imu_page_tx
+            .send((accel.0, accel.1, Some(g_force), Some(*max_g_force)))
+            .is_ok()
+}
+
+/// Replay the OBDII, IMU and temperature records from a session log
+#[allow(clippy::too_many_arguments)]
+pub fn session_replay_thread(
+    thread_info: ThreadingRef,
+    obdii_tx: std::sync::mpsc::Sender<OBDIIData>,
+    channels_tx: ChannelsSender,
+    imu_tx: ImuSender,
+    imu_page_tx: ImuSender,
+    temp_tx: Option<std::sync::mpsc::Sender<Vec<f64>>>,
+    file_name: &Path,
+    clock: &ReplayClock,
+) {
+    let reader = match SessionReader::open(file_name) {
+        Ok(reader) => reader,
+        Err(e) => {
+            println!("Unable to open {:?}: {:?}", file_name, e);
+            return;
+        }
+    };
+
+    let start_time = reader.start_time;
+    let mut max_g_force: f64 = 0.0;
+    let mut custom: Vec<Option<OBDIICommandType>> = Vec::new();
+    let mut can: Vec<Option<OBDIICommandType>> = Vec::new();
+
+    for record in reader {
+        if thread_info.close.lock().unwrap().get() {
+            return;
+        }
+
+        match record.channel {
+            Channel::ObdiiPids => {
+                let commands = record
+                    .data
+                    .iter()
+                    .filter_map(|pid| OBDIICommandType::from_pid(*pid))
+                    .collect();
+                let _ = channels_tx.send(commands);
+            }
+            Channel::Obdii(pid) => {
+                let data = match OBDIICommandType::from_pid(pid)
+                    .and_then(|command| OBDIIData::decode(command, &record.data))
+                {
+                    Some(data) => data,
+                    None => continue,
+                };
+
+                if !clock.wait_until(start_time + record.time, &thread_info.close) {
+                    return;
+                }
+                thread_info.telemetry.lock().unwrap().update_obdii(&data);
+
+                if obdii_tx.send(data).is_err() {
+                    return;
+                }
+            }
+            Channel::CustomNames => {
+                // The definition file might have changed since the
+                // session was recorded, so match the PIDs by name.
+                custom = String::from_utf8_lossy(&record.data)
+                    .split('\n')
+                    .map(OBDIICommandType::from_name)
+                    .collect();
+            }
+            Channel::CanNames => {
+                // The same goes for the DBC file
+                can = String::from_utf8_lossy(&record.data)
+                    .split('\n')
+                    .map(OBDIICommandType::from_name)
+                    .collect();
+            }
+            Channel::Custom(_) | Channel::Can(_) => {
+                let command = match record.channel {
+                    Channel::Custom(index) => custom.get(index as usize),
+                    Channel::Can(index) => can.get(index as usize),
+                    _ => None,
+                };
+                let data = match (command.cloned().flatten(), record.values().first()) {
+                    (Some(command), Some(value)) => OBDIIData::from_float(command, *value),
+                    _ => continue,
+                };
+
+                if !clock.wait_until(start_time + record.time, &thread_info.close) {
+                    return;
+                }
+
+                if obdii_tx.send(data).is_err() {
+                    return;
+                }
+            }
+            Channel::Imu => {
+                let values = record.values();
+                if values.len() < 2 {
+                    continue;
+                }
+
+                if !clock.wait_until(start_time + record.time, &thread_info.close) {
+                    return;
+                }
+
+                if !replay_accel(
+                    &thread_info,
+                    &imu_tx,
+                    &imu_page_tx,
+                    (values[0], values[1]),
+                    &mut max_g_force,
+                ) {
+                    return;
+                }
+            }
+            Channel::Temperature => {
+                if let Some(temp_tx) = &temp_tx {
+                    if !clock.wait_until(start_time + record.time, &thread_info.close) {
+                        return;
+                    }
+
+                    if temp_tx.send(record.values()).is_err() {
+                        return;
+                    }
+                }
+            }
+            // The GPS data is replayed from the GPX file
+            _ => {}
+        }
+    }
+}
+
+/// Convert the time written by the OBDII thread
+/// (such as "2020-10-17 14:49:10.123456789 UTC") into a Duration
+fn obdii_time_to_duration(time: &str) -> Option<Duration> {
+    let time = time.trim().replace(" UTC", " +0000");
+    let time = chrono::DateTime::parse_from_str(&time, "%Y-%m-%d %H:%M:%S%.f %z").ok()?;
+
+    Some(Duration::new(
+        u64::try_from(time.timestamp()).ok()?,
+        time.timestamp_subsec_nanos(),
+    ))
+}
+
+pub fn obdii_replay_thread(
+    thread_info: ThreadingRef,
+    obdii_tx: std::sync::mpsc::Sender<OBDIIData>,
+    channels_tx: ChannelsSender,
+    file_name: &Path,
+    clock: &ReplayClock,
+) {
+    let file_name = side_file(file_name, "-obdii.cvs");
+    let reader = match File::open(&file_name) {
+        Ok(fd) => BufReader::new(fd),
+        Err(e) => {
+            println!("Unable to open {:?}: {:?}", file_name, e);
+            return;
+        }
+    };
+
+    let mut lines = reader.lines();
+    let commands: Vec<Option<OBDIICommandType>> = match lines.next() {
+        Some(Ok(header)) => header
+            .split(',')
+            .skip(1)
+            .map(OBDIICommandType::from_name)
+            .collect(),
+        _ => return,
+    };
+    let _ = channels_tx.send(commands.iter().flatten().cloned().collect());
+
+    for line in lines {
+        if thread_info.close.lock().unwrap().get() {
+            return;
+        }
+
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+
+        // The fuel status can include commas, so leave anything after
+        // the last column together.
+        let mut fields = line.splitn(commands.len() + 1, ',');
+
+        let time = match fields.next().and_then(obdii_time_to_duration) {
+            Some(time) => time,
+            None => continue,
+        };
+        if !clock.wait_until(time, &thread_info.close) {
+            return;
+        }
+
+        for (command, value) in commands.iter().zip(fields) {
+            if let Some(data) = command.and_then(|c| OBDIIData::from_value(c, value)) {
+                thread_info.telemetry.lock().unwrap().update_obdii(&data);
+
+                if obdii_tx.send(data).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+pub fn imu_replay_thread(
+    thread_info: ThreadingRef,
+    imu_tx: ImuSender,
+    imu_page_tx: ImuSender,
+    file_name: &Path,
+    clock: &ReplayClock,
+) {
+    let file_name = side_file(file_name, "-imu.cvs");
+    let reader = match File::open(&file_name) {
+        Ok(fd) => BufReader::new(fd),
+        Err(e) => {
+            println!("Unable to open {:?}: {:?}", file_name, e);
+            return;
+        }
+    };
+
+    let mut max_g_force: f64 = 0.0;
+
+    // Skip the CVS headers
+    for (i, line) in reader.lines().skip(1).enumerate() {
+        if thread_info.close.lock().unwrap().get() {
+            return;
+        }
+
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+
+        let accel: Vec<f64> = line
+            .split(',')
+            .take(2)
+            .filter_map(|v| v.trim().parse().ok())
+            .collect();
+        if accel.len() != 2 {
+            continue;
+        }
+
+        // The IMU log started along with the rest of the recording
+        let offset = Duration::from_secs_f64(i as f64 / IMU_REPLAY_FREQ);
+        if !clock.wait_until(clock.recorded + offset, &thread_info.close) {
+            return;
+        }
+
+        if !replay_accel(
+            &thread_info,
+            &imu_tx,
+            &imu_page_tx,
+            (accel[0], accel[1]),
+            &mut max_g_force,
+        ) {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_side_file() {
+        assert_eq!(
+            side_file(Path::new("tests/test-track-cowpalace"), "-imu.cvs"),
+            PathBuf::from("tests/test-track-cowpalace-imu.cvs")
+        );
+    }
+
+    #[test]
+    fn test_replay_gps() {
+        let file = Path::new("tests/test-track-cowpalace");
+        let mut replay = ReplayGps::open(file, ReplayClock::start(file, 1000.0)).unwrap();
+        let close = Mutex::new(Cell::new(false));
+
+        // The replay starts just before the first point
+        let fix = replay.get_gps_data(&close).unwrap();
+        let distance = lat_lon_distance(fix.lat, fix.lon, 37.7060849, -122.4209836);
+        assert!((distance - RUN_UP).abs() < 0.01);
+        assert_eq!(fix.time, "2020-10-17T14:49:09.000Z");
+
+        // A second (1ms at 1000x) later
+        let fix = replay.get_gps_data(&close).unwrap();
+        assert_eq!(fix.lat, 37.7060849);
+        assert_eq!(fix.lon, -122.4209836);
+        assert_eq!(fix.status, 1);
+        assert_eq!(fix.time, "2020-10-17T14:49:10.000Z");
+        assert_eq!(fix.track, 206.4523);
+        assert_eq!(fix.hdop, None);
+
+        // The second point has the same timestamp
+        replay.get_gps_data(&close).unwrap();
+
+        // The third point is one second (1ms at 1000x) later
+        let fix = replay.get_gps_data(&close).unwrap();
+        assert!(fix.speed > 0.9 && fix.speed < 1.1);
+
+        // Closing stops the replay
+        close.lock().unwrap().set(true);
+        assert_eq!(replay.get_gps_data(&close), Err(GpsError::EndOfStream));
+    }
+
+    #[test]
+    fn test_replay_clock() {
+        let clock = ReplayClock::start(Path::new("tests/test-track-cowpalace"), 1.0);
+        // The IMU log starts with the first point,
+        // the GPS replay a second before that
+        assert_eq!(clock.recorded, Duration::from_secs(1602946150));
+        assert_eq!(clock.epoch, Duration::from_secs(1602946149));
+
+        // Anything from before the start is due straight away
+        let close = Mutex::new(Cell::new(false));
+        assert!(clock.wait_until(Duration::from_secs(1), &close));
+        assert!(clock.start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_obdii_time() {
+        assert_eq!(
+            obdii_time_to_duration("2020-10-17 14:49:10.500 UTC"),
+            Some(Duration::new(1602946150, 500_000_000))
+        );
+        assert_eq!(obdii_time_to_duration("1969-12-31 23:59:59 UTC"), None);
+    }
+}
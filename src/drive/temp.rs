@@ -15,8 +15,8 @@
  */
 
 use crate::drive::threading::ThreadingRef;
+use crate::session_log::Channel;
 use industrial_io as iio;
-use std::path::PathBuf;
 use std::process;
 
 struct TempContext {
@@ -87,11 +87,7 @@ impl TempContext {
     }
 }
 
-pub fn temp_thread(
-    thread_info: ThreadingRef,
-    temp_tx: std::sync::mpsc::Sender<Vec<f64>>,
-    _file_name: &mut PathBuf,
-) {
+pub fn temp_thread(thread_info: ThreadingRef, temp_tx: std::sync::mpsc::Sender<Vec<f64>>) {
     // Create the IIO context
     let ctx;
     match iio::Context::new() {
@@ -109,6 +105,8 @@ pub fn temp_thread(
     while !thread_info.close.lock().unwrap().get() && temp_context.get_num_sensors() > 0 {
         let temp = temp_context.get_temperature_celsius();
 
+        thread_info.log_values(Channel::Temperature, &temp);
+
         temp_tx.send(temp).unwrap();
     }
 }
@@ -14,13 +14,19 @@
  * limitations under the License.
  */
 
-use crate::drive::course::MapWrapper;
+use crate::drive::course::{MapWrapper, SectorTime};
+use crate::drive::dtc::DtcReport;
+use crate::drive::fix_quality::FixQuality;
 use crate::drive::obdii;
 use crate::drive::obdii::OBDIICommandType;
+use crate::drive::replay::{ReplayClock, ReplayClockRef};
+use crate::session_log::{Channel, SessionLog};
+use crate::telemetry::Telemetry;
+use crate::utils::{format_time, gps_time_to_duration, GpsData};
 use gtk::prelude::*;
 use std::cell::Cell;
 use std::cell::RefCell;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::sync::mpsc;
 use std::sync::Arc;
@@ -33,8 +39,14 @@ pub struct Threading {
     pub(crate) start_on_track: Mutex<Cell<bool>>,
     pub(crate) on_track: Mutex<Cell<bool>>,
     pub(crate) serialise: Mutex<Cell<bool>>,
+    pub(crate) deserialise: Mutex<Cell<bool>>,
     pub(crate) calibrate: Mutex<Cell<bool>>,
+    pub(crate) read_dtcs: Mutex<Cell<bool>>,
+    pub(crate) clear_dtcs: Mutex<Cell<bool>>,
     pub(crate) time_file: RwLock<std::path::PathBuf>,
+    pub(crate) telemetry: Mutex<Telemetry>,
+    pub(crate) session_log: Mutex<Option<SessionLog>>,
+    replay_clock: Mutex<Option<ReplayClockRef>>,
 }
 
 pub type ThreadingRef = Arc<Threading>;
@@ -46,15 +58,81 @@ impl Threading {
             start_on_track: Mutex::new(Cell::new(false)),
             on_track: Mutex::new(Cell::new(false)),
             serialise: Mutex::new(Cell::new(false)),
+            deserialise: Mutex::new(Cell::new(false)),
             calibrate: Mutex::new(Cell::new(false)),
+            read_dtcs: Mutex::new(Cell::new(false)),
+            clear_dtcs: Mutex::new(Cell::new(false)),
             time_file: RwLock::new(PathBuf::new()),
+            telemetry: Mutex::new(Telemetry::default()),
+            session_log: Mutex::new(None),
+            replay_clock: Mutex::new(None),
         })
     }
 
+    /// The clock used to replay `file`, started by whichever
+    /// replay thread asks for it first
+    pub fn replay_clock(&self, file: &Path, speed: f64) -> ReplayClockRef {
+        self.replay_clock
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| ReplayClock::start(file, speed))
+            .clone()
+    }
+
+    /// Start logging the data from all of the threads to `file`
+    pub fn start_session_log(&self, file: &Path) {
+        match SessionLog::create(file) {
+            Ok(log) => {
+                self.session_log.lock().unwrap().replace(log);
+            }
+            Err(e) => {
+                println!("Unable to create the session log {:?}: {}", file, e);
+            }
+        }
+    }
+
+    /// Add a record to the session log, if there is one
+    pub fn log(&self, channel: Channel, data: &[u8]) {
+        if let Some(log) = self.session_log.lock().unwrap().as_mut() {
+            if let Err(e) = log.write(channel, data) {
+                println!("Unable to write to the session log: {}", e);
+            }
+        }
+    }
+
+    pub fn log_values(&self, channel: Channel, values: &[f64]) {
+        if let Some(log) = self.session_log.lock().unwrap().as_mut() {
+            if let Err(e) = log.write_values(channel, values) {
+                println!("Unable to write to the session log: {}", e);
+            }
+        }
+    }
+
+    /// Log `fix`, anything the GPS didn't report is logged as NaN
+    pub fn log_fix(&self, fix: &GpsData) {
+        let time = gps_time_to_duration(&fix.time).map(|time| time.as_secs_f64());
+
+        self.log_values(
+            Channel::Gps,
+            &[
+                fix.lat,
+                fix.lon,
+                fix.alt as f64,
+                fix.speed as f64,
+                fix.track as f64,
+                fix.status as f64,
+                time.unwrap_or(f64::NAN),
+                fix.hdop.map_or(f64::NAN, |hdop| hdop as f64),
+                fix.sats.map_or(f64::NAN, |sats| sats as f64),
+                fix.error.map_or(f64::NAN, |error| error as f64),
+            ],
+        );
+    }
+
     pub fn time_update_idle_thread(
         &self,
-        elapsed_rx: &std::sync::mpsc::Receiver<Duration>,
-        times_rx: &std::sync::mpsc::Receiver<(Duration, Duration, Duration)>,
+        elapsed_rx: &std::sync::mpsc::Receiver<(Duration, Option<Duration>)>,
+        times_rx: &std::sync::mpsc::Receiver<(usize, Duration, Duration, Duration, bool)>,
         time_diff_rx: &std::sync::mpsc::Receiver<(bool, Duration)>,
         builder: gtk::Builder,
     ) -> glib::source::Continue {
@@ -62,7 +140,7 @@ impl Threading {
 
         let rec = elapsed_rx.recv_timeout(timeout);
         match rec {
-            Ok(elapsed) => {
+            Ok((elapsed, predicted)) => {
                 let current_time = builder
                     .get_object::<gtk::Label>("CurrentTime")
                     .expect("Can't find CurrentTime in ui file.");
@@ -73,6 +151,14 @@ impl Threading {
                     elapsed.subsec_millis()
                 );
                 current_time.set_label(&time);
+
+                let predicted_time = builder
+                    .get_object::<gtk::Label>("PredictedTime")
+                    .expect("Can't find PredictedTime in ui file.");
+                match predicted {
+                    Some(predicted) => predicted_time.set_label(&format_time(predicted)),
+                    None => predicted_time.set_label("--:--:---"),
+                }
             }
             Err(mpsc::RecvTimeoutError::Timeout) => {}
             _ => {
@@ -127,7 +213,12 @@ impl Threading {
 
         let rec = times_rx.recv_timeout(timeout);
         match rec {
-            Ok((last, best, worst)) => {
+            Ok((lap, last, best, worst, suspect)) => {
+                let lap_count = builder
+                    .get_object::<gtk::Label>("LapCount")
+                    .expect("Can't find LapCount in ui file.");
+                lap_count.set_label(&format!("Lap {}", lap + 1));
+
                 let last_time = builder
                     .get_object::<gtk::Label>("LastTime")
                     .expect("Can't find LastTime in ui file.");
@@ -137,7 +228,14 @@ impl Threading {
                     last.as_secs() % 60,
                     last.subsec_millis()
                 );
-                last_time.set_label(&time);
+                // Show the driver that the last lap might be wrong
+                if suspect {
+                    last_time.set_markup(&format!("<span foreground=\"#FF8C00\">{}?</span>", time));
+                    last_time.set_tooltip_text(Some("Timed with a poor GPS fix"));
+                } else {
+                    last_time.set_label(&time);
+                    last_time.set_tooltip_text(None);
+                }
 
                 let best_time = builder
                     .get_object::<gtk::Label>("BestTime")
@@ -185,6 +283,54 @@ impl Threading {
         }
     }
 
+    /// Update the sector page, `sector_labels` holds the
+    /// time, best and difference labels for each sector.
+    pub fn sector_update_idle_thread(
+        &self,
+        sector_rx: &std::sync::mpsc::Receiver<SectorTime>,
+        sector_labels: &[(gtk::Label, gtk::Label, gtk::Label)],
+        builder: gtk::Builder,
+    ) -> glib::source::Continue {
+        let timeout = Duration::new(0, 100);
+        let rec = sector_rx.recv_timeout(timeout);
+        match rec {
+            Ok(sector) => {
+                if let Some((time_label, best_label, diff_label)) = sector_labels.get(sector.sector)
+                {
+                    time_label.set_label(&format_time(sector.time));
+                    best_label.set_label(&format_time(sector.best));
+
+                    match sector.diff {
+                        Some((true, diff)) => {
+                            diff_label.set_markup(&format!(
+                                "<span foreground=\"#00994C\">-{}</span>",
+                                format_time(diff)
+                            ));
+                        }
+                        Some((false, diff)) => {
+                            diff_label.set_markup(&format!(
+                                "<span foreground=\"#CC0000\">+{}</span>",
+                                format_time(diff)
+                            ));
+                        }
+                        None => diff_label.set_label(""),
+                    }
+                }
+
+                if let Some(theoretical_best) = sector.theoretical_best {
+                    let theoretical_best_time = builder
+                        .get_object::<gtk::Label>("TheoreticalBestTime")
+                        .expect("Can't find TheoreticalBestTime in ui file.");
+                    theoretical_best_time.set_label(&format_time(theoretical_best));
+                }
+
+                glib::source::Continue(true)
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => glib::source::Continue(true),
+            _ => glib::source::Continue(false),
+        }
+    }
+
     pub fn obdii_update_idle_thread(
         &self,
         obdii_rx: &std::sync::mpsc::Receiver<obdii::OBDIIData>,
@@ -305,6 +451,15 @@ impl Threading {
                         text = format!("{:3}", data.val_float.unwrap());
                         label.set_text(&text);
                     } else if data.command == OBDIICommandType::FuelStatus {
+                    } else if let Some(values) =
+                        obdii_data.borrow_mut().chart_values_mut(data.command)
+                    {
+                        // Custom PIDs and CAN signals, the charts are
+                        // redrawn by a timer
+                        values.push_front(data.val_float.unwrap());
+                        if values.len() > obdii::VECTOR_LEN {
+                            values.pop_back();
+                        }
                     }
                 }
                 Err(mpsc::RecvTimeoutError::Timeout) => return glib::source::Continue(true),
@@ -314,6 +469,125 @@ impl Threading {
         glib::source::Continue(true)
     }
 
+    /// Only show the OBDII values the car can provide
+    pub fn obdii_channels_idle_thread(
+        &self,
+        channels_rx: &std::sync::mpsc::Receiver<Vec<OBDIICommandType>>,
+        builder: gtk::Builder,
+    ) -> glib::source::Continue {
+        let timeout = Duration::new(0, 100);
+        let rec = channels_rx.recv_timeout(timeout);
+        match rec {
+            Ok(commands) => {
+                for command in OBDIICommandType::all() {
+                    for id in obdii_widgets(command).iter() {
+                        let widget = builder
+                            .get_object::<gtk::Widget>(id)
+                            .unwrap_or_else(|| panic!("Can't find {} in ui file.", id));
+                        widget.set_visible(commands.contains(&command));
+                    }
+                }
+                glib::source::Continue(true)
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => glib::source::Continue(true),
+            _ => glib::source::Continue(false),
+        }
+    }
+
+    /// Show how often each OBDII value is being read
+    pub fn obdii_rates_idle_thread(
+        &self,
+        rates_rx: &std::sync::mpsc::Receiver<Vec<(OBDIICommandType, f64)>>,
+        builder: gtk::Builder,
+    ) -> glib::source::Continue {
+        let timeout = Duration::new(0, 100);
+        let rec = rates_rx.recv_timeout(timeout);
+        match rec {
+            Ok(rates) => {
+                let label = builder
+                    .get_object::<gtk::Label>("OBDIIRates")
+                    .expect("Can't find OBDIIRates in ui file.");
+
+                let text = rates
+                    .iter()
+                    .map(|(command, rate)| format!("{}: {:.1} Hz", command.name(), rate))
+                    .collect::<Vec<String>>()
+                    .join("   ");
+                label.set_text(&text);
+
+                glib::source::Continue(true)
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => glib::source::Continue(true),
+            _ => glib::source::Continue(false),
+        }
+    }
+
+    pub fn dtc_update_idle_thread(
+        &self,
+        dtc_rx: &std::sync::mpsc::Receiver<Result<DtcReport, String>>,
+        builder: gtk::Builder,
+    ) -> glib::source::Continue {
+        let timeout = Duration::new(0, 100);
+        let rec = dtc_rx.recv_timeout(timeout);
+        match rec {
+            Ok(Ok(report)) => {
+                let values = [
+                    ("DtcStoredValue", &report.stored),
+                    ("DtcPendingValue", &report.pending),
+                    ("DtcPermanentValue", &report.permanent),
+                ];
+                for (id, codes) in values.iter() {
+                    let label = builder
+                        .get_object::<gtk::Label>(id)
+                        .unwrap_or_else(|| panic!("Can't find {} in ui file.", id));
+
+                    if codes.is_empty() {
+                        label.set_text("None");
+                    } else {
+                        let text = codes
+                            .iter()
+                            .map(|c| c.to_string())
+                            .collect::<Vec<String>>()
+                            .join(" ");
+                        label.set_text(&text);
+                    }
+                }
+
+                let label = builder
+                    .get_object::<gtk::Label>("DtcFreezeFrameValue")
+                    .expect("Can't find DtcFreezeFrameValue in ui file.");
+                match report.freeze_frame {
+                    Some(freeze_frame) => {
+                        let mut text = freeze_frame.dtc.to_string();
+                        for value in freeze_frame.values.iter() {
+                            text.push_str(&format!("\n{}: {}", value.command.name(), value));
+                        }
+                        label.set_text(&text);
+                    }
+                    None => label.set_text("None"),
+                }
+
+                let status = builder
+                    .get_object::<gtk::Label>("DtcStatus")
+                    .expect("Can't find DtcStatus in ui file.");
+                let now = chrono::Local::now();
+                status.set_text(&format!("Read at {}", now.format("%H:%M:%S")));
+
+                glib::source::Continue(true)
+            }
+            Ok(Err(e)) => {
+                let status = builder
+                    .get_object::<gtk::Label>("DtcStatus")
+                    .expect("Can't find DtcStatus in ui file.");
+                status.set_text(&e);
+
+                glib::source::Continue(true)
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => glib::source::Continue(true),
+            _ => glib::source::Continue(false),
+        }
+    }
+
     pub fn map_update_idle_thread(
         &self,
         location_rx: &std::sync::mpsc::Receiver<(f64, f64, i32, Option<bool>)>,
@@ -360,6 +634,34 @@ impl Threading {
         }
     }
 
+    /// Show the quality of the GPS fix
+    pub fn fix_update_idle_thread(
+        &self,
+        fix_rx: &std::sync::mpsc::Receiver<(FixQuality, String)>,
+        builder: gtk::Builder,
+    ) -> glib::source::Continue {
+        let timeout = Duration::new(0, 100);
+        let rec = fix_rx.recv_timeout(timeout);
+        match rec {
+            Ok((quality, text)) => {
+                let label = builder
+                    .get_object::<gtk::Label>("GpsQuality")
+                    .expect("Can't find GpsQuality in ui file.");
+
+                let colour = match quality {
+                    FixQuality::NoFix => "#CC0000",
+                    FixQuality::Degraded => "#FF8C00",
+                    FixQuality::Good => "#00994C",
+                };
+                label.set_markup(&format!("<span foreground=\"{}\">{}</span>", colour, text));
+
+                glib::source::Continue(true)
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => glib::source::Continue(true),
+            _ => glib::source::Continue(false),
+        }
+    }
+
     pub fn imu_draw_idle_thread(
         &self,
         imu_rx: &std::sync::mpsc::Receiver<(f64, f64, Option<f64>, Option<f64>)>,
@@ -489,3 +791,22 @@ impl Threading {
         }
     }
 }
+
+/// The widgets that show the value of `command`
+fn obdii_widgets(command: OBDIICommandType) -> &'static [&'static str] {
+    match command {
+        OBDIICommandType::Rpm => &["OBDIIChartOne"],
+        OBDIICommandType::Throttle => &["ThrottleBar", "OBDIIChartThree"],
+        OBDIICommandType::EngineLoad => &["LoadBar", "OBDIIChartFour"],
+        OBDIICommandType::TimingAdv => &["TimingAdvLabel", "TimingAdvValue"],
+        OBDIICommandType::Maf => &["MAFLabel", "MAFValue", "OBDIIChartTwo"],
+        OBDIICommandType::CoolantTemp => &["CoolantTempLabel", "CoolantTempValue"],
+        OBDIICommandType::IntakeTemp => &["IntakeTempLabel", "IntakeTempValue"],
+        OBDIICommandType::ShortFuelT1 => &["ShortFuelB1Label", "ShortFuelB1Value"],
+        OBDIICommandType::LongFuelT1 => &["LongFuelB1Label", "LongFuelB1Value"],
+        OBDIICommandType::Speed
+        | OBDIICommandType::FuelStatus
+        | OBDIICommandType::Custom(_)
+        | OBDIICommandType::Can(_) => &[],
+    }
+}
@@ -0,0 +1,247 @@
+/*
+ * Copyright 2020 Alistair Francis <alistair@alistair23.me>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *    http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Detects when the start, segment and finish lines are crossed
+//! and times the laps and sectors. This is used by the drive page
+//! and when analysing a recording.
+
+use crate::drive::course::{Course, SectorTime};
+use crate::drive::fix_quality::FixQuality;
+use crate::drive::gate::{crossing_time, Gate};
+use crate::drive::read_track::Coord;
+use std::time::Duration;
+
+/// Something that happened at a fix
+pub enum TimingEvent {
+    /// A lap was started
+    Started,
+    /// A sector was finished
+    Sector(SectorTime),
+    /// A lap was finished, with the number of laps so far, the
+    /// lap time and if the lap is suspect. A lap is suspect if the
+    /// GPS fix was degraded or lost at any point during it.
+    Lap(usize, Duration, bool),
+    /// We finished and aren't starting another lap
+    Finished,
+}
+
+pub struct LapTimer {
+    start_gate: Gate,
+    finish_gate: Gate,
+    segment_starts: Vec<Gate>,
+    circuit: bool,
+
+    /// The location and time of the previous fix
+    prev_fix: Option<((f64, f64), Duration)>,
+    lap_start: Duration,
+    /// The sector we are currently in and when we started it
+    sector: usize,
+    sector_start: Duration,
+    elapsed_time: Duration,
+    on_track: bool,
+    /// We have left the start gate since the lap started. Until then
+    /// GPS noise around the start line can't finish the lap.
+    left_start: bool,
+    /// The current lap was timed with a poor fix
+    suspect: bool,
+
+    /// The locations and times of the current lap, split by segment
+    segment_times: Vec<(Coord, Duration)>,
+    lap_times: Vec<Vec<(Coord, Duration)>>,
+}
+
+impl LapTimer {
+    pub fn new(course: &Course) -> LapTimer {
+        let start = &course.segments.first().unwrap().start;
+        // On a circuit the start line is also the finish line
+        let finish = match course.circuit {
+            true => start,
+            false => &course.segments.last().unwrap().finish,
+        };
+
+        LapTimer {
+            start_gate: Gate::new(start),
+            finish_gate: Gate::new(finish),
+            segment_starts: course
+                .segments
+                .iter()
+                .map(|segment| Gate::new(&segment.start))
+                .collect(),
+            circuit: course.circuit,
+            prev_fix: None,
+            lap_start: Duration::from_secs(0),
+            sector: 0,
+            sector_start: Duration::from_secs(0),
+            elapsed_time: Duration::from_secs(0),
+            on_track: false,
+            left_start: false,
+            suspect: false,
+            segment_times: Vec::new(),
+            lap_times: Vec::new(),
+        }
+    }
+
+    pub fn on_track(&self) -> bool {
+        self.on_track
+    }
+
+    /// The time since the start of the current (or last) lap
+    pub fn elapsed_time(&self) -> Duration {
+        self.elapsed_time
+    }
+
+    fn start_lap(&mut self, time: Duration, quality: FixQuality) {
+        self.lap_start = time;
+        self.left_start = false;
+        self.suspect = quality != FixQuality::Good;
+        self.sector = 0;
+        self.sector_start = time;
+        self.lap_times.clear();
+        self.segment_times.clear();
+    }
+
+    /// Add a fix at `time`, heading `track`. The lap and sector times are
+    /// added to `course` and the events are returned in the order they
+    /// happened. Fixes without a position are ignored, so the next
+    /// crossing is found between the fixes either side of them.
+    pub fn update(
+        &mut self,
+        course: &mut Course,
+        lat: f64,
+        lon: f64,
+        track: f32,
+        time: Duration,
+        quality: FixQuality,
+    ) -> Vec<TimingEvent> {
+        let mut events = Vec::new();
+
+        if quality != FixQuality::Good {
+            self.suspect = true;
+        }
+        if quality == FixQuality::NoFix {
+            return events;
+        }
+
+        let mut started = false;
+        let mut crossed_segments = 0;
+
+        if let Some((prev, prev_time)) = self.prev_fix {
+            // Check to see if we crossed the start line
+            if !self.on_track && self.start_gate.right_direction(track) {
+                if let Some(fraction) = self.start_gate.crossing(prev, (lat, lon)) {
+                    self.start_lap(crossing_time(prev_time, time, fraction), quality);
+                    self.on_track = true;
+                    started = true;
+                    events.push(TimingEvent::Started);
+                }
+            }
+
+            // Check to see if we crossed the finish line
+            if self.on_track
+                && !started
+                && self.left_start
+                && self.finish_gate.right_direction(track)
+            {
+                let finish_time = self
+                    .finish_gate
+                    .crossing(prev, (lat, lon))
+                    .map(|fraction| crossing_time(prev_time, time, fraction));
+
+                if let Some(finish_time) = finish_time {
+                    self.elapsed_time = finish_time.checked_sub(self.lap_start).unwrap_or_default();
+
+                    // Only record the last sector if we didn't miss any splits
+                    if self.sector + 1 == course.segments.len() {
+                        let sector_time = finish_time
+                            .checked_sub(self.sector_start)
+                            .unwrap_or_default();
+                        events.push(TimingEvent::Sector(
+                            course.add_sector(self.sector, sector_time),
+                        ));
+                    }
+
+                    let lap = course.add_lap(self.elapsed_time);
+                    // If we just set the best time, update the
+                    // best_times vector
+                    if course.best == self.elapsed_time {
+                        // Include the last segment and the finish
+                        let mut segment_times = std::mem::take(&mut self.segment_times);
+                        segment_times.push((Coord::new(lat, lon, None), self.elapsed_time));
+                        self.lap_times.push(segment_times);
+
+                        course.best_times.clear();
+                        course.best_times.append(&mut self.lap_times);
+                    }
+                    events.push(TimingEvent::Lap(lap, self.elapsed_time, self.suspect));
+
+                    if self.circuit {
+                        // On a circuit the next lap starts straight away
+                        self.start_lap(finish_time, quality);
+                        events.push(TimingEvent::Started);
+                    } else {
+                        self.on_track = false;
+                        events.push(TimingEvent::Finished);
+                    }
+                }
+            }
+
+            if self.on_track {
+                for (i, segment) in self.segment_starts.iter().enumerate() {
+                    let fraction = match segment.crossing(prev, (lat, lon)) {
+                        Some(fraction) => fraction,
+                        None => continue,
+                    };
+                    crossed_segments += 1;
+
+                    // Starting the next segment finishes the current sector
+                    if i == self.sector + 1 {
+                        let split_time = crossing_time(prev_time, time, fraction);
+                        let sector_time = split_time
+                            .checked_sub(self.sector_start)
+                            .unwrap_or_default();
+                        events.push(TimingEvent::Sector(
+                            course.add_sector(self.sector, sector_time),
+                        ));
+
+                        self.sector = i;
+                        self.sector_start = split_time;
+                    }
+                }
+            }
+        }
+        self.prev_fix = Some(((lat, lon), time));
+
+        if self.on_track && !self.left_start {
+            self.left_start =
+                self.start_gate.distance(lat, lon) > self.start_gate.geometry().depth as f64;
+        }
+
+        if self.on_track {
+            self.elapsed_time = time.checked_sub(self.lap_start).unwrap_or_default();
+
+            // Save the current location and time
+            self.segment_times
+                .push((Coord::new(lat, lon, None), self.elapsed_time));
+
+            // Split a new segment for every segment start we crossed
+            for _ in 0..crossed_segments {
+                self.lap_times.push(std::mem::take(&mut self.segment_times));
+            }
+        }
+
+        events
+    }
+}
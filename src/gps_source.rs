@@ -0,0 +1,135 @@
+/*
+ * Copyright 2020 Alistair Francis <alistair@alistair23.me>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *    http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::drive::replay::{ReplayClock, ReplayClockRef, ReplayGps};
+use crate::nmea::{get_nmea_data, NmeaParser};
+use crate::utils::{get_gps_lat_lon, GpsData, GpsError, GpsdState};
+use gpsd_proto::handshake;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Where we get the GPS data from
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum GpsSource {
+    /// A gpsd instance, possibly on another machine. If `pps` is set
+    /// the PPS pulses are used to time the fixes more precisely.
+    Gpsd {
+        host: String,
+        port: u16,
+        #[serde(default)]
+        pps: bool,
+    },
+    /// A GPS receiver sending NMEA sentences directly.
+    /// `device` can be a serial port or a file of recorded NMEA data.
+    Nmea { device: String, baud: u32 },
+    /// A previously recorded session, replayed `speed` times faster
+    /// than real time. The session log (or the `-obdii.cvs` and
+    /// `-imu.cvs` files) recorded with it is replayed as well.
+    Replay { file: PathBuf, speed: f64 },
+}
+
+impl Default for GpsSource {
+    fn default() -> Self {
+        GpsSource::Gpsd {
+            host: "127.0.0.1".to_string(),
+            port: 2947,
+            pps: false,
+        }
+    }
+}
+
+pub enum GpsReader {
+    Gpsd(io::BufReader<TcpStream>, GpsdState),
+    Nmea(Box<dyn io::BufRead + Send>, NmeaParser),
+    Replay(ReplayGps),
+}
+
+impl GpsReader {
+    /// Connect to the GPS source. A replay uses `replay_clock`, if
+    /// there is one, so it stays in sync with the other replays.
+    pub fn connect(
+        source: &GpsSource,
+        replay_clock: Option<ReplayClockRef>,
+    ) -> io::Result<GpsReader> {
+        match source {
+            GpsSource::Gpsd { host, port, pps } => {
+                let stream = TcpStream::connect((host.as_str(), *port))?;
+
+                let mut reader = io::BufReader::new(stream.try_clone()?);
+                let mut writer = io::BufWriter::new(stream);
+
+                handshake(&mut reader, &mut writer).map_err(|e| {
+                    io::Error::new(io::ErrorKind::Other, format!("gpsd handshake: {:?}", e))
+                })?;
+
+                if *pps {
+                    writer.write_all(b"?WATCH={\"enable\":true,\"json\":true,\"pps\":true};\n")?;
+                    writer.flush()?;
+                }
+
+                Ok(GpsReader::Gpsd(reader, GpsdState::default()))
+            }
+            GpsSource::Nmea { device, baud } => {
+                let reader: Box<dyn io::BufRead + Send> =
+                    if std::fs::metadata(device)?.file_type().is_file() {
+                        Box::new(io::BufReader::new(File::open(device)?))
+                    } else {
+                        let port = serialport::new(device, *baud)
+                            .timeout(Duration::from_secs(2))
+                            .open()
+                            .map_err(io::Error::from)?;
+                        Box::new(io::BufReader::new(port))
+                    };
+
+                Ok(GpsReader::Nmea(reader, NmeaParser::new()))
+            }
+            GpsSource::Replay { file, speed } => {
+                let clock = replay_clock.unwrap_or_else(|| ReplayClock::start(file, *speed));
+                Ok(GpsReader::Replay(ReplayGps::open(file, clock)?))
+            }
+        }
+    }
+
+    /// Gets the next location/velocity data from the GPS.
+    /// A replay stops waiting for the next point if `close` is set.
+    pub fn get_gps_data(&mut self, close: &Mutex<Cell<bool>>) -> Result<GpsData, GpsError> {
+        match self {
+            GpsReader::Gpsd(reader, state) => {
+                get_gps_lat_lon(reader, state).map_err(|_| GpsError::Read)
+            }
+            GpsReader::Nmea(reader, parser) => get_nmea_data(reader, parser),
+            GpsReader::Replay(replay) => replay.get_gps_data(close),
+        }
+    }
+
+    /// When the last PPS pulse arrived, if there has been one
+    /// since we last asked
+    pub fn take_pps(&mut self) -> Option<Instant> {
+        match self {
+            GpsReader::Gpsd(_, state) => state.pps.take(),
+            _ => None,
+        }
+    }
+}
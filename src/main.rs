@@ -17,17 +17,136 @@
 #![forbid(unsafe_code)]
 #[macro_use]
 mod utils;
+mod analyze;
+mod auto_course;
+mod config;
 mod display;
 mod drive;
+mod gps_source;
+mod nmea;
 mod record;
+mod session_log;
+mod telemetry;
 
 use gio::prelude::*;
 
 use std::env;
+use std::path::Path;
+use std::process;
 
 use crate::display::Display;
 
+/// Convert a session log into CSV files, without starting the UI
+fn convert(log: &str) {
+    match session_log::convert(Path::new(log)) {
+        Ok(files) => {
+            for file in files {
+                println!("Created {}", file.display());
+            }
+        }
+        Err(e) => {
+            println!("Unable to convert {}: {}", log, e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Print the lap times of a recording, without starting the UI
+fn analyze(args: &[String]) {
+    let circuit = args.iter().any(|a| a == "--circuit");
+    let files: Vec<&String> = args.iter().filter(|a| !a.starts_with("--")).collect();
+
+    if files.len() != 2 {
+        println!("Usage: dashsight analyze [--circuit] <course> <recording>");
+        println!("The recording can be a GPX file or a -session.dslog file");
+        process::exit(1);
+    }
+
+    let limits = config::Config::load().fix_limits;
+    match analyze::analyze(Path::new(files[0]), Path::new(files[1]), circuit, &limits) {
+        Ok(analysis) => print!("{}", analysis),
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Create a course from a recording of a few laps, without starting the UI
+fn make_course(args: &[String]) {
+    let usage = || {
+        println!("Usage: dashsight make-course [--sectors <n> | --corners] <recording> <course>");
+        process::exit(1);
+    };
+    let mut sectors = auto_course::Sectors::Even(3);
+    let mut files = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--corners" => sectors = auto_course::Sectors::Corners,
+            "--sectors" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(n) if n > 0 => sectors = auto_course::Sectors::Even(n),
+                _ => usage(),
+            },
+            _ => files.push(arg),
+        }
+    }
+    if files.len() != 2 {
+        usage();
+    }
+
+    match auto_course::create(Path::new(files[0]), Path::new(files[1]), sectors) {
+        Ok(course) => {
+            println!("Found {} laps", course.laps);
+            print!("Using a {:.0}m lap", course.length);
+            match course.lap_time {
+                Some(time) => println!(" ({})", utils::format_time(time)),
+                None => println!(),
+            }
+            println!("Created {} with {} sectors", files[1], course.sectors.len());
+            println!("Load it as a circuit on the Drive page");
+        }
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
 fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    match args.get(1).map(|a| a.as_str()) {
+        Some("convert") => {
+            match args.get(2) {
+                Some(log) => convert(log),
+                None => {
+                    println!("Usage: {} convert <session log>", args[0]);
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+        Some("analyze") => {
+            analyze(&args[2..]);
+            return;
+        }
+        Some("make-course") => {
+            make_course(&args[2..]);
+            return;
+        }
+        _ => {}
+    }
+
+    // Fix any recordings that were cut short last time
+    record::journal::recover();
+
+    // Load the manufacturer specific PIDs and CAN signals before
+    // anything reads from the car
+    drive::custom_pid::load_definitions();
+    drive::can::load_dbc();
+
     let uiapp = gtk::Application::new(Some("org.alistair23.DashSight"), Default::default())
         .expect("Application::new failed");
 
@@ -35,5 +154,5 @@ fn main() {
         Display::on_startup(app);
     });
 
-    uiapp.run(&env::args().collect::<Vec<_>>());
+    uiapp.run(&args);
 }
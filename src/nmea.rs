@@ -0,0 +1,355 @@
+/*
+ * Copyright 2020 Alistair Francis <alistair@alistair23.me>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *    http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::utils::{GpsData, GpsError};
+use std::io;
+
+const KNOTS_TO_MS: f32 = 0.514_444;
+
+/// Parses NMEA 0183 sentences from a GPS receiver.
+/// GPS receivers send a burst of sentences for every fix, so the
+/// date, speed and heading from RMC and VTG sentences (and the
+/// error estimate from GST sentences) are saved until the GGA
+/// sentence with the position arrives.
+#[derive(Default)]
+pub struct NmeaParser {
+    date: Option<String>,
+    speed: Option<f32>,
+    track: Option<f32>,
+    error: Option<f32>,
+}
+
+impl NmeaParser {
+    pub fn new() -> NmeaParser {
+        NmeaParser::default()
+    }
+
+    /// Parse a single NMEA sentence.
+    /// Returns the GPS data if this sentence completes a fix.
+    pub fn parse_sentence(&mut self, sentence: &str) -> Option<GpsData> {
+        let body = checked_body(sentence.trim())?;
+        let fields: Vec<&str> = body.split(',').collect();
+
+        // Ignore the talker ID (GP, GN, GL...), we only care
+        // about the sentence type.
+        let sentence_type = fields[0].get(2..)?;
+
+        match sentence_type {
+            "GGA" => self.parse_gga(&fields),
+            "RMC" => {
+                self.parse_rmc(&fields);
+                None
+            }
+            "VTG" => {
+                self.parse_vtg(&fields);
+                None
+            }
+            "GST" => {
+                self.parse_gst(&fields);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// $GPGGA,time,lat,N,lon,W,quality,sats,hdop,alt,M,geoid,M,age,station
+    fn parse_gga(&mut self, fields: &[&str]) -> Option<GpsData> {
+        if fields.len() < 10 {
+            return None;
+        }
+
+        let quality: i32 = fields[6].parse().ok()?;
+        if quality == 0 {
+            // No fix
+            return None;
+        }
+
+        let lat = parse_coord(fields[2], fields[3])?;
+        let lon = parse_coord(fields[4], fields[5])?;
+        let alt: f32 = fields[9].parse().ok()?;
+
+        // Match the gpsd status, 1 is a fix and 2 is a DGPS (or better) fix
+        let status = match quality {
+            1 => 1,
+            _ => 2,
+        };
+
+        let time = match (&self.date, parse_time(fields[1])) {
+            (Some(date), Some(time)) => format!("{}T{}Z", date, time),
+            _ => "1970-01-01T00:00:00.000Z".to_string(),
+        };
+
+        Some(GpsData {
+            lat,
+            lon,
+            alt,
+            status,
+            time,
+            speed: self.speed.unwrap_or(0.0),
+            track: self.track.unwrap_or(0.0),
+            hdop: fields[8].parse().ok(),
+            sats: fields[7].parse().ok(),
+            error: self.error,
+        })
+    }
+
+    /// $GPRMC,time,status,lat,N,lon,W,speed,track,date,mag,E
+    fn parse_rmc(&mut self, fields: &[&str]) {
+        if fields.len() < 10 {
+            return;
+        }
+
+        self.date = parse_date(fields[9]);
+
+        if fields[2] == "A" {
+            self.speed = fields[7].parse::<f32>().ok().map(|s| s * KNOTS_TO_MS);
+            self.track = fields[8].parse().ok();
+        }
+    }
+
+    /// $GPVTG,track,T,mag,M,knots,N,kmh,K,mode
+    fn parse_vtg(&mut self, fields: &[&str]) {
+        if fields.len() < 9 {
+            return;
+        }
+
+        if let Ok(track) = fields[1].parse() {
+            self.track = Some(track);
+        }
+        if let Ok(kmh) = fields[7].parse::<f32>() {
+            self.speed = Some(kmh / 3.6);
+        }
+    }
+
+    /// $GPGST,time,rms,major,minor,orient,lat err,lon err,alt err
+    fn parse_gst(&mut self, fields: &[&str]) {
+        if fields.len() < 8 {
+            return;
+        }
+
+        // The standard deviation of the latitude and longitude errors
+        self.error = match (fields[6].parse::<f32>(), fields[7].parse::<f32>()) {
+            (Ok(lat), Ok(lon)) => Some(lat.hypot(lon)),
+            _ => None,
+        };
+    }
+}
+
+/// Returns the sentence without the leading '$' and the checksum,
+/// or None if the checksum doesn't match.
+fn checked_body(sentence: &str) -> Option<&str> {
+    let sentence = sentence.strip_prefix('$')?;
+    let star = sentence.rfind('*')?;
+    let (body, checksum) = (&sentence[..star], &sentence[(star + 1)..]);
+
+    let expected = u8::from_str_radix(checksum, 16).ok()?;
+    let calculated = body.bytes().fold(0, |acc, c| acc ^ c);
+
+    if expected == calculated {
+        Some(body)
+    } else {
+        None
+    }
+}
+
+/// Convert a NMEA (d)ddmm.mmmm coordinate into decimal degrees
+fn parse_coord(value: &str, hemisphere: &str) -> Option<f64> {
+    let dot = value.find('.').unwrap_or(value.len());
+    if dot < 2 {
+        return None;
+    }
+
+    let degrees: f64 = value.get(..(dot - 2))?.parse().ok()?;
+    let minutes: f64 = value.get((dot - 2)..)?.parse().ok()?;
+    let coord = degrees + minutes / 60.0;
+
+    match hemisphere {
+        "N" | "E" => Some(coord),
+        "S" | "W" => Some(-coord),
+        _ => None,
+    }
+}
+
+/// Convert hhmmss.sss into hh:mm:ss.sss
+fn parse_time(value: &str) -> Option<String> {
+    if value.len() < 6 {
+        return None;
+    }
+
+    let hours: u32 = value.get(0..2)?.parse().ok()?;
+    let minutes: u32 = value.get(2..4)?.parse().ok()?;
+    let seconds: f64 = value.get(4..)?.parse().ok()?;
+
+    Some(format!("{:02}:{:02}:{:06.3}", hours, minutes, seconds))
+}
+
+/// Convert ddmmyy into YYYY-MM-DD
+fn parse_date(value: &str) -> Option<String> {
+    if value.len() != 6 {
+        return None;
+    }
+
+    let day: u32 = value.get(0..2)?.parse().ok()?;
+    let month: u32 = value.get(2..4)?.parse().ok()?;
+    let year: u32 = value.get(4..6)?.parse().ok()?;
+
+    // NMEA only has a two digit year
+    let year = if year < 80 { 2000 + year } else { 1900 + year };
+
+    Some(format!("{}-{:02}-{:02}", year, month, day))
+}
+
+/// Gets the relevent location/velocity data from a NMEA stream
+/// Returns the same data as `utils::get_gps_lat_lon()`
+/// Lines that aren't valid UTF-8, such as from noise on a serial
+/// line, are skipped.
+pub fn get_nmea_data(
+    reader: &mut dyn io::BufRead,
+    parser: &mut NmeaParser,
+) -> Result<GpsData, GpsError> {
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => return Err(GpsError::EndOfStream),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return Err(GpsError::Read),
+        }
+
+        let sentence = match std::str::from_utf8(&line) {
+            Ok(sentence) => sentence,
+            Err(_) => continue,
+        };
+
+        if let Some(data) = parser.parse_sentence(sentence) {
+            return Ok(data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum() {
+        assert!(checked_body("$GPVTG,054.7,T,034.4,M,005.5,N,010.2,K*48").is_some());
+        assert!(checked_body("$GPVTG,054.7,T,034.4,M,005.5,N,010.2,K*49").is_none());
+        assert!(checked_body("GPVTG,054.7,T,034.4,M,005.5,N,010.2,K*48").is_none());
+    }
+
+    #[test]
+    fn test_parse_fix() {
+        let mut parser = NmeaParser::new();
+
+        assert!(parser
+            .parse_sentence(
+                "$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A\r\n"
+            )
+            .is_none());
+
+        let fix = parser
+            .parse_sentence("$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47\r\n")
+            .unwrap();
+
+        assert!((fix.lat - 48.1173).abs() < 0.000_001);
+        assert!((fix.lon - 11.516_666_666).abs() < 0.000_001);
+        assert_eq!(fix.alt, 545.4);
+        assert_eq!(fix.status, 1);
+        assert_eq!(fix.time, "1994-03-23T12:35:19.000Z");
+        assert!((fix.speed - 11.523_546).abs() < 0.0001);
+        assert_eq!(fix.track, 84.4);
+        assert_eq!(fix.hdop, Some(0.9));
+        assert_eq!(fix.sats, Some(8));
+        assert_eq!(fix.error, None);
+
+        parser.parse_sentence("$GPVTG,054.7,T,034.4,M,005.5,N,010.2,K*48");
+
+        let fix = parser
+            .parse_sentence("$GNGGA,123520,4807.038,S,01131.000,W,2,08,0.9,545.4,M,46.9,M,,*5F\r\n")
+            .unwrap();
+        assert!((fix.speed - 2.833_333).abs() < 0.0001);
+        assert_eq!(fix.track, 54.7);
+    }
+
+    #[test]
+    fn test_parse_non_ascii() {
+        // These would split a character in half
+        assert_eq!(parse_coord("é1.5", "N"), None);
+        assert_eq!(parse_coord("4807.038", "N"), Some(48.1173));
+        assert_eq!(parse_time("1é3519"), None);
+        assert_eq!(parse_time("123é19"), None);
+        assert_eq!(parse_date("2é039"), None);
+        assert_eq!(parse_date("230é4"), None);
+    }
+
+    #[test]
+    fn test_parse_gst() {
+        let mut parser = NmeaParser::new();
+
+        assert!(parser
+            .parse_sentence("$GPGST,172814.0,0.006,0.023,0.020,273.6,0.023,0.020,0.031*6A")
+            .is_none());
+        let fix = parser
+            .parse_sentence("$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47")
+            .unwrap();
+        assert!((fix.error.unwrap() - 0.030_479).abs() < 0.000_01);
+    }
+
+    #[test]
+    fn test_no_fix() {
+        let mut parser = NmeaParser::new();
+
+        assert!(parser
+            .parse_sentence("$GPGGA,123519,,,,,0,00,99.99,,,,,,*45")
+            .is_none());
+    }
+
+    #[test]
+    fn test_read_stream() {
+        let nmea = "$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A\r\n\
+                    $GPGSA,A,3,04,05,,09,12,,,24,,,,,2.5,1.3,2.1*39\r\n\
+                    $GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47\r\n";
+        let mut reader = io::BufReader::new(nmea.as_bytes());
+        let mut parser = NmeaParser::new();
+
+        let fix = get_nmea_data(&mut reader, &mut parser).unwrap();
+        assert!((fix.lat - 48.1173).abs() < 0.000_001);
+
+        // End of the stream
+        assert_eq!(
+            get_nmea_data(&mut reader, &mut parser),
+            Err(GpsError::EndOfStream)
+        );
+    }
+
+    #[test]
+    fn test_read_invalid_utf8() {
+        let mut nmea =
+            b"$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A\r\n".to_vec();
+        nmea.extend_from_slice(b"$GP\xff\xfe garbage\r\n");
+        nmea.extend_from_slice(
+            b"$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47\r\n",
+        );
+        let mut reader = io::BufReader::new(nmea.as_slice());
+        let mut parser = NmeaParser::new();
+
+        let fix = get_nmea_data(&mut reader, &mut parser).unwrap();
+        assert_eq!(fix.time, "1994-03-23T12:35:19.000Z");
+    }
+}
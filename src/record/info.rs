@@ -15,16 +15,17 @@
  */
 
 use crate::display::*;
+use crate::drive::threading::ThreadingRef;
+use crate::gps_source::{GpsReader, GpsSource};
+use crate::record::journal::Journal;
 use crate::record::print;
-use gpsd_proto::handshake;
+use crate::utils::GpsError;
 use gtk::prelude::*;
 use gtk::ResponseType;
 use std::cell::Cell;
 use std::fs::File;
 use std::fs::OpenOptions;
-use std::io;
 use std::io::Error;
-use std::net::TcpStream;
 use std::path::PathBuf;
 use std::sync::mpsc;
 use std::sync::Arc;
@@ -73,7 +74,8 @@ impl RecordInfo {
         })
     }
 
-    pub fn file_picker_clicked(&self, display: DisplayRef) {
+    /// Returns the file that was picked, if any
+    pub fn file_picker_clicked(&self, display: DisplayRef) -> Option<PathBuf> {
         let builder = display.builder.clone();
 
         let window: gtk::ApplicationWindow = builder
@@ -93,9 +95,12 @@ impl RecordInfo {
             if let Some(filepath) = file_chooser.get_filename() {
                 self.new_file.lock().unwrap().set(true);
                 let mut track_file = self.track_file.write().unwrap();
-                *track_file = filepath;
+                *track_file = filepath.clone();
+                return Some(filepath);
             }
         }
+
+        None
     }
 
     pub fn record_button_clicked(&self) {
@@ -141,14 +146,26 @@ impl RecordInfo {
         }
     }
 
-    pub fn run(&self, location_tx: std::sync::mpsc::Sender<(f64, f64, i32)>) {
-        let gpsd_connect;
+    pub fn run(
+        &self,
+        location_tx: std::sync::mpsc::Sender<(f64, f64, i32)>,
+        gps_source: &GpsSource,
+        telemetry: Option<ThreadingRef>,
+    ) {
+        let mut gps_reader;
+
+        // A replay shares its clock with the replays of the other data
+        let replay_clock = match (gps_source, telemetry.as_ref()) {
+            (GpsSource::Replay { file, speed }, Some(telemetry)) => {
+                Some(telemetry.replay_clock(file, *speed))
+            }
+            _ => None,
+        };
 
         loop {
-            let stream = TcpStream::connect("127.0.0.1:2947");
-            match stream {
-                Ok(stream) => {
-                    gpsd_connect = stream;
+            match GpsReader::connect(gps_source, replay_clock.clone()) {
+                Ok(reader) => {
+                    gps_reader = reader;
                     break;
                 }
                 Err(err) => {
@@ -156,23 +173,26 @@ impl RecordInfo {
                         return;
                     }
 
-                    println!("Failed to connect to GPSD: {:?}", err);
+                    println!("Failed to connect to the GPS: {:?}", err);
                     std::thread::sleep(std::time::Duration::from_secs(5));
                     continue;
                 }
             }
         }
 
-        let mut reader = io::BufReader::new(&gpsd_connect);
-        let mut writer = io::BufWriter::new(&gpsd_connect);
-
         let mut track_file: Result<File, std::io::Error> =
             Err(Error::new(std::io::ErrorKind::NotFound, "No file yet"));
-
-        handshake(&mut reader, &mut writer).unwrap();
+        let mut journal: Option<Journal> = None;
+        let mut in_segment = false;
 
         while !self.close.lock().unwrap().get() {
             if self.new_file.lock().unwrap().get() {
+                // Finish the previous file before starting a new one
+                if let Ok(fd) = track_file.as_mut() {
+                    close_track(fd, journal.take(), in_segment);
+                    in_segment = false;
+                }
+
                 track_file = OpenOptions::new()
                     .read(true)
                     .write(true)
@@ -188,6 +208,20 @@ impl RecordInfo {
                             print::gpx_track_start(&mut fd, name.to_string()).unwrap();
                         }
                     }
+
+                    journal = match Journal::create(&self.track_file.read().unwrap()) {
+                        Ok(mut journal) => match journal.sync_now(fd) {
+                            Ok(()) => Some(journal),
+                            Err(e) => {
+                                println!("Unable to sync the track: {:?}", e);
+                                None
+                            }
+                        },
+                        Err(e) => {
+                            println!("Unable to create a journal: {:?}", e);
+                            None
+                        }
+                    };
                 }
                 self.new_file.lock().unwrap().set(false);
             }
@@ -196,43 +230,78 @@ impl RecordInfo {
                 if let Ok(mut fd) = track_file.as_mut() {
                     if self.save.lock().unwrap().get() {
                         print::gpx_track_seg_start(&mut fd).unwrap();
+                        in_segment = true;
                     } else {
                         print::gpx_track_seg_stop(&mut fd).unwrap();
+                        in_segment = false;
                     }
                 }
                 self.toggle_save.lock().unwrap().set(false);
             }
 
-            let msg = crate::utils::get_gps_lat_lon(&mut reader);
+            let msg = gps_reader.get_gps_data(&self.close);
 
             match msg {
-                Ok((lat, lon, alt, status, time, speed, track)) => {
-                    if location_tx.send((lat, lon, status)).is_err() {
+                Ok(fix) => {
+                    if location_tx.send((fix.lat, fix.lon, fix.status)).is_err() {
                         break;
                     }
 
+                    if let Some(telemetry) = telemetry.as_ref() {
+                        telemetry.log_fix(&fix);
+                    }
+
                     if self.save.lock().unwrap().get() && !self.toggle_save.lock().unwrap().get() {
                         if let Ok(mut fd) = track_file.as_mut() {
                             // Only record the point if we are moving
-                            if speed > 0.5 {
-                                print::gpx_point_info(&mut fd, lat, lon, alt, time, track).unwrap();
+                            if fix.speed > 0.5 {
+                                let telemetry = telemetry
+                                    .as_ref()
+                                    .map(|t| t.telemetry.lock().unwrap().snapshot());
+                                print::gpx_point_info(&mut fd, &fix, telemetry).unwrap();
+                            }
+
+                            if let Some(journal) = journal.as_mut() {
+                                if let Err(e) = journal.sync(fd) {
+                                    println!("Unable to sync the track: {:?}", e);
+                                }
                             }
                         }
                     }
                 }
+                Err(GpsError::EndOfStream) => {
+                    println!("The GPS has stopped sending data");
+                    break;
+                }
                 Err(err) => {
-                    println!("Failed to get a message from GPSD: {:?}", err);
+                    println!("Failed to get a message from the GPS: {:?}", err);
                     std::thread::sleep(std::time::Duration::from_millis(10));
                     continue;
                 }
             }
         }
 
-        if let Ok(mut fd) = track_file.as_mut() {
-            print::gpx_track_stop(&mut fd).unwrap();
-            print::gpx_stop(&mut fd).unwrap();
-            fd.sync_all().unwrap();
+        if let Ok(fd) = track_file.as_mut() {
+            close_track(fd, journal, in_segment);
+        }
+    }
+}
+
+/// Close the elements of the GPX file and remove its journal
+fn close_track(fd: &mut File, journal: Option<Journal>, in_segment: bool) {
+    if in_segment {
+        print::gpx_track_seg_stop(fd).unwrap();
+    }
+    print::gpx_track_stop(fd).unwrap();
+    print::gpx_stop(fd).unwrap();
+
+    match journal {
+        Some(journal) => {
+            if let Err(e) = journal.finish(fd) {
+                println!("Unable to remove the journal: {:?}", e);
+            }
         }
+        None => fd.sync_all().unwrap(),
     }
 }
 
@@ -249,6 +318,6 @@ mod tests {
         // Tell run to exit straight away, otherwise we loop for a GPSD conection
         rec_info.close.lock().unwrap().set(true);
 
-        rec_info.run(location_tx);
+        rec_info.run(location_tx, &GpsSource::default(), None);
     }
 }
@@ -0,0 +1,411 @@
+/*
+ * Copyright 2020 Alistair Francis <alistair@alistair23.me>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *    http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Keeps track of the GPX files that are being written, so that
+//! they can be repaired if DashSight doesn't exit cleanly.
+//!
+//! While a GPX file is open a journal file is kept in the
+//! `journal` directory next to the config file. The journal contains
+//! the path of the GPX file and how much of it has been synced to disk.
+//! The GPX file is synced at least every second, so a power cut can
+//! only lose the last second of data. When the GPX file is closed
+//! cleanly the journal is removed. On the next start any journals
+//! that are left over are used to repair their GPX files.
+
+use crate::config::Config;
+use crate::record::print;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How often the GPX file is synced to disk
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The elements that can be open between two track points
+const TRACK_ELEMENTS: [&str; 3] = ["gpx", "trk", "trkseg"];
+
+fn journal_dir() -> Option<PathBuf> {
+    let mut dir = Config::dir()?;
+    dir.push("journal");
+    Some(dir)
+}
+
+pub struct Journal {
+    path: PathBuf,
+    gpx: PathBuf,
+    last_sync: Instant,
+}
+
+impl Journal {
+    /// Start a journal for the GPX file `gpx`
+    pub fn create(gpx: &Path) -> io::Result<Journal> {
+        match journal_dir() {
+            Some(dir) => Journal::create_in(&dir, gpx),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No directory for the journal",
+            )),
+        }
+    }
+
+    fn create_in(dir: &Path, gpx: &Path) -> io::Result<Journal> {
+        fs::create_dir_all(dir)?;
+
+        // Use an absolute path, we might be started from somewhere else
+        let gpx = fs::canonicalize(gpx).unwrap_or_else(|_| gpx.to_path_buf());
+
+        let mut hasher = DefaultHasher::new();
+        gpx.hash(&mut hasher);
+        let path = dir.join(format!("{:016x}.journal", hasher.finish()));
+
+        let journal = Journal {
+            path,
+            gpx,
+            last_sync: Instant::now(),
+        };
+        journal.write(0)?;
+
+        Ok(journal)
+    }
+
+    /// Replace the journal, so that it is never left half written
+    fn write(&self, synced: u64) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut fd = File::create(&tmp)?;
+
+        writeln!(fd, "{}", self.gpx.display())?;
+        writeln!(fd, "{}", synced)?;
+        fd.sync_all()?;
+
+        fs::rename(&tmp, &self.path)
+    }
+
+    /// Sync the GPX file to disk, if it hasn't been synced recently
+    pub fn sync(&mut self, fd: &File) -> io::Result<()> {
+        if self.last_sync.elapsed() >= SYNC_INTERVAL {
+            self.sync_now(fd)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sync the GPX file to disk and record how much has been written
+    pub fn sync_now(&mut self, fd: &File) -> io::Result<()> {
+        fd.sync_data()?;
+        self.last_sync = Instant::now();
+
+        self.write(fd.metadata()?.len())
+    }
+
+    /// The GPX file has been closed cleanly, so the journal isn't needed
+    pub fn finish(self, fd: &File) -> io::Result<()> {
+        fd.sync_all()?;
+
+        fs::remove_file(&self.path)
+    }
+}
+
+/// Returns the name of the element a line starts or ends, and if the
+/// line closes it. Lines that open and close an element, such as
+/// `<ele>30.7</ele>`, return None.
+fn line_element(line: &str) -> Option<(&str, bool)> {
+    let (tag, closing) = match line.strip_prefix("</") {
+        Some(tag) => (tag, true),
+        None => (line.strip_prefix('<')?, false),
+    };
+
+    if tag.starts_with('?') || (!closing && (line.contains("</") || line.ends_with("/>"))) {
+        return None;
+    }
+
+    let end = tag
+        .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+        .unwrap_or(tag.len());
+
+    Some((&tag[..end], closing))
+}
+
+/// Find how much of a DashSight GPX file can be kept. Returns the
+/// length of the data that ends after a complete track point (or
+/// other element) and the elements that are still open at that point.
+///
+/// This relies on DashSight writing each element on its own line.
+fn complete_length(data: &[u8]) -> (usize, Vec<String>) {
+    let mut open: Vec<String> = Vec::new();
+    let mut in_gpx_tag = false;
+    let mut seen_gpx = false;
+    let mut pos = 0;
+    let mut complete = (0, Vec::new());
+
+    for line in data.split(|b| *b == b'\n') {
+        // The last line was never finished
+        if pos + line.len() >= data.len() {
+            break;
+        }
+        pos += line.len() + 1;
+
+        // After a power cut the end of the file can contain garbage
+        let line = match std::str::from_utf8(line) {
+            Ok(line) if !line.contains('\0') => line.trim(),
+            _ => break,
+        };
+
+        if in_gpx_tag {
+            // The gpx start tag is split over multiple lines
+            if line.ends_with('>') {
+                in_gpx_tag = false;
+                seen_gpx = true;
+                open.push("gpx".to_string());
+            }
+        } else if let Some((name, closing)) = line_element(line) {
+            if closing {
+                if open.last().map(|e| e.as_str()) != Some(name) {
+                    // This doesn't match what we wrote
+                    break;
+                }
+                open.pop();
+            } else if name == "gpx" && !line.ends_with('>') {
+                in_gpx_tag = true;
+            } else {
+                seen_gpx |= name == "gpx";
+                open.push(name.to_string());
+            }
+        }
+
+        if !in_gpx_tag
+            && !open.is_empty()
+            && open.iter().all(|e| TRACK_ELEMENTS.contains(&e.as_str()))
+        {
+            complete = (pos, open.clone());
+        } else if seen_gpx && open.is_empty() {
+            // The file was closed
+            complete = (pos, Vec::new());
+        }
+    }
+
+    complete
+}
+
+/// Repair a GPX file that wasn't closed. Anything after the last complete
+/// track point is removed and the open elements are closed.
+pub fn repair(gpx: &Path) -> io::Result<()> {
+    let mut data = Vec::new();
+    File::open(gpx)?.read_to_end(&mut data)?;
+
+    let (length, open) = complete_length(&data);
+    if length == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "No GPX data to recover",
+        ));
+    }
+    if open.is_empty() && length == data.len() {
+        // The file is already complete
+        return Ok(());
+    }
+
+    let fd = OpenOptions::new().write(true).open(gpx)?;
+    fd.set_len(length as u64)?;
+    fd.sync_all()?;
+
+    let mut fd = OpenOptions::new().append(true).open(gpx)?;
+    for element in open.iter().rev() {
+        match element.as_str() {
+            "trkseg" => print::gpx_track_seg_stop(&mut fd)?,
+            "trk" => print::gpx_track_stop(&mut fd)?,
+            _ => print::gpx_stop(&mut fd)?,
+        }
+    }
+    fd.sync_all()
+}
+
+/// Repair the GPX files of all the journals in `dir`.
+/// Returns the GPX files that were repaired.
+fn recover_in(dir: &Path) -> Vec<PathBuf> {
+    let mut repaired = Vec::new();
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return repaired,
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("journal") {
+            continue;
+        }
+
+        let journal = match fs::read_to_string(&path) {
+            Ok(journal) => journal,
+            Err(e) => {
+                println!("Unable to read {:?}: {:?}", path, e);
+                continue;
+            }
+        };
+        let mut lines = journal.lines();
+        let gpx = lines.next().map(PathBuf::from);
+        let synced: u64 = lines.next().and_then(|l| l.parse().ok()).unwrap_or(0);
+
+        if let Some(gpx) = gpx.filter(|gpx| gpx.exists()) {
+            if let Ok(metadata) = fs::metadata(&gpx) {
+                if metadata.len() < synced {
+                    println!("{:?} is shorter than expected, data has been lost", gpx);
+                }
+            }
+
+            match repair(&gpx) {
+                Ok(()) => repaired.push(gpx),
+                Err(e) => println!("Unable to repair {:?}: {}", gpx, e),
+            }
+        }
+
+        if let Err(e) = fs::remove_file(&path) {
+            println!("Unable to remove {:?}: {:?}", path, e);
+        }
+    }
+
+    repaired
+}
+
+/// Repair any GPX files that weren't closed the last time
+/// DashSight was running.
+pub fn recover() {
+    if let Some(dir) = journal_dir() {
+        for gpx in recover_in(&dir) {
+            println!("Recovered {:?}", gpx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drive::read_track::get_track_points;
+    use crate::utils::GpsData;
+
+    fn write_points(fd: &mut File, count: usize) {
+        for i in 0..count {
+            let fix = GpsData {
+                lat: 37.706 + i as f64 * 0.0001,
+                lon: -122.42,
+                alt: 30.7,
+                status: 1,
+                time: format!("2020-10-17T14:49:{:02}.000Z", i),
+                speed: 12.5,
+                track: 206.4,
+                hdop: None,
+                sats: None,
+                error: None,
+            };
+            print::gpx_point_info(fd, &fix, None).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_complete_length() {
+        let gpx = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+                   <gpx version=\"1.1\" creator=\"DashSight\"\n\
+                   \txmlns=\"http://www.topografix.com/GPX/1/1\">\n\
+                   <metadata>\n\
+                   <link href=\"https://github.com/DashSight/DashSight\">\n\
+                   <text>DashSight</text>\n\
+                   </link>\n\
+                   </metadata>\n\
+                   <trk>\n\
+                   <name>test</name>\n\
+                   <trkseg>\n\
+                   <trkpt lat=\"37.7\" lon=\"-122.4\">\n\
+                   <ele>30.7</ele>\n\
+                   </trkpt>\n";
+        let truncated = format!("{}<trkpt lat=\"37.8\" lon=\"-122.4\">\n<ele>3", gpx);
+
+        assert_eq!(
+            complete_length(truncated.as_bytes()),
+            (
+                gpx.len(),
+                vec!["gpx".to_string(), "trk".to_string(), "trkseg".to_string()]
+            )
+        );
+
+        // Zeros left by a power cut
+        let mut zeros = gpx.as_bytes().to_vec();
+        zeros.extend_from_slice(&[0; 64]);
+        zeros.push(b'\n');
+        assert_eq!(complete_length(&zeros).0, gpx.len());
+
+        // Nothing after the gpx start tag can be used
+        assert_eq!(complete_length(&gpx.as_bytes()[..60]).0, 0);
+
+        let closed = format!("{}</trkseg>\n</trk>\n</gpx>\n", gpx);
+        assert_eq!(
+            complete_length(closed.as_bytes()),
+            (closed.len(), Vec::new())
+        );
+    }
+
+    #[test]
+    fn test_recover() {
+        let dir = std::env::temp_dir().join("dashsight-test-journal");
+        let gpx = std::env::temp_dir().join("dashsight-test-journal.gpx");
+
+        let mut fd = File::create(&gpx).unwrap();
+        let mut journal = Journal::create_in(&dir, &gpx).unwrap();
+
+        print::gpx_start(&mut fd).unwrap();
+        print::gpx_metadata(&mut fd).unwrap();
+        print::gpx_track_start(&mut fd, "test".to_string()).unwrap();
+        print::gpx_track_seg_start(&mut fd).unwrap();
+        write_points(&mut fd, 5);
+        journal.sync_now(&fd).unwrap();
+        write_points(&mut fd, 2);
+
+        // The power is cut in the middle of a point
+        fd.write_all(b"      <trkpt lat=\"37.7\" lon=\"-122.4\">\n        <ele>")
+            .unwrap();
+        drop(fd);
+        drop(journal);
+
+        assert!(!fs::read_to_string(&gpx).unwrap().ends_with("</gpx>\n"));
+
+        assert_eq!(recover_in(&dir), vec![fs::canonicalize(&gpx).unwrap()]);
+        let repaired = fs::read_to_string(&gpx).unwrap();
+        assert!(repaired.ends_with("      </trkpt>\n    </trkseg>\n  </trk>\n</gpx>\n"));
+        let points = get_track_points(File::open(&gpx).unwrap()).unwrap();
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0].len(), 7);
+
+        // The journal has been removed
+        assert!(recover_in(&dir).is_empty());
+
+        // A cleanly closed file doesn't need to be repaired
+        let mut fd = File::create(&gpx).unwrap();
+        let journal = Journal::create_in(&dir, &gpx).unwrap();
+        print::gpx_start(&mut fd).unwrap();
+        print::gpx_track_start(&mut fd, "test".to_string()).unwrap();
+        print::gpx_track_seg_start(&mut fd).unwrap();
+        write_points(&mut fd, 3);
+        print::gpx_track_seg_stop(&mut fd).unwrap();
+        print::gpx_track_stop(&mut fd).unwrap();
+        print::gpx_stop(&mut fd).unwrap();
+        journal.finish(&fd).unwrap();
+        assert!(recover_in(&dir).is_empty());
+
+        fs::remove_file(&gpx).unwrap();
+    }
+}
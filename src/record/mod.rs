@@ -15,5 +15,6 @@
  */
 
 pub mod info;
+pub mod journal;
 pub mod print;
 pub mod track;
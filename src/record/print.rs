@@ -14,16 +14,29 @@
  * limitations under the License.
  */
 
+use crate::drive::read_track::Coord;
+use crate::telemetry::TelemetrySnapshot;
+use crate::utils::GpsData;
 use std::fs::File;
 use std::io::Write;
 
+/// The namespace for the DashSight GPX extensions
+pub const DASHSIGHT_NAMESPACE: &str = "https://github.com/DashSight/DashSight/GpxExtensions/v1";
+
+/// The Garmin TrackPointExtension, for the speed and heading
+pub const GARMIN_TPX_NAMESPACE: &str = "http://www.garmin.com/xmlschemas/TrackPointExtension/v2";
+
 pub fn gpx_start(fd: &mut File) -> Result<(), std::io::Error> {
     fd.write_all(b"<?xml version=\"1.0\" encoding=\"utf-8\"?>\n")?;
     fd.write_all(b"<gpx version=\"1.1\" creator=\"DashSight\"\n")?;
     fd.write_all(b"        xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\"\n")?;
-    fd.write_all(b"        xmlns=\"http://www.topografix.com/GPX/1.1\"\n")?;
-    fd.write_all(b"        xsi:schemaLocation=\"http://www.topografix.com/GPS/1/1\n")?;
-    fd.write_all(b"        http://www.topografix.com/GPX/1/1/gpx.xsd\">\n")?;
+    fd.write_all(b"        xmlns=\"http://www.topografix.com/GPX/1/1\"\n")?;
+    writeln!(fd, "        xmlns:gpxtpx=\"{}\"", GARMIN_TPX_NAMESPACE)?;
+    writeln!(fd, "        xmlns:dashsight=\"{}\"", DASHSIGHT_NAMESPACE)?;
+    fd.write_all(b"        xsi:schemaLocation=\"http://www.topografix.com/GPX/1/1\n")?;
+    fd.write_all(b"        http://www.topografix.com/GPX/1/1/gpx.xsd\n")?;
+    writeln!(fd, "        {}", GARMIN_TPX_NAMESPACE)?;
+    fd.write_all(b"        https://www8.garmin.com/xmlschemas/TrackPointExtensionv2.xsd\">\n")?;
     Ok(())
 }
 
@@ -52,18 +65,101 @@ pub fn gpx_track_stop(fd: &mut File) -> Result<(), std::io::Error> {
     Ok(())
 }
 
+/// Write a recorded point. The speed and heading go in the Garmin
+/// TrackPointExtension, the fix status, HDOP, satellite count, position
+/// error and telemetry in the DashSight namespace. The HDOP and satellite
+/// count are only written if the GPS reports them, which for gpsd is in
+/// its SKY messages (read by `utils::get_gps_lat_lon`).
 pub fn gpx_point_info(
     fd: &mut File,
-    lat: f64,
-    lon: f64,
-    alt: f32,
-    time: String,
-    heading: f32,
+    fix: &GpsData,
+    telemetry: Option<TelemetrySnapshot>,
 ) -> Result<(), std::io::Error> {
-    writeln!(fd, "      <trkpt lat=\"{}\" lon=\"{}\">", lat, lon)?;
-    writeln!(fd, "        <ele>{}</ele>", alt)?;
-    writeln!(fd, "        <time>{}</time>", time)?;
-    writeln!(fd, "        <degreesType>{}</degreesType>", heading)?;
+    writeln!(fd, "      <trkpt lat=\"{}\" lon=\"{}\">", fix.lat, fix.lon)?;
+    writeln!(fd, "        <ele>{}</ele>", fix.alt)?;
+    writeln!(fd, "        <time>{}</time>", fix.time)?;
+    writeln!(fd, "        <extensions>")?;
+    writeln!(fd, "          <gpxtpx:TrackPointExtension>")?;
+    writeln!(fd, "            <gpxtpx:speed>{}</gpxtpx:speed>", fix.speed)?;
+    writeln!(
+        fd,
+        "            <gpxtpx:course>{}</gpxtpx:course>",
+        fix.track
+    )?;
+    writeln!(fd, "          </gpxtpx:TrackPointExtension>")?;
+    writeln!(
+        fd,
+        "          <dashsight:status>{}</dashsight:status>",
+        fix.status
+    )?;
+    if let Some(hdop) = fix.hdop {
+        writeln!(fd, "          <dashsight:hdop>{}</dashsight:hdop>", hdop)?;
+    }
+    if let Some(sats) = fix.sats {
+        writeln!(fd, "          <dashsight:sats>{}</dashsight:sats>", sats)?;
+    }
+    if let Some(error) = fix.error {
+        writeln!(
+            fd,
+            "          <dashsight:positionError>{}</dashsight:positionError>",
+            error
+        )?;
+    }
+    if let Some(telemetry) = telemetry {
+        let values = [
+            ("rpm", telemetry.rpm),
+            ("throttle", telemetry.throttle),
+            ("engineLoad", telemetry.load),
+            ("vehicleSpeed", telemetry.speed),
+            ("lateralG", telemetry.lateral_g),
+            ("longitudinalG", telemetry.longitudinal_g),
+        ];
+
+        for (name, value) in values.iter() {
+            if let Some(value) = value {
+                writeln!(
+                    fd,
+                    "          <dashsight:{0}>{1}</dashsight:{0}>",
+                    name, value
+                )?;
+            }
+        }
+    }
+    writeln!(fd, "        </extensions>")?;
+    writeln!(fd, "      </trkpt>")?;
+    Ok(())
+}
+
+/// Write a point of a course, this only has the location,
+/// heading and size of the timing gate
+pub fn gpx_course_point(fd: &mut File, coord: &Coord) -> Result<(), std::io::Error> {
+    writeln!(
+        fd,
+        "      <trkpt lat=\"{}\" lon=\"{}\">",
+        coord.lat, coord.lon
+    )?;
+    writeln!(fd, "        <extensions>")?;
+    if let Some(head) = coord.head {
+        writeln!(fd, "          <gpxtpx:TrackPointExtension>")?;
+        writeln!(fd, "            <gpxtpx:course>{}</gpxtpx:course>", head)?;
+        writeln!(fd, "          </gpxtpx:TrackPointExtension>")?;
+    }
+    let settings = [
+        ("gateWidth", coord.gate.width),
+        ("gateDepth", coord.gate.depth),
+        ("gateTolerance", coord.gate.tolerance),
+        ("gateAngle", coord.gate.angle),
+    ];
+    for (name, value) in settings.iter() {
+        if let Some(value) = value {
+            writeln!(
+                fd,
+                "          <dashsight:{}>{}</dashsight:{}>",
+                name, value, name
+            )?;
+        }
+    }
+    writeln!(fd, "        </extensions>")?;
     writeln!(fd, "      </trkpt>")?;
     Ok(())
 }
@@ -77,3 +173,70 @@ pub fn gpx_track_seg_stop(fd: &mut File) -> Result<(), std::io::Error> {
     fd.write_all(b"    </trkseg>\n")?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drive::read_track::get_track_points;
+    use std::fs::OpenOptions;
+
+    #[test]
+    fn test_gpx_round_trip() {
+        let file_name = std::env::temp_dir().join("dashsight-test-print.gpx");
+        let mut fd = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&file_name)
+            .unwrap();
+
+        let fix = GpsData {
+            lat: 37.7060849,
+            lon: -122.4209836,
+            alt: 30.75,
+            status: 2,
+            time: "2020-10-17T14:49:10.000Z".to_string(),
+            speed: 12.5,
+            track: 206.4523,
+            hdop: Some(0.9),
+            sats: Some(11),
+            error: Some(1.5),
+        };
+
+        gpx_start(&mut fd).unwrap();
+        gpx_metadata(&mut fd).unwrap();
+        gpx_track_start(&mut fd, "test".to_string()).unwrap();
+        gpx_track_seg_start(&mut fd).unwrap();
+        let telemetry = TelemetrySnapshot {
+            rpm: Some(4250.0),
+            lateral_g: Some(-0.8),
+            ..Default::default()
+        };
+        gpx_point_info(&mut fd, &fix, Some(telemetry)).unwrap();
+        gpx_track_seg_stop(&mut fd).unwrap();
+        gpx_track_stop(&mut fd).unwrap();
+        gpx_stop(&mut fd).unwrap();
+
+        let track_points = get_track_points(File::open(&file_name).unwrap()).unwrap();
+        let point = &track_points[0][0];
+
+        assert_eq!(point.lat, fix.lat);
+        assert_eq!(point.lon, fix.lon);
+        assert_eq!(point.ele, Some(fix.alt));
+        assert_eq!(point.time, Some(fix.time));
+        assert_eq!(point.speed, Some(fix.speed));
+        assert_eq!(point.head, Some(fix.track));
+        assert_eq!(point.status, Some(fix.status));
+        assert_eq!(point.hdop, fix.hdop);
+        assert_eq!(point.sats, fix.sats);
+        assert_eq!(point.error, fix.error);
+
+        let gpx = std::fs::read_to_string(&file_name).unwrap();
+        assert!(gpx.contains("<dashsight:rpm>4250</dashsight:rpm>"));
+        assert!(gpx.contains("<dashsight:lateralG>-0.8</dashsight:lateralG>"));
+        assert!(!gpx.contains("<dashsight:throttle>"));
+
+        std::fs::remove_file(&file_name).unwrap();
+    }
+}
@@ -14,15 +14,84 @@
  * limitations under the License.
  */
 
+use crate::config::Config;
 use crate::display::DisplayRef;
+use crate::drive::can;
+use crate::drive::imu;
+use crate::drive::obdii;
+use crate::drive::replay;
+use crate::drive::threading::{Threading, ThreadingRef};
+use crate::gps_source::GpsSource;
 use crate::record::info::MapWrapper;
 use crate::record::info::RecordInfo;
 use crate::record::info::RecordInfoRef;
 use gtk::prelude::*;
+use std::cell::RefCell;
+use std::path::Path;
 use std::process;
 use std::sync::mpsc;
 use std::thread;
 
+type ImuSender = mpsc::Sender<(f64, f64, Option<f64>, Option<f64>)>;
+
+/// Start reading the OBDII and IMU data, so that it can be added
+/// to the recorded GPX file. Everything is also saved to a session
+/// log alongside `track_file`, the same as on the Drive page.
+fn spawn_telemetry_threads(
+    thread_info: &ThreadingRef,
+    config: &Config,
+    track_file: &Path,
+    obdii_tx: mpsc::Sender<obdii::OBDIIData>,
+    imu_tx: ImuSender,
+    imu_page_tx: ImuSender,
+) {
+    // The record page shows every channel, nothing is hidden
+    let (channels_tx, _) = mpsc::channel();
+    let (rates_tx, _) = mpsc::channel();
+    let (dtc_tx, _) = mpsc::channel();
+
+    if let GpsSource::Replay { file, speed } = &config.gps {
+        replay::spawn_replay_threads(
+            thread_info,
+            file,
+            *speed,
+            obdii_tx,
+            channels_tx,
+            imu_tx,
+            imu_page_tx,
+            None,
+        );
+    } else {
+        thread_info.start_session_log(&replay::side_file(track_file, "-session.dslog"));
+
+        if let Some(can_config) = config.can.clone() {
+            let thread_info_clone = thread_info.clone();
+            let obdii_tx = obdii_tx.clone();
+            thread::spawn(move || {
+                can::can_thread(thread_info_clone, obdii_tx, &can_config);
+            });
+        }
+
+        let thread_info_clone = thread_info.clone();
+        let config = config.clone();
+        thread::spawn(move || {
+            obdii::obdii_thread(
+                thread_info_clone,
+                obdii_tx,
+                channels_tx,
+                rates_tx,
+                dtc_tx,
+                &config,
+            );
+        });
+
+        let thread_info_clone = thread_info.clone();
+        thread::spawn(move || {
+            imu::imu_thread(thread_info_clone, imu_tx, imu_page_tx);
+        });
+    }
+}
+
 pub fn button_press_event(display: DisplayRef) {
     let builder = display.builder.clone();
 
@@ -71,10 +140,21 @@ pub fn button_press_event(display: DisplayRef) {
     record_page.pack1(&map_frame, true, true);
 
     let (location_tx, location_rx) = mpsc::channel::<(f64, f64, i32)>();
+    let (obdii_tx, obdii_rx) = mpsc::channel::<obdii::OBDIIData>();
+    let (imu_tx, imu_rx) = mpsc::channel::<(f64, f64, Option<f64>, Option<f64>)>();
+    let (imu_page_tx, imu_page_rx) = mpsc::channel::<(f64, f64, Option<f64>, Option<f64>)>();
     let rec_info = RecordInfo::new();
+    let config = Config::load();
     let mut map_wrapper = MapWrapper::new(champlain_view, path_layer, point);
     let mut first_connect = true;
 
+    // Only used if we are adding the OBDII and IMU data to the GPX file
+    let telemetry = if config.gpx_telemetry {
+        Some(Threading::new())
+    } else {
+        None
+    };
+
     let rec_info_weak = RecordInfoRef::downgrade(&rec_info);
     glib::timeout_add_local(10, move || {
         let rec_info = upgrade_weak!(rec_info_weak, glib::source::Continue(false));
@@ -83,19 +163,49 @@ pub fn button_press_event(display: DisplayRef) {
             return glib::source::Continue(false);
         }
 
+        // The OBDII and IMU data isn't displayed when recording
+        while obdii_rx.try_recv().is_ok() {}
+        while imu_rx.try_recv().is_ok() {}
+        while imu_page_rx.try_recv().is_ok() {}
+
         rec_info.idle_thread(&location_rx, &mut map_wrapper, &mut first_connect)
     });
 
+    // The replay files are already known, so start those straight away.
+    // Otherwise wait until we know where to save the data.
+    let telemetry_tx = RefCell::new(Some((obdii_tx, imu_tx, imu_page_tx)));
+    if let (Some(thread_info), GpsSource::Replay { file, .. }) = (&telemetry, &config.gps) {
+        if let Some((obdii_tx, imu_tx, imu_page_tx)) = telemetry_tx.borrow_mut().take() {
+            spawn_telemetry_threads(thread_info, &config, file, obdii_tx, imu_tx, imu_page_tx);
+        }
+    }
+
     let file_picker_button = builder
         .get_object::<gtk::Button>("RecordFileSaveButton")
         .expect("Can't find RecordFileSaveButton in ui file.");
 
     let display_weak = DisplayRef::downgrade(&display);
     let rec_info_weak = RecordInfoRef::downgrade(&rec_info);
+    let telemetry_clone = telemetry.clone();
+    let config_clone = config.clone();
     file_picker_button.connect_clicked(move |_| {
         let display = upgrade_weak!(display_weak);
         let rec_info = upgrade_weak!(rec_info_weak);
-        rec_info.file_picker_clicked(display);
+
+        if let Some(track_file) = rec_info.file_picker_clicked(display) {
+            if let Some(thread_info) = &telemetry_clone {
+                if let Some((obdii_tx, imu_tx, imu_page_tx)) = telemetry_tx.borrow_mut().take() {
+                    spawn_telemetry_threads(
+                        thread_info,
+                        &config_clone,
+                        &track_file,
+                        obdii_tx,
+                        imu_tx,
+                        imu_page_tx,
+                    );
+                }
+            }
+        }
     });
 
     let record_button = builder
@@ -109,9 +219,10 @@ pub fn button_press_event(display: DisplayRef) {
     });
 
     let rec_info_weak = RecordInfoRef::downgrade(&rec_info);
+    let telemetry_clone = telemetry.clone();
     let _handler = thread::spawn(move || {
         let rec_info = rec_info_weak.upgrade().unwrap();
-        rec_info.run(location_tx)
+        rec_info.run(location_tx, &config.gps, telemetry_clone)
     });
 
     let back_button = builder
@@ -125,6 +236,10 @@ pub fn button_press_event(display: DisplayRef) {
         let rec_info = RecordInfoRef::downgrade(&rec_info_clone).upgrade().unwrap();
         rec_info.close.lock().unwrap().set(true);
 
+        if let Some(thread_info) = &telemetry {
+            thread_info.close.lock().unwrap().set(true);
+        }
+
         // handler.join().unwrap();
 
         stack.set_visible_child_name("SplashImage");
@@ -0,0 +1,677 @@
+/*
+ * Copyright 2020 Alistair Francis <alistair@alistair23.me>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *    http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The session log records everything from a drive in a single file.
+//!
+//! The file starts with a header:
+//!     "DSLOG\0", version (u16), start time (u64, us since the Unix Epoch)
+//! followed by records:
+//!     length (u16), channel (u16), time (u64, us since the start),
+//!     data (length bytes), CRC32 of everything before it (u32)
+//! All values are little endian. The times come from a monotonic
+//! clock, so they aren't affected by changes to the system time.
+//!
+//! Records are only ever appended. If we lose power part way through
+//! writing a record the CRC won't match and we stop reading there,
+//! so everything before it can still be used.
+
+use crate::drive::dtc;
+use crate::drive::obdii::{OBDIICommandType, OBDIIData};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const MAGIC: &[u8; 6] = b"DSLOG\0";
+const VERSION: u16 = 1;
+
+/// The length, channel and time of a record
+const RECORD_HEADER_LEN: usize = 12;
+
+/// What a record contains
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+    /// lat, lon, alt, speed, track, status, fix time (s since the epoch),
+    /// hdop, sats, error (m). Older logs only have the first six.
+    Gps,
+    /// accel x, y, z then gyro x, y, z
+    Imu,
+    /// One value per sensor, in degrees Celsius
+    Temperature,
+    /// lap number, lap time (s)
+    Lap,
+    /// sector number, sector time (s)
+    Sector,
+    /// The raw bytes returned by the car for the mode 1 PID
+    Obdii(u8),
+    /// The mode 1 PIDs polled from the car, one byte each
+    ObdiiPids,
+    /// The mode used to read the DTCs (or 4 if they were cleared),
+    /// then two bytes for each code
+    Dtc,
+    /// The names of the custom PIDs, separated by new lines
+    CustomNames,
+    /// The decoded value of a custom PID, by its index
+    Custom(u8),
+    /// The names of the CAN signals, separated by new lines
+    CanNames,
+    /// The decoded value of a CAN signal, by its index
+    Can(u16),
+}
+
+impl Channel {
+    fn id(self) -> u16 {
+        match self {
+            Channel::Gps => 1,
+            Channel::Imu => 2,
+            Channel::Temperature => 3,
+            Channel::Lap => 4,
+            Channel::Sector => 5,
+            Channel::ObdiiPids => 6,
+            Channel::Dtc => 7,
+            Channel::CustomNames => 8,
+            Channel::CanNames => 9,
+            Channel::Obdii(pid) => 0x100 | pid as u16,
+            Channel::Custom(index) => 0x200 | index as u16,
+            Channel::Can(index) => 0x1000 | index,
+        }
+    }
+
+    fn from_id(id: u16) -> Option<Channel> {
+        match id {
+            1 => Some(Channel::Gps),
+            2 => Some(Channel::Imu),
+            3 => Some(Channel::Temperature),
+            4 => Some(Channel::Lap),
+            5 => Some(Channel::Sector),
+            6 => Some(Channel::ObdiiPids),
+            7 => Some(Channel::Dtc),
+            8 => Some(Channel::CustomNames),
+            9 => Some(Channel::CanNames),
+            0x100..=0x1FF => Some(Channel::Obdii(id as u8)),
+            0x200..=0x2FF => Some(Channel::Custom(id as u8)),
+            0x1000..=0x1FFF => Some(Channel::Can(id & 0xFFF)),
+            _ => None,
+        }
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+/// Convert a list of values into the data for a record
+fn encode_values(values: &[f64]) -> Vec<u8> {
+    values
+        .iter()
+        .flat_map(|v| v.to_le_bytes().to_vec())
+        .collect()
+}
+
+/// How often the log is synced to disk
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct SessionLog {
+    file: File,
+    start: Instant,
+    last_sync: Instant,
+}
+
+impl SessionLog {
+    /// Create a new session log, replacing `path` if it exists
+    pub fn create(path: &Path) -> io::Result<SessionLog> {
+        let mut file = File::create(path)?;
+        let start_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.extend_from_slice(&(start_time.as_micros() as u64).to_le_bytes());
+        file.write_all(&header)?;
+
+        Ok(SessionLog {
+            file,
+            start: Instant::now(),
+            last_sync: Instant::now(),
+        })
+    }
+
+    /// Append a record with the raw `data`
+    pub fn write(&mut self, channel: Channel, data: &[u8]) -> io::Result<()> {
+        if data.len() > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Record is too long",
+            ));
+        }
+        let time = self.start.elapsed().as_micros() as u64;
+
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + data.len() + 4);
+        record.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        record.extend_from_slice(&channel.id().to_le_bytes());
+        record.extend_from_slice(&time.to_le_bytes());
+        record.extend_from_slice(data);
+        record.extend_from_slice(&crc32(&record).to_le_bytes());
+
+        // Write the whole record at once, so a crash can only
+        // damage the last record.
+        self.file.write_all(&record)?;
+
+        if self.last_sync.elapsed() >= SYNC_INTERVAL {
+            self.file.sync_data()?;
+            self.last_sync = Instant::now();
+        }
+
+        Ok(())
+    }
+
+    /// Append a record containing `values`
+    pub fn write_values(&mut self, channel: Channel, values: &[f64]) -> io::Result<()> {
+        self.write(channel, &encode_values(values))
+    }
+}
+
+pub struct Record {
+    /// The time since the start of the session
+    pub time: Duration,
+    pub channel: Channel,
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    /// The data as a list of values
+    pub fn values(&self) -> Vec<f64> {
+        self.data
+            .chunks_exact(8)
+            .map(|v| {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(v);
+                f64::from_le_bytes(bytes)
+            })
+            .collect()
+    }
+}
+
+/// Reads the records from a session log.
+/// The iterator stops at the end of the file, or at the first
+/// damaged record.
+pub struct SessionReader<R: Read> {
+    reader: R,
+    /// When the session started, since the Unix Epoch
+    pub start_time: Duration,
+}
+
+impl SessionReader<BufReader<File>> {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        SessionReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> SessionReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; 16];
+        reader.read_exact(&mut header)?;
+
+        if &header[0..6] != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a DashSight session log",
+            ));
+        }
+
+        let version = u16::from_le_bytes([header[6], header[7]]);
+        if version != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported session log version: {}", version),
+            ));
+        }
+
+        let mut start_time = [0; 8];
+        start_time.copy_from_slice(&header[8..16]);
+
+        Ok(SessionReader {
+            reader,
+            start_time: Duration::from_micros(u64::from_le_bytes(start_time)),
+        })
+    }
+
+    fn read_record(&mut self) -> Option<(u16, u64, Vec<u8>)> {
+        let mut header = [0; RECORD_HEADER_LEN];
+        self.reader.read_exact(&mut header).ok()?;
+
+        let len = u16::from_le_bytes([header[0], header[1]]) as usize;
+        let channel = u16::from_le_bytes([header[2], header[3]]);
+        let mut time = [0; 8];
+        time.copy_from_slice(&header[4..12]);
+
+        let mut data = vec![0; len + 4];
+        self.reader.read_exact(&mut data).ok()?;
+        let crc = data.split_off(len);
+
+        let mut record = header.to_vec();
+        record.extend_from_slice(&data);
+        if crc32(&record).to_le_bytes() != crc[..] {
+            println!("Damaged record in the session log, stopping");
+            return None;
+        }
+
+        Some((channel, u64::from_le_bytes(time), data))
+    }
+}
+
+impl<R: Read> Iterator for SessionReader<R> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        loop {
+            let (channel, time, data) = self.read_record()?;
+
+            // Skip anything from a newer version we don't understand
+            if let Some(channel) = Channel::from_id(channel) {
+                return Some(Record {
+                    time: Duration::from_micros(time),
+                    channel,
+                    data,
+                });
+            }
+        }
+    }
+}
+
+/// A CSV file created by `convert()`
+struct CsvFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+/// Returns the CSV file called `<log>-<name>.csv`, creating it with
+/// `header` the first time it is used.
+fn csv_file<'a>(
+    files: &'a mut Vec<(String, CsvFile)>,
+    log: &Path,
+    name: &str,
+    header: &str,
+) -> io::Result<&'a mut BufWriter<File>> {
+    let index = match files.iter().position(|(n, _)| n == name) {
+        Some(index) => index,
+        None => {
+            let stem = log
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or("session");
+            let path = log.with_file_name(format!("{}-{}.csv", stem, name));
+            let mut writer = BufWriter::new(File::create(&path)?);
+            writeln!(writer, "time,utc,{}", header)?;
+
+            files.push((name.to_string(), CsvFile { path, writer }));
+            files.len() - 1
+        }
+    };
+
+    Ok(&mut files[index].1.writer)
+}
+
+/// Collects the OBDII values from one pass over the polled PIDs
+/// into a row of a CSV file
+struct ObdiiRow {
+    pids: Vec<u8>,
+    /// The column names
+    names: Vec<String>,
+    time: String,
+    values: Vec<Option<String>>,
+}
+
+impl ObdiiRow {
+    fn new(pids: Vec<u8>, names: Vec<String>) -> ObdiiRow {
+        ObdiiRow {
+            values: vec![None; pids.len()],
+            pids,
+            names,
+            time: String::new(),
+        }
+    }
+
+    /// A row for the mode 1 `pids`
+    fn mode1(pids: Vec<u8>) -> ObdiiRow {
+        let names = pids
+            .iter()
+            .map(|pid| match OBDIICommandType::from_pid(*pid) {
+                Some(command) => command.name().to_string(),
+                None => format!("PID_{:02X}", pid),
+            })
+            .collect();
+
+        ObdiiRow::new(pids, names)
+    }
+
+    fn header(&self) -> String {
+        self.names.join(",")
+    }
+
+    /// Add the value of `pid`. If the row already has a value for
+    /// `pid` a new pass has started, so the finished row is returned.
+    fn add(&mut self, time: &str, pid: u8, value: String) -> Option<String> {
+        let index = self.pids.iter().position(|p| *p == pid)?;
+
+        let row = if self.values[index].is_some() {
+            self.take()
+        } else {
+            None
+        };
+
+        if self.values.iter().all(|v| v.is_none()) {
+            self.time = time.to_string();
+        }
+        self.values[index] = Some(value);
+
+        row
+    }
+
+    /// Returns the row, if it has any values, and starts a new one
+    fn take(&mut self) -> Option<String> {
+        if self.values.iter().all(|v| v.is_none()) {
+            return None;
+        }
+
+        let values = self
+            .values
+            .iter_mut()
+            .map(|v| match v.take() {
+                // The fuel status can include commas
+                Some(v) if v.contains(',') => format!("\"{}\"", v),
+                Some(v) => v,
+                None => String::new(),
+            })
+            .collect::<Vec<String>>()
+            .join(",");
+
+        Some(format!("{},{}", self.time, values))
+    }
+}
+
+fn join(values: &[f64]) -> String {
+    values
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Convert a session log into CSV files, one per type of data,
+/// saved next to the log. Returns the files that were created.
+pub fn convert(log: &Path) -> io::Result<Vec<PathBuf>> {
+    let reader = SessionReader::open(log)?;
+    let start_time = reader.start_time;
+    let mut files: Vec<(String, CsvFile)> = Vec::new();
+    let mut obdii: Option<ObdiiRow> = None;
+    let mut custom: Option<ObdiiRow> = None;
+    let mut can_names: Vec<String> = Vec::new();
+
+    for record in reader {
+        let utc: chrono::DateTime<chrono::Utc> = (UNIX_EPOCH + start_time + record.time).into();
+        let time = format!(
+            "{:.6},{}",
+            record.time.as_secs_f64(),
+            utc.format("%Y-%m-%dT%H:%M:%S%.6fZ")
+        );
+        let values = record.values();
+
+        match record.channel {
+            Channel::Gps => {
+                let fd = csv_file(
+                    &mut files,
+                    log,
+                    "gps",
+                    "lat,lon,alt,speed,track,status,fix_time,hdop,sats,error",
+                )?;
+                writeln!(fd, "{},{}", time, join(&values))?;
+            }
+            Channel::Imu => {
+                let fd = csv_file(
+                    &mut files,
+                    log,
+                    "imu",
+                    "accel_x,accel_y,accel_z,gyro_x,gyro_y,gyro_z",
+                )?;
+                writeln!(fd, "{},{}", time, join(&values))?;
+            }
+            Channel::Temperature => {
+                let header = (1..=values.len())
+                    .map(|i| format!("temp_{}", i))
+                    .collect::<Vec<String>>()
+                    .join(",");
+                let fd = csv_file(&mut files, log, "temperature", &header)?;
+                writeln!(fd, "{},{}", time, join(&values))?;
+            }
+            Channel::Lap => {
+                let fd = csv_file(&mut files, log, "laps", "lap,lap_time,suspect")?;
+                writeln!(fd, "{},{}", time, join(&values))?;
+            }
+            Channel::Sector => {
+                let fd = csv_file(&mut files, log, "sectors", "sector,sector_time")?;
+                writeln!(fd, "{},{}", time, join(&values))?;
+            }
+            Channel::Dtc => {
+                if let Some((event, codes)) = dtc::decode_log_record(&record.data) {
+                    let codes = codes
+                        .iter()
+                        .map(|c| c.to_string())
+                        .collect::<Vec<String>>()
+                        .join(" ");
+                    let fd = csv_file(&mut files, log, "dtc", "event,codes")?;
+                    writeln!(fd, "{},{},{}", time, event, codes)?;
+                }
+            }
+            Channel::ObdiiPids => {
+                // The columns can't change once the file is created
+                if obdii.is_none() {
+                    obdii = Some(ObdiiRow::mode1(record.data.clone()));
+                }
+            }
+            Channel::Obdii(pid) => {
+                // Older logs don't say what was polled, they polled
+                // everything.
+                let row = obdii.get_or_insert_with(|| {
+                    let pids = OBDIICommandType::all().iter().map(|c| c.pid()).collect();
+                    ObdiiRow::mode1(pids)
+                });
+                let value = OBDIICommandType::from_pid(pid)
+                    .and_then(|c| OBDIIData::decode(c, &record.data))
+                    .map(|data| data.to_string())
+                    .unwrap_or_default();
+
+                if let Some(line) = row.add(&time, pid, value) {
+                    let fd = csv_file(&mut files, log, "obdii", &row.header())?;
+                    writeln!(fd, "{}", line)?;
+                }
+            }
+            Channel::CustomNames => {
+                if custom.is_none() {
+                    let names: Vec<String> = String::from_utf8_lossy(&record.data)
+                        .split('\n')
+                        .map(|n| n.to_string())
+                        .collect();
+                    let indexes = (0..names.len()).map(|i| i as u8).collect();
+                    custom = Some(ObdiiRow::new(indexes, names));
+                }
+            }
+            Channel::Custom(index) => {
+                // Without the names we don't know the columns
+                if let (Some(row), Some(value)) = (custom.as_mut(), values.first()) {
+                    if let Some(line) = row.add(&time, index, value.to_string()) {
+                        let fd = csv_file(&mut files, log, "custom", &row.header())?;
+                        writeln!(fd, "{}", line)?;
+                    }
+                }
+            }
+            Channel::CanNames => {
+                if can_names.is_empty() {
+                    can_names = String::from_utf8_lossy(&record.data)
+                        .split('\n')
+                        .map(|n| n.to_string())
+                        .collect();
+                }
+            }
+            Channel::Can(index) => {
+                // The signals arrive at different rates, so each
+                // value gets its own row
+                if let (Some(name), Some(value)) = (can_names.get(index as usize), values.first()) {
+                    let fd = csv_file(&mut files, log, "can", "signal,value")?;
+                    writeln!(fd, "{},{},{}", time, name, value)?;
+                }
+            }
+        }
+    }
+
+    // Write the last rows
+    for (name, row) in [("obdii", obdii), ("custom", custom)] {
+        if let Some(mut row) = row {
+            if let Some(line) = row.take() {
+                let fd = csv_file(&mut files, log, name, &row.header())?;
+                writeln!(fd, "{}", line)?;
+            }
+        }
+    }
+
+    let mut paths = Vec::new();
+    for (_, mut file) in files {
+        file.writer.flush()?;
+        paths.push(file.path);
+    }
+
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_read() {
+        let path = std::env::temp_dir().join("dashsight-test-session.dslog");
+        let mut log = SessionLog::create(&path).unwrap();
+
+        log.write_values(Channel::Gps, &[37.706, -122.42, 30.7, 12.5, 206.4, 1.0])
+            .unwrap();
+        log.write(Channel::Obdii(0x0C), &[0x1A, 0xF8]).unwrap();
+        log.write_values(Channel::Lap, &[1.0, 62.1]).unwrap();
+
+        let records: Vec<Record> = SessionReader::open(&path).unwrap().collect();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].channel, Channel::Gps);
+        assert_eq!(records[0].values()[1], -122.42);
+        assert_eq!(records[1].channel, Channel::Obdii(0x0C));
+        assert_eq!(records[1].data, vec![0x1A, 0xF8]);
+        assert_eq!(records[2].values(), vec![1.0, 62.1]);
+        assert!(records[2].time >= records[0].time);
+
+        // Lose the end of the last record, like a power cut would
+        let data = std::fs::read(&path).unwrap();
+        let records: Vec<Record> = SessionReader::new(&data[..data.len() - 3])
+            .unwrap()
+            .collect();
+        assert_eq!(records.len(), 2);
+
+        // Damage the second record
+        let mut damaged = data.clone();
+        let second = 16 + RECORD_HEADER_LEN + 6 * 8 + 4 + RECORD_HEADER_LEN;
+        damaged[second] ^= 0xFF;
+        let records: Vec<Record> = SessionReader::new(&damaged[..]).unwrap().collect();
+        assert_eq!(records.len(), 1);
+
+        assert!(SessionReader::new(&b"not a session log"[..]).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_convert() {
+        let path = std::env::temp_dir().join("dashsight-test-convert.dslog");
+        let mut log = SessionLog::create(&path).unwrap();
+
+        log.write_values(Channel::Imu, &[0.5, -0.25, 9.8, 0.0, 0.0, 0.1])
+            .unwrap();
+        log.write(Channel::ObdiiPids, &[0x0C, 0x03]).unwrap();
+        log.write(Channel::Obdii(0x0C), &[0x1A, 0xF8]).unwrap();
+        log.write(Channel::Obdii(0x03), &[0x02, 0x00]).unwrap();
+        log.write(Channel::Obdii(0x0C), &[0x1B, 0x00]).unwrap();
+        log.write(Channel::Dtc, &[0x03, 0x01, 0x33, 0x01, 0x71])
+            .unwrap();
+        log.write(Channel::CustomNames, b"OIL_TEMP\nBOOST").unwrap();
+        log.write_values(Channel::Custom(1), &[85.5]).unwrap();
+        log.write_values(Channel::Custom(0), &[104.0]).unwrap();
+        log.write(Channel::CanNames, b"WheelSpeedFL\nSteeringAngle")
+            .unwrap();
+        log.write_values(Channel::Can(1), &[-90.0]).unwrap();
+        log.write_values(Channel::Can(0), &[100.25]).unwrap();
+
+        let mut files = convert(&path).unwrap();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                path.with_file_name("dashsight-test-convert-can.csv"),
+                path.with_file_name("dashsight-test-convert-custom.csv"),
+                path.with_file_name("dashsight-test-convert-dtc.csv"),
+                path.with_file_name("dashsight-test-convert-imu.csv"),
+                path.with_file_name("dashsight-test-convert-obdii.csv")
+            ]
+        );
+
+        let can = std::fs::read_to_string(&files[0]).unwrap();
+        let lines: Vec<&str> = can.lines().collect();
+        assert_eq!(lines[0], "time,utc,signal,value");
+        assert!(lines[1].ends_with(",SteeringAngle,-90"));
+        assert!(lines[2].ends_with(",WheelSpeedFL,100.25"));
+
+        let custom = std::fs::read_to_string(&files[1]).unwrap();
+        let lines: Vec<&str> = custom.lines().collect();
+        assert_eq!(lines[0], "time,utc,OIL_TEMP,BOOST");
+        assert!(lines[1].ends_with(",104,85.5"));
+
+        let dtc = std::fs::read_to_string(&files[2]).unwrap();
+        let lines: Vec<&str> = dtc.lines().collect();
+        assert_eq!(lines[0], "time,utc,event,codes");
+        assert!(lines[1].ends_with(",stored,P0133 P0171"));
+
+        let obdii = std::fs::read_to_string(&files[4]).unwrap();
+        let lines: Vec<&str> = obdii.lines().collect();
+        assert_eq!(lines[0], "time,utc,RPM,FUEL_STATUS");
+        assert!(lines[1].contains(",1726,\"Closed loop"));
+        assert!(lines[2].ends_with(",1728,"));
+        assert_eq!(lines.len(), 3);
+
+        for file in files {
+            std::fs::remove_file(file).unwrap();
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
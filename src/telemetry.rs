@@ -0,0 +1,204 @@
+/*
+ * Copyright 2020 Alistair Francis <alistair@alistair23.me>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *    http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::drive::obdii::{OBDIICommandType, OBDIIData};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Samples further than this from a GPS fix aren't attached to it.
+/// A full round of OBDII requests can take over a second.
+const MAX_SAMPLE_AGE: Duration = Duration::from_secs(2);
+
+/// How long samples are kept for. This is longer than `MAX_SAMPLE_AGE`
+/// as the fix can arrive a while after it was taken.
+const HISTORY: Duration = Duration::from_secs(4);
+
+/// The acceleration of gravity, used to convert the IMU data into g
+const GRAVITY: f64 = 9.8;
+
+#[derive(Clone, Copy)]
+struct Sample {
+    value: f64,
+    time: Instant,
+}
+
+/// How far apart `a` and `b` are
+fn apart(a: Instant, b: Instant) -> Duration {
+    a.saturating_duration_since(b)
+        .max(b.saturating_duration_since(a))
+}
+
+/// The recent samples of a value, oldest first
+#[derive(Default)]
+struct History(VecDeque<Sample>);
+
+impl History {
+    fn push(&mut self, value: f64, time: Instant) {
+        while let Some(oldest) = self.0.front() {
+            if time.saturating_duration_since(oldest.time) <= HISTORY {
+                break;
+            }
+            self.0.pop_front();
+        }
+
+        self.0.push_back(Sample { value, time });
+    }
+
+    /// The sample closest to `time`, if there is one within `MAX_SAMPLE_AGE`
+    fn nearest(&self, time: Instant) -> Option<f64> {
+        self.0
+            .iter()
+            .min_by_key(|s| apart(s.time, time))
+            .filter(|s| apart(s.time, time) <= MAX_SAMPLE_AGE)
+            .map(|s| s.value)
+    }
+}
+
+/// The recent OBDII and IMU values, so they can be written out with
+/// each GPS fix. Each fix gets the samples closest to when it was
+/// taken, up to `MAX_SAMPLE_AGE` away, rather than interpolating
+/// between them. With the OBDII polling rates they can still be a
+/// few hundred milliseconds apart.
+#[derive(Default)]
+pub struct Telemetry {
+    rpm: History,
+    throttle: History,
+    load: History,
+    speed: History,
+    lateral_g: History,
+    longitudinal_g: History,
+}
+
+/// The telemetry values at a point in time
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TelemetrySnapshot {
+    pub rpm: Option<f64>,
+    /// Throttle position, in %
+    pub throttle: Option<f64>,
+    /// Engine load, in %
+    pub load: Option<f64>,
+    /// Vehicle speed from the car, in km/h
+    pub speed: Option<f64>,
+    /// Lateral acceleration, in g
+    pub lateral_g: Option<f64>,
+    /// Longitudinal acceleration, in g
+    pub longitudinal_g: Option<f64>,
+}
+
+impl Telemetry {
+    pub fn update_obdii(&mut self, data: &OBDIIData) {
+        self.update_obdii_at(data, Instant::now());
+    }
+
+    fn update_obdii_at(&mut self, data: &OBDIIData, time: Instant) {
+        let value = match data.val_float {
+            Some(value) => value,
+            None => return,
+        };
+
+        match data.command {
+            OBDIICommandType::Rpm => self.rpm.push(value, time),
+            OBDIICommandType::Throttle => self.throttle.push(value, time),
+            OBDIICommandType::EngineLoad => self.load.push(value, time),
+            OBDIICommandType::Speed => self.speed.push(value, time),
+            _ => {}
+        }
+    }
+
+    /// Update the acceleration, `x` and `y` are the rotated
+    /// IMU data in m/s^2.
+    pub fn update_accel(&mut self, x: f64, y: f64) {
+        self.update_accel_at(x, y, Instant::now());
+    }
+
+    fn update_accel_at(&mut self, x: f64, y: f64, time: Instant) {
+        self.lateral_g.push(x / GRAVITY, time);
+        self.longitudinal_g.push(y / GRAVITY, time);
+    }
+
+    /// Returns the values closest to now
+    pub fn snapshot(&self) -> TelemetrySnapshot {
+        self.snapshot_at(Instant::now())
+    }
+
+    /// Returns the values closest to `time`, such as when a fix was taken
+    pub fn snapshot_at(&self, time: Instant) -> TelemetrySnapshot {
+        TelemetrySnapshot {
+            rpm: self.rpm.nearest(time),
+            throttle: self.throttle.nearest(time),
+            load: self.load.nearest(time),
+            speed: self.speed.nearest(time),
+            lateral_g: self.lateral_g.nearest(time),
+            longitudinal_g: self.longitudinal_g.nearest(time),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let mut telemetry = Telemetry::default();
+        assert_eq!(telemetry.snapshot(), TelemetrySnapshot::default());
+
+        telemetry.update_obdii(&OBDIIData::from_value(OBDIICommandType::Rpm, "4250").unwrap());
+        telemetry.update_obdii(&OBDIIData::from_value(OBDIICommandType::Speed, "87").unwrap());
+        telemetry
+            .update_obdii(&OBDIIData::from_value(OBDIICommandType::CoolantTemp, "90").unwrap());
+        telemetry.update_accel(4.9, -9.8);
+
+        let snapshot = telemetry.snapshot();
+        assert_eq!(snapshot.rpm, Some(4250.0));
+        assert_eq!(snapshot.speed, Some(87.0));
+        assert_eq!(snapshot.throttle, None);
+        assert_eq!(snapshot.lateral_g, Some(0.5));
+        assert_eq!(snapshot.longitudinal_g, Some(-1.0));
+
+        // Everything is too old to use
+        let later = Instant::now() + MAX_SAMPLE_AGE + Duration::from_millis(10);
+        assert_eq!(telemetry.snapshot_at(later), TelemetrySnapshot::default());
+    }
+
+    #[test]
+    fn test_nearest_sample() {
+        let mut telemetry = Telemetry::default();
+        let start = Instant::now();
+        let rpm = |value| OBDIIData::from_value(OBDIICommandType::Rpm, value).unwrap();
+
+        telemetry.update_obdii_at(&rpm("3000"), start);
+        telemetry.update_obdii_at(&rpm("3500"), start + Duration::from_millis(300));
+        telemetry.update_obdii_at(&rpm("4000"), start + Duration::from_millis(600));
+        for i in 0..40 {
+            let x = i as f64 * GRAVITY / 10.0;
+            telemetry.update_accel_at(x, 0.0, start + Duration::from_millis(i * 25));
+        }
+
+        // The fix was taken before the latest samples arrived
+        let fix = start + Duration::from_millis(250);
+        let snapshot = telemetry.snapshot_at(fix);
+        assert_eq!(snapshot.rpm, Some(3500.0));
+        assert_eq!(snapshot.lateral_g, Some(1.0));
+        assert_eq!(snapshot.longitudinal_g, Some(0.0));
+        assert_eq!(telemetry.snapshot_at(start).rpm, Some(3000.0));
+
+        // Old samples are dropped
+        telemetry.update_obdii_at(&rpm("5000"), start + HISTORY + Duration::from_millis(100));
+        assert_eq!(telemetry.rpm.0.len(), 3);
+        assert_eq!(telemetry.snapshot_at(start).rpm, Some(3500.0));
+    }
+}
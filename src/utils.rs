@@ -14,9 +14,11 @@
  * limitations under the License.
  */
 
-use gpsd_proto::{get_data, ResponseData};
+use crate::drive::gate::GateGeometry;
+use gpsd_proto::{get_data, Mode, ResponseData};
 use ncollide2d::shape::ConvexPolygon;
 use std::io;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
 #[macro_export]
 macro_rules! upgrade_weak {
@@ -31,10 +33,11 @@ macro_rules! upgrade_weak {
     };
 }
 
-pub fn lat_lon_comp(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> bool {
-    let earth = 6378.137; // Radius of earth in km
-    let error = 2.0; // Error range, in metres
+/// The radius of the Earth, in metres
+const EARTH_RADIUS: f64 = 6_378_137.0;
 
+/// Returns the distance between two points, in metres
+pub fn lat_lon_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
     let d_lat = (lat2 * std::f64::consts::PI / 180.0) - (lat1 * std::f64::consts::PI / 180.0);
     let d_lon = (lon2 * std::f64::consts::PI / 180.0) - (lon1 * std::f64::consts::PI / 180.0);
 
@@ -45,107 +48,151 @@ pub fn lat_lon_comp(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> bool {
 
     let c = 2.0 * a.sqrt().atan2((1.0 - a).sqrt());
 
-    let d = earth * c * 1000.0;
+    EARTH_RADIUS * c
+}
+
+/// Returns the initial bearing from the first point to the second,
+/// in degrees clockwise from north
+pub fn lat_lon_bearing(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let lat1 = lat1.to_radians();
+    let lat2 = lat2.to_radians();
+    let d_lon = (lon2 - lon1).to_radians();
 
-    d < error
+    let y = d_lon.sin() * lat2.cos();
+    let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * d_lon.cos();
+
+    (y.atan2(x).to_degrees() + 360.0) % 360.0
 }
 
-/// Generate a polygon based on the information provided.
-/// This takes the start or end latitude, longitude and heading
-/// to generate a polygon we use to determine if we have crossed
-/// the start or end.
-/// We ignore the curvature of the Earth to simplify this.
-pub fn genereate_polygon(lat: f64, lon: f64, track: f32) -> ConvexPolygon<f64> {
-    // Be lazy and assume
-    //    - 111,111 metres is 1 degree latitude
-    //    - 111,111 * cos(lat) meters is 1 degree longitude
-
-    let top_left_x = (5.0 * (track * std::f32::consts::PI / 180.0).cos())
-        - (3.5 * (track * std::f32::consts::PI / 180.0).sin());
-    let top_left_y = (5.0 * (track * std::f32::consts::PI / 180.0).sin())
-        + (3.5 * (track * std::f32::consts::PI / 180.0).cos());
-
-    let bot_left_x = (5.0 * (track * std::f32::consts::PI / 180.0).cos())
-        + (0.5 * (track * std::f32::consts::PI / 180.0).sin());
-    let bot_left_y = (5.0 * (track * std::f32::consts::PI / 180.0).sin())
-        - (0.5 * (track * std::f32::consts::PI / 180.0).cos());
-
-    let top_right_x = (5.0 * (track * std::f32::consts::PI / 180.0).cos())
-        + (3.5 * (track * std::f32::consts::PI / 180.0).sin());
-    let top_right_y = (5.0 * (track * std::f32::consts::PI / 180.0).sin())
-        - (3.5 * (track * std::f32::consts::PI / 180.0).cos());
-
-    let bot_right_x = (5.0 * (track * std::f32::consts::PI / 180.0).cos())
-        - (0.5 * (track * std::f32::consts::PI / 180.0).sin());
-    let bot_right_y = (5.0 * (track * std::f32::consts::PI / 180.0).sin())
-        + (0.5 * (track * std::f32::consts::PI / 180.0).cos());
-
-    let top_left = nalgebra::geometry::Point2::new(
-        lat + (top_left_y as f64 / 111111.0),
-        lon - (top_left_x as f64 / (111111.5 * lat.cos())),
-    );
-    let bot_left = nalgebra::geometry::Point2::new(
-        lat + (bot_left_y as f64 / 111111.0),
-        lon - (bot_left_x as f64 / (111111.5 * lat.cos())),
-    );
-    let top_right = nalgebra::geometry::Point2::new(
-        lat - (top_right_y as f64 / 111111.0),
-        lon + (top_right_x as f64 / (111111.5 * lat.cos())),
-    );
-    let bot_right = nalgebra::geometry::Point2::new(
-        lat - (bot_right_y as f64 / 111111.0),
-        lon + (bot_right_x as f64 / (111111.5 * lat.cos())),
-    );
-
-    let poly_points = vec![top_left, bot_left, bot_right, top_right];
-    ConvexPolygon::try_new(poly_points).unwrap()
+/// Returns the point `distance` metres from the first point, going in
+/// the direction `bearing` (in degrees clockwise from north)
+pub fn lat_lon_destination(lat: f64, lon: f64, bearing: f64, distance: f64) -> (f64, f64) {
+    let lat1 = lat.to_radians();
+    let bearing = bearing.to_radians();
+    let angle = distance / EARTH_RADIUS;
+
+    let lat2 = (lat1.sin() * angle.cos() + lat1.cos() * angle.sin() * bearing.cos()).asin();
+    let d_lon =
+        (bearing.sin() * angle.sin() * lat1.cos()).atan2(angle.cos() - lat1.sin() * lat2.sin());
+
+    (lat2.to_degrees(), lon + d_lon.to_degrees())
 }
 
-pub fn right_direction(recorded_heading: Option<f32>, current_heading: f32) -> bool {
-    match recorded_heading {
-        Some(rec) => {
-            if current_heading == 0.0 {
-                true
-            } else {
-                // Check overflow
-                if rec < 30.0 {
-                    if current_heading >= 0.0 && current_heading < rec + 30.0
-                        || current_heading < 360.0 && current_heading >= 330.0 + rec
-                    {
-                        return true;
-                    }
-
-                    return false;
-                }
+/// Convert a GPS time string (such as "2020-10-17T14:49:10.000Z")
+/// into the time since the Unix Epoch
+pub fn gps_time_to_duration(time: &str) -> Option<Duration> {
+    let time = chrono::DateTime::parse_from_rfc3339(time).ok()?;
 
-                if rec > 330.0 {
-                    if current_heading <= 360.0 && current_heading > rec - 30.0
-                        || current_heading > 0.0 && current_heading <= rec - 330.0
-                    {
-                        return true;
-                    }
+    if time.timestamp() < 0 {
+        return None;
+    }
 
-                    return false;
-                }
+    Some(Duration::new(
+        time.timestamp() as u64,
+        time.timestamp_subsec_nanos(),
+    ))
+}
 
-                if current_heading >= rec - 30.0 && current_heading <= rec + 30.0 {
-                    return true;
-                }
+/// Convert a time since the Unix Epoch into a GPS time string,
+/// the opposite of `gps_time_to_duration()`
+pub fn duration_to_gps_time(time: Duration) -> String {
+    let utc: chrono::DateTime<chrono::Utc> = (UNIX_EPOCH + time).into();
 
-                false
-            }
+    utc.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+/// Format a time as minutes:seconds:milliseconds
+pub fn format_time(time: Duration) -> String {
+    format!(
+        "{:02}:{:02}:{:03}",
+        time.as_secs() / 60,
+        time.as_secs() % 60,
+        time.subsec_millis()
+    )
+}
+
+/// Generate a polygon based on the information provided.
+/// This takes the latitude, longitude and heading of a start or end
+/// line and generates the gate around it, from the `geometry`.
+pub fn genereate_polygon(
+    lat: f64,
+    lon: f64,
+    track: f32,
+    geometry: &GateGeometry,
+) -> ConvexPolygon<f64> {
+    let poly_points = geometry
+        .corners(lat, lon, track)
+        .iter()
+        .map(|(lat, lon)| nalgebra::geometry::Point2::new(*lat, *lon))
+        .collect();
+
+    ConvexPolygon::try_new(poly_points).unwrap()
+}
+
+/// Check if `current_heading` is within `tolerance` degrees either side
+/// of the recorded heading. A current heading of 0 means we don't know
+/// which way we are going, so that is always the right direction.
+pub fn right_direction(
+    recorded_heading: Option<f32>,
+    current_heading: f32,
+    tolerance: f32,
+) -> bool {
+    match recorded_heading {
+        Some(rec) if current_heading != 0.0 => {
+            let diff = (current_heading - rec).rem_euclid(360.0);
+
+            diff.min(360.0 - diff) <= tolerance
         }
-        None => true,
+        _ => true,
     }
 }
 
-/// The GPS data
-/// (lat, lon, alt, status, time, speed, track (heading))
-type GpsData = (f64, f64, f32, i32, String, f32, f32);
+/// The GPS data for a single fix
+#[derive(Clone, Debug, PartialEq)]
+pub struct GpsData {
+    pub lat: f64,
+    pub lon: f64,
+    pub alt: f32,
+    /// 0 is no fix, 1 is a fix and 2 is a DGPS (or better) fix
+    pub status: i32,
+    pub time: String,
+    /// Speed in metres per second
+    pub speed: f32,
+    /// The heading, in degrees from north
+    pub track: f32,
+    /// Horizontal dilution of precision, if the GPS reports it
+    pub hdop: Option<f32>,
+    /// The number of satellites used for the fix, if the GPS reports it
+    pub sats: Option<u32>,
+    /// The estimated horizontal position error in metres,
+    /// if the GPS reports it
+    pub error: Option<f32>,
+}
+
+/// Why we didn't get a fix from the GPS
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GpsError {
+    /// Nothing usable was read, the next read might work
+    Read,
+    /// There is nothing more to read, such as at the end of a file
+    EndOfStream,
+}
+
+/// The fix quality from the latest gpsd SKY and GST messages, and
+/// when the last PPS message arrived. gpsd sends these separately
+/// from the TPV with the location.
+#[derive(Default)]
+pub struct GpsdState {
+    hdop: Option<f32>,
+    sats: Option<u32>,
+    error: Option<f32>,
+    pub pps: Option<Instant>,
+}
 
 /// Gets the relevent location/velocity data from the GPS device
-/// Returns latitude, longitude, altitude, time, speed and track
-pub fn get_gps_lat_lon(reader: &mut dyn io::BufRead) -> Result<GpsData, ()> {
+/// Returns latitude, longitude, altitude, time, speed and track, with
+/// the fix quality from `state`
+pub fn get_gps_lat_lon(reader: &mut dyn io::BufRead, state: &mut GpsdState) -> Result<GpsData, ()> {
     loop {
         let msg = get_data(reader);
         let gpsd_message = match msg {
@@ -160,24 +207,57 @@ pub fn get_gps_lat_lon(reader: &mut dyn io::BufRead) -> Result<GpsData, ()> {
             ResponseData::Tpv(t) => {
                 // Check if we have a longitude and latitude
                 if t.lat.is_some() && t.lon.is_some() && t.alt.is_some() {
+                    // gpsd leaves out the status for a normal fix,
+                    // the mode tells us if we have a fix at all
+                    let status = match t.mode {
+                        Mode::NoFix => 0,
+                        _ => t.status.filter(|status| *status > 0).unwrap_or(1),
+                    };
+
+                    // Use the GST error estimate if we have one,
+                    // otherwise the TPV error estimate
+                    let error = state.error.or_else(|| match (t.epx, t.epy) {
+                        (Some(epx), Some(epy)) => Some(epx.max(epy)),
+                        _ => None,
+                    });
+
                     // Return the longitude and latitude
                     // If we don't have a time (which apparently can happen)
                     // then return the Unix Epoch start time instead
-                    return Ok((
-                        t.lat.unwrap(),
-                        t.lon.unwrap(),
-                        t.alt.unwrap(),
-                        t.status.unwrap_or(0),
-                        t.time
+                    return Ok(GpsData {
+                        lat: t.lat.unwrap(),
+                        lon: t.lon.unwrap(),
+                        alt: t.alt.unwrap(),
+                        status,
+                        time: t
+                            .time
                             .unwrap_or_else(|| "1970-01-01T00:00:00.000Z".to_string()),
-                        t.speed.unwrap_or(0.0),
-                        t.track.unwrap_or(0.0),
-                    ));
+                        speed: t.speed.unwrap_or(0.0),
+                        track: t.track.unwrap_or(0.0),
+                        hdop: state.hdop,
+                        sats: state.sats,
+                        error,
+                    });
                 }
             }
-            ResponseData::Sky(_) => {}
-            ResponseData::Pps(_) => {}
-            ResponseData::Gst(_) => {}
+            ResponseData::Sky(sky) => {
+                state.hdop = sky.hdop;
+                state.sats = sky
+                    .satellites
+                    .map(|sats| sats.iter().filter(|sat| sat.used).count() as u32);
+            }
+            ResponseData::Pps(_) => {
+                // The pulse is at the start of a GPS second, we only
+                // need to know when it arrived
+                state.pps = Some(Instant::now());
+            }
+            ResponseData::Gst(gst) => {
+                // The standard deviation of the latitude and longitude errors
+                state.error = match (gst.lat, gst.lon) {
+                    (Some(lat), Some(lon)) => Some(lat.hypot(lon)),
+                    _ => None,
+                };
+            }
         }
     }
 }
@@ -209,7 +289,7 @@ mod tests {
 
     #[test]
     fn test_poly() {
-        let poly = genereate_polygon(37.32447900, -121.92460133, 45.0);
+        let poly = genereate_polygon(37.32447900, -121.92460133, 45.0, &GateGeometry::default());
 
         assert_eq!(
             poly.contains_point(
@@ -236,7 +316,7 @@ mod tests {
 
     #[test]
     fn test_poly2() {
-        let poly = genereate_polygon(37.3244322, -121.9245186, 109.1828);
+        let poly = genereate_polygon(37.3244322, -121.9245186, 109.1828, &GateGeometry::default());
 
         assert_eq!(
             poly.contains_point(
@@ -255,22 +335,70 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_distance() {
+        assert_eq!(
+            lat_lon_distance(37.3244321, -121.924479, 37.3244321, -121.924479),
+            0.0
+        );
+        let d = lat_lon_distance(37.7060849, -122.4209836, 37.7060763, -122.4209859);
+        assert!(d > 0.9 && d < 1.1);
+    }
+
+    #[test]
+    fn test_bearing() {
+        assert!(lat_lon_bearing(37.0, -122.0, 37.1, -122.0).abs() < 0.001);
+        assert!((lat_lon_bearing(37.0, -122.0, 37.0, -121.9) - 90.0).abs() < 0.1);
+        assert!((lat_lon_bearing(37.0, -122.0, 36.9, -122.0) - 180.0).abs() < 0.001);
+        assert!((lat_lon_bearing(37.0, -122.0, 37.0, -122.1) - 270.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_destination() {
+        let (lat, lon) = lat_lon_destination(37.0, -122.0, 30.0, 250.0);
+        assert!((lat_lon_distance(37.0, -122.0, lat, lon) - 250.0).abs() < 0.001);
+        assert!((lat_lon_bearing(37.0, -122.0, lat, lon) - 30.0).abs() < 0.001);
+
+        // 1 degree of latitude is about 111km
+        let (lat, lon) = lat_lon_destination(37.0, -122.0, 0.0, 111_319.5);
+        assert!((lat - 38.0).abs() < 0.0001);
+        assert!((lon + 122.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_gps_time() {
+        assert_eq!(
+            gps_time_to_duration("2020-10-17T14:49:10.250Z"),
+            Some(Duration::new(1602946150, 250_000_000))
+        );
+        assert_eq!(gps_time_to_duration("not a time"), None);
+
+        assert_eq!(
+            duration_to_gps_time(Duration::new(1602946150, 250_000_000)),
+            "2020-10-17T14:49:10.250Z"
+        );
+    }
+
     #[test]
     fn test_current_heading() {
         // We don't have enough information, just return true
-        assert_eq!(right_direction(None, 3.14), true);
-        assert_eq!(right_direction(Some(2.79), 0.0), true);
-
-        assert_eq!(right_direction(Some(15.0), 350.0), true);
-        assert_eq!(right_direction(Some(45.0), 45.0), true);
-        assert_eq!(right_direction(Some(45.0), 75.0), true);
-        assert_eq!(right_direction(Some(110.0), 130.0), true);
-        assert_eq!(right_direction(Some(350.0), 10.0), true);
-        assert_eq!(right_direction(Some(350.0), 20.0), true);
-
-        assert_eq!(right_direction(Some(15.0), 340.0), false);
-        assert_eq!(right_direction(Some(45.0), 1.0), false);
-        assert_eq!(right_direction(Some(110.0), 79.0), false);
-        assert_eq!(right_direction(Some(350.0), 21.0), false);
+        assert_eq!(right_direction(None, 3.14, 30.0), true);
+        assert_eq!(right_direction(Some(2.79), 0.0, 30.0), true);
+
+        assert_eq!(right_direction(Some(15.0), 350.0, 30.0), true);
+        assert_eq!(right_direction(Some(45.0), 45.0, 30.0), true);
+        assert_eq!(right_direction(Some(45.0), 75.0, 30.0), true);
+        assert_eq!(right_direction(Some(110.0), 130.0, 30.0), true);
+        assert_eq!(right_direction(Some(350.0), 10.0, 30.0), true);
+        assert_eq!(right_direction(Some(350.0), 20.0, 30.0), true);
+
+        assert_eq!(right_direction(Some(15.0), 340.0, 30.0), false);
+        assert_eq!(right_direction(Some(45.0), 1.0, 30.0), false);
+        assert_eq!(right_direction(Some(110.0), 79.0, 30.0), false);
+        assert_eq!(right_direction(Some(350.0), 21.0, 30.0), false);
+
+        // A narrower tolerance, for a tight hill climb
+        assert_eq!(right_direction(Some(350.0), 359.0, 10.0), true);
+        assert_eq!(right_direction(Some(350.0), 1.0, 10.0), false);
     }
 }